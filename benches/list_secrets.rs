@@ -0,0 +1,53 @@
+//! Times listing and finding secrets in a database with many secrets, e.g. after a big import.
+//! Run using cargo bench --bench list_secrets.
+
+use std::time::Instant;
+
+use tempfile::TempDir;
+use totpm::db::{model::Secret, with_db, SecretFilter, DB};
+
+const NUM_SECRETS: usize = 1000;
+const ITERATIONS: u32 = 100;
+
+fn bench(db: &DB, name: &str, filter: SecretFilter) {
+    let start = Instant::now();
+    let mut found = 0;
+    for _ in 0..ITERATIONS {
+        found = db.find_secrets(&filter).unwrap().len();
+    }
+    println!("{:<24} {:>5} secrets {:>12.3?} per iteration", name, found, start.elapsed() / ITERATIONS);
+}
+
+fn main() {
+    let tempdir = TempDir::new().unwrap();
+    let db_path = tempdir.path().join("secrets.sqlite");
+    with_db(&db_path, |db| {
+        for i in 0..NUM_SECRETS {
+            let mut secret = Secret::new(
+                format!("service{:04}.example.com", i),
+                format!("user{}", i % 10),
+                None,
+                None,
+                vec![0; 64],
+                vec![0; 128],
+            );
+            secret.tags = vec![format!("tag{}", i % 100)];
+            db.add_secret(secret)?;
+        }
+        Ok(())
+    }).unwrap();
+
+    println!("{} secrets, {} iterations", NUM_SECRETS, ITERATIONS);
+    with_db(&db_path, |db| {
+        bench(db, "list everything", SecretFilter::default());
+        bench(db, "substring match", SecretFilter { service: "0042", ..Default::default() });
+        bench(db, "exact match", SecretFilter {
+            service: "service0042.example.com",
+            account: "user2",
+            exact: true,
+            ..Default::default()
+        });
+        bench(db, "tag match", SecretFilter { tag: Some("tag42"), ..Default::default() });
+        Ok(())
+    }).unwrap();
+}
@@ -10,7 +10,7 @@ echo "NBSWY3DPNBSWY3DP" | totpm add --secret-on-stdin foo bar
 [ "$(totpm list)" == "foo (bar)" ]
 [ "$(totpm gen foo bar)" != "" ]
 
-totpm del foo bar
+totpm del --yes foo bar
 [ "$(totpm list)" == "" ]
 
 echo "NBSWY3DPNBSWY3DP" | totpm add --secret-on-stdin foo bar
@@ -0,0 +1,127 @@
+#ifndef TOTPM_H
+#define TOTPM_H
+
+/* Generated by cbindgen from src/ffi.rs by `make header`. Do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Outcome of a call through the C API. When a call fails, totpm_last_error describes why.
+ */
+typedef enum TotpmStatus {
+  TOTPM_STATUS_OK,
+  /**
+   * A pointer was null, or a string wasn't valid UTF-8.
+   */
+  TOTPM_STATUS_INVALID_ARGUMENT,
+  /**
+   * totpm isn't initialized, or the config file can't be read.
+   */
+  TOTPM_STATUS_NOT_INITIALIZED,
+  /**
+   * There is no secret with the given id.
+   */
+  TOTPM_STATUS_NOT_FOUND,
+  /**
+   * The user's presence could not be verified.
+   */
+  TOTPM_STATUS_PRESENCE_NOT_VERIFIED,
+  /**
+   * Anything else.
+   */
+  TOTPM_STATUS_FAILED,
+} TotpmStatus;
+
+/**
+ * An open secrets store, opaque to C.
+ */
+typedef struct TotpmStore TotpmStore;
+
+/**
+ * A secret, as listed by totpm_list. Strings are owned by the list they're in.
+ */
+typedef struct TotpmSecret {
+  int64_t id;
+  char *service;
+  char *account;
+} TotpmSecret;
+
+/**
+ * Secrets listed by totpm_list, which must be freed using totpm_free_list.
+ */
+typedef struct TotpmSecretList {
+  struct TotpmSecret *secrets;
+  size_t len;
+} TotpmSecretList;
+
+/**
+ * Returns a description of the last failed call on this thread, or null if none has failed.
+ * The string is valid until the next failed call on this thread.
+ */
+const char *totpm_last_error(void);
+
+/**
+ * Opens the secrets store configured in the config file at the given path, and stores it in *store.
+ * Presence is not verified until a code is generated. The calling process must be able to read
+ * the system data directory, so for system installs, use the D-Bus daemon instead.
+ *
+ * # Safety
+ * config_path must be a null-terminated string and store a valid pointer.
+ * The store must be closed using totpm_close.
+ */
+enum TotpmStatus totpm_open(const char *config_path, struct TotpmStore **store);
+
+/**
+ * Lists the secrets matching the given service and account, either of which may be null to match anything,
+ * and stores them in *list.
+ *
+ * Fails with InvalidArgument if service or account isn't valid UTF-8, and with Failed if a listed name
+ * contains a null character.
+ *
+ * # Safety
+ * store must have been opened by totpm_open, service and account must be null or null-terminated strings,
+ * and list must be a valid pointer. The list must be freed using totpm_free_list.
+ */
+enum TotpmStatus totpm_list(struct TotpmStore *store,
+                            const char *service,
+                            const char *account,
+                            struct TotpmSecretList *list);
+
+/**
+ * Verifies presence and generates a code for the secret with the given id, storing it in *code.
+ *
+ * # Safety
+ * store must have been opened by totpm_open, and code must be a valid pointer.
+ * The code must be freed using totpm_free_string.
+ */
+enum TotpmStatus totpm_gen(struct TotpmStore *store, int64_t id, char **code);
+
+/**
+ * Frees a string returned by totpm_gen. Does nothing if s is null.
+ *
+ * # Safety
+ * s must be null or returned by totpm_gen, and not freed before.
+ */
+void totpm_free_string(char *s);
+
+/**
+ * Frees the secrets in a list filled in by totpm_list, and empties the list.
+ *
+ * # Safety
+ * list must be null or filled in by totpm_list, and not freed before.
+ */
+void totpm_free_list(struct TotpmSecretList *list);
+
+/**
+ * Closes a store opened by totpm_open. Does nothing if store is null.
+ *
+ * # Safety
+ * store must be null or opened by totpm_open, and not closed before.
+ */
+void totpm_close(struct TotpmStore *store);
+
+#endif /* TOTPM_H */
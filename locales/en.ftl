@@ -0,0 +1,131 @@
+# Messages shown by totpm, in English. Every message must also be in each of the other .ftl files.
+
+error-code = error code: { $code }
+io-failed = an io operation failed: { $error }
+rerun-with-debug = try re-running the command with -vv for more information
+config-unreadable = unable to parse configuration file: { $error }
+config-unwritable = unable to write default configuration to file: { $error }
+user-not-found = user does not exist: { $user }
+secret-undecodable = unable to decode secret
+secret-file-insecure = refusing to read secret from { $path }, which other users can access
+secret-file-insecure-hint = make sure you own it and run chmod 600 { $path }
+secret-invalid = the secret is { $reason }
+context-file-invalid = unable to parse context file { $path }: { $error }
+unexpected-prompt = refusing to answer a prompt that doesn't ask for a one-time code: { $prompt }
+no-context = no service given and no { $file } file found in the current directory or its parents
+invalid-pv-method = invalid presence verification method: { $method }
+invalid-selection-method = invalid selection method: { $method }
+invalid-selection-method-hint = valid methods are interactive, filter, mru and fail_fast
+profile-not-found = profile not found in configuration file: { $name }
+root-required = root permissions required
+secret-not-found = service/account combination not found
+secret-ambiguous = more than one secret matched the given parameters
+import-failed = unable to import secrets: { $error }
+import-conflict = { $secret } already exists; nothing was imported
+import-conflict-hint = use --on-conflict skip, overwrite or duplicate to import anyway
+setup-cancelled = setup cancelled
+deletion-cancelled = nothing was deleted
+deletion-cancelled-hint = answer yes to the question, or pass --yes to delete without asking
+invalid-code = invalid code
+store-verification-failed = found { $count } { $count ->
+        [one] problem
+       *[other] problems
+    } in the secrets store
+doctor-checks-failed = { $count } { $count ->
+        [one] check
+       *[other] checks
+    } failed; see the hints above
+auto-type-unavailable = no tool for typing the code is installed; install wtype or ydotool for wayland, or xdotool for x11,
+auto-type-unavailable-hint = or set auto_type in the configuration file
+config-key-not-found = no such config key, or it isn't set: { $key }
+backup-invalid = unable to restore backup: { $reason }
+backup-key-handle-mismatch-hint = re-run the command with --force to restore it anyway
+backup-keys-not-loadable = { $count } { $count ->
+        [one] secret
+       *[other] secrets
+    } in the backup can't be used with the current primary key
+backup-keys-not-loadable-hint = backups can only be restored on the machine and tpm they were made with
+nothing-to-undo = nothing to undo
+shell-unterminated-quote = unterminated quote
+pcrs-not-configured = the given pcrs are not in the configuration file
+pcrs-not-configured-hint = add them to { $path } as e.g. 'pcrs = [7]' and re-run the command
+error-context = while { $context }:
+reference-mismatch = generated code { $code } does not match reference code { $reference }
+test-mode-unavailable = --test-seed is only available in debug builds
+secret-mismatch = the secrets did not match; nothing was added
+pin-mismatch = the pins did not match
+config-insecure = refusing to use { $path }, which can be modified by other users than root
+config-insecure-hint = make sure it is owned by root and not writable by anyone else, e.g. using 'chmod 644'
+store-not-initialized = the totp store is not initialized
+store-not-initialized-hint = initialize it by running 'totpm init' and then re-run the command
+store-already-initialized = the totp store is already initialized
+tcti-not-loadable = unable to load { $library }, which is needed to talk to the TPM
+tcti-not-loadable-hint = make sure that the { $package } package is installed
+invalid-tcti-forms = valid forms are:
+    { $forms }
+invalid-tcti-hint = run totpm init --probe-tpm to find out which tpm configurations work on this machine
+no-free-persistent-handle = no free persistent handle for the primary key in { $handles }
+no-free-persistent-handle-hint = free one up using tpm2_evictcontrol, or change persistent_handle in the configuration file
+pcr-policy-failed = the primary key is bound to pcrs which have changed since it was created
+pcr-policy-failed-warning = if you didn't update your firmware, bootloader or secure boot settings, your boot chain may have been tampered with
+pcr-policy-failed-hint = otherwise, boot the previous configuration to use your secrets again; see the README for how to update safely
+wrong-pin = wrong pin
+tpm-locked-out = the tpm is locked out after too many wrong pins
+tpm-locked-out-hint = wait for the lockout to expire and try again
+pv-policy-unavailable = the secret can only be used with the consent of a presence verifier, but none is configured
+pv-policy-unavailable-hint = add the [pv_policy] section it was added with to your config file
+pv-policy-failed = the tpm rejected the presence verifier's consent to use the secret
+pv-policy-failed-hint = the secret may have been added with another presence verifier key; restore the [pv_policy] section it was added with
+tpm-failed = a tpm operation failed: { $error }
+db-locked = the secrets database is locked by another totpm process
+db-locked-hint = if no other totpm process is running, remove { $path } and re-run the command
+db-too-new = the secrets database was created by a newer version of totpm (schema version { $version }, but this version only supports up to { $supported })
+db-too-new-hint = upgrade totpm to use it
+db-failed = a database operation failed: { $error }
+key-handle-corrupted = the primary key handle is corrupted and your secrets are permanently lost
+key-handle-corrupted-hint = you can reset the password store by running 'totpm clear' followed by 'totpm init'
+metadata-key-unavailable = the metadata of some secrets is encrypted and can only be read using the tpm
+metadata-key-unavailable-hint = set encrypt_metadata = true in your config file and re-run the command
+migration-target-unavailable = there is no migration target on this machine
+migration-target-unavailable-hint = run 'totpm migrate target' and export the secrets to the new target on the old machine
+migration-target-mismatch = the secrets were exported to a different migration target
+migration-target-mismatch-hint = export them again to the most recent migration target of this machine
+migration-target-not-approved = the migration target has not been approved on this machine
+migration-target-not-approved-hint = as root, run 'totpm migrate approve <target file>' and re-run the command
+migration-data-malformed = the migration target or exported secrets are corrupted
+keys-not-migratable = the keys of the following secrets are bound to the current primary key:
+keys-not-migratable-hint = re-add them with migratable_keys = true in your config file and re-run the command
+shared-primary-key = the primary key is shared with other users, whose secrets would become unusable
+shared-primary-key-hint = re-run the command with --force to rekey anyway
+metadata-not-recoverable = the secrets to recover have encrypted metadata, which can only be read using the tpm they were added with
+metadata-not-recoverable-hint = secrets added with encrypt_metadata = true can't be recovered from a secondary tpm
+pin-required = the secret is protected by a pin, which can only be entered interactively
+metadata-corrupted = the encrypted metadata of some secrets is corrupted
+worker-stopped = the thread serving the totp store has stopped
+config-conflict-hint = pass --local-config or --system-config to select a configuration explicitly
+backup-key-handle-mismatch = the backup was made under primary key handle { $backup_handle }, but the current primary key handle is { $current_handle }
+config-conflict = warning: using { $local }, which refers to a different TPM or primary key than { $system }
+fprintd-listen-failed = fprintd: unable to listen for signal
+fprintd-start-failed = fprintd: unable to start fingerprint verification
+fprintd-process-failed = fprintd: unable to process incoming signals
+fprintd-stop-failed = fprintd: unable to stop fingerprint verification
+fprintd-restart-failed = fprintd: unable to restart fingerprint verification
+fprintd-disconnected = fprintd: fingerprint reader disconnected
+fprintd-unknown-error = fprintd: fingerprint scan failed with unknown error
+fprintd-claim-failed = fprintd: unable to claim device
+fprintd-no-default-device = fprintd: couldn't get default device
+fprintd-bus-unavailable = fprintd: couldn't connect to bus
+fprintd-list-fingers-failed = fprintd: unable to list enrolled fingers: { $error }
+pv-place-finger = place your finger on the fingerprint reader
+pv-no-match = fingerprint not recognized, try again
+pv-retry = fingerprint not recognized, try again
+pv-swipe-too-short = swipe was too short, try again
+pv-finger-not-centered = finger was not centered on the reader, try again
+pv-remove-and-retry = remove your finger from the reader and try again
+pv-too-fast = finger was removed too quickly, try again
+pv-enter-pin = enter your totpm PIN:{ " " }
+pv-wrong-pin = wrong PIN, try again
+term-cancel = [cancel]
+term-invalid-selection = invalid selection
+term-confirm-choices = [y/N]
+term-yes-answers = y yes
@@ -0,0 +1,131 @@
+# Meddelanden som visas av totpm, på svenska.
+
+error-code = felkod: { $code }
+io-failed = en in/ut-operation misslyckades: { $error }
+rerun-with-debug = kör kommandot igen med -vv för mer information
+config-unreadable = kan inte tolka konfigurationsfilen: { $error }
+config-unwritable = kan inte skriva standardkonfigurationen till fil: { $error }
+user-not-found = användaren finns inte: { $user }
+secret-undecodable = kan inte avkoda hemligheten
+secret-file-insecure = vägrar läsa hemligheten från { $path }, som andra användare kan komma åt
+secret-file-insecure-hint = se till att du äger den och kör chmod 600 { $path }
+secret-invalid = hemligheten är ogiltig: { $reason }
+context-file-invalid = kan inte tolka kontextfilen { $path }: { $error }
+unexpected-prompt = vägrar svara på en fråga som inte efterfrågar en engångskod: { $prompt }
+no-context = ingen tjänst angiven, och ingen { $file }-fil hittades i den aktuella katalogen eller dess föräldrar
+invalid-pv-method = ogiltig metod för närvaroverifiering: { $method }
+invalid-selection-method = ogiltig urvalsmetod: { $method }
+invalid-selection-method-hint = giltiga metoder är interactive, filter, mru och fail_fast
+profile-not-found = profilen finns inte i konfigurationsfilen: { $name }
+root-required = root-behörighet krävs
+secret-not-found = kombinationen av tjänst och konto hittades inte
+secret-ambiguous = mer än en hemlighet matchade de angivna parametrarna
+import-failed = kan inte importera hemligheter: { $error }
+import-conflict = { $secret } finns redan; ingenting importerades
+import-conflict-hint = använd --on-conflict skip, overwrite eller duplicate för att importera ändå
+setup-cancelled = installationen avbröts
+deletion-cancelled = inget raderades
+deletion-cancelled-hint = svara ja på frågan, eller ange --yes för att radera utan att fråga
+invalid-code = ogiltig kod
+store-verification-failed = hittade { $count } { $count ->
+        [one] problem
+       *[other] problem
+    } i hemlighetslagret
+doctor-checks-failed = { $count } { $count ->
+        [one] kontroll
+       *[other] kontroller
+    } misslyckades; se tipsen ovan
+auto-type-unavailable = inget verktyg för att skriva koden är installerat; installera wtype eller ydotool för wayland, eller xdotool för x11,
+auto-type-unavailable-hint = eller ange auto_type i konfigurationsfilen
+config-key-not-found = konfigurationsnyckeln finns inte eller är inte satt: { $key }
+backup-invalid = kan inte återställa säkerhetskopian: { $reason }
+backup-key-handle-mismatch-hint = kör kommandot igen med --force för att återställa den ändå
+backup-keys-not-loadable = { $count } { $count ->
+        [one] hemlighet
+       *[other] hemligheter
+    } i säkerhetskopian kan inte användas med den nuvarande primärnyckeln
+backup-keys-not-loadable-hint = säkerhetskopior kan bara återställas på den maskin och tpm de gjordes med
+nothing-to-undo = inget att ångra
+shell-unterminated-quote = oavslutat citattecken
+pcrs-not-configured = de angivna pcr:erna finns inte i konfigurationsfilen
+pcrs-not-configured-hint = lägg till dem i { $path }, t.ex. som 'pcrs = [7]', och kör kommandot igen
+error-context = vid { $context }:
+reference-mismatch = den genererade koden { $code } stämmer inte med referenskoden { $reference }
+test-mode-unavailable = --test-seed finns bara i debug-byggen
+secret-mismatch = hemligheterna stämde inte överens; ingenting lades till
+pin-mismatch = pin-koderna stämde inte överens
+config-insecure = vägrar använda { $path }, som kan ändras av andra användare än root
+config-insecure-hint = se till att den ägs av root och inte är skrivbar för någon annan, t.ex. med 'chmod 644'
+store-not-initialized = totp-lagret är inte initierat
+store-not-initialized-hint = initiera det genom att köra 'totpm init' och kör sedan kommandot igen
+store-already-initialized = totp-lagret är redan initierat
+tcti-not-loadable = kan inte ladda { $library }, som behövs för att kommunicera med TPM:en
+tcti-not-loadable-hint = se till att paketet { $package } är installerat
+invalid-tcti-forms = giltiga former är:
+    { $forms }
+invalid-tcti-hint = kör totpm init --probe-tpm för att ta reda på vilka tpm-konfigurationer som fungerar på den här maskinen
+no-free-persistent-handle = inget ledigt beständigt handtag för primärnyckeln i { $handles }
+no-free-persistent-handle-hint = frigör ett med tpm2_evictcontrol, eller ändra persistent_handle i konfigurationsfilen
+pcr-policy-failed = primärnyckeln är bunden till pcr:er som har ändrats sedan den skapades
+pcr-policy-failed-warning = om du inte har uppdaterat din firmware, starthanterare eller dina secure boot-inställningar kan din startkedja ha manipulerats
+pcr-policy-failed-hint = starta annars den tidigare konfigurationen för att använda dina hemligheter igen; se README för hur du uppdaterar säkert
+wrong-pin = fel pin-kod
+tpm-locked-out = tpm:en är låst efter för många felaktiga pin-koder
+tpm-locked-out-hint = vänta tills låsningen upphör och försök igen
+pv-policy-unavailable = hemligheten kan bara användas med godkännande från en närvaroverifierare, men ingen är konfigurerad
+pv-policy-unavailable-hint = lägg till [pv_policy]-sektionen som den lades till med i din konfigurationsfil
+pv-policy-failed = tpm:en avvisade närvaroverifierarens godkännande att använda hemligheten
+pv-policy-failed-hint = hemligheten kan ha lagts till med en annan nyckel för närvaroverifiering; återställ [pv_policy]-sektionen som den lades till med
+tpm-failed = en tpm-operation misslyckades: { $error }
+db-locked = hemlighetsdatabasen är låst av en annan totpm-process
+db-locked-hint = om ingen annan totpm-process körs, ta bort { $path } och kör kommandot igen
+db-too-new = hemlighetsdatabasen skapades av en nyare version av totpm (schemaversion { $version }, men den här versionen stöder bara upp till { $supported })
+db-too-new-hint = uppgradera totpm för att använda den
+db-failed = en databasoperation misslyckades: { $error }
+key-handle-corrupted = primärnyckelns handtag är skadat och dina hemligheter är förlorade för gott
+key-handle-corrupted-hint = du kan återställa lagret genom att köra 'totpm clear' följt av 'totpm init'
+metadata-key-unavailable = metadata för vissa hemligheter är krypterad och kan bara läsas med tpm:en
+metadata-key-unavailable-hint = ange encrypt_metadata = true i din konfigurationsfil och kör kommandot igen
+migration-target-unavailable = det finns inget migreringsmål på den här maskinen
+migration-target-unavailable-hint = kör 'totpm migrate target' och exportera hemligheterna till det nya målet på den gamla maskinen
+migration-target-mismatch = hemligheterna exporterades till ett annat migreringsmål
+migration-target-mismatch-hint = exportera dem igen till den här maskinens senaste migreringsmål
+migration-target-not-approved = migreringsmålet har inte godkänts på den här maskinen
+migration-target-not-approved-hint = kör 'totpm migrate approve <målfil>' som root och kör kommandot igen
+migration-data-malformed = migreringsmålet eller de exporterade hemligheterna är skadade
+keys-not-migratable = nycklarna för följande hemligheter är bundna till den nuvarande primärnyckeln:
+keys-not-migratable-hint = lägg till dem igen med migratable_keys = true i din konfigurationsfil och kör kommandot igen
+shared-primary-key = primärnyckeln delas med andra användare, vars hemligheter skulle bli oanvändbara
+shared-primary-key-hint = kör kommandot igen med --force för att byta nyckel ändå
+metadata-not-recoverable = hemligheterna som ska återställas har krypterad metadata, som bara kan läsas med den tpm de lades till med
+metadata-not-recoverable-hint = hemligheter som lagts till med encrypt_metadata = true kan inte återställas från en sekundär tpm
+pin-required = hemligheten skyddas av en pin-kod, som bara kan anges interaktivt
+metadata-corrupted = den krypterade metadatan för vissa hemligheter är skadad
+worker-stopped = tråden som betjänar totp-lagret har stannat
+config-conflict-hint = ange --local-config eller --system-config för att välja en konfiguration uttryckligen
+backup-key-handle-mismatch = säkerhetskopian gjordes under primärnyckelhandtaget { $backup_handle }, men det nuvarande primärnyckelhandtaget är { $current_handle }
+config-conflict = varning: använder { $local }, som refererar till en annan TPM eller primärnyckel än { $system }
+fprintd-listen-failed = fprintd: kan inte lyssna efter signaler
+fprintd-start-failed = fprintd: kan inte starta fingeravtrycksverifieringen
+fprintd-process-failed = fprintd: kan inte behandla inkommande signaler
+fprintd-stop-failed = fprintd: kan inte stoppa fingeravtrycksverifieringen
+fprintd-restart-failed = fprintd: kan inte starta om fingeravtrycksverifieringen
+fprintd-disconnected = fprintd: fingeravtrycksläsaren kopplades från
+fprintd-unknown-error = fprintd: fingeravtrycksläsningen misslyckades med ett okänt fel
+fprintd-claim-failed = fprintd: kan inte ta enheten i anspråk
+fprintd-no-default-device = fprintd: kunde inte hämta standardenheten
+fprintd-bus-unavailable = fprintd: kunde inte ansluta till bussen
+fprintd-list-fingers-failed = fprintd: kan inte lista registrerade fingrar: { $error }
+pv-place-finger = placera fingret på fingeravtrycksläsaren
+pv-no-match = fingeravtrycket kändes inte igen, försök igen
+pv-retry = fingeravtrycket kändes inte igen, försök igen
+pv-swipe-too-short = dragningen var för kort, försök igen
+pv-finger-not-centered = fingret var inte centrerat på läsaren, försök igen
+pv-remove-and-retry = ta bort fingret från läsaren och försök igen
+pv-too-fast = fingret togs bort för snabbt, försök igen
+pv-enter-pin = ange din PIN-kod för totpm:{ " " }
+pv-wrong-pin = fel PIN-kod, försök igen
+term-cancel = [avbryt]
+term-invalid-selection = ogiltigt val
+term-confirm-choices = [j/N]
+term-yes-answers = j ja
@@ -1,6 +1,12 @@
 use std::path::PathBuf;
-use clap::{command, Parser, Subcommand};
+use clap::{command, Args, Parser, Subcommand};
 
+use crate::{db::model::{MAX_OFFSET_SECONDS, MAX_T0}, secret_format::SecretFormat, system_user::UidRange, units::HumanDuration};
+#[cfg(feature = "import")]
+use crate::commands::import::ConflictStrategy;
+
+/// The most time steps before or after the current one that a code window may cover.
+const MAX_WINDOW: i64 = 10;
 
 #[derive(Parser)]
 #[derive(Debug)]
@@ -14,66 +20,185 @@ pub struct Opts {
     #[arg(short, long)]
     pub config: Option<PathBuf>,
 
-    /// Print debugging information and non-critical TPM.
-    #[arg(short, long, default_value = "false")]
-    pub debug: bool,
+    /// Use the user-local configuration file, even if a system-wide one exists.
+    #[arg(long, default_value = "false", conflicts_with_all = ["config", "system_config"])]
+    pub local_config: bool,
+
+    /// Use the system-wide configuration file, even if a user-local one exists.
+    #[arg(long, default_value = "false", conflicts_with = "config")]
+    pub system_config: bool,
+
+    /// Print more information about what totpm and the TPM libraries are doing; may be given up to three times.
+    /// Warnings are always printed; -v adds informational messages, -vv debugging information and -vvv everything.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// How to choose between several matching secrets: interactive, filter, mru or fail_fast.
+    /// Overrides the selection setting in the configuration file.
+    #[arg(long, global = true)]
+    pub select: Option<String>,
+
+    /// Use the secrets store of the given profile from the configuration file.
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Make auth values and other randomness deterministic, for reproducible integration tests.
+    /// Only available in debug builds.
+    #[arg(long, global = true, hide = true)]
+    pub test_seed: Option<u64>,
 }
 
 #[derive(Subcommand)]
 #[derive(Debug)]
 pub enum Command {
     /// Add a new TOTP secret.
-    Add {
-        /// Name of the service to add a secret for.
-        service: String,
+    Add(AddArgs),
 
-        /// Username associated with the secret.
-        account: String,
+    /// Delete an existing TOTP secret, after showing it and asking for confirmation.
+    Del {
+        /// Name of the service to delete secret for.
+        #[arg(required_unless_present = "id")]
+        service: Option<String>,
 
-        /// Number of security code digits.
-        /// Defaults to 6; don't change unless you know what you're doing.
-        #[arg(short, long)]
-        digits: Option<u8>,
+        /// Username associated with the secret to delete.
+        #[arg(required_unless_present_any = ["id", "all"])]
+        account: Option<String>,
 
-        /// How often to generate a new security code.
-        /// Defaults to every 30 seconds; don't change unless you know what you're doing.
-        #[arg(short, long)]
-        interval: Option<u32>,
+        /// Delete the secret with the given id, as shown by list --ids, instead of matching on names.
+        #[arg(long, conflicts_with_all = ["service", "account", "all"])]
+        id: Option<i64>,
 
-        /// Read secret from standard input instead of directly from tty.
-        /// Only use this for non-interactive use cases, to avoid echoing secret to screen.
+        /// Only match secrets whose service and account are exactly the given ones,
+        /// rather than containing them.
+        #[arg(short, long, default_value = "false")]
+        exact: bool,
+
+        /// Treat the service and account as glob patterns which must match whole names, e.g. 'git*',
+        /// rather than as substrings. * matches any characters, ? any one character and [...] any one of the
+        /// enclosed characters. Otherwise, % and _ only match themselves.
+        #[arg(long, default_value = "false", conflicts_with = "exact")]
+        glob: bool,
+
+        /// Delete every secret whose service and account contain the given ones.
+        /// The account may then be omitted to match all accounts. Unlike deleting a single secret, this can't be undone.
         #[arg(long, default_value = "false")]
-        secret_on_stdin: bool,
+        all: bool,
+
+        /// Don't ask for confirmation.
+        #[arg(short, long, default_value = "false")]
+        yes: bool,
     },
 
-    /// Delete an existing TOTP secret.
-    Del {
-        /// Name of the service to delete secret for.
-        service: String,
+    /// Pin a secret, so that list shows it and pickers offer it before secrets that aren't pinned.
+    Pin(PinArgs),
 
-        /// Username associated with the secret to delete.
-        account: String,
-    },
+    /// Unpin a secret pinned using pin.
+    Unpin(PinArgs),
 
     /// Generate a security code.
     Gen {
         /// Service to generate security code for.
-        service: String,
+        /// If omitted, the secret is taken from the closest .totpm file in the current directory or its parents.
+        service: Option<String>,
 
         /// Username to generate security code for.
         account: Option<String>,
+
+        /// Only consider secrets with the given tag.
+        #[arg(short, long)]
+        tag: Option<String>,
+
+        /// Generate a code for the secret with the given id, as shown by list --ids, instead of matching on names.
+        #[arg(long, conflicts_with_all = ["service", "account", "tag"])]
+        id: Option<i64>,
+
+        /// Only match secrets whose service and account are exactly the given ones, rather than containing them,
+        /// so that e.g. "git" doesn't match github and gitlab. An omitted account still matches any account.
+        #[arg(short, long, default_value = "false")]
+        exact: bool,
+
+        /// Treat the service and account as glob patterns which must match whole names, e.g. 'git*',
+        /// rather than as substrings. * matches any characters, ? any one character and [...] any one of the
+        /// enclosed characters. Otherwise, % and _ only match themselves.
+        #[arg(long, default_value = "false", conflicts_with_all = ["exact", "id"])]
+        glob: bool,
+
+        /// If several secrets match, pick the one most recently used to generate a code.
+        #[arg(long, default_value = "false")]
+        mru: bool,
+
+        /// Print a code for every matching secret instead of picking one, verifying presence only once,
+        /// e.g. gen --all --tag work. Without a service, every secret matches.
+        #[arg(long, default_value = "false", conflicts_with_all = ["id", "mru", "type_code", "window", "reference_check"])]
+        all: bool,
+
+        /// Type the code into the focused window instead of printing it, using the tool set by auto_type
+        /// in the configuration file.
+        #[arg(long = "type", default_value = "false")]
+        type_code: bool,
+
+        /// Print the codes for N time steps before and after the current one as well,
+        /// each with the range of seconds relative to now during which it's current.
+        /// Useful when the service's clock is off. N must be between 1 and 10.
+        #[arg(long, value_name = "N", conflicts_with_all = ["type_code", "reference_check"], value_parser = clap::value_parser!(u32).range(1..=MAX_WINDOW))]
+        window: Option<u32>,
+
+        /// Max time to wait for presence verification, e.g. 30s, overriding pv_timeout in the configuration file.
+        #[arg(long)]
+        pv_timeout: Option<HumanDuration>,
+
+        /// Debugging aid: read the base32-encoded secret from stdin and fail unless the generated code
+        /// matches a software implementation of RFC 6238. Only use with test secrets.
+        #[arg(long, hide = true, default_value = "false")]
+        reference_check: bool,
     },
 
     /// List all accounts matching the given partial service and account names.
     List {
         service: Option<String>,
         account: Option<String>,
+
+        /// Only list secrets with the given tag.
+        #[arg(short, long)]
+        tag: Option<String>,
+
+        /// Show all details of each secret.
+        #[arg(short, long, default_value = "false")]
+        long: bool,
+
+        /// Only list secrets that look stale or duplicated, with suggestions for cleaning them up.
+        #[arg(long, default_value = "false")]
+        stale: bool,
+
+        /// Treat the service and account as glob patterns which must match whole names, e.g. 'git*',
+        /// rather than as substrings. * matches any characters, ? any one character and [...] any one of the
+        /// enclosed characters. Otherwise, % and _ only match themselves.
+        #[arg(long, default_value = "false")]
+        glob: bool,
+
+        /// List at most N secrets.
+        #[arg(long, value_name = "N", conflicts_with = "stale")]
+        limit: Option<u32>,
+
+        /// Skip the first N matching secrets, e.g. to list the next page after --limit.
+        #[arg(long, value_name = "N", default_value = "0", conflicts_with = "stale")]
+        offset: u32,
+
+        /// Print the id of each secret before it, for use with gen --id and del --id.
+        #[arg(long, default_value = "false")]
+        ids: bool,
+
+        /// Print the secrets as a single JSON array of objects with their id, service, account, digits, interval,
+        /// tags, notes, url, icon and whether it's pinned, for use by frontends and scripts.
+        #[arg(long, default_value = "false", conflicts_with_all = ["long", "stale", "ids"])]
+        json: bool,
     },
 
     /// Batch import secrets from file.
     #[cfg(feature = "import")]
     Import {
-        /// JSON file to import secrets from.
+        /// JSON file to import secrets from, or - to read them from standard input, e.g. when piping
+        /// from a decryption tool, so that the plaintext secrets never touch the disk.
         /// The file should consist of a single JSON object, mapping service names to secrets as follows:
         ///
         /// {
@@ -81,20 +206,37 @@ pub enum Command {
         ///         "account": "my_account@example.com",
         ///         "secret": "...",
         ///         "digits": 6,
-        ///         "interval": 30
+        ///         "interval": 30,
+        ///         "t0": 0
         ///     }
         /// }
         ///
-        /// The `digits` and `interval` fields are optional, and will default to 6 and 30 respectively.
+        /// The `digits`, `interval` and `t0` fields are optional, and will default to 6, 30 and 0 respectively.
         file: PathBuf,
+
+        /// Encoding of the secrets: base32, hex or auto. Auto treats secrets as hex if they have a 0x prefix
+        /// or contain 0, 1, 8 or 9, which can't appear in base32.
+        #[arg(long, default_value = "auto")]
+        secret_format: SecretFormat,
+
+        /// What to do with secrets whose service and account already exist: skip them, overwrite the existing
+        /// secrets, keeping their tags and notes, add duplicates, or abort without importing anything.
+        #[arg(long, value_name = "STRATEGY", default_value = "abort")]
+        on_conflict: ConflictStrategy,
+
+        /// Check the file and list what would be done with each secret, without adding anything or using the TPM.
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
     },
 
     /// Initialize the TOTP store.
     Init {
         /// TPM configuration to use.
-        /// May be either "device", "device:/path/to/tpm", or "swtpm:host=...,port=..."
-        #[arg(short, long, default_value = "device:/dev/tpmrm0")]
-        tpm: String,
+        /// May be "device", "device:/path/to/tpm", "tabrmd", "tabrmd:bus_name=...,bus_type=system|session",
+        /// "swtpm:host=...,port=..." or "mssim:host=...,port=..."
+        /// Defaults to "device:/dev/tpmrm0". With --probe-tpm, only this configuration is tried.
+        #[arg(short, long)]
+        tpm: Option<String>,
 
         /// Path to directory where totpm should store system-wide data.
         /// The directory is created if it does not exist.
@@ -108,12 +250,26 @@ pub enum Command {
         #[arg(short = 'p', long)]
         user_data_path: Option<PathBuf>,
 
-        /// User which will own system-wide data files. Will be created if it does not exist.
+        /// User which will own system-wide data files. Will be created if it does not exist,
+        /// using useradd or, where that isn't installed, adduser.
         #[arg(short, long)]
         user: Option<String>,
 
+        /// Home directory to give the user if it's created. The directory itself is not created.
+        #[arg(long, conflicts_with = "local")]
+        user_home: Option<PathBuf>,
+
+        /// Range of ids to pick the user's id from if it's created, e.g. 800-899.
+        /// Defaults to the range for system users configured for useradd or adduser.
+        #[arg(long, conflicts_with = "local")]
+        user_uid_range: Option<UidRange>,
+
+        /// Comma-separated supplementary groups to add the user to if it's created, e.g. tss for TPM access.
+        #[arg(long, value_delimiter = ',', conflicts_with = "local")]
+        user_groups: Vec<String>,
+
         /// Method to use for presence verification.
-        /// Valid values are `fprintd` and `none`.
+        /// Valid values are `fprintd`, `pin` and `none`.
         /// Defaults to `fprintd` for system install, `none` for local install.
         #[arg(short, long)]
         presence_verification: Option<String>,
@@ -124,6 +280,205 @@ pub enum Command {
         /// - use user-local defaults for arguments that are not explicitly specified
         #[arg(short, long, default_value = "false")]
         local: bool,
+
+        /// Comma-separated PCRs to bind the primary key to, e.g. 7 for the secure boot state.
+        /// Codes can then only be generated while these PCRs are unchanged, so booting another OS
+        /// or a tampered boot chain leaves the secrets unusable. So do firmware and bootloader updates
+        /// that change the PCRs; see the README before using this.
+        #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..24))]
+        pcrs: Vec<u8>,
+
+        /// Also install a desktop entry and icon, for the current user if --local is given or system-wide otherwise.
+        /// If totpm is already initialized, only the desktop entry is installed.
+        #[arg(long, default_value = "false")]
+        install_desktop_entry: bool,
+
+        /// Instead of initializing, try talking to the TPM through each common TPM configuration,
+        /// or only the one given by --tpm, and report which ones work.
+        #[arg(long, default_value = "false", conflicts_with_all = [
+            "system_data_path", "user_data_path", "user", "user_home", "user_uid_range", "user_groups",
+            "presence_verification", "local", "pcrs", "install_desktop_entry",
+        ])]
+        probe_tpm: bool,
+    },
+
+    /// Move secrets to another machine's TPM, without their keys ever leaving a TPM unencrypted.
+    /// Only secrets added with migratable_keys = true in the configuration file can be moved.
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommand,
+    },
+
+    /// Walk through moving your accounts from a phone authenticator: find exports in a directory,
+    /// import them, compare a few codes with the old authenticator, and list the accounts to remove from it.
+    /// Exports may be in the JSON format read by import, or lists of otpauth:// URIs, one per line.
+    #[cfg(feature = "import")]
+    MigrateWizard {
+        /// Directory containing the exports from your old authenticator.
+        dir: PathBuf,
+    },
+
+    /// Inspect or change the totpm configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Reverse the last add, add --update or del. Only the most recent one can be undone.
+    Undo,
+
+    /// Read a PAM prompt from stdin and print only the security code, if the prompt asks for one.
+    /// Use this to answer the TOTP prompt of e.g. sudo from the TPM store; see the README.
+    PamHelper {
+        /// Service to generate security code for.
+        service: String,
+
+        /// Username to generate security code for.
+        account: Option<String>,
+    },
+
+    /// Print only the security code, for use as an ssh askpass program when logging in to servers using TOTP.
+    /// The prompt ssh passes to askpass programs is checked, so that codes are never sent as passwords.
+    SshHelper {
+        /// Service to generate security code for.
+        service: String,
+
+        /// Username to generate security code for.
+        account: Option<String>,
+
+        /// Prompt to respond to, as passed by ssh to askpass programs. Must be given after --.
+        #[arg(last = true)]
+        prompt: Option<String>,
+
+        /// Don't generate a code, only exit successfully if exactly one secret matches.
+        /// Useful with Match exec in ~/.ssh/config.
+        #[arg(long, default_value = "false")]
+        check: bool,
+    },
+
+    /// Start an interactive session, accepting gen, list, add, del and undo commands.
+    /// Presence is only verified once, when the session starts.
+    Shell,
+
+    /// Administrative operations on other users' secrets. Requires root privileges.
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+
+    /// Print an anonymized snapshot of version, config, TPM, doctor results and last error, for attaching to
+    /// bug reports. Service and account names and the TPM's connection settings are redacted. Nothing is sent anywhere.
+    Bugreport,
+
+    /// Serve the secrets store over D-Bus as org.totpm.Manager on the session bus, for GUI frontends.
+    /// The TPM is opened once, but presence is still verified for every code generated.
+    #[cfg(feature = "daemon")]
+    Daemon,
+
+    /// Write a tmpfiles.d entry creating the system data directory and, if built with the daemon feature,
+    /// a systemd user unit running the daemon, so that packages and manual installs set things up like init does.
+    InstallUnits {
+        /// Directory to write the units into: /etc for manual installs, or e.g. /usr/lib when packaging.
+        #[arg(long, default_value = "/etc")]
+        dir: PathBuf,
+
+        /// User owning the system data directory.
+        #[arg(short, long, default_value = "totpm")]
+        user: String,
+    },
+
+    /// Show whether the totp store is initialized, which TPM and primary key handle it uses,
+    /// how presence is verified, and the number of stored secrets. Doesn't require presence verification.
+    Status,
+
+    /// Check that totpm is set up correctly: that the configuration file parses, the TPM is reachable,
+    /// the primary key loads, the auth value is private, the secrets database opens and fprintd works.
+    /// Prints a PASS/FAIL report with hints on how to fix any problems.
+    Doctor {
+        /// Also list secrets worth cleaning up: ones unused for a year, and ones with the same service and account
+        /// as another secret in the same or another profile.
+        #[arg(long, default_value = "false")]
+        hygiene: bool,
+    },
+
+    /// Print the version of totpm, along with its schema version, supported import and export formats,
+    /// enabled features and the spec revision of the configured TPM.
+    Version {
+        /// Print a single JSON object instead of text, for use by scripts and orchestration tools.
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+
+    /// Write a consistent snapshot of the secrets database and a manifest to a new directory.
+    /// The backup can only be restored on the machine whose TPM it was made with.
+    Backup {
+        /// Directory to create for the backup.
+        path: PathBuf,
+    },
+
+    /// Write all secrets to a file, with their keys still wrapped under the primary key.
+    /// The keys are useless without this machine's TPM, so the file is safe to archive.
+    Export {
+        /// File to create for the export. Service and account names are stored in plain text.
+        #[arg(long, value_name = "FILE")]
+        wrapped: PathBuf,
+    },
+
+    /// Replace the secrets database with one previously saved using the backup command.
+    /// The backup is checked to be usable with the current primary key before anything is replaced,
+    /// and the replaced database is kept in the trash for 30 days.
+    Restore {
+        /// Backup directory to restore, or file written by export --wrapped if --wrapped is given.
+        path: PathBuf,
+
+        /// Restore even if the backup was made under a different primary key handle.
+        #[arg(long, default_value = "false")]
+        force: bool,
+
+        /// Recover the secrets in a backup made on another machine, using the secondary keys duplicated to this one.
+        /// The recovered secrets are added to the current database instead of replacing it.
+        #[arg(long, default_value = "false", conflicts_with = "force")]
+        from_secondary: bool,
+
+        /// Add the secrets in a file written by export --wrapped back to the database, e.g. after it was lost.
+        /// Secrets that are still in the database are left alone.
+        #[arg(long, default_value = "false", conflicts_with = "from_secondary")]
+        wrapped: bool,
+    },
+
+    /// Move all secrets to a new primary key and evict the old one from the TPM, e.g. after the auth value may have leaked.
+    /// Only works if all secrets were added with migratable_keys = true in the configuration file.
+    Rekey {
+        /// Rekey even if other users' secrets depend on the current primary key.
+        /// Their secrets will become unusable.
+        #[arg(long, default_value = "false")]
+        force: bool,
+    },
+
+    /// Check a code against the stored secret, e.g. to make sure a freshly added secret is right.
+    /// Exits with an error unless the code is valid.
+    Verify {
+        /// Service of the secret to check the code against.
+        service: String,
+
+        /// Username of the secret, if needed to tell it apart from others, followed by the code to check.
+        #[arg(required = true, num_args = 1..=2, value_name = "[ACCOUNT] CODE")]
+        account_and_code: Vec<String>,
+
+        /// Only consider secrets with the given tag.
+        #[arg(short, long)]
+        tag: Option<String>,
+
+        /// Also accept codes for up to this many time steps (at most 10) before or after the current one.
+        #[arg(short, long, default_value = "1", value_parser = clap::value_parser!(u32).range(0..=MAX_WINDOW))]
+        window: u32,
+    },
+
+    /// Check the secrets database for corruption and secrets with broken keys.
+    VerifyStore {
+        /// Also try to load each secret's key into the TPM. Requires presence verification.
+        #[arg(long, default_value = "false")]
+        load_keys: bool,
     },
 
     /// Remove all stored TOTP secrets, rendering them unusable.
@@ -134,7 +489,318 @@ pub enum Command {
 
         /// Also delete system-level data, rendering all secrets on this machine unusable.
         /// Requires root privileges.
-        #[arg(short, long, default_value = "false")]
+        #[arg(short, long, default_value = "false", conflicts_with_all = ["service", "tag"])]
         system: bool,
+
+        /// Only remove the secrets of this service, matched exactly. All of them are removed in one transaction.
+        #[arg(long)]
+        service: Option<String>,
+
+        /// Only remove the secrets with this tag. If given with --service, only secrets matching both are removed.
+        #[arg(long)]
+        tag: Option<String>,
+
+        /// Don't ask for confirmation before removing the secrets of --service or --tag.
+        #[arg(short, long, default_value = "false")]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+#[derive(Debug)]
+pub enum AdminCommand {
+    /// Copy secrets from one user to another, e.g. when handing over accounts.
+    /// Only secrets matching the given service, account and tag are copied.
+    Transfer {
+        /// User to copy secrets from.
+        #[arg(long)]
+        from: String,
+
+        /// User to copy secrets to.
+        #[arg(long)]
+        to: String,
+
+        /// Only copy secrets whose service name contains this string.
+        #[arg(long)]
+        service: Option<String>,
+
+        /// Only copy secrets whose account name contains this string.
+        #[arg(long)]
+        account: Option<String>,
+
+        /// Only copy secrets with this tag.
+        #[arg(short, long)]
+        tag: Option<String>,
+    },
+}
+
+#[derive(Args)]
+#[derive(Debug)]
+pub struct PinArgs {
+    /// Name of the service of the secret.
+    #[arg(required_unless_present = "id")]
+    pub service: Option<String>,
+
+    /// Username associated with the secret.
+    pub account: Option<String>,
+
+    /// Match the secret with the given id, as shown by list --ids, instead of matching on names.
+    #[arg(long, conflicts_with_all = ["service", "account"])]
+    pub id: Option<i64>,
+
+    /// Only match secrets whose service and account are exactly the given ones, rather than containing them.
+    #[arg(short, long, default_value = "false")]
+    pub exact: bool,
+}
+
+#[derive(Args)]
+#[derive(Debug)]
+pub struct AddArgs {
+    /// Name of the service to add a secret for.
+    pub service: String,
+
+    /// Username associated with the secret.
+    pub account: String,
+
+    /// Number of security code digits.
+    /// Defaults to 6; don't change unless you know what you're doing.
+    #[arg(short, long)]
+    pub digits: Option<u8>,
+
+    /// How often to generate a new security code.
+    /// Defaults to every 30 seconds; don't change unless you know what you're doing.
+    #[arg(short, long)]
+    pub interval: Option<u32>,
+
+    /// Read secret from standard input instead of directly from tty.
+    /// Only use this for non-interactive use cases, to avoid echoing secret to screen.
+    #[arg(long, default_value = "false")]
+    pub secret_on_stdin: bool,
+
+    /// Read the secret from the given file, which must only be accessible by you, instead of from the tty.
+    /// Keeps the secret out of shell history and terminal scrollback, for automation.
+    #[arg(long, conflicts_with = "secret_on_stdin", required_if_eq("secret_format", "raw"))]
+    pub secret_file: Option<PathBuf>,
+
+    /// Don't ask for the secret a second time when reading it from the tty.
+    #[arg(long, default_value = "false")]
+    pub no_confirm: bool,
+
+    /// Encoding of the secret: base32, hex, raw or auto. Auto treats the secret as hex if it has a 0x prefix
+    /// or contains 0, 1, 8 or 9, which can't appear in base32. Raw takes the contents of --secret-file as is.
+    #[arg(long, default_value = "auto")]
+    pub secret_format: SecretFormat,
+
+    /// Tag to attach to the secret. May be given multiple times.
+    #[arg(short, long = "tag")]
+    pub tags: Vec<String>,
+
+    /// Free-text note to store with the secret, e.g. a hint about where recovery codes are kept.
+    #[arg(short, long)]
+    pub note: Option<String>,
+
+    /// URL of the service, e.g. its login page, for frontends to show or open.
+    #[arg(long)]
+    pub url: Option<String>,
+
+    /// Icon for frontends to show for the secret, as a URL or a freedesktop icon name.
+    #[arg(long)]
+    pub icon: Option<String>,
+
+    /// Warn when generating codes for or listing the secret once it's older than this many days.
+    #[arg(long)]
+    pub rotate_after_days: Option<u32>,
+
+    /// Seconds to add to the current time when generating codes for the secret, e.g. -45 for a service
+    /// whose clock is 45 seconds behind. At most one year in either direction.
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i64).range(-MAX_OFFSET_SECONDS..=MAX_OFFSET_SECONDS))]
+    pub offset_seconds: Option<i64>,
+
+    /// Time in seconds since the epoch from which the service counts time steps (T0 in RFC 6238).
+    /// Defaults to 0, which is what nearly all services use. At most the end of the year 9999 in either direction.
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i64).range(-MAX_T0..=MAX_T0))]
+    pub t0: Option<i64>,
+
+    /// Replace the secret of an existing service/account pair instead of adding a new one.
+    /// Tags, notes, url, icon, rotation window, time offset and T0 are kept unless given.
+    #[arg(short, long, default_value = "false")]
+    pub update: bool,
+
+    /// Protect the secret with a PIN, which is prompted for and must be entered to generate codes.
+    /// The TPM locks out after too many wrong PINs.
+    #[arg(long, default_value = "false")]
+    pub pin: bool,
+
+    /// Max time to wait for presence verification, e.g. 30s, overriding pv_timeout in the configuration file.
+    /// Has no effect in the shell, where presence is verified on startup.
+    #[arg(long)]
+    pub pv_timeout: Option<HumanDuration>,
+}
+
+#[derive(Subcommand)]
+#[derive(Debug)]
+pub enum MigrateCommand {
+    /// On the new machine: create a migration target and write its public part to a file.
+    Target {
+        file: PathBuf,
+    },
+
+    /// On the old machine, as root: approve the given migration target, so that secrets can be exported to it.
+    /// Secrets can only be migrated to targets created by a TPM.
+    Approve {
+        target_file: PathBuf,
+    },
+
+    /// On the old machine: duplicate all migratable secrets to the given approved migration target,
+    /// writing them to a new bundle file.
+    Export {
+        target_file: PathBuf,
+        bundle_file: PathBuf,
+    },
+
+    /// On the new machine: import the secrets in a bundle file created by migrate export.
+    Import {
+        bundle_file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+#[derive(Debug)]
+pub enum ConfigCommand {
+    /// Print the effective configuration, along with where each value came from.
+    Effective,
+
+    /// Rewrite the configuration file with every key set, filling in keys missing from it with their defaults,
+    /// e.g. after upgrading totpm. Keys already in the file are kept as they are.
+    Upgrade,
+
+    /// Print the effective value of a key, e.g. pv_timeout, or pv.require.gen for keys in sections.
+    Get {
+        key: String,
+    },
+
+    /// Set a key in the configuration file, e.g. 'totpm config set pv_timeout 30s', and rewrite it.
+    /// The value is parsed as TOML, e.g. true or ["fprintd", "pin"], or taken as a string if it isn't valid TOML.
+    /// Use --local-config or --system-config to choose which file to change.
+    Set {
+        key: String,
+        value: String,
+    },
+}
+
+#[derive(Parser)]
+#[derive(Debug)]
+#[command(no_binary_name = true, disable_version_flag = true)]
+/// A single line of input to the interactive shell.
+pub struct ShellLine {
+    #[command(subcommand)]
+    pub command: ShellCommand,
+}
+
+#[derive(Subcommand)]
+#[derive(Debug)]
+pub enum ShellCommand {
+    /// Add a new TOTP secret.
+    Add(AddArgs),
+
+    /// Delete an existing TOTP secret.
+    Del {
+        /// Name of the service to delete secret for.
+        service: String,
+
+        /// Username associated with the secret to delete.
+        account: String,
+
+        /// Don't ask for confirmation.
+        #[arg(short, long, default_value = "false")]
+        yes: bool,
+    },
+
+    /// Generate a security code.
+    Gen {
+        /// Service to generate security code for.
+        service: String,
+
+        /// Username to generate security code for.
+        account: Option<String>,
+
+        /// Only consider secrets with the given tag.
+        #[arg(short, long)]
+        tag: Option<String>,
+
+        /// If several secrets match, pick the one most recently used to generate a code.
+        #[arg(long, default_value = "false")]
+        mru: bool,
+    },
+
+    /// List all accounts matching the given partial service and account names.
+    List {
+        service: Option<String>,
+        account: Option<String>,
+
+        /// Only list secrets with the given tag.
+        #[arg(short, long)]
+        tag: Option<String>,
+
+        /// Show all details of each secret.
+        #[arg(short, long, default_value = "false")]
+        long: bool,
+
+        /// Only list secrets that look stale or duplicated, with suggestions for cleaning them up.
+        #[arg(long, default_value = "false")]
+        stale: bool,
     },
+
+    /// Reverse the last add, add --update or del.
+    Undo,
+
+    /// End the session.
+    #[command(alias = "quit")]
+    Exit,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Opts, clap::Error> {
+        Opts::try_parse_from(["totpm"].iter().chain(args))
+    }
+
+    #[test]
+    fn add_accepts_t0_only_within_range() {
+        for t0 in [-MAX_T0, 0, MAX_T0] {
+            match parse(&["add", "svc", "acct", "--t0", &t0.to_string()]).unwrap().command {
+                Command::Add(args) => assert_eq!(args.t0, Some(t0)),
+                command => panic!("wrong command: {:#?}", command),
+            }
+        }
+        for t0 in [i64::MIN, -MAX_T0 - 1, MAX_T0 + 1, i64::MAX] {
+            assert!(parse(&["add", "svc", "acct", "--t0", &t0.to_string()]).is_err());
+        }
+    }
+
+    #[test]
+    fn gen_accepts_window_only_within_range() {
+        for window in [1, MAX_WINDOW] {
+            match parse(&["gen", "svc", "--window", &window.to_string()]).unwrap().command {
+                Command::Gen { window: parsed, .. } => assert_eq!(parsed.map(i64::from), Some(window)),
+                command => panic!("wrong command: {:#?}", command),
+            }
+        }
+        for window in [0, MAX_WINDOW + 1] {
+            assert!(parse(&["gen", "svc", "--window", &window.to_string()]).is_err());
+        }
+    }
+
+    #[test]
+    fn verify_accepts_window_only_within_range() {
+        for window in [0, MAX_WINDOW] {
+            match parse(&["verify", "svc", "123456", "--window", &window.to_string()]).unwrap().command {
+                Command::Verify { window: parsed, .. } => assert_eq!(i64::from(parsed), window),
+                command => panic!("wrong command: {:#?}", command),
+            }
+        }
+        assert!(parse(&["verify", "svc", "123456", "--window", &(MAX_WINDOW + 1).to_string()]).is_err());
+    }
 }
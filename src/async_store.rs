@@ -0,0 +1,195 @@
+use std::{future::Future, pin::Pin, sync::{mpsc, Arc, Condvar, Mutex}, task::{Context, Poll, Waker}, thread, time::SystemTime};
+
+use crate::{config::Config, db::model::Secret, totp_store::{Error, Result, TotpStore, WithTPM}};
+
+type Job = Box<dyn FnOnce(&mut TotpStore<WithTPM>) + Send>;
+
+/// A TOTP store running on a worker thread, so that async code, e.g. a daemon serving concurrent requests,
+/// doesn't block its reactor while the TPM or presence verifier is busy. Requests are handled one at a time,
+/// in the order they were made. Handles can be cloned, and the store is closed when the last one is dropped.
+#[derive(Clone)]
+pub struct AsyncStore {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl AsyncStore {
+    /// Opens the store with the TPM on a new worker thread, without verifying presence.
+    /// Blocks until the store has been opened.
+    pub fn with_tpm_unverified(config: Config) -> Result<Self> {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (opened_sender, opened_receiver) = mpsc::channel();
+        thread::Builder::new().name("totpm-store".to_owned()).spawn(move || {
+            let mut store = match TotpStore::with_tpm_unverified(config) {
+                Ok(store) => store,
+                Err(e) => {
+                    let _ = opened_sender.send(Err(e));
+                    return
+                },
+            };
+            let _ = opened_sender.send(Ok(()));
+            for job in job_receiver {
+                job(&mut store);
+            }
+            log::info!("all handles to the store are gone; stopping worker thread");
+        })?;
+        opened_receiver.recv().map_err(|_| Error::WorkerStopped)??;
+        Ok(AsyncStore { jobs })
+    }
+
+    /// Runs the given function on the store in the worker thread.
+    /// If the function panics, the worker thread stops, and this and all later requests fail with WorkerStopped.
+    pub fn run<T, F>(&self, f: F) -> Reply<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut TotpStore<WithTPM>) -> Result<T> + Send + 'static,
+    {
+        let (reply, sender) = Reply::new();
+        // If the worker has stopped, the job is dropped along with its sender, which resolves the reply
+        let _ = self.jobs.send(Box::new(move |store| sender.send(f(store))));
+        reply
+    }
+
+    /// Lists the secrets matching the given service and account.
+    pub fn list(&self, service: Option<&str>, account: Option<&str>) -> Reply<Vec<Secret>> {
+        let (service, account) = (service.map(str::to_owned), account.map(str::to_owned));
+        self.run(move |store| store.list(service.as_deref(), account.as_deref()))
+    }
+
+    /// Verifies presence and generates a code for the secret with the given id at the given time.
+    pub fn gen(&self, secret_id: i64, timestamp: SystemTime) -> Reply<String> {
+        self.run(move |store| {
+            store.verify_presence()?;
+            store.gen(secret_id, timestamp)
+        })
+    }
+}
+
+struct Slot<T> {
+    value: Option<Result<T>>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// The result of a request to an AsyncStore, available once the worker thread has handled the request.
+/// Either await it, or wait for it without an async runtime using wait.
+pub struct Reply<T> {
+    slot: Arc<(Mutex<Slot<T>>, Condvar)>,
+}
+
+/// The worker thread's end of a Reply. Dropping it without sending a value makes the reply fail.
+struct ReplySender<T> {
+    slot: Arc<(Mutex<Slot<T>>, Condvar)>,
+}
+
+impl <T> Reply<T> {
+    fn new() -> (Self, ReplySender<T>) {
+        let slot = Arc::new((Mutex::new(Slot { value: None, closed: false, waker: None }), Condvar::new()));
+        (Reply { slot: slot.clone() }, ReplySender { slot })
+    }
+
+    /// Blocks until the request has been handled, and returns its result.
+    pub fn wait(self) -> Result<T> {
+        let (slot, ready) = &*self.slot;
+        let mut slot = ready.wait_while(slot.lock().unwrap(), |slot| !slot.closed).unwrap();
+        slot.value.take().unwrap_or(Err(Error::WorkerStopped))
+    }
+}
+
+impl <T> Future for Reply<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.0.lock().unwrap();
+        if !slot.closed {
+            slot.waker = Some(cx.waker().clone());
+            return Poll::Pending
+        }
+        Poll::Ready(slot.value.take().unwrap_or(Err(Error::WorkerStopped)))
+    }
+}
+
+impl <T> ReplySender<T> {
+    fn send(self, value: Result<T>) {
+        self.slot.0.lock().unwrap().value = Some(value);
+    }
+}
+
+impl <T> Drop for ReplySender<T> {
+    fn drop(&mut self) {
+        let (slot, ready) = &*self.slot;
+        let mut slot = slot.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        slot.closed = true;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+        ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, task::Wake};
+
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::presence_verification::PresenceVerificationMethod;
+
+    use super::*;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn requests_are_served_by_worker_thread() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.path().join("sys")),
+            Some(dir.path().join("user")),
+            Some(PresenceVerificationMethod::None),
+        );
+        TotpStore::init(cfg.clone()).unwrap();
+        let store = AsyncStore::with_tpm_unverified(cfg).unwrap();
+
+        let secret = store.run(|store| store.add("foo", "bar", None, None, b"12345678901234567890")).wait().unwrap();
+        let listed = block_on(store.list(Some("foo"), None)).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, secret.id);
+
+        // RFC 6238 test vector, truncated to 6 digits
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(59);
+        assert_eq!(block_on(store.gen(secret.id, time)).unwrap(), "287082");
+        assert!(matches!(block_on(store.gen(secret.id + 1, time)), Err(Error::DBError(_))));
+    }
+
+    #[test]
+    fn requests_fail_once_worker_has_stopped() {
+        let (reply, sender) = Reply::<()>::new();
+        drop(sender);
+        assert!(matches!(reply.wait(), Err(Error::WorkerStopped)));
+
+        let (reply, sender) = Reply::new();
+        sender.send(Ok(42));
+        assert_eq!(block_on(reply).unwrap(), 42);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{env, ffi::OsStr, io::{self, Write}, path::Path, process::{Command, Stdio}};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Tool used to type generated codes into the focused window.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoTypeMethod {
+    /// wtype or ydotool under Wayland, xdotool under X11, whichever is installed.
+    #[default]
+    Auto,
+    /// wtype, for wlroots-based Wayland compositors.
+    Wtype,
+    /// ydotool, which works under any compositor but needs the ydotoold daemon.
+    Ydotool,
+    /// xdotool, for X11.
+    Xdotool,
+}
+
+impl AutoTypeMethod {
+    /// Returns the tool to use for this method in the current session, or None if there isn't any.
+    pub fn resolve(self) -> Option<AutoTypeMethod> {
+        resolve(
+            self,
+            env::var_os("WAYLAND_DISPLAY").is_some(),
+            env::var_os("DISPLAY").is_some(),
+            |program| find_in_path(program, &env::var_os("PATH").unwrap_or_default()),
+        )
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            AutoTypeMethod::Auto | AutoTypeMethod::Wtype => "wtype",
+            AutoTypeMethod::Ydotool => "ydotool",
+            AutoTypeMethod::Xdotool => "xdotool",
+        }
+    }
+
+    /// Arguments which make the tool type text read from stdin, so that it doesn't show up in the process list.
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            AutoTypeMethod::Auto | AutoTypeMethod::Wtype => &["-"],
+            AutoTypeMethod::Ydotool => &["type", "--file", "-"],
+            AutoTypeMethod::Xdotool => &["type", "--clearmodifiers", "--file", "-"],
+        }
+    }
+}
+
+fn resolve(
+    method: AutoTypeMethod,
+    wayland: bool,
+    x11: bool,
+    installed: impl Fn(&str) -> bool,
+) -> Option<AutoTypeMethod> {
+    let candidates: &[AutoTypeMethod] = match method {
+        AutoTypeMethod::Auto if wayland => &[AutoTypeMethod::Wtype, AutoTypeMethod::Ydotool],
+        AutoTypeMethod::Auto if x11 => &[AutoTypeMethod::Xdotool],
+        AutoTypeMethod::Auto => &[AutoTypeMethod::Ydotool],
+        _ => std::slice::from_ref(&method),
+    };
+    candidates.iter().copied().find(|candidate| installed(candidate.program()))
+}
+
+fn find_in_path(program: &str, path: &OsStr) -> bool {
+    env::split_paths(path).any(|dir| Path::new(&dir).join(program).is_file())
+}
+
+/// Types the given text into the focused window using the given tool.
+pub fn type_text(method: AutoTypeMethod, text: &str) -> io::Result<()> {
+    log::info!("typing code using {}", method.program());
+    let mut child = Command::new(method.program())
+        .args(method.args())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let written = child.stdin.take().unwrap().write_all(text.as_bytes());
+    let status = child.wait()?;
+    written?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} failed: {}", method.program(), status)))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_picks_installed_tool_for_session() {
+        let all = |_: &str| true;
+        let only_ydotool = |program: &str| program == "ydotool";
+        assert_eq!(resolve(AutoTypeMethod::Auto, true, true, all), Some(AutoTypeMethod::Wtype));
+        assert_eq!(resolve(AutoTypeMethod::Auto, true, false, only_ydotool), Some(AutoTypeMethod::Ydotool));
+        assert_eq!(resolve(AutoTypeMethod::Auto, false, true, all), Some(AutoTypeMethod::Xdotool));
+        assert_eq!(resolve(AutoTypeMethod::Auto, false, true, only_ydotool), None);
+        assert_eq!(resolve(AutoTypeMethod::Xdotool, true, false, all), Some(AutoTypeMethod::Xdotool));
+        assert_eq!(resolve(AutoTypeMethod::Wtype, true, false, only_ydotool), None);
+    }
+}
@@ -1,3 +1,35 @@
+use std::fmt::Display;
+
+/// Why a string isn't strictly valid RFC 4648 base32. Positions are 0-based character offsets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// A character outside of the base32 alphabet.
+    InvalidChar { position: usize, char: char },
+    /// Padding in the wrong place or of the wrong length, starting at the given position.
+    InvalidPadding { position: usize },
+    /// The number of data characters can't be produced by encoding whole bytes.
+    NonCanonicalLength(usize),
+    /// The bits left over after the last whole byte aren't zero, so the last character is wrong.
+    NonZeroTrailingBits,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::InvalidChar { position, char } => {
+                write!(f, "invalid character '{}' at position {}; only A-Z and 2-7 are allowed", char, position + 1)
+            },
+            DecodeError::InvalidPadding { position } => write!(f, "invalid padding at position {}", position + 1),
+            DecodeError::NonCanonicalLength(len) => {
+                write!(f, "{} characters can't be valid base32; the secret is probably truncated", len)
+            },
+            DecodeError::NonZeroTrailingBits => {
+                f.write_str("the last character has bits set that aren't part of any byte; the secret is probably truncated")
+            },
+        }
+    }
+}
+
 struct BitBuffer {
     bit_offset: u8,
     bytes: Vec<u8>,
@@ -53,6 +85,47 @@ pub fn decode(base32: &str) -> Option<Vec<u8>> {
     Some(buffer.into_bytes())
 }
 
+/// Like decode, but rejects anything that isn't valid RFC 4648 base32, explaining why.
+/// Lowercase letters are accepted, and padding is optional, but if present it must be complete.
+pub fn decode_strict(base32: &str) -> Result<Vec<u8>, DecodeError> {
+    let data_len = base32.find('=').unwrap_or(base32.len());
+    if let Some((position, char)) = base32[..data_len].chars().enumerate()
+        .find(|(_, c)| !matches!(c.to_ascii_uppercase(), 'A' ..= 'Z' | '2' ..= '7')) {
+        return Err(DecodeError::InvalidChar { position, char })
+    }
+    // Encoding n bytes gives 8n/5 characters, rounded up; the other lengths modulo 8 can't occur
+    if matches!(data_len % 8, 1 | 3 | 6) {
+        return Err(DecodeError::NonCanonicalLength(data_len))
+    }
+    let padding = &base32[data_len..];
+    if let Some(position) = padding.find(|c| c != '=') {
+        return Err(DecodeError::InvalidPadding { position: data_len + position })
+    }
+    if !padding.is_empty() && padding.len() != (8 - data_len % 8) % 8 {
+        return Err(DecodeError::InvalidPadding { position: data_len })
+    }
+    let bytes = decode(&base32[..data_len]).unwrap();
+    if !trailing_bits_are_zero(&base32[..data_len], bytes.len()) {
+        return Err(DecodeError::NonZeroTrailingBits)
+    }
+    Ok(bytes)
+}
+
+/// Returns true if the bits of the last character of the given valid base32 data,
+/// which decodes into the given number of bytes, that aren't part of any byte are all zero.
+fn trailing_bits_are_zero(data: &str, decoded_len: usize) -> bool {
+    let unused_bits = data.len() * 5 - decoded_len * 8;
+    let last = match data.chars().last() {
+        Some(c) => c.to_ascii_uppercase() as u8,
+        None => return true,
+    };
+    let value = match last {
+        b'A' ..= b'Z' => last - b'A',
+        _ => last - b'2' + 26,
+    };
+    value & ((1u8 << unused_bits) - 1) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,6 +182,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decode_strict_accepts_canonical_base32() {
+        assert_eq!(decode_strict("NBSWY3DP"), Ok(b"hello".to_vec()));
+        assert_eq!(decode_strict("obxxiylun4======"), Ok(b"potato".to_vec()));
+        assert_eq!(decode_strict("OBXXIYLUN4"), Ok(b"potato".to_vec()));
+        assert_eq!(decode_strict(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn decode_strict_explains_errors() {
+        assert_eq!(decode_strict("NBSW Y3DP"), Err(DecodeError::InvalidChar { position: 4, char: ' ' }));
+        assert_eq!(decode_strict("1BSWY3DP"), Err(DecodeError::InvalidChar { position: 0, char: '1' }));
+        assert_eq!(decode_strict("OBXXIYLUN4====="), Err(DecodeError::InvalidPadding { position: 10 }));
+        assert_eq!(decode_strict("OBXXIYLUN4==A==="), Err(DecodeError::InvalidPadding { position: 12 }));
+        assert_eq!(decode_strict("NBSWY3"), Err(DecodeError::NonCanonicalLength(6)));
+        assert_eq!(decode_strict("NBSWY3D"), Err(DecodeError::NonZeroTrailingBits));
+        assert_eq!(decode_strict("OBXXIYLUN5"), Err(DecodeError::NonZeroTrailingBits));
+    }
+
     #[test]
     fn bit_buffer_writes_left_to_right() {
         let mut buf = BitBuffer::new();
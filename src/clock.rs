@@ -0,0 +1,216 @@
+use std::{
+    fmt::Display,
+    fs,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Seconds since the epoch according to the hardware clock, as exposed by the kernel.
+const RTC_SINCE_EPOCH_PATH: &str = "/sys/class/rtc/rtc0/since_epoch";
+
+/// Largest difference between system and hardware clock, in seconds, that isn't worth warning about.
+/// Codes are valid for 30 seconds, and most servers accept one interval of skew in either direction.
+const MAX_RTC_OFFSET: i64 = 60;
+
+const NTP_PORT: u16 = 123;
+
+/// How long to wait for an NTP server before giving up on checking against it.
+const NTP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Seconds from the NTP epoch, 1900-01-01, to the Unix epoch.
+const NTP_UNIX_EPOCH: f64 = 2_208_988_800.0;
+
+/// A reason to believe that the system clock doesn't show the real time, so that generated codes are invalid.
+/// Note that the time zone doesn't matter, since codes are always based on UTC.
+#[derive(Debug, PartialEq)]
+pub enum Issue {
+    /// libfaketime is loaded into the process.
+    Faketime,
+    /// The system clock is the given number of seconds ahead of the hardware clock.
+    RtcOffset(i64),
+    /// The system clock is the given number of seconds ahead of the given NTP server.
+    NtpOffset(String, i64),
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::Faketime => f.write_str(
+                "libfaketime is preloaded; codes are generated for the fake time and will likely be rejected",
+            ),
+            Issue::RtcOffset(offset) => write!(
+                f,
+                "the system clock is {} seconds {} the hardware clock; if codes are rejected, check your clock",
+                offset.abs(),
+                if *offset > 0 { "ahead of" } else { "behind" },
+            ),
+            Issue::NtpOffset(server, offset) => write!(
+                f,
+                "the system clock is {} seconds {} {}; codes will likely be rejected until the clock is fixed",
+                offset.abs(),
+                if *offset > 0 { "ahead of" } else { "behind" },
+                server,
+            ),
+        }
+    }
+}
+
+/// Checks for signs that the system clock is being faked or is way off.
+/// Checks that can't be performed, e.g. because there is no readable hardware clock, are skipped.
+pub fn check() -> Vec<Issue> {
+    let mut issues = Vec::new();
+    if fs::read_to_string("/proc/self/maps").is_ok_and(|maps| is_faketime_loaded(&maps)) {
+        issues.push(Issue::Faketime);
+    }
+    if let Some(rtc) = read_rtc() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        if let Some(issue) = rtc_offset(now, rtc) {
+            issues.push(issue);
+        }
+    }
+    issues
+}
+
+/// Checks the system clock against the given NTP server, reporting an issue if it's off by more than max_offset seconds.
+/// If the server can't be reached, this is logged and the check is skipped.
+pub fn check_ntp(server: &str, max_offset: f64) -> Option<Issue> {
+    match ntp_offset(server) {
+        Ok(offset) if offset.abs() > max_offset => Some(Issue::NtpOffset(server.to_owned(), offset.round() as i64)),
+        Ok(offset) => {
+            log::debug!("system clock is {:.3} seconds ahead of {}", offset, server);
+            None
+        },
+        Err(e) => {
+            log::info!("unable to check the system clock against {}: {}", server, e);
+            None
+        },
+    }
+}
+
+/// Asks the given NTP server for the time using SNTP (RFC 4330), returning how many seconds the system clock is ahead.
+fn ntp_offset(server: &str) -> io::Result<f64> {
+    let address = (server, NTP_PORT).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::other("no address found"))?;
+    let local: SocketAddr = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(NTP_TIMEOUT))?;
+    socket.connect(address)?;
+
+    let sent = unix_time(SystemTime::now());
+    socket.send(&ntp_request(sent))?;
+    let mut response = [0u8; 48];
+    if socket.recv(&mut response)? < response.len() {
+        return Err(io::Error::other("truncated response"))
+    }
+    let received = unix_time(SystemTime::now());
+    ntp_response_offset(&response, sent, received).ok_or_else(|| io::Error::other("invalid response"))
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+/// Returns an SNTP client request, carrying the given time as its transmit timestamp
+/// so that the response can be matched against it.
+fn ntp_request(sent: f64) -> [u8; 48] {
+    let mut request = [0u8; 48];
+    // Leap indicator 0, version 4, mode 3 (client)
+    request[0] = 0x23;
+    request[40..48].copy_from_slice(&to_ntp_timestamp(sent));
+    request
+}
+
+/// Returns the offset of the local clock from the server's, given the server's response to a request sent
+/// and received at the given local times, or None if the response isn't a valid answer to that request.
+fn ntp_response_offset(response: &[u8; 48], sent: f64, received: f64) -> Option<f64> {
+    let mode = response[0] & 0x7;
+    let stratum = response[1];
+    // Stratum 0 is a "kiss-o'-death" message telling us to back off
+    if mode != 4 || stratum == 0 || response[24..32] != to_ntp_timestamp(sent) {
+        return None
+    }
+    let server_received = from_ntp_timestamp(response[32..40].try_into().unwrap());
+    let server_sent = from_ntp_timestamp(response[40..48].try_into().unwrap());
+    Some(((sent - server_received) + (received - server_sent)) / 2.0)
+}
+
+fn to_ntp_timestamp(unix_time: f64) -> [u8; 8] {
+    let time = unix_time + NTP_UNIX_EPOCH;
+    let seconds = time.trunc() as u32;
+    let fraction = (time.fract() * 4_294_967_296.0) as u32;
+    let mut timestamp = [0u8; 8];
+    timestamp[..4].copy_from_slice(&seconds.to_be_bytes());
+    timestamp[4..].copy_from_slice(&fraction.to_be_bytes());
+    timestamp
+}
+
+fn from_ntp_timestamp(timestamp: [u8; 8]) -> f64 {
+    let seconds = u32::from_be_bytes(timestamp[..4].try_into().unwrap());
+    let fraction = u32::from_be_bytes(timestamp[4..].try_into().unwrap());
+    seconds as f64 + fraction as f64 / 4_294_967_296.0 - NTP_UNIX_EPOCH
+}
+
+fn is_faketime_loaded(maps: &str) -> bool {
+    maps.lines()
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .any(|path| path.rsplit('/').next().is_some_and(|name| name.starts_with("libfaketime")))
+}
+
+fn read_rtc() -> Option<i64> {
+    fs::read_to_string(RTC_SINCE_EPOCH_PATH).ok()?.trim().parse().ok()
+}
+
+fn rtc_offset(now: i64, rtc: i64) -> Option<Issue> {
+    let offset = now - rtc;
+    if offset.abs() > MAX_RTC_OFFSET {
+        Some(Issue::RtcOffset(offset))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faketime_is_detected_in_maps() {
+        let maps = "\
+55d0c3a00000-55d0c3a2c000 r--p 00000000 fd:01 1234 /usr/bin/totpm
+7f1c2e000000-7f1c2e004000 r-xp 00000000 fd:01 5678 /usr/lib64/faketime/libfaketime.so.1
+7f1c2e100000-7f1c2e121000 rw-p 00000000 00:00 0
+";
+        assert!(is_faketime_loaded(maps));
+        assert!(!is_faketime_loaded(&maps.replace("libfaketime", "libc")));
+    }
+
+    #[test]
+    fn small_rtc_offsets_are_ignored() {
+        assert_eq!(rtc_offset(1000, 1000 - MAX_RTC_OFFSET), None);
+        assert_eq!(rtc_offset(1000, 1000 + MAX_RTC_OFFSET), None);
+        assert_eq!(rtc_offset(1000, 1000 - 3600), Some(Issue::RtcOffset(3600)));
+        assert_eq!(rtc_offset(1000, 1000 + 3600), Some(Issue::RtcOffset(-3600)));
+    }
+
+    #[test]
+    fn ntp_offset_is_computed_from_matching_responses_only() {
+        let sent = 1_700_000_000.25;
+        let mut response = [0u8; 48];
+        response[0] = 0x24;
+        response[1] = 2;
+        response[24..32].copy_from_slice(&ntp_request(sent)[40..48]);
+        // The server is 100 seconds ahead, and the round trip takes a second
+        response[32..40].copy_from_slice(&to_ntp_timestamp(sent + 100.5));
+        response[40..48].copy_from_slice(&to_ntp_timestamp(sent + 100.5));
+        let offset = ntp_response_offset(&response, sent, sent + 1.0).unwrap();
+        assert!((offset + 100.0).abs() < 0.001, "offset was {}", offset);
+
+        assert_eq!(ntp_response_offset(&response, sent + 1.0, sent + 2.0), None);
+        response[1] = 0;
+        assert_eq!(ntp_response_offset(&response, sent, sent + 1.0), None);
+    }
+}
@@ -1,30 +1,133 @@
-use std::io::{self, Write};
+use std::{fs::File, io::{self, Read, Write}, os::unix::fs::MetadataExt, path::Path};
 
 use rpassword::read_password;
 
-use crate::{base32, config::Config, result::{Error, Result}, totp_store::TotpStore};
+use crate::{args::AddArgs, config::Config, db::{model::Secret, SecretFilter}, privileges::{real_user_id, EuidSwapGuard}, result::{Context, Error, Result}, selection::{create_selector, Selector}, totp_store::{TotpStore, WithTPM}};
 
-pub fn run(
-    config: Config,
-    service: &str,
-    account: &str,
-    digits: Option<u8>,
-    interval: Option<u32>,
-    secret_on_stdin: bool,
+pub fn run(config: Config, args: AddArgs) -> Result<()> {
+    let secret_bytes = read_secret(&args)?;
+    let pin = read_new_pin(&args)?;
+    let mut selector = create_selector(config.selection);
+    let required = config.pv.require.add;
+    let mut store = super::open_store_for(config, required)?;
+    add_secret(&mut store, selector.as_mut(), args, &secret_bytes, pin.as_deref())
+}
+
+pub fn run_with_store(store: &mut TotpStore<WithTPM>, selector: &mut dyn Selector, args: AddArgs) -> Result<()> {
+    let secret_bytes = read_secret(&args)?;
+    let pin = read_new_pin(&args)?;
+    add_secret(store, selector, args, &secret_bytes, pin.as_deref())
+}
+
+fn add_secret(
+    store: &mut TotpStore<WithTPM>,
+    selector: &mut dyn Selector,
+    args: AddArgs,
+    secret_bytes: &[u8],
+    pin: Option<&str>,
 ) -> Result<()> {
-    let secret = if secret_on_stdin {
-        let mut buf = String::new();
-        io::stdin().read_line(&mut buf)?;
-        buf.trim().to_owned()
+    let existing = if args.update {
+        find_exact(store, &args.service, &args.account)?
     } else {
-        print!("Enter secret value for {} ({}): ", service, account);
-        io::stdout().flush()?;
-        read_password()?
+        Vec::new()
+    };
+    let modify = |secret: &mut Secret| {
+        if !args.tags.is_empty() {
+            secret.tags = args.tags;
+        }
+        if args.note.is_some() {
+            secret.notes = args.note;
+        }
+        if args.url.is_some() {
+            secret.url = args.url;
+        }
+        if args.icon.is_some() {
+            secret.icon = args.icon;
+        }
+        if args.rotate_after_days.is_some() {
+            secret.rotate_after_days = args.rotate_after_days;
+        }
+        if let Some(offset_seconds) = args.offset_seconds {
+            secret.offset_seconds = offset_seconds;
+        }
+        if let Some(t0) = args.t0 {
+            secret.t0 = t0;
+        }
     };
 
-    log::info!("adding secret for {} ({})", service, account);
-    let secret_bytes = base32::decode(&secret).ok_or(Error::SecretFormatError)?;
-    let mut store = TotpStore::with_tpm(config)?;
-    store.add(service, account, digits, interval, &secret_bytes)?;
+    if existing.is_empty() {
+        store.add_ex(&args.service, &args.account, args.digits, args.interval, secret_bytes, pin, modify)?;
+        return Ok(())
+    }
+
+    let old = selector.select(
+        "found multiple secrets for the given service/account combination",
+        &existing,
+    )?.ok_or(Error::AmbiguousSecret)?;
+    log::info!("replacing secret for {}", old);
+    store.replace_ex(old.id, &args.service, &args.account, args.digits, args.interval, secret_bytes, pin, modify)?;
     Ok(())
 }
+
+/// Returns all secrets with exactly the given service and account.
+pub(crate) fn find_exact(store: &mut TotpStore<WithTPM>, service: &str, account: &str) -> Result<Vec<Secret>> {
+    let secrets = store.find(&SecretFilter { service, account, exact: true, ..Default::default() })?
+        .into_iter()
+        .filter(|secret| secret.service == service && secret.account == account)
+        .collect();
+    Ok(secrets)
+}
+
+/// If a PIN was requested, prompts for it twice and returns it.
+fn read_new_pin(args: &AddArgs) -> Result<Option<String>> {
+    if !args.pin {
+        return Ok(None)
+    }
+    let pin = super::read_pin(&format!("Enter new PIN for {} ({}): ", args.service, args.account))?;
+    if super::read_pin("Repeat PIN: ")? != pin {
+        return Err(Error::PinMismatch)
+    }
+    Ok(Some(pin))
+}
+
+/// Reads a secret in the given format from either the secret file, stdin or the tty, and decodes it.
+fn read_secret(args: &AddArgs) -> Result<Vec<u8>> {
+    let (service, account, format) = (&args.service, &args.account, args.secret_format);
+    if let Some(path) = &args.secret_file {
+        log::info!("adding secret for {} ({}) from {}", service, account, path.to_str().unwrap());
+        return format.decode_bytes(&read_secret_file(path)?).map_err(Error::InvalidSecret)
+    }
+    if args.secret_on_stdin {
+        let mut buf = String::new();
+        io::stdin().read_line(&mut buf)?;
+        log::info!("adding secret for {} ({})", service, account);
+        return format.decode(buf.trim()).map_err(Error::InvalidSecret)
+    }
+
+    print!("Enter secret value for {} ({}): ", service, account);
+    io::stdout().flush()?;
+    let secret = format.decode(&read_password()?).map_err(Error::InvalidSecret)?;
+    // The secret can never be read back, so a typo would go unnoticed until the codes don't work
+    if !args.no_confirm {
+        print!("Repeat secret value: ");
+        io::stdout().flush()?;
+        if format.decode(&read_password()?).ok().as_ref() != Some(&secret) {
+            return Err(Error::SecretMismatch)
+        }
+    }
+    log::info!("adding secret for {} ({})", service, account);
+    Ok(secret)
+}
+
+/// Reads the given secret file as the user who ran totpm, refusing files that anyone else can access.
+fn read_secret_file(path: &Path) -> Result<Vec<u8>> {
+    let _euid = EuidSwapGuard::real_user()?;
+    let mut file = File::open(path).context(|| format!("reading {}", path.to_str().unwrap()))?;
+    let metadata = file.metadata()?;
+    if metadata.uid() != real_user_id() || metadata.mode() & 0o077 != 0 {
+        return Err(Error::InsecureSecretFile(path.to_owned()))
+    }
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
@@ -0,0 +1,139 @@
+use std::{path::PathBuf, process::Command};
+
+use crate::{config::Config, db::{self, model::Secret, SecretFilter, DB}, privileges::{is_root, EuidSwapGuard}, result::{Error, Result}};
+
+/// A local user, as far as admin commands are concerned.
+#[derive(Debug, PartialEq)]
+struct User {
+    name: String,
+    uid: u32,
+    home: PathBuf,
+}
+
+/// Copies all secrets matching the given filter from one user's secrets database to another's.
+///
+/// All users on a system install share the same primary key, so the wrapped HMAC keys can be copied as-is,
+/// without ever being unwrapped. Secrets for service/account combinations the receiving user
+/// already has are skipped.
+pub fn transfer(config: Config, from: &str, to: &str, filter: &SecretFilter) -> Result<()> {
+    if !is_root() {
+        return Err(Error::RootRequired)
+    }
+    let from = get_user(from)?;
+    let to = get_user(to)?;
+    let from_db_path = config.secrets_db_path_in(&from.home);
+    let to_db_path = config.secrets_db_path_in(&to.home);
+    if from_db_path == to_db_path {
+        eprintln!("{} and {} share the same secrets database; nothing to transfer", from.name, to.name);
+        return Ok(())
+    }
+
+    log::info!("reading secrets of user {} from {}", from.name, from_db_path.to_str().unwrap());
+    let secrets = {
+        let _euid = EuidSwapGuard::new(from.uid)?;
+        db::with_db_locking(&from_db_path, config.db_locking, |db| db.find_secrets(filter))?
+    };
+    let num_secrets = secrets.len();
+
+    log::info!("writing secrets of user {} to {}", to.name, to_db_path.to_str().unwrap());
+    let copied = {
+        let _euid = EuidSwapGuard::new(to.uid)?;
+        db::with_db_locking(&to_db_path, config.db_locking, |db| copy_secrets(db, secrets))?
+    };
+    println!("copied {} of {} matching secrets from {} to {}", copied, num_secrets, from.name, to.name);
+    Ok(())
+}
+
+/// Adds the given secrets to the database, skipping any service/account combination that already exists.
+/// Secrets with encrypted metadata are skipped too, since the metadata key belongs to the previous owner.
+/// Usage statistics are reset, since they belong to the previous owner as well.
+/// Returns the number of secrets added.
+fn copy_secrets(db: &DB, secrets: Vec<Secret>) -> db::Result<usize> {
+    let mut copied = 0;
+    for mut secret in secrets {
+        if secret.metadata_encrypted {
+            eprintln!("skipping secret {}, which has encrypted metadata", secret.id);
+            continue;
+        }
+        let exists = db.find_secrets(&SecretFilter {
+            service: &secret.service,
+            account: &secret.account,
+            exact: true,
+            ..Default::default()
+        })?.iter().any(|x| x.service == secret.service && x.account == secret.account);
+        if exists {
+            eprintln!("skipping {}, which already exists", secret);
+            continue;
+        }
+        secret.last_used_at = None;
+        secret.use_count = 0;
+        db.add_secret(secret)?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+fn get_user(name: &str) -> Result<User> {
+    let passwd_bytes = Command::new("/usr/bin/getent")
+        .arg("passwd")
+        .arg(name)
+        .output()?
+        .stdout;
+    String::from_utf8(passwd_bytes)
+        .ok()
+        .and_then(|entry| parse_passwd_entry(&entry))
+        .ok_or(Error::UserNotFoundError(name.to_string()))
+}
+
+/// Parses a line in /etc/passwd format.
+fn parse_passwd_entry(entry: &str) -> Option<User> {
+    let fields = entry.trim().split(':').collect::<Vec<_>>();
+    if fields.len() != 7 {
+        return None
+    }
+    Some(User {
+        name: fields[0].to_string(),
+        uid: fields[2].parse().ok()?,
+        home: PathBuf::from(fields[5]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn parse_passwd_entry_extracts_name_uid_and_home() {
+        assert_eq!(
+            parse_passwd_entry("alice:x:1000:1000:Alice:/home/alice:/bin/bash\n"),
+            Some(User { name: "alice".to_string(), uid: 1000, home: PathBuf::from("/home/alice") }),
+        );
+        assert_eq!(parse_passwd_entry(""), None);
+        assert_eq!(parse_passwd_entry("alice:x:notanumber:1000:Alice:/home/alice:/bin/bash"), None);
+    }
+
+    #[test]
+    fn copy_secrets_skips_existing_secrets_and_resets_usage() {
+        let db = NamedTempFile::new().unwrap();
+        let mut existing = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        existing.use_count = 5;
+        db::with_db(db.path(), |tx| tx.add_secret(existing.clone())).unwrap();
+
+        let mut new = Secret::new("svc".to_owned(), "other".to_owned(), None, None, vec![3], vec![4]);
+        new.use_count = 7;
+        new.last_used_at = Some(100);
+        let copied = db::with_db(db.path(), |tx| copy_secrets(tx, vec![existing, new])).unwrap();
+        assert_eq!(copied, 1);
+
+        let secrets = db::with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap();
+        assert_eq!(secrets.len(), 2);
+        assert_eq!(secrets[0].account, "acct");
+        assert_eq!(secrets[0].use_count, 5);
+        assert_eq!(secrets[1].account, "other");
+        assert_eq!(secrets[1].public_data, vec![3]);
+        assert_eq!(secrets[1].use_count, 0);
+        assert_eq!(secrets[1].last_used_at, None);
+    }
+}
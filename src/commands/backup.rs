@@ -0,0 +1,96 @@
+use std::{fs::{DirBuilder, Permissions}, os::unix::fs::{DirBuilderExt, PermissionsExt}, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{config::Config, db, housekeeping::atomic_write, privileges::PrivilegeDropGuard, result::Result, totp_store::{self, read_primary_key_persistent_handle}};
+
+/// Name of the database snapshot within a backup directory.
+pub const BACKUP_DB_FILE: &str = "secrets.sqlite";
+
+/// Name of the manifest within a backup directory.
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+/// Describes a backup, so that it can be validated before being restored.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Version of totpm that created the backup.
+    pub totpm_version: String,
+    /// When the backup was created, in seconds since the epoch.
+    pub created_at: i64,
+    /// Schema version of the database snapshot.
+    pub schema_version: u32,
+    /// TPM the secrets in the backup are bound to.
+    pub tpm: String,
+    /// Persistent handle of the primary key the secrets in the backup are wrapped under.
+    pub primary_key_handle: u32,
+    /// Number of secrets in the backup.
+    pub num_secrets: usize,
+}
+
+/// Backs up the secrets database to a new directory at the given path, containing a consistent
+/// snapshot of the database and a manifest.
+/// The backup contains no plaintext secrets, but is only usable together with the TPM primary key
+/// it was made with; it is not a way to move secrets to another machine.
+pub fn run(config: Config, path: &Path) -> Result<()> {
+    log::info!("reading primary key persistent handle");
+    let primary_key_handle = read_primary_key_persistent_handle(&config)
+        .or(Err(totp_store::Error::NotInitialized))?;
+    PrivilegeDropGuard::new()?.forever()?;
+
+    log::info!("creating backup directory with permissions 0700 at {}", path.to_str().unwrap());
+    DirBuilder::new().mode(0o700).create(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(0o700))?;
+
+    let num_secrets = db::with_db_locking(config.secrets_db_path(), config.db_locking, |db| Ok(db.list_secrets("", "")?.len()))?;
+    db::backup(config.secrets_db_path(), path.join(BACKUP_DB_FILE), config.db_locking)?;
+
+    let manifest = Manifest {
+        totpm_version: env!("CARGO_PKG_VERSION").to_owned(),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+        schema_version: db::CURRENT_SCHEMA_VERSION,
+        tpm: config.tpm.clone(),
+        primary_key_handle,
+        num_secrets,
+    };
+    atomic_write(&path.join(MANIFEST_FILE), toml::to_string(&manifest)?)?;
+    println!("backed up {} secrets to {}", num_secrets, path.to_str().unwrap());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::{presence_verification::PresenceVerificationMethod, totp_store::TotpStore};
+
+    use super::*;
+
+    #[test]
+    fn backup_writes_snapshot_and_manifest() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.path().join("sys")),
+            Some(dir.path().join("user")),
+            Some(PresenceVerificationMethod::None),
+        );
+        TotpStore::init(cfg.clone()).unwrap();
+        let secret = TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+
+        let backup_path = dir.path().join("backup");
+        run(cfg.clone(), &backup_path).unwrap();
+        let manifest: Manifest = toml::from_str(&std::fs::read_to_string(backup_path.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(manifest.primary_key_handle, read_primary_key_persistent_handle(&cfg).unwrap());
+        assert_eq!(manifest.num_secrets, 1);
+        assert_eq!(manifest.schema_version, db::CURRENT_SCHEMA_VERSION);
+        let secrets = db::with_db(backup_path.join(BACKUP_DB_FILE), |db| db.list_secrets("", "")).unwrap();
+        assert_eq!(secrets, vec![secret]);
+
+        assert!(run(cfg, &backup_path).is_err());
+    }
+}
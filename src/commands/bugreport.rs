@@ -0,0 +1,190 @@
+#[allow(deprecated)]
+use std::env::home_dir;
+use std::path::Path;
+
+use crate::{config::{local_path, Config}, presence_verification::ConstPresenceVerifier, privileges::{EuidSwapGuard, PrivilegeDropGuard}, result::{Error, Result}, totp_store::TotpStore, tpm::TPM};
+
+/// Where the last error encountered by totpm is recorded, relative to the user's home directory.
+const LAST_ERROR_PATH: &str = ".cache/totpm/last_error";
+
+/// Text that redacted parts of a bug report are replaced with.
+const REDACTED: &str = "***";
+
+/// Prints a snapshot of the local totpm installation, along with the results of totpm doctor, suitable for attaching
+/// to a bug report. Nothing is sent anywhere, secrets are never read, and the TPM is only queried without privileges.
+/// Paths inside the user's home directory, the TCTI configuration and service and account names are redacted.
+pub fn run(config_path: &Path, config: Result<Config>) -> Result<()> {
+    PrivilegeDropGuard::new()?.forever()?;
+    let mut lines = Vec::new();
+    lines.push(format!("totpm {}", env!("CARGO_PKG_VERSION")));
+    lines.push(format!("features: {}", enabled_features().join(", ")));
+    lines.push(format!("kernel: {}", read_trimmed(Path::new("/proc/sys/kernel/osrelease"))));
+
+    lines.push(String::new());
+    lines.push(format!("# config ({})", config_path.to_str().unwrap()));
+    match &config {
+        Ok(config) => lines.push(toml::to_string(config)?.trim_end().to_string()),
+        Err(e) => lines.push(format!("unable to load config: {:?}", e)),
+    }
+
+    lines.push(String::new());
+    lines.push("# tpm".to_string());
+    match &config {
+        Ok(config) => lines.extend(tpm_info(config)),
+        Err(_) => lines.push("no config; tpm not queried".to_string()),
+    }
+
+    lines.push(String::new());
+    lines.push("# doctor (without privileges)".to_string());
+    lines.extend(super::doctor::report_lines(config_path, config.as_ref())?);
+
+    lines.push(String::new());
+    lines.push("# last error".to_string());
+    lines.push(read_trimmed(&local_path(Path::new(LAST_ERROR_PATH))));
+
+    #[allow(deprecated)]
+    let home = home_dir().unwrap_or_default();
+    let mut report = redact(&lines.join("\n"), &home);
+    if let Ok(config) = &config {
+        report = redact_names(&redact_tcti(&report, &config.tpm), &secret_names(config));
+    }
+    println!("{}", report);
+    Ok(())
+}
+
+/// Records the given error, so that it can be included in a later bug report.
+/// Failing to record the error is not considered an error in itself.
+pub fn record_error(error: &Error) {
+    let path = local_path(Path::new(LAST_ERROR_PATH));
+    let result = EuidSwapGuard::real_user().and_then(|_euid| {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, format!("code: {}\n{:#?}\n", error.code(), error))
+    });
+    if let Err(e) = result {
+        log::warn!("unable to record error to {}: {}", path.to_str().unwrap(), e);
+    }
+}
+
+/// Returns the names of all features this binary was compiled with.
+pub(crate) fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "import") {
+        features.push("import");
+    }
+    if cfg!(feature = "fprintd") {
+        features.push("fprintd");
+    }
+    if cfg!(feature = "install") {
+        features.push("install");
+    }
+    features
+}
+
+fn tpm_info(config: &Config) -> Vec<String> {
+    let pv = Box::new(ConstPresenceVerifier::new(true));
+    match TPM::new(pv, &config.tpm).and_then(|mut tpm| tpm.info()) {
+        Ok(info) => vec![
+            format!("manufacturer: {}", info.manufacturer),
+            format!("vendor: {}", info.vendor),
+            format!("firmware: {}", info.firmware_version),
+            format!("spec revision: {}", info.spec_revision),
+        ],
+        Err(e) => vec![format!("unable to query tpm: {:?}", e)],
+    }
+}
+
+/// Returns the service and account names of the secrets in every profile, skipping any profile that can't be read.
+fn secret_names(config: &Config) -> Vec<String> {
+    let mut configs = vec![config.clone()];
+    configs.extend(config.profiles.keys().filter_map(|name| config.clone().with_profile(name).ok()));
+    configs.into_iter()
+        .filter(|config| config.secrets_db_path().is_file())
+        .filter_map(|config| TotpStore::without_tpm(config).and_then(|mut store| store.list(None, None)).ok())
+        .flatten()
+        .flat_map(|secret| [secret.service, secret.account])
+        .collect()
+}
+
+fn read_trimmed(path: &Path) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents.trim_end().to_string(),
+        Err(e) => format!("unavailable ({})", e.kind()),
+    }
+}
+
+/// Replaces all occurrences of the given home directory with '~'.
+fn redact(text: &str, home: &Path) -> String {
+    match home.to_str() {
+        Some(home) if home.len() > 1 => text.replace(home, "~"),
+        _ => text.to_string(),
+    }
+}
+
+/// Replaces the given TCTI configuration with just the name of the TCTI, e.g. swtpm:host=... with swtpm:***.
+fn redact_tcti(text: &str, tcti: &str) -> String {
+    match tcti.split_once(':') {
+        Some((name, conf)) if !conf.is_empty() => text.replace(tcti, &format!("{}:{}", name, REDACTED)),
+        _ => text.to_string(),
+    }
+}
+
+/// Replaces each occurrence of the given names which isn't part of a longer word.
+/// Longer names are replaced first, so that a name containing another one is redacted whole.
+fn redact_names(text: &str, names: &[String]) -> String {
+    let mut names = names.iter().filter(|name| !name.is_empty()).collect::<Vec<_>>();
+    names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+    names.dedup();
+    let mut text = text.to_string();
+    for name in names {
+        let mut redacted = String::new();
+        let mut rest = text.as_str();
+        while let Some(pos) = rest.find(name.as_str()) {
+            redacted.push_str(&rest[..pos]);
+            rest = &rest[pos + name.len()..];
+            let in_word = redacted.chars().next_back().is_some_and(char::is_alphanumeric)
+                || rest.chars().next().is_some_and(char::is_alphanumeric);
+            redacted.push_str(if in_word { name } else { REDACTED });
+        }
+        redacted.push_str(rest);
+        text = redacted;
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_replaces_home_directory() {
+        let home = Path::new("/home/alice");
+        assert_eq!(
+            redact("user_data_path = \"/home/alice/.local/state/totpm\"", home),
+            "user_data_path = \"~/.local/state/totpm\"",
+        );
+        assert_eq!(redact("/var/lib/totpm", home), "/var/lib/totpm");
+    }
+
+    #[test]
+    fn redact_ignores_root_home_directory() {
+        assert_eq!(redact("/var/lib/totpm", Path::new("/")), "/var/lib/totpm");
+    }
+
+    #[test]
+    fn redact_tcti_keeps_only_the_tcti_name() {
+        assert_eq!(
+            redact_tcti("tpm = \"swtpm:host=10.0.0.5,port=2321\"", "swtpm:host=10.0.0.5,port=2321"),
+            "tpm = \"swtpm:***\"",
+        );
+        assert_eq!(redact_tcti("tpm = \"device\"", "device"), "tpm = \"device\"");
+    }
+
+    #[test]
+    fn redact_names_replaces_whole_names_only() {
+        let names = ["git".to_owned(), "github".to_owned(), "alice".to_owned(), "alice".to_owned()];
+        assert_eq!(
+            redact_names("AmbiguousSecret(github, alice): gitlab and git", &names),
+            "AmbiguousSecret(***, ***): gitlab and ***",
+        );
+    }
+}
@@ -1,13 +1,59 @@
-use crate::{config::Config, result::Result, totp_store::TotpStore};
+use crate::{config::Config, db::SecretFilter, result::Result, totp_store::TotpStore};
 
+/// Removes all secrets, or only those of the given service and/or with the given tag, in one transaction.
+/// Secrets of a service or tag are listed and only removed once the user confirms, unless yes is true.
 pub fn run(
     config: Config,
     system: bool,
     go_ahead: bool,
+    yes: bool,
+    service: Option<&str>,
+    tag: Option<&str>,
 ) -> Result<()> {
     if !go_ahead {
         eprintln!("verification flag not specified; aborting");
         return Ok(())
     }
+    if service.is_some() || tag.is_some() {
+        let filter = SecretFilter { service: service.unwrap_or(""), tag, exact: true, ..Default::default() };
+        return super::del::run_all(config, &filter, yes)
+    }
     Ok(TotpStore::clear(config, system)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::presence_verification::PresenceVerificationMethod;
+
+    use super::*;
+
+    #[test]
+    fn clearing_a_service_or_tag_removes_only_matching_secrets() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.path().join("sys")),
+            Some(dir.path().join("user")),
+            Some(PresenceVerificationMethod::None),
+        );
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        store.add("github", "alice", None, None, &[0; 10]).unwrap();
+        store.add("github", "bob", None, None, &[0; 10]).unwrap();
+        store.add_ex("gitlab", "alice", None, None, &[0; 10], None, |secret| secret.tags = vec!["work".to_owned()]).unwrap();
+        store.add_ex("gitlab", "bob", None, None, &[0; 10], None, |secret| secret.tags = vec!["home".to_owned()]).unwrap();
+        drop(store);
+
+        run(cfg.clone(), false, true, true, Some("github"), None).unwrap();
+        run(cfg.clone(), false, true, true, None, Some("work")).unwrap();
+
+        let remaining = TotpStore::without_tpm(cfg).unwrap().list(None, None).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!((remaining[0].service.as_str(), remaining[0].account.as_str()), ("gitlab", "bob"));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,422 @@
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use crate::{config::{env_var_name, Config, OVERLAY_KEYS, OVERLAY_PATH}, housekeeping::atomic_write, privileges::PrivilegeDropGuard, result::{Error, Result}};
+
+/// TPM to fill in if an old config file somehow has none; the same as the default of init.
+const DEFAULT_TPM: &str = "device:/dev/tpmrm0";
+
+/// Where the effective value of a config key came from.
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    File,
+    /// The calling user's overlay on top of the system config.
+    Overlay,
+    /// A TOTPM_* environment variable.
+    Environment,
+    /// A command line flag, such as --select or --profile.
+    Flag,
+    Default,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::File => f.write_str("file"),
+            Source::Overlay => f.write_str("overlay"),
+            Source::Environment => f.write_str("environment"),
+            Source::Flag => f.write_str("flag"),
+            Source::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// Prints the effective configuration loaded from the given path, the given user overlay and environment overrides,
+/// with the given keys set by command line flags, annotating each value with its source.
+pub fn effective(
+    config_path: &Path,
+    config: &Config,
+    overlay: Option<&toml::Table>,
+    env: &toml::Table,
+    flags: &[&str],
+) -> Result<()> {
+    println!("# config file: {}", config_path.to_str().unwrap());
+    if overlay.is_some() {
+        println!("# overlay: ~/{}", OVERLAY_PATH);
+    }
+    for (key, value, source) in effective_values(config_path, config, overlay, env, flags)? {
+        match source {
+            Source::Environment => println!("{} = {} # {} ({})", key, value, source, env_var_name(&key)),
+            _ => println!("{} = {} # {}", key, value, source),
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the config file at the given path with every key this version of totpm knows about,
+/// filling in the ones missing from the file with their defaults, so that the file keeps loading as keys are added.
+/// Keys that are already set, including ones this version doesn't know about, are kept as they are,
+/// along with comments and formatting.
+/// If local is true, required keys get the defaults of a local install rather than a system one.
+pub fn upgrade(config_path: &Path, local: bool) -> Result<()> {
+    PrivilegeDropGuard::new()?.forever()?;
+    let permissions = std::fs::metadata(config_path)?.permissions();
+    let contents = std::fs::read_to_string(config_path)?;
+    let (values, added) = upgraded_values(toml::Table::from_str(&contents)?, local)?;
+    if added.is_empty() {
+        println!("{} is already up to date", config_path.to_str().unwrap());
+        return Ok(())
+    }
+
+    let mut document = parse_document(&contents)?;
+    for key in &added {
+        document.insert(key, to_item(key, &values[key])?);
+    }
+    write_values(config_path, &document.to_string(), permissions)?;
+    for key in &added {
+        println!("added {} = {}", key, values[key]);
+    }
+    println!("upgraded {}", config_path.to_str().unwrap());
+    Ok(())
+}
+
+/// Prints the effective value of the given key, e.g. pv_timeout or pv.require.gen.
+/// Strings are printed as they are, and other values in TOML syntax.
+pub fn get(config: &Config, key: &str) -> Result<()> {
+    let values = toml::Table::try_from(config)?;
+    match lookup(&values, key) {
+        Some(toml::Value::String(value)) => println!("{}", value),
+        Some(value) => println!("{}", value),
+        None => return Err(Error::ConfigKeyNotFound(key.to_owned())),
+    }
+    Ok(())
+}
+
+/// Sets the given key, e.g. pv_timeout or pv.require.gen, in the config file at the given path and rewrites it.
+/// The value is parsed as TOML if possible, e.g. true or ["fprintd", "pin"], and taken as a string otherwise.
+/// The file is only rewritten if the result is still a valid config.
+pub fn set(config_path: &Path, key: &str, value: &str) -> Result<()> {
+    PrivilegeDropGuard::new()?.forever()?;
+    let permissions = std::fs::metadata(config_path)?.permissions();
+    let contents = std::fs::read_to_string(config_path)?;
+    let mut document = parse_document(&contents)?;
+    insert(&mut document, key, parse_value(value))?;
+    let values = toml::Table::from_str(&document.to_string())?;
+    let config: Config = toml::Value::Table(values.clone()).try_into()?;
+    if lookup(&toml::Table::try_from(&config)?, key).is_none() {
+        return Err(Error::ConfigKeyNotFound(key.to_owned()))
+    }
+
+    write_values(config_path, &document.to_string(), permissions)?;
+    println!("set {} = {} in {}", key, lookup(&values, key).unwrap(), config_path.to_str().unwrap());
+    Ok(())
+}
+
+/// Parses the given value as a TOML value, or returns it as a string if it isn't one.
+fn parse_value(value: &str) -> toml_edit::Value {
+    let mut value = toml_edit::Value::from_str(value).unwrap_or_else(|_| toml_edit::Value::from(value));
+    value.decor_mut().clear();
+    value
+}
+
+/// Returns the value of the given dotted key.
+fn lookup<'a>(values: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    let (table_keys, key) = split_key(key);
+    let mut table = values;
+    for table_key in table_keys {
+        table = table.get(table_key)?.as_table()?;
+    }
+    table.get(key)
+}
+
+/// Sets the given dotted key, creating any tables it's in.
+/// A comment after the value it replaces is kept.
+fn insert(document: &mut toml_edit::DocumentMut, key: &str, mut value: toml_edit::Value) -> Result<()> {
+    let (table_keys, last_key) = split_key(key);
+    let mut table: &mut dyn toml_edit::TableLike = document.as_table_mut();
+    for table_key in table_keys {
+        table = table.entry(table_key)
+            .or_insert_with(|| {
+                let mut table = toml_edit::Table::new();
+                table.set_implicit(true);
+                toml_edit::Item::Table(table)
+            })
+            .as_table_like_mut()
+            .ok_or_else(|| Error::ConfigKeyNotFound(key.to_owned()))?;
+    }
+    if let Some(old_value) = table.get(last_key).and_then(toml_edit::Item::as_value) {
+        *value.decor_mut() = old_value.decor().clone();
+    }
+    table.insert(last_key, toml_edit::Item::Value(value));
+    Ok(())
+}
+
+/// Splits the given dotted key into the keys of the tables it's in, and its last part.
+fn split_key(key: &str) -> (Vec<&str>, &str) {
+    let mut parts = key.split('.').collect::<Vec<_>>();
+    let last = parts.pop().unwrap();
+    (parts, last)
+}
+
+/// Parses the given config file contents as a document that can be edited without losing comments and formatting.
+fn parse_document(contents: &str) -> Result<toml_edit::DocumentMut> {
+    // Report invalid files the same way as when loading them; toml uses the same parser, so the second parse can't fail
+    toml::Table::from_str(contents)?;
+    Ok(toml_edit::DocumentMut::from_str(contents).expect("config file is valid TOML"))
+}
+
+/// Returns the given value of the given top-level key as a document item, formatted the way toml writes it.
+/// Tables are placed after the ones already in the document they're inserted into.
+fn to_item(key: &str, value: &toml::Value) -> Result<toml_edit::Item> {
+    let mut values = toml::Table::new();
+    values.insert(key.to_owned(), value.clone());
+    let mut item = parse_document(&toml::to_string(&values)?)?.remove(key).unwrap();
+    move_tables_to_end(&mut item);
+    Ok(item)
+}
+
+fn move_tables_to_end(item: &mut toml_edit::Item) {
+    if let Some(table) = item.as_table_mut() {
+        table.set_position(usize::MAX);
+        for (_, item) in table.iter_mut() {
+            move_tables_to_end(item);
+        }
+    }
+}
+
+/// Atomically replaces the config file at the given path with the given contents, keeping its permissions.
+fn write_values(config_path: &Path, contents: &str, permissions: std::fs::Permissions) -> Result<()> {
+    atomic_write(config_path, contents)?;
+    std::fs::set_permissions(config_path, permissions)?;
+    Ok(())
+}
+
+/// Returns the given config file values with the missing ones filled in, along with the keys that were filled in.
+/// Fails if the result still isn't a valid config.
+fn upgraded_values(file_values: toml::Table, local: bool) -> Result<(toml::Table, Vec<String>)> {
+    let mut values = toml::Table::try_from(Config::default(local, DEFAULT_TPM.to_owned(), None, None, None))?;
+    values.extend(file_values.clone());
+    let config: Config = toml::Value::Table(values).try_into()?;
+
+    let mut values = file_values;
+    let mut added = Vec::new();
+    for (key, value) in toml::Table::try_from(&config)? {
+        if !values.contains_key(&key) {
+            values.insert(key.clone(), value);
+            added.push(key);
+        }
+    }
+    Ok((values, added))
+}
+
+/// Returns the value of every config key along with its source. Flags take precedence over the environment,
+/// which takes precedence over the overlay, then the config file.
+fn effective_values(
+    config_path: &Path,
+    config: &Config,
+    overlay: Option<&toml::Table>,
+    env: &toml::Table,
+    flags: &[&str],
+) -> Result<Vec<(String, toml::Value, Source)>> {
+    let file_values = toml::Table::from_str(&std::fs::read_to_string(config_path)?)?;
+    let values = toml::Table::try_from(config)?
+        .into_iter()
+        .map(|(key, value)| {
+            let in_overlay = overlay.is_some_and(|overlay| overlay.contains_key(&key) && OVERLAY_KEYS.contains(&key.as_str()));
+            let source = if flags.contains(&key.as_str()) {
+                Source::Flag
+            } else if env.contains_key(&key) {
+                Source::Environment
+            } else if in_overlay {
+                Source::Overlay
+            } else if file_values.contains_key(&key) {
+                Source::File
+            } else {
+                Source::Default
+            };
+            (key, value, source)
+        })
+        .collect();
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn set_parses_and_inserts_dotted_keys() {
+        let cfg_file = NamedTempFile::new().unwrap();
+        std::fs::write(cfg_file.path(), "
+            tpm = \"device:/dev/tpmrm0\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+        ").unwrap();
+
+        set(cfg_file.path(), "pv.require.gen", "true").unwrap();
+        set(cfg_file.path(), "pv_method", "[\"fprintd\", \"pin\"]").unwrap();
+        set(cfg_file.path(), "ntp_server", "pool.ntp.org").unwrap();
+        assert!(set(cfg_file.path(), "pv_timeout", "forever").is_err());
+        assert!(set(cfg_file.path(), "no_such_key", "1").is_err());
+
+        let values = toml::Table::from_str(&std::fs::read_to_string(cfg_file.path()).unwrap()).unwrap();
+        assert_eq!(lookup(&values, "pv.require.gen"), Some(&toml::Value::Boolean(true)));
+        assert_eq!(lookup(&values, "pv_method").and_then(|v| v.as_array()).map(|v| v.len()), Some(2));
+        assert_eq!(lookup(&values, "ntp_server"), Some(&toml::Value::String("pool.ntp.org".to_owned())));
+        assert_eq!(lookup(&values, "pv_timeout"), None);
+        assert_eq!(lookup(&values, "no_such_key"), None);
+    }
+
+    #[test]
+    fn set_keeps_comments_and_formatting() {
+        let cfg_file = NamedTempFile::new().unwrap();
+        std::fs::write(cfg_file.path(), "# Installed by totpm init
+tpm = \"device:/dev/tpmrm0\"
+system_data_path = \"/var/lib/totpm\"
+user_data_path = \".local/state/totpm\"
+pv_method = \"fprintd\" # no PINs on shared machines
+").unwrap();
+
+        set(cfg_file.path(), "pv_method", "[\"fprintd\", \"none\"]").unwrap();
+        set(cfg_file.path(), "pv.require.gen", "true").unwrap();
+        assert_eq!(std::fs::read_to_string(cfg_file.path()).unwrap(), "# Installed by totpm init
+tpm = \"device:/dev/tpmrm0\"
+system_data_path = \"/var/lib/totpm\"
+user_data_path = \".local/state/totpm\"
+pv_method = [\"fprintd\", \"none\"] # no PINs on shared machines
+
+[pv.require]
+gen = true
+");
+    }
+
+    #[test]
+    fn upgraded_values_fills_in_missing_keys_and_keeps_the_rest() {
+        let file_values = toml::Table::from_str("
+            tpm = \"device:/dev/tpm0\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_timeout = \"30s\"
+            from_the_future = 1
+        ").unwrap();
+
+        let (values, added) = upgraded_values(file_values, false).unwrap();
+        assert!(added.contains(&"pv_method".to_owned()));
+        assert!(added.contains(&"selection".to_owned()));
+        assert!(!added.contains(&"pv_timeout".to_owned()));
+        assert_eq!(values["pv_method"], toml::Value::String("fprintd".to_owned()));
+        assert_eq!(values["tpm"], toml::Value::String("device:/dev/tpm0".to_owned()));
+        assert_eq!(values["pv_timeout"], toml::Value::String("30s".to_owned()));
+        assert_eq!(values["from_the_future"], toml::Value::Integer(1));
+        assert!(upgraded_values(values, false).unwrap().1.is_empty());
+
+        let invalid = toml::Table::from_str("selection = \"whatever\"").unwrap();
+        assert!(upgraded_values(invalid, true).is_err());
+    }
+
+    #[test]
+    fn upgrade_keeps_comments_and_formatting() {
+        let cfg_file = NamedTempFile::new().unwrap();
+        let cfg_str = "# Installed by totpm init
+tpm = \"device:/dev/tpmrm0\" # the resource manager
+system_data_path = \"/var/lib/totpm\"
+user_data_path = \".local/state/totpm\"
+pv_method = \"fprintd\"
+
+# Reworded for our users
+[pv_prompts]
+place_finger = \"Touch the reader\"
+";
+        std::fs::write(cfg_file.path(), cfg_str).unwrap();
+
+        upgrade(cfg_file.path(), false).unwrap();
+        let upgraded = std::fs::read_to_string(cfg_file.path()).unwrap();
+        assert!(upgraded.starts_with(&cfg_str[..cfg_str.find("\n\n").unwrap()]));
+        assert!(upgraded.contains("# Reworded for our users\n[pv_prompts]\nplace_finger = \"Touch the reader\"\n"));
+        let values = toml::Table::from_str(&upgraded).unwrap();
+        assert_eq!(values["pv_timeout"], toml::Value::String("10s".to_owned()));
+        assert!(upgraded.find("[pv_prompts]").unwrap() < upgraded.find("[primary_key]").unwrap());
+    }
+
+    #[test]
+    fn effective_values_reports_missing_keys_as_defaults() {
+        let cfg_file = NamedTempFile::new().unwrap();
+        let cfg_str = "
+            tpm = \"device:/dev/tpmrm0\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+        ";
+        std::fs::write(cfg_file.path(), cfg_str).unwrap();
+        let cfg = Config::deserialize(toml::Deserializer::new(cfg_str)).unwrap();
+
+        let values = effective_values(cfg_file.path(), &cfg, None, &toml::Table::new(), &[]).unwrap();
+        for (key, value, source) in values {
+            match key.as_str() {
+                "pv_timeout" => {
+                    assert_eq!(value, toml::Value::String("10s".to_owned()));
+                    assert_eq!(source, Source::Default);
+                },
+                "tpm" => {
+                    assert_eq!(value, toml::Value::String("device:/dev/tpmrm0".to_owned()));
+                    assert_eq!(source, Source::File);
+                },
+                "pv_prompts" | "pv" | "encrypt_metadata" | "selection" | "gen_selection" | "auto_type" | "migratable_keys"
+                | "primary_key" | "db_locking" | "normalize_names" => assert_eq!(source, Source::Default),
+                _ => assert_eq!(source, Source::File),
+            }
+        }
+    }
+
+    #[test]
+    fn effective_values_reports_overlaid_keys() {
+        let cfg_file = NamedTempFile::new().unwrap();
+        let cfg_str = "
+            tpm = \"device:/dev/tpmrm0\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+        ";
+        std::fs::write(cfg_file.path(), cfg_str).unwrap();
+        let cfg = Config::deserialize(toml::Deserializer::new(cfg_str)).unwrap();
+        let overlay = toml::Table::from_str("pv_timeout = \"30s\"\ntpm = \"device\"").unwrap();
+
+        let sources = effective_values(cfg_file.path(), &cfg, Some(&overlay), &toml::Table::new(), &[]).unwrap()
+            .into_iter()
+            .map(|(key, _, source)| (key, source))
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(sources["pv_timeout"], Source::Overlay);
+        assert_eq!(sources["tpm"], Source::File);
+        assert_eq!(sources["selection"], Source::Default);
+    }
+
+    #[test]
+    fn effective_values_reports_environment_and_flag_overrides() {
+        let cfg_file = NamedTempFile::new().unwrap();
+        let cfg_str = "
+            tpm = \"device:/dev/tpmrm0\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+            selection = \"filter\"
+            ntp_server = \"time.example.com\"
+        ";
+        std::fs::write(cfg_file.path(), cfg_str).unwrap();
+        let cfg = Config::deserialize(toml::Deserializer::new(cfg_str)).unwrap();
+        let overlay = toml::Table::from_str("pv_timeout = \"30s\"\nntp_server = \"pool.ntp.org\"").unwrap();
+        let env = toml::Table::from_str("pv_timeout = \"1m\"\nselection = \"mru\"").unwrap();
+
+        let sources = effective_values(cfg_file.path(), &cfg, Some(&overlay), &env, &["selection", "gen_selection"]).unwrap()
+            .into_iter()
+            .map(|(key, _, source)| (key, source))
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(sources["selection"], Source::Flag);
+        assert_eq!(sources["pv_timeout"], Source::Environment);
+        assert_eq!(sources["ntp_server"], Source::Overlay);
+        assert_eq!(sources["tpm"], Source::File);
+        assert_eq!(sources["auto_type"], Source::Default);
+    }
+}
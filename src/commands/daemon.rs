@@ -0,0 +1,191 @@
+use std::{io, time::{Duration, SystemTime}};
+
+use dbus::{blocking::Connection, message::MessageType, Message, MethodErr};
+
+use crate::{config::Config, db::model::Secret, result::Result, totp_store::{self, TotpStore, WithTPM}};
+
+pub const BUS_NAME: &str = "org.totpm.Manager";
+pub const OBJECT_PATH: &str = "/org/totpm/Manager";
+pub const INTERFACE: &str = "org.totpm.Manager";
+
+/// How long to wait for incoming messages before checking the connection again.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Serves the secrets store on the session bus until the connection is lost.
+/// The TPM is only opened once, but presence is verified for every code generated.
+///
+/// Methods of the org.totpm.Manager interface:
+/// - ListSecrets(service: s, account: s) -> a(xss): id, service and account of all secrets matching
+///   the given service and account, which may be empty
+/// - ListSecretDetails(service: s, account: s) -> a(xssss): like ListSecrets, but also with the url and icon
+///   of each secret, for frontends to render entries with; empty if not set
+/// - GenerateCode(id: x) -> s: a code for the secret with the given id, after verifying presence
+/// - GenerateCodeWithPin(id: x, pin: s) -> s: like GenerateCode, for secrets protected by a PIN
+pub fn run(config: Config) -> Result<()> {
+    // Opening the store drops any elevated privileges for good, so the session bus is only connected to after it
+    let mut store = TotpStore::with_tpm_unverified(config)?;
+    let conn = Connection::new_session().map_err(io::Error::other)?;
+    conn.request_name(BUS_NAME, false, true, true).map_err(io::Error::other)?;
+    log::info!("serving {} on the session bus", BUS_NAME);
+    loop {
+        conn.channel().read_write(Some(POLL_INTERVAL))
+            .map_err(|_| io::Error::other("lost connection to the session bus"))?;
+        while let Some(msg) = conn.channel().pop_message() {
+            if msg.msg_type() != MessageType::MethodCall {
+                continue
+            }
+            let reply = handle(&mut store, &msg).unwrap_or_else(|e| e.to_message(&msg));
+            if !msg.get_no_reply() && conn.channel().send(reply).is_err() {
+                log::warn!("unable to reply to {:?}", msg.sender());
+            }
+        }
+    }
+}
+
+/// What the daemon needs from the secrets store.
+trait Store {
+    fn list(&mut self, service: &str, account: &str) -> totp_store::Result<Vec<Secret>>;
+    fn verify_presence(&mut self) -> totp_store::Result<()>;
+    fn gen(&mut self, id: i64, pin: Option<&str>) -> totp_store::Result<String>;
+}
+
+impl Store for TotpStore<WithTPM> {
+    fn list(&mut self, service: &str, account: &str) -> totp_store::Result<Vec<Secret>> {
+        TotpStore::list(self, Some(service), Some(account))
+    }
+
+    fn verify_presence(&mut self) -> totp_store::Result<()> {
+        TotpStore::verify_presence(self)
+    }
+
+    fn gen(&mut self, id: i64, pin: Option<&str>) -> totp_store::Result<String> {
+        self.gen_with_pin(id, SystemTime::now(), pin)
+    }
+}
+
+fn handle(store: &mut dyn Store, msg: &Message) -> std::result::Result<Message, MethodErr> {
+    if msg.path().as_deref() != Some(OBJECT_PATH) || msg.interface().as_deref() != Some(INTERFACE) {
+        return Err(MethodErr::no_interface(&msg.interface().map(|i| i.to_string()).unwrap_or_default()))
+    }
+    match msg.member().as_deref() {
+        Some("ListSecrets") => {
+            let (service, account): (&str, &str) = msg.read2()?;
+            let secrets = store.list(service, account).map_err(failed)?;
+            let secrets = secrets.into_iter()
+                .map(|secret| (secret.id, secret.service, secret.account))
+                .collect::<Vec<_>>();
+            Ok(msg.method_return().append1(secrets))
+        },
+        Some("ListSecretDetails") => {
+            let (service, account): (&str, &str) = msg.read2()?;
+            let secrets = store.list(service, account).map_err(failed)?;
+            let secrets = secrets.into_iter()
+                .map(|secret| (
+                    secret.id,
+                    secret.service,
+                    secret.account,
+                    secret.url.unwrap_or_default(),
+                    secret.icon.unwrap_or_default(),
+                ))
+                .collect::<Vec<_>>();
+            Ok(msg.method_return().append1(secrets))
+        },
+        Some("GenerateCode") => {
+            let id: i64 = msg.read1()?;
+            store.verify_presence().map_err(failed)?;
+            let code = store.gen(id, None).map_err(failed)?;
+            Ok(msg.method_return().append1(code))
+        },
+        Some("GenerateCodeWithPin") => {
+            let (id, pin): (i64, &str) = msg.read2()?;
+            store.verify_presence().map_err(failed)?;
+            let code = store.gen(id, Some(pin)).map_err(failed)?;
+            Ok(msg.method_return().append1(code))
+        },
+        member => Err(MethodErr::no_method(member.unwrap_or_default())),
+    }
+}
+
+fn failed(error: crate::totp_store::Error) -> MethodErr {
+    MethodErr::failed(&format!("{:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::model::Secret, totp_store::Error};
+
+    use super::*;
+
+    /// Serves a single secret with a PIN, and records every call.
+    #[derive(Default)]
+    struct StubStore {
+        calls: Vec<String>,
+        present: bool,
+    }
+
+    impl Store for StubStore {
+        fn list(&mut self, service: &str, account: &str) -> totp_store::Result<Vec<Secret>> {
+            self.calls.push(format!("list {} {}", service, account));
+            let mut secret = Secret::new("github".to_owned(), "alice".to_owned(), None, None, vec![], vec![]);
+            secret.id = 1;
+            secret.url = Some("https://github.com".to_owned());
+            Ok(vec![secret])
+        }
+
+        fn verify_presence(&mut self) -> totp_store::Result<()> {
+            self.calls.push("verify presence".to_owned());
+            match self.present {
+                true => Ok(()),
+                false => Err(Error::TpmError(crate::tpm::Error::PresenceVerificationFailed)),
+            }
+        }
+
+        fn gen(&mut self, id: i64, pin: Option<&str>) -> totp_store::Result<String> {
+            self.calls.push(format!("gen {}", id));
+            match pin {
+                Some("1234") => Ok("123456".to_owned()),
+                _ => Err(Error::PinRequired),
+            }
+        }
+    }
+
+    fn call(member: &str) -> Message {
+        let mut msg = Message::new_method_call(BUS_NAME, OBJECT_PATH, INTERFACE, member).unwrap();
+        msg.set_serial(1);
+        msg
+    }
+
+    #[test]
+    fn list_secret_details_returns_matching_secrets() {
+        let mut store = StubStore::default();
+        let reply = handle(&mut store, &call("ListSecretDetails").append2("git", "")).unwrap();
+        let secrets: Vec<(i64, String, String, String, String)> = reply.read1().unwrap();
+        assert_eq!(secrets, vec![(1, "github".to_owned(), "alice".to_owned(), "https://github.com".to_owned(), String::new())]);
+        assert_eq!(store.calls, vec!["list git "]);
+    }
+
+    #[test]
+    fn generate_code_verifies_presence_first() {
+        let mut store = StubStore::default();
+        assert!(handle(&mut store, &call("GenerateCodeWithPin").append2(1i64, "1234")).is_err());
+        assert_eq!(store.calls, vec!["verify presence"]);
+    }
+
+    #[test]
+    fn generate_code_with_pin_passes_pin_to_store() {
+        let mut store = StubStore { present: true, ..Default::default() };
+        assert!(handle(&mut store, &call("GenerateCode").append1(1i64)).is_err());
+        let reply = handle(&mut store, &call("GenerateCodeWithPin").append2(1i64, "1234")).unwrap();
+        assert_eq!(reply.read1::<&str>().unwrap(), "123456");
+        assert_eq!(store.calls, vec!["verify presence", "gen 1", "verify presence", "gen 1"]);
+    }
+
+    #[test]
+    fn unknown_members_and_interfaces_are_rejected() {
+        let mut store = StubStore::default();
+        assert!(handle(&mut store, &call("DeleteEverything")).is_err());
+        let msg = Message::new_method_call(BUS_NAME, OBJECT_PATH, "org.example.Other", "ListSecrets").unwrap();
+        assert!(handle(&mut store, &msg).is_err());
+        assert!(store.calls.is_empty());
+    }
+}
@@ -1,21 +1,156 @@
-use crate::{config::Config, term::pick_one, totp_store::TotpStore};
+use std::io::{self, BufRead};
 
-pub fn run(config: Config, service: &str, account: &str) -> Result<(), crate::result::Error> {
-    let mut store = TotpStore::without_tpm(config);
-    let alternatives = store.list(Some(service), Some(account))?;
+use crate::{config::Config, db::SecretFilter, result::Error, selection::{create_selector, Selector}, term, totp_store::TotpStore};
+
+/// Deletes the secret matching the given filter, after asking for confirmation unless yes is true.
+pub fn run(config: Config, filter: &SecretFilter, yes: bool) -> Result<(), crate::result::Error> {
+    let mut selector = create_selector(config.selection);
+    if config.encrypt_metadata {
+        let required = config.pv.require.del;
+        del_matching(&mut super::open_store_for(config, required)?, selector.as_mut(), filter, yes, &mut io::stdin().lock())
+    } else {
+        super::verify_presence_if_required(&config, config.pv.require.del)?;
+        del_matching(&mut TotpStore::without_tpm(config)?, selector.as_mut(), filter, yes, &mut io::stdin().lock())
+    }
+}
+
+pub fn run_with_store<P>(
+    store: &mut TotpStore<P>,
+    selector: &mut dyn Selector,
+    service: &str,
+    account: &str,
+    yes: bool,
+) -> Result<(), crate::result::Error> {
+    let filter = SecretFilter { service, account, ..Default::default() };
+    del_matching(store, selector, &filter, yes, &mut io::stdin().lock())
+}
+
+/// Asks the user to confirm a deletion on stderr, unless yes is true.
+/// Anything but a yes, including no answer at all, fails with DeletionCancelled.
+fn confirm<In: BufRead>(inp: &mut In, question: &str, yes: bool) -> Result<(), Error> {
+    match yes || term::confirm(inp, &mut io::stderr(), question)? {
+        true => Ok(()),
+        false => Err(Error::DeletionCancelled),
+    }
+}
+
+fn del_matching<P, In: BufRead>(
+    store: &mut TotpStore<P>,
+    selector: &mut dyn Selector,
+    filter: &SecretFilter,
+    yes: bool,
+    inp: &mut In,
+) -> Result<(), crate::result::Error> {
+    let alternatives = store.find(filter)?;
     
     if alternatives.is_empty() {
         println!("service/account combination not found");
         return Ok(())
     }
 
-    if let Some(alt) = pick_one(
-        &mut std::io::stdin().lock(),
-        &mut std::io::stdout(),
+    if let Some(alt) = selector.select(
         "found multiple matches for the given service/account combination",
-        alternatives.iter()
-    ) {
+        &alternatives,
+    )? {
+        confirm(inp, &format!("Delete {}?", alt), yes)?;
         store.del(alt.id)?;
     }
     Ok(())
 }
+
+/// Deletes every secret matching the given filter, after asking for confirmation unless yes is true.
+pub fn run_all(config: Config, filter: &SecretFilter, yes: bool) -> Result<(), crate::result::Error> {
+    if config.encrypt_metadata {
+        let required = config.pv.require.del;
+        del_all_matching(&mut super::open_store_for(config, required)?, filter, yes, &mut io::stdin().lock())
+    } else {
+        super::verify_presence_if_required(&config, config.pv.require.del)?;
+        del_all_matching(&mut TotpStore::without_tpm(config)?, filter, yes, &mut io::stdin().lock())
+    }
+}
+
+fn del_all_matching<P, In: BufRead>(
+    store: &mut TotpStore<P>,
+    filter: &SecretFilter,
+    yes: bool,
+    inp: &mut In,
+) -> Result<(), crate::result::Error> {
+    let secrets = store.find(filter)?;
+    if secrets.is_empty() {
+        println!("service/account combination not found");
+        return Ok(())
+    }
+    for secret in &secrets {
+        println!("{}", secret);
+    }
+    confirm(inp, &format!("Permanently delete these {} secret(s)?", secrets.len()), yes)?;
+    store.del_all(&secrets.iter().map(|secret| secret.id).collect::<Vec<_>>())?;
+    println!("deleted {} secret(s)", secrets.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::{presence_verification::PresenceVerificationMethod, selection::FailFastSelector, totp_store::WithTPM};
+
+    use super::*;
+
+    fn store(tpm: &SwTpm, dir: &std::path::Path) -> TotpStore<WithTPM> {
+        let cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.join("sys")),
+            Some(dir.join("user")),
+            Some(PresenceVerificationMethod::None)
+        );
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg).unwrap();
+        store.add("github", "alice", None, None, &[0; 10]).unwrap();
+        store.add("gitlab", "alice", None, None, &[0; 10]).unwrap();
+        store
+    }
+
+    #[test]
+    fn declined_deletion_fails_and_deletes_nothing() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let mut store = store(&tpm, dir.path());
+        let filter = SecretFilter { service: "github", ..Default::default() };
+
+        let result = del_matching(&mut store, &mut FailFastSelector, &filter, false, &mut "n\n".as_bytes());
+        assert!(matches!(result, Err(Error::DeletionCancelled)), "{:#?}", result);
+        let result = del_all_matching(&mut store, &SecretFilter::default(), false, &mut "n\n".as_bytes());
+        assert!(matches!(result, Err(Error::DeletionCancelled)), "{:#?}", result);
+        assert_eq!(store.list(None, None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn deletion_without_answer_fails_and_deletes_nothing() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let mut store = store(&tpm, dir.path());
+        let filter = SecretFilter { service: "github", ..Default::default() };
+
+        let result = del_matching(&mut store, &mut FailFastSelector, &filter, false, &mut io::empty());
+        assert!(matches!(result, Err(Error::DeletionCancelled)), "{:#?}", result);
+        let result = del_all_matching(&mut store, &SecretFilter::default(), false, &mut io::empty());
+        assert!(matches!(result, Err(Error::DeletionCancelled)), "{:#?}", result);
+        assert_eq!(store.list(None, None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn confirmed_deletion_deletes_only_matching_secret() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let mut store = store(&tpm, dir.path());
+        let filter = SecretFilter { service: "github", ..Default::default() };
+
+        del_matching(&mut store, &mut FailFastSelector, &filter, false, &mut "y\n".as_bytes()).unwrap();
+        let remaining = store.list(None, None).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].service, "gitlab");
+    }
+}
@@ -0,0 +1,296 @@
+use std::{fmt::Display, os::unix::fs::PermissionsExt, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{
+    config::Config,
+    db::{self, model::Secret},
+    hygiene,
+    presence_verification::{ConstPresenceVerifier, PresenceVerificationMethod},
+    privileges::PrivilegeDropGuard,
+    result::{Error, Result},
+    totp_store::TotpStore,
+    tpm::TPM,
+};
+
+/// Name under which hygiene issues report the secrets not in any configured profile.
+const DEFAULT_PROFILE: &str = "default";
+
+/// Outcome of a single diagnostic check.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass(String),
+    /// The check failed: (what went wrong, how to fix it).
+    Fail(String, String),
+    /// The check doesn't apply, or depends on one that failed.
+    Skip(String),
+}
+
+/// A named diagnostic check, along with its outcome.
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            Outcome::Pass(detail) => write!(f, "PASS  {}: {}", self.name, detail),
+            Outcome::Fail(reason, hint) => write!(f, "FAIL  {}: {}\n      hint: {}", self.name, reason, hint),
+            Outcome::Skip(reason) => write!(f, "SKIP  {}: {}", self.name, reason),
+        }
+    }
+}
+
+/// Checks that totpm is set up correctly, printing a PASS/FAIL report with hints on how to fix any problems.
+/// If hygiene is true, also lists secrets in any profile which the user may want to clean up, using their names
+/// and usage times. Secrets' keys are never read.
+pub fn run(config_path: &Path, config: Result<Config>, hygiene: bool) -> Result<()> {
+    let mut checks = run_checks(config_path, config.as_ref())?;
+    let config = match config {
+        Ok(config) if hygiene => config,
+        _ => return report(&checks),
+    };
+
+    let (outcome, issues) = check_hygiene(&config);
+    checks.push(Check { name: "hygiene", outcome });
+    let result = report(&checks);
+    for line in issues {
+        println!("{}", line);
+    }
+    result
+}
+
+/// Returns the lines of the report printed by run, without hygiene checks, for inclusion in bug reports.
+/// Privileges are dropped for good.
+pub(crate) fn report_lines(config_path: &Path, config: std::result::Result<&Config, &Error>) -> Result<Vec<String>> {
+    Ok(run_checks(config_path, config)?.iter().map(Check::to_string).collect())
+}
+
+/// Runs every check that applies to the given config. Privileges are dropped for good once they're not needed.
+fn run_checks(config_path: &Path, config: std::result::Result<&Config, &Error>) -> Result<Vec<Check>> {
+    let mut checks = Vec::new();
+    let config = match config {
+        Ok(config) => {
+            checks.push(Check { name: "config", outcome: Outcome::Pass(format!("{} parses", config_path.to_str().unwrap())) });
+            config
+        },
+        Err(e) => {
+            checks.push(Check {
+                name: "config",
+                outcome: Outcome::Fail(
+                    format!("unable to load {}: {:?}", config_path.to_str().unwrap(), e),
+                    "fix the configuration file, or run totpm init to create one".to_owned(),
+                ),
+            });
+            PrivilegeDropGuard::new()?.forever()?;
+            return Ok(checks)
+        },
+    };
+
+    checks.push(Check { name: "auth value", outcome: check_auth_value(&config.auth_value_path()) });
+    let (tpm, outcome) = check_tpm(config);
+    checks.push(Check { name: "tpm", outcome });
+    let outcome = match tpm {
+        Some(mut tpm) => check_primary_key(&mut tpm, config),
+        None => Outcome::Skip("tpm unreachable".to_owned()),
+    };
+    checks.push(Check { name: "primary key", outcome });
+    PrivilegeDropGuard::new()?.forever()?;
+
+    checks.push(Check { name: "secrets database", outcome: check_db(config) });
+    checks.push(Check { name: "fprintd", outcome: check_fprintd(config) });
+    Ok(checks)
+}
+
+fn report(checks: &[Check]) -> Result<()> {
+    for check in checks {
+        println!("{}", check);
+    }
+    match checks.iter().filter(|check| matches!(check.outcome, Outcome::Fail(..))).count() {
+        0 => Ok(()),
+        failed => Err(Error::DoctorChecksFailed(failed)),
+    }
+}
+
+fn check_auth_value(path: &Path) -> Outcome {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.permissions().mode() & 0o077 == 0 => {
+            Outcome::Pass(format!("{} is only accessible by its owner", path.to_str().unwrap()))
+        },
+        Ok(metadata) => Outcome::Fail(
+            format!("{} has permissions {:o}", path.to_str().unwrap(), metadata.permissions().mode() & 0o777),
+            format!("run chmod 600 {}", path.to_str().unwrap()),
+        ),
+        Err(e) => Outcome::Fail(
+            format!("unable to access {}: {}", path.to_str().unwrap(), e),
+            "run totpm init, or check system_data_path in the configuration file".to_owned(),
+        ),
+    }
+}
+
+fn check_tpm(config: &Config) -> (Option<TPM>, Outcome) {
+    let pv = Box::new(ConstPresenceVerifier::new(true));
+    match TPM::new(pv, &config.tpm).and_then(|mut tpm| tpm.info().map(|info| (tpm, info))) {
+        Ok((tpm, info)) => {
+            let outcome = Outcome::Pass(format!("{} is reachable ({} {})", config.tpm, info.manufacturer, info.vendor));
+            (Some(tpm), outcome)
+        },
+        Err(e) => {
+            let outcome = Outcome::Fail(
+                format!("unable to talk to {}: {:?}", config.tpm, e),
+                "run totpm init --probe-tpm to find a working tpm setting, and make sure you're in the tss group".to_owned(),
+            );
+            (None, outcome)
+        },
+    }
+}
+
+fn check_primary_key(tpm: &mut TPM, config: &Config) -> Outcome {
+    let handle = std::fs::read_to_string(config.primary_key_handle_path())
+        .ok()
+        .and_then(|handle| handle.trim().parse::<u32>().ok());
+    let (handle, auth_value) = match (handle, std::fs::read(config.auth_value_path())) {
+        (Some(handle), Ok(auth_value)) => (handle, auth_value),
+        _ => return Outcome::Skip("primary key handle or auth value unavailable".to_owned()),
+    };
+    let result = auth_value.try_into()
+        .map_err(crate::tpm::Error::from)
+        .and_then(|auth_value| tpm.get_persistent_primary(handle, auth_value));
+    match result {
+        Ok(_) => Outcome::Pass(format!("found at {:#010x}", handle)),
+        Err(e) => Outcome::Fail(
+            format!("unable to load primary key at {:#010x}: {:?}", handle, e),
+            "if the tpm was cleared, the primary key is gone; run totpm clear --system and totpm init".to_owned(),
+        ),
+    }
+}
+
+fn check_db(config: &Config) -> Outcome {
+    let path = config.secrets_db_path();
+    if !path.is_file() {
+        return Outcome::Skip(format!("{} doesn't exist yet; it's created when adding a secret", path.to_str().unwrap()))
+    }
+    match db::with_db_locking(&path, config.db_locking, |db| db.integrity_check()) {
+        Ok(messages) if messages.is_empty() => Outcome::Pass(format!("{} opens", path.to_str().unwrap())),
+        Ok(messages) => Outcome::Fail(
+            format!("{} is corrupt: {}", path.to_str().unwrap(), messages.join("; ")),
+            "restore a backup using totpm restore".to_owned(),
+        ),
+        Err(e) => Outcome::Fail(
+            format!("unable to open {}: {:?}", path.to_str().unwrap(), e),
+            "check the permissions of the database, and that no other totpm process is hanging".to_owned(),
+        ),
+    }
+}
+
+/// Checks the secrets of every profile for hygiene issues, returning the outcome along with lines describing
+/// each flagged secret.
+fn check_hygiene(config: &Config) -> (Outcome, Vec<String>) {
+    if config.encrypt_metadata {
+        return (Outcome::Skip("secret names are encrypted; use totpm list --stale to check each profile".to_owned()), Vec::new())
+    }
+    let mut profiles = vec![(DEFAULT_PROFILE, config.clone())];
+    for name in config.profiles.keys() {
+        profiles.push((name, config.clone().with_profile(name).unwrap()));
+    }
+
+    let mut secrets: Vec<(&str, Vec<Secret>)> = Vec::new();
+    let mut db_paths = Vec::new();
+    for (name, config) in profiles {
+        // Profiles sharing a database would otherwise flag all of each other's secrets
+        let db_path = config.secrets_db_path();
+        if !db_path.is_file() || db_paths.contains(&db_path) {
+            continue
+        }
+        match TotpStore::without_tpm(config).and_then(|mut store| store.list(None, None)) {
+            Ok(profile_secrets) => secrets.push((name, profile_secrets)),
+            Err(e) => return (
+                Outcome::Fail(
+                    format!("unable to list the secrets of profile {}: {:?}", name, e),
+                    "check the permissions of the profile's secrets database".to_owned(),
+                ),
+                Vec::new(),
+            ),
+        }
+        db_paths.push(db_path);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let mut flagged = 0;
+    let mut lines = Vec::new();
+    for (name, profile_secrets) in &secrets {
+        let others = secrets.iter()
+            .filter(|(other_name, _)| other_name != name)
+            .map(|(other_name, other_secrets)| (*other_name, other_secrets.as_slice()))
+            .collect::<Vec<_>>();
+        for (secret, issues) in hygiene::check_with_profiles(profile_secrets, &others, now) {
+            flagged += 1;
+            lines.push(format!("{}: {} ({})", name, secret.service, secret.account));
+            lines.extend(issues.iter().map(|issue| format!("  ! {}", issue)));
+        }
+    }
+    match flagged {
+        0 => (Outcome::Pass("no secrets to clean up".to_owned()), lines),
+        _ => (Outcome::Pass(format!("{} secret(s) to clean up, listed below", flagged)), lines),
+    }
+}
+
+#[cfg(feature = "fprintd")]
+fn check_fprintd(config: &Config) -> Outcome {
+    if !config.pv_method.uses(PresenceVerificationMethod::Fprintd) {
+        return Outcome::Skip("presence verification doesn't use fprintd".to_owned())
+    }
+    match crate::presence_verification::fprintd::enrolled_fingers(config.fprintd_user.as_deref().unwrap_or_default()) {
+        Ok(fingers) if fingers.is_empty() => Outcome::Fail(
+            "no fingerprints enrolled".to_owned(),
+            "enroll a fingerprint using fprintd-enroll".to_owned(),
+        ),
+        Ok(fingers) => Outcome::Pass(format!("enrolled fingers: {}", fingers.join(", "))),
+        Err(e) => Outcome::Fail(
+            format!("unable to reach fprintd: {:?}", e),
+            "make sure fprintd is installed and a fingerprint reader is connected".to_owned(),
+        ),
+    }
+}
+
+#[cfg(not(feature = "fprintd"))]
+fn check_fprintd(config: &Config) -> Outcome {
+    if !config.pv_method.uses(PresenceVerificationMethod::Fprintd) {
+        return Outcome::Skip("presence verification doesn't use fprintd".to_owned())
+    }
+    Outcome::Fail(
+        "totpm was built without fprintd support".to_owned(),
+        "rebuild totpm with the fprintd feature, or change pv_method in the configuration file".to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::Permissions;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn check_auth_value_fails_unless_only_owner_has_access() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("auth_value");
+        assert!(matches!(check_auth_value(&path), Outcome::Fail(..)));
+        std::fs::write(&path, "secret").unwrap();
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(check_auth_value(&path), Outcome::Fail(..)));
+        std::fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        assert!(matches!(check_auth_value(&path), Outcome::Pass(_)));
+    }
+
+    #[test]
+    fn report_fails_with_number_of_failed_checks() {
+        let checks = [
+            Check { name: "a", outcome: Outcome::Pass("ok".to_owned()) },
+            Check { name: "b", outcome: Outcome::Fail("broken".to_owned(), "fix it".to_owned()) },
+            Check { name: "c", outcome: Outcome::Skip("n/a".to_owned()) },
+        ];
+        assert!(matches!(report(&checks), Err(Error::DoctorChecksFailed(1))));
+        assert!(report(&checks[..1]).is_ok());
+    }
+}
@@ -0,0 +1,24 @@
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+
+use crate::{config::Config, migration::WrappedExport, result::{Context, Result}, totp_store::{self, read_primary_key_persistent_handle}};
+
+/// Writes all secrets, with their keys still wrapped under the primary key, to a new file at the given path.
+/// The keys are useless without this machine's TPM, so the file is safe to archive;
+/// restore --wrapped puts the secrets back if the database is lost.
+pub fn wrapped(config: Config, file: &Path) -> Result<()> {
+    let primary_key_handle = read_primary_key_persistent_handle(&config)
+        .or(Err(totp_store::Error::NotInitialized))?;
+    let mut totp_store = super::open_store(config.clone())?;
+    let export = WrappedExport {
+        tpm: config.tpm.clone(),
+        primary_key_handle,
+        secrets: totp_store.export_wrapped()?,
+    };
+
+    // Metadata is in plaintext, and nobody else's business.
+    let mut out = OpenOptions::new().write(true).create_new(true).mode(0o600).open(file)
+        .context(|| format!("creating {}", file.to_str().unwrap()))?;
+    out.write_all(toml::to_string(&export)?.as_bytes())?;
+    println!("exported {} secrets to {}", export.secrets.len(), file.to_str().unwrap());
+    Ok(())
+}
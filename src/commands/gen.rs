@@ -1,31 +1,179 @@
-use crate::{config::Config, result::{Error, Result}, term::pick_one, totp_store::TotpStore};
+use std::{io, time::{Duration, SystemTime, UNIX_EPOCH}};
 
+use crate::{autotype::{self, AutoTypeMethod}, base32, config::Config, db::{model::Secret, SecretFilter}, reference, result::{Context, Error, Result}, selection::{create_selector, Selector}, totp_store::{TotpStore, WithTPM}};
+
+/// Generates a code for the secret matching the given filter.
 pub fn run(
     config: Config,
+    filter: &SecretFilter,
+    type_code: bool,
+    window: Option<u32>,
+    reference_check: bool,
+) -> Result<()> {
+    let auto_type = match type_code {
+        true => Some(config.auto_type.resolve().ok_or(Error::AutoTypeUnavailable)?),
+        false => None,
+    };
+    let reference_secret = if reference_check {
+        Some(read_reference_secret()?)
+    } else {
+        None
+    };
+    let mut selector = create_selector(config.gen_selection.unwrap_or(config.selection));
+    let mut totp_store = super::open_store_for(config.clone(), config.pv.require.gen)?;
+    if let Some(window) = window {
+        let secret = select_secret(&mut totp_store, selector.as_mut(), filter)?;
+        super::warn_if_clock_unreliable(&config, &secret);
+        for line in window_lines(&mut totp_store, &secret, window, SystemTime::now())? {
+            println!("{}", line);
+        }
+        return Ok(())
+    }
+    gen_code(&mut totp_store, selector.as_mut(), filter, reference_secret.as_deref(), auto_type)
+}
+
+/// Generates a code for every secret matching the given filter, verifying presence only once,
+/// and prints each code next to the secret it's for.
+pub fn run_all(config: Config, filter: &SecretFilter) -> Result<()> {
+    let mut totp_store = super::open_store_for(config.clone(), config.pv.require.gen)?;
+    let secrets = totp_store.find(filter)?;
+    let Some(shortest_interval) = secrets.iter().min_by_key(|secret| secret.interval) else {
+        return Err(Error::SecretNotFound)
+    };
+    super::warn_if_clock_unreliable(&config, shortest_interval);
+    let lines = totp_store.batch(|store| all_lines(store, &secrets, SystemTime::now()))?;
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Generates a code for the matching secret, using the given selector to choose between several matches.
+/// If a reference secret is given, the code is also computed in software from it,
+/// and an error is returned if the two don't match.
+/// If an auto-type method is given, the code is typed into the focused window instead of printed.
+pub fn run_with_store(
+    totp_store: &mut TotpStore<WithTPM>,
+    selector: &mut dyn Selector,
     service: &str,
-    account: Option<&str>
+    account: Option<&str>,
+    tag: Option<&str>,
+    reference_secret: Option<&[u8]>,
+    auto_type: Option<AutoTypeMethod>,
+) -> Result<()> {
+    let filter = SecretFilter { service, account: account.unwrap_or(""), tag, ..Default::default() };
+    gen_code(totp_store, selector, &filter, reference_secret, auto_type)
+}
+
+fn gen_code(
+    totp_store: &mut TotpStore<WithTPM>,
+    selector: &mut dyn Selector,
+    filter: &SecretFilter,
+    reference_secret: Option<&[u8]>,
+    auto_type: Option<AutoTypeMethod>,
 ) -> Result<()> {
-    let mut totp_store = TotpStore::with_tpm(config.clone())?;
-    let alternatives = totp_store.list(Some(service), account)?;
+    let alt = select_secret(totp_store, selector, filter)?;
+    super::warn_if_clock_unreliable(totp_store.config(), &alt);
+    let now = SystemTime::now();
+    let code = if alt.has_pin {
+        let pin = super::read_pin(&format!("Enter PIN for {}: ", alt))?;
+        totp_store.gen_with_pin(alt.id, now, Some(&pin))
+    } else {
+        totp_store.gen(alt.id, now)
+    }.context(|| format!("generating a code for {} (id {})", alt, alt.id))?;
+    if let Some(secret) = reference_secret {
+        let reference_code = reference::hotp(secret, alt.time_step(now), alt.digits);
+        if code != reference_code {
+            return Err(Error::ReferenceMismatch(code, reference_code));
+        }
+        log::info!("code matches reference implementation");
+    }
+    match auto_type {
+        Some(method) => autotype::type_text(method, &code)?,
+        None => println!("{}", code),
+    }
+    Ok(())
+}
+
+/// Returns the secret matching the given filter, using the given selector to choose between several matches.
+fn select_secret(totp_store: &mut TotpStore<WithTPM>, selector: &mut dyn Selector, filter: &SecretFilter) -> Result<Secret> {
+    let alternatives = totp_store.find(filter)?;
     
     if alternatives.is_empty() {
         return Err(Error::SecretNotFound);
     }
 
-    if let Some(alt) = pick_one(
-        &mut std::io::stdin().lock(),
-        &mut std::io::stdout(),
+    match selector.select(
         "found multiple matches for the given service/account combination",
-        alternatives.iter()
-    ) {
-        let code = totp_store.gen(alt.id, std::time::SystemTime::now())?;
-        println!("{}", code);
-        Ok(())
-    } else {
-        Err(Error::AmbiguousSecret)
+        &alternatives,
+    )? {
+        Some(alt) => Ok(alt.clone()),
+        None => Err(Error::AmbiguousSecret),
     }
 }
 
+/// Returns a line for each of the given secrets, with its code at the given time followed by the secret.
+/// Codes are padded to the same width, so that the secrets line up.
+fn all_lines(totp_store: &mut TotpStore<WithTPM>, secrets: &[Secret], now: SystemTime) -> Result<Vec<String>> {
+    let width = secrets.iter().map(|secret| secret.digits as usize).max().unwrap_or_default();
+    secrets.iter()
+        .map(|secret| {
+            let code = if secret.has_pin {
+                let pin = super::read_pin(&format!("Enter PIN for {}: ", secret))?;
+                totp_store.gen_with_pin(secret.id, now, Some(&pin))
+            } else {
+                totp_store.gen(secret.id, now)
+            }.context(|| format!("generating a code for {} (id {})", secret, secret.id))?;
+            Ok(format!("{:<width$}  {}", code, secret))
+        })
+        .collect()
+}
+
+/// Returns a line for each time step from window steps before the current one to window steps after it,
+/// with the step's offset from the current one, its code, and when it's valid in seconds relative to now.
+/// Only the current step's code counts as using the secret.
+fn window_lines(totp_store: &mut TotpStore<WithTPM>, secret: &Secret, window: u32, now: SystemTime) -> Result<Vec<String>> {
+    let pin = match secret.has_pin {
+        true => Some(super::read_pin(&format!("Enter PIN for {}: ", secret))?),
+        false => None,
+    };
+    let interval = secret.interval as i64;
+    let current = secret.time_step(now) as i64;
+    let service_now = secret.service_time(now).duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let mut lines = Vec::new();
+    for step in -(window as i64)..=window as i64 {
+        if current.saturating_add(step) < 0 {
+            continue
+        }
+        // Step boundaries are in the service's time, but gen expects our own
+        let start = secret.time_step_start(current.saturating_add(step) as u64);
+        let local_start = now.saturating_add(start).saturating_sub(service_now);
+        if local_start < 0 {
+            continue
+        }
+        let code = match step {
+            0 => totp_store.gen_with_pin(secret.id, UNIX_EPOCH + Duration::from_secs(now as u64), pin.as_deref())?,
+            _ => totp_store.peek_with_pin(secret.id, UNIX_EPOCH + Duration::from_secs(local_start as u64), pin.as_deref())?,
+        };
+        lines.push(format!(
+            "{:+} {} {:+}s..{:+}s",
+            step,
+            code,
+            start.saturating_sub(service_now),
+            start.saturating_add(interval).saturating_sub(service_now),
+        ));
+    }
+    Ok(lines)
+}
+
+/// Reads the known plaintext of a test secret, base32-encoded, from stdin.
+fn read_reference_secret() -> Result<Vec<u8>> {
+    let mut buf = String::new();
+    io::stdin().read_line(&mut buf)?;
+    base32::decode(buf.trim()).ok_or(Error::SecretFormatError)
+}
+
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
@@ -33,30 +181,166 @@ mod tests {
     use testutil::tpm::SwTpm;
 
     use crate::presence_verification::PresenceVerificationMethod;
+    use crate::selection::SelectionMethod;
     use crate::tpm::Error::PresenceVerificationFailed;
     use crate::totp_store::Error::TpmError;
 
     use super::*;
 
+    #[test]
+    fn window_lines_show_neighbouring_codes_with_validity() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg).unwrap();
+        store.add("foo", "bar", None, None, b"12345678901234567890").unwrap();
+        let secret = store.list(Some("foo"), Some("bar")).unwrap().remove(0);
+
+        // RFC 6238 test vector for 1111111109, truncated to 6 digits, is current for the middle step
+        let now = UNIX_EPOCH + Duration::from_secs(1111111109);
+        let lines = window_lines(&mut store, &secret, 1, now).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("-1 ") && lines[0].ends_with(" -59s..-29s"));
+        assert_eq!(lines[1], "+0 081804 -29s..+1s");
+        assert!(lines[2].starts_with("+1 ") && lines[2].ends_with(" +1s..+31s"));
+
+        // Steps before the epoch are skipped
+        assert_eq!(window_lines(&mut store, &secret, 2, UNIX_EPOCH + Duration::from_secs(59)).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn window_lines_record_only_current_step_as_used() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg).unwrap();
+        store.add("foo", "bar", None, None, b"12345678901234567890").unwrap();
+        let secret = store.list(Some("foo"), Some("bar")).unwrap().remove(0);
+
+        window_lines(&mut store, &secret, 3, UNIX_EPOCH + Duration::from_secs(1111111109)).unwrap();
+        let secret = store.list(Some("foo"), Some("bar")).unwrap().remove(0);
+        assert_eq!(secret.use_count, 1);
+        assert_eq!(secret.last_used_at, Some(1111111109));
+    }
+
+    #[test]
+    fn all_lines_has_a_code_for_every_secret() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        store.add("foo", "bar", None, None, b"12345678901234567890").unwrap();
+        store.add("baz", "quux", Some(8), None, b"12345678901234567890").unwrap();
+        let secrets = store.find(&SecretFilter::default()).unwrap();
+
+        // RFC 6238 test vectors for 59, truncated to 6 and 8 digits
+        let lines = all_lines(&mut store, &secrets, UNIX_EPOCH + Duration::from_secs(59)).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.contains(&format!("287082    {}", secrets.iter().find(|s| s.service == "foo").unwrap())));
+        assert!(lines.contains(&format!("94287082  {}", secrets.iter().find(|s| s.service == "baz").unwrap())));
+        drop(store);
+
+        match run_all(cfg, &SecretFilter { service: "nope", ..Default::default() }).unwrap_err() {
+            crate::result::Error::SecretNotFound => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
     #[test]
     fn gen_succeeds_on_unambiguous_secret() {
         let (_tpm, _dir, cfg) = setup();
         TotpStore::init(cfg.clone()).unwrap();
         let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
         store.add("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
-        run(cfg, "foo", None).unwrap();
+        run(cfg, &SecretFilter { service: "foo", ..Default::default() }, false, None, false).unwrap();
     }
 
     #[test]
     fn gen_fails_on_secret_not_found() {
         let (_tpm, _dir, cfg) = setup();
         TotpStore::init(cfg.clone()).unwrap();
-        match run(cfg, "foo", None).unwrap_err() {
+        match run(cfg, &SecretFilter { service: "foo", ..Default::default() }, false, None, false).unwrap_err() {
+            crate::result::Error::SecretNotFound => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn gen_only_considers_secrets_with_given_tag() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        store.add_ex("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0], None, |s| s.tags = vec!["work".to_owned()]).unwrap();
+        store.add("foo", "baz", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        drop(store);
+
+        run(cfg.clone(), &SecretFilter { service: "foo", tag: Some("work"), ..Default::default() }, false, None, false).unwrap();
+        match run(cfg, &SecretFilter { service: "foo", tag: Some("personal"), ..Default::default() }, false, None, false).unwrap_err() {
+            crate::result::Error::SecretNotFound => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn reference_check_fails_on_wrong_reference_secret() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        store.add("foo", "bar", None, None, b"12345678901234567890").unwrap();
+        let mut selector = create_selector(cfg.selection);
+        run_with_store(&mut store, selector.as_mut(), "foo", None, None, Some(b"12345678901234567890"), None).unwrap();
+        match run_with_store(&mut store, selector.as_mut(), "foo", None, None, Some(b"09876543210987654321"), None).unwrap_err() {
+            crate::result::Error::ReferenceMismatch(_, _) => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn gen_fails_on_ambiguous_secret_with_fail_fast_selection() {
+        let (_tpm, _dir, mut cfg) = setup();
+        cfg.selection = SelectionMethod::FailFast;
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        store.add("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        store.add("foo", "baz", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        drop(store);
+
+        run(cfg.clone(), &SecretFilter { service: "foo", account: "bar", ..Default::default() }, false, None, false).unwrap();
+        match run(cfg, &SecretFilter { service: "foo", ..Default::default() }, false, None, false).unwrap_err() {
+            crate::result::Error::AmbiguousSecret => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn gen_by_id_bypasses_name_matching() {
+        let (_tpm, _dir, mut cfg) = setup();
+        cfg.selection = SelectionMethod::FailFast;
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        store.add("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        let baz = store.add("foo", "baz", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        drop(store);
+
+        run(cfg.clone(), &SecretFilter { id: Some(baz.id), ..Default::default() }, false, None, false).unwrap();
+        match run(cfg, &SecretFilter { id: Some(baz.id + 1), ..Default::default() }, false, None, false).unwrap_err() {
             crate::result::Error::SecretNotFound => {},
             err => panic!("wrong error: {:#?}", err),
         }
     }
 
+    #[test]
+    fn gen_selection_overrides_selection() {
+        let (_tpm, _dir, mut cfg) = setup();
+        cfg.selection = SelectionMethod::FailFast;
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        store.add("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        store.add("foo", "baz", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        drop(store);
+
+        run(cfg.clone(), &SecretFilter { service: "foo", account: "baz", ..Default::default() }, false, None, false).unwrap();
+        cfg.gen_selection = Some(SelectionMethod::Mru);
+        run(cfg, &SecretFilter { service: "foo", ..Default::default() }, false, None, false).unwrap();
+    }
+
     // disabled until we get around to solving permissions for this properly
     #[ignore]
     #[test]
@@ -64,18 +348,18 @@ mod tests {
     fn presence_verification_happens_after_disambiguation() {
         let (_tpm, _dir, cfg) = setup();
         let mut failing_cfg = cfg.clone();
-        failing_cfg.pv_method = PresenceVerificationMethod::AlwaysFail;
+        failing_cfg.pv_method = PresenceVerificationMethod::AlwaysFail.into();
         TotpStore::init(cfg.clone()).unwrap();
 
         // If there are no matching accounts, we should quit before PV happens
-        let error = run(failing_cfg.clone(), "foo", Some("bar")).unwrap_err();
+        let error = run(failing_cfg.clone(), &SecretFilter { service: "foo", account: "bar", ..Default::default() }, false, None, false).unwrap_err();
         if let Error::SecretNotFound = error {} else {
             panic!("wrong error: {:#?}", error)
         }
 
         // If there is exactly one matching accounts, we should see PV happening and failing
         TotpStore::with_tpm(cfg.clone()).unwrap().add("foo", "bar", Some(6), Some(30), &[0,0,0,0,0,0,0,0,0,0]).unwrap();
-        let error = run(failing_cfg.clone(), "foo", Some("bar")).unwrap_err();
+        let error = run(failing_cfg.clone(), &SecretFilter { service: "foo", account: "bar", ..Default::default() }, false, None, false).unwrap_err();
         if let Error::TotpStoreError(TpmError(PresenceVerificationFailed)) = error {} else {
             panic!("wrong error: {:#?}", error)
         }
@@ -1,38 +1,212 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, fmt::Display, io::{self, Read}, path::Path, str::FromStr};
 use serde::Deserialize;
-use crate::{base32, config::Config, result::Error, totp_store::TotpStore};
+use crate::{config::Config, db::{model::{Secret, MAX_T0}, SecretFilter}, result::{Context, Error}, secret_format::SecretFormat, totp_store::{self, TotpStore}};
 
 #[derive(Deserialize)]
-struct ServiceInfo {
+pub(crate) struct ServiceInfo {
     pub account: String,
     pub secret: String,
     pub digits: Option<u8>,
     pub interval: Option<u32>,
+    /// Time from which time steps are counted, as in add --t0.
+    pub t0: Option<i64>,
+    /// URL of the service, as in add --url.
+    pub url: Option<String>,
+    /// Icon for the service, as in add --icon.
+    pub icon: Option<String>,
 }
 
-pub fn run(config: Config, file: &Path) -> Result<(), Error> {
-    let imports = import_json(file)?;
-    let mut store = TotpStore::with_tpm(config)?;
-    for (service, info) in imports {
-        let secret_bytes = base32::decode(&info.secret).ok_or(Error::SecretFormatError)?;
-        store.add(&service, &info.account, info.digits, info.interval, &secret_bytes)?;
+/// What to do with an imported secret whose service and account already exist.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum ConflictStrategy {
+    /// Keep the existing secret and don't import the new one.
+    Skip,
+    /// Replace the existing secret, keeping its tags, notes and other settings.
+    Overwrite,
+    /// Import the new secret alongside the existing one.
+    Duplicate,
+    /// Import nothing at all.
+    #[default]
+    Abort,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(ConflictStrategy::Skip),
+            "overwrite" => Ok(ConflictStrategy::Overwrite),
+            "duplicate" => Ok(ConflictStrategy::Duplicate),
+            "abort" => Ok(ConflictStrategy::Abort),
+            _ => Err(format!("invalid conflict strategy: '{}' (expected skip, overwrite, duplicate or abort)", s)),
+        }
+    }
+}
+
+/// An entry of an import file, with its secret decoded.
+struct Entry {
+    service: String,
+    info: ServiceInfo,
+    secret: Vec<u8>,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.service, self.info.account)
+    }
+}
+
+/// What importing an entry amounts to.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Add,
+    Skip,
+    /// Replace the secret with the given id.
+    Replace(i64),
+}
+
+/// Imports the secrets in the given file, or standard input if the file is "-",
+/// resolving conflicts with existing secrets as given.
+/// Every secret is decoded and every conflict resolved before anything is added,
+/// and the secrets are all added in a single transaction, so an import either succeeds or changes nothing.
+/// If dry_run is true, what would be done is listed instead, without using the TPM.
+pub fn run(
+    config: Config,
+    file: &Path,
+    secret_format: SecretFormat,
+    on_conflict: ConflictStrategy,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let imports = match file.to_str() {
+        Some("-") => parse_json(io::stdin().lock())?,
+        _ => import_json(file)?,
+    };
+    let entries = decode_entries(imports, secret_format)?;
+    if dry_run {
+        return report_dry_run(config, &entries, on_conflict)
+    }
+    let mut store = super::open_store(config)?;
+    store.batch(|store| {
+        let actions = entries.iter()
+            .map(|entry| action(entry, &existing_ids(store, entry)?, on_conflict))
+            .collect::<Result<Vec<_>, Error>>()?;
+        for (entry, action) in entries.iter().zip(actions) {
+            let modify = |secret: &mut Secret| {
+                secret.t0 = entry.info.t0.unwrap_or_default();
+                if entry.info.url.is_some() {
+                    secret.url = entry.info.url.clone();
+                }
+                if entry.info.icon.is_some() {
+                    secret.icon = entry.info.icon.clone();
+                }
+            };
+            let (digits, interval) = (entry.info.digits, entry.info.interval);
+            match action {
+                Action::Add => {
+                    store.add_ex(&entry.service, &entry.info.account, digits, interval, &entry.secret, None, modify)?;
+                },
+                Action::Skip => eprintln!("skipping {}, which already exists", entry),
+                Action::Replace(id) => {
+                    store.replace_ex(id, &entry.service, &entry.info.account, digits, interval, &entry.secret, None, modify)?;
+                },
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Decodes the secrets of the given imports, sorted by service and account.
+/// Raw secrets are rejected, since JSON strings can't hold arbitrary bytes, as are T0s add --t0 wouldn't accept.
+fn decode_entries(imports: HashMap<String, ServiceInfo>, secret_format: SecretFormat) -> Result<Vec<Entry>, Error> {
+    if secret_format == SecretFormat::Raw {
+        return Err(Error::ImportFormatError(
+            "raw secrets can't be imported from JSON; use --secret-format hex or base32".to_owned()
+        ))
+    }
+    let mut entries = imports.into_iter()
+        .map(|(service, info)| {
+            let secret = secret_format.decode(&info.secret).map_err(|e| Error::ImportFormatError(
+                format!("the secret for {} ({}) is {}", service, info.account, e)
+            ))?;
+            if info.t0.is_some_and(|t0| !(-MAX_T0..=MAX_T0).contains(&t0)) {
+                return Err(Error::ImportFormatError(
+                    format!("the t0 of {} ({}) is more than {} seconds from the epoch", service, info.account, MAX_T0)
+                ))
+            }
+            Ok(Entry { service, info, secret })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    entries.sort_by(|a, b| (&a.service, &a.info.account).cmp(&(&b.service, &b.info.account)));
+    Ok(entries)
+}
+
+/// Returns the ids of the existing secrets with the same service and account as the given entry.
+fn existing_ids<P>(store: &mut TotpStore<P>, entry: &Entry) -> totp_store::Result<Vec<i64>> {
+    let filter = SecretFilter { service: &entry.service, account: &entry.info.account, exact: true, ..Default::default() };
+    Ok(store.find(&filter)?.into_iter()
+        .filter(|secret| secret.account == entry.info.account)
+        .map(|secret| secret.id)
+        .collect())
+}
+
+/// Decides what to do with the given entry, given the ids of existing secrets with the same service and account.
+fn action(entry: &Entry, existing: &[i64], on_conflict: ConflictStrategy) -> Result<Action, Error> {
+    match (existing, on_conflict) {
+        ([], _) | (_, ConflictStrategy::Duplicate) => Ok(Action::Add),
+        (_, ConflictStrategy::Skip) => Ok(Action::Skip),
+        ([id], ConflictStrategy::Overwrite) => Ok(Action::Replace(*id)),
+        (_, ConflictStrategy::Overwrite) => Err(Error::AmbiguousSecret),
+        (_, ConflictStrategy::Abort) => Err(Error::ImportConflict(entry.to_string())),
+    }
+}
+
+/// Prints what importing the given entries would do.
+fn report_dry_run(config: Config, entries: &[Entry], on_conflict: ConflictStrategy) -> Result<(), Error> {
+    let mut store = TotpStore::without_tpm(config)?;
+    let mut added = 0;
+    for entry in entries {
+        let existing = match existing_ids(&mut store, entry) {
+            Ok(existing) => existing,
+            Err(totp_store::Error::MetadataKeyUnavailable) => {
+                println!("would add {} (can't check for conflicts, since metadata is encrypted)", entry);
+                added += 1;
+                continue
+            },
+            Err(e) => return Err(e.into()),
+        };
+        match action(entry, &existing, on_conflict)? {
+            Action::Add if existing.is_empty() => println!("would add {}", entry),
+            Action::Add => println!("would add {} (already exists)", entry),
+            Action::Skip => {
+                println!("would skip {} (already exists)", entry);
+                continue
+            },
+            Action::Replace(_) => println!("would overwrite {}", entry),
+        }
+        added += 1;
     }
+    println!("{} of {} secret(s) would be added or overwritten; nothing was written", added, entries.len());
     Ok(())
 }
 
-fn import_json(file: &Path) -> Result<HashMap<String, ServiceInfo>, crate::result::Error> {
-    let json_file = std::fs::File::open(file)?;
-    serde_json::de::from_reader(json_file)
+pub(crate) fn import_json(file: &Path) -> Result<HashMap<String, ServiceInfo>, crate::result::Error> {
+    parse_json(std::fs::File::open(file).context(|| format!("reading {}", file.to_str().unwrap()))?)
+}
+
+fn parse_json<R: Read>(reader: R) -> Result<HashMap<String, ServiceInfo>, crate::result::Error> {
+    serde_json::de::from_reader(reader)
         .map_err(|_| crate::result::Error::ImportFormatError("not a json file or invalid schema".to_string()))
-}    
+}
 
 #[cfg(test)]
 mod tests {
     use std::time::SystemTime;
-    use tempfile::{tempdir, NamedTempFile, TempDir};
+    use tempfile::{NamedTempFile, TempDir};
     use testutil::tpm::SwTpm;
-    use crate::{config::Config, presence_verification::PresenceVerificationMethod, totp_store::{TotpStore, WithTPM}};
-    use super::run;
+    use crate::{commands::test_util::setup, config::Config, totp_store::{TotpStore, WithTPM}};
+    use crate::secret_format::SecretFormat;
+    use super::{run, ConflictStrategy, MAX_T0};
 
     #[test]
     fn import_succeeds_on_well_formed_json() {
@@ -66,9 +240,39 @@ mod tests {
         assert_ne!(code, "");
     }
 
+    #[test]
+    fn import_decodes_hex_secrets() {
+        let (_tpm, _tmpdir, mut totp_store) = test_import("{
+            \"foo\": {
+                \"account\": \"bar\",
+                \"secret\": \"3132333435363738393031323334353637383930\"
+            }
+        }").unwrap();
+
+        let accounts = totp_store.list(Some("foo"), Some("bar")).unwrap();
+        let code = totp_store.gen(accounts[0].id, SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(59)).unwrap();
+        assert_eq!(code, "287082");
+    }
+
+    #[test]
+    fn import_counts_time_steps_from_t0() {
+        let (_tpm, _tmpdir, mut totp_store) = test_import("{
+            \"foo\": {
+                \"account\": \"bar\",
+                \"secret\": \"3132333435363738393031323334353637383930\",
+                \"t0\": 30
+            }
+        }").unwrap();
+
+        let accounts = totp_store.list(Some("foo"), Some("bar")).unwrap();
+        assert_eq!(accounts[0].t0, 30);
+        let code = totp_store.gen(accounts[0].id, SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(89)).unwrap();
+        assert_eq!(code, "287082");
+    }
+
     #[test]
     fn import_succeeds_on_empty_json() {
-        let (_tpm, _tmpdir, totp_store) = test_import("{}").unwrap();
+        let (_tpm, _tmpdir, mut totp_store) = test_import("{}").unwrap();
         let accounts = totp_store.list(None, None).unwrap();
         assert_eq!(accounts.len(), 0);
     }
@@ -172,6 +376,118 @@ mod tests {
         }");
     }
 
+    #[test]
+    fn dry_run_writes_nothing() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let json_file = NamedTempFile::new().unwrap();
+        std::fs::write(json_file.path(), "{
+            \"foo\": {
+                \"account\": \"bar\",
+                \"secret\": \"MFRGGZDFMVTGO2DJNJVWY3LON5YHC4TT\"
+            }
+        }").unwrap();
+        run(cfg.clone(), json_file.path(), SecretFormat::Auto, ConflictStrategy::Abort, true).unwrap();
+
+        let mut store = TotpStore::without_tpm(cfg).unwrap();
+        assert_eq!(0, store.list(None, None).unwrap().len());
+    }
+
+    #[test]
+    fn dry_run_fails_on_invalid_secret() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let json_file = NamedTempFile::new().unwrap();
+        std::fs::write(json_file.path(), "{
+            \"foo\": {
+                \"account\": \"bar\",
+                \"secret\": \"not base32!\"
+            }
+        }").unwrap();
+        match run(cfg, json_file.path(), SecretFormat::Auto, ConflictStrategy::Abort, true) {
+            Err(crate::result::Error::ImportFormatError(_)) => {},
+            result => panic!("wrong result: {:#?}", result),
+        }
+    }
+
+    #[test]
+    fn out_of_range_t0_is_rejected() {
+        let (_tpm, _dir, cfg) = setup();
+        for t0 in [i64::MIN, -MAX_T0 - 1, MAX_T0 + 1, i64::MAX] {
+            let json_file = NamedTempFile::new().unwrap();
+            let json = format!("{{ \"foo\": {{ \"account\": \"bar\", \"secret\": \"NBSWY3DP\", \"t0\": {} }} }}", t0);
+            std::fs::write(json_file.path(), json).unwrap();
+            match run(cfg.clone(), json_file.path(), SecretFormat::Auto, ConflictStrategy::Abort, true) {
+                Err(crate::result::Error::ImportFormatError(_)) => {},
+                result => panic!("wrong result for t0 {}: {:#?}", t0, result),
+            }
+        }
+    }
+
+    #[test]
+    fn extreme_t0_within_range_is_imported() {
+        let (_tpm, _tmpdir, mut totp_store) = test_import(&format!("{{
+            \"foo\": {{ \"account\": \"bar\", \"secret\": \"NBSWY3DP\", \"t0\": {} }},
+            \"baz\": {{ \"account\": \"bar\", \"secret\": \"NBSWY3DP\", \"t0\": {} }}
+        }}", -MAX_T0, MAX_T0)).unwrap();
+
+        let secrets = totp_store.list(None, Some("bar")).unwrap();
+        assert_eq!(secrets.len(), 2);
+        for secret in secrets {
+            assert_eq!(secret.t0.abs(), MAX_T0);
+            assert_eq!(totp_store.gen(secret.id, SystemTime::now()).unwrap().len(), 6);
+        }
+    }
+
+    #[test]
+    fn raw_secret_format_is_rejected() {
+        let (_tpm, _dir, cfg) = setup();
+        let json_file = NamedTempFile::new().unwrap();
+        std::fs::write(json_file.path(), "{ \"foo\": { \"account\": \"bar\", \"secret\": \"NBSWY3DP\" } }").unwrap();
+        match run(cfg, json_file.path(), SecretFormat::Raw, ConflictStrategy::Abort, true) {
+            Err(crate::result::Error::ImportFormatError(_)) => {},
+            result => panic!("wrong result: {:#?}", result),
+        }
+    }
+
+    #[test]
+    fn conflicts_are_resolved_using_given_strategy() {
+        let first = "{ \"foo\": { \"account\": \"bar\", \"secret\": \"3132333435363738393031323334353637383930\" } }";
+        let second = "{ \"foo\": { \"account\": \"bar\", \"secret\": \"MFRGGZDFMVTGO2DJNJVWY3LON5YHC4TT\" } }";
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(59);
+        for (on_conflict, expected_count, expect_first_code) in [
+            (ConflictStrategy::Skip, 1, true),
+            (ConflictStrategy::Overwrite, 1, false),
+            (ConflictStrategy::Duplicate, 2, true),
+        ] {
+            let (_tpm, _dir, cfg) = setup();
+            test_import_with_config(&cfg, first).unwrap();
+            import_json_string(&cfg, second, on_conflict).unwrap();
+
+            let mut store = TotpStore::with_tpm(cfg).unwrap();
+            let secrets = store.list(Some("foo"), Some("bar")).unwrap();
+            assert_eq!(secrets.len(), expected_count, "{:?}", on_conflict);
+            assert_eq!(store.gen(secrets[0].id, time).unwrap() == "287082", expect_first_code, "{:?}", on_conflict);
+        }
+    }
+
+    #[test]
+    fn conflicts_abort_whole_import_by_default() {
+        let (_tpm, _dir, cfg) = setup();
+        test_import_with_config(&cfg, "{ \"foo\": { \"account\": \"bar\", \"secret\": \"MFRGGZDFMVTGO2DJNJVWY3LON5YHC4TT\" } }").unwrap();
+        let result = import_json_string(&cfg, "{
+            \"foo\": { \"account\": \"bar\", \"secret\": \"MFRGGZDFMVTGO2DJNJVWY3LON5YHC4TT\" },
+            \"baz\": { \"account\": \"quux\", \"secret\": \"MFRGGZDFMVTGO2DJNJVWY3LON5YHC4TT\" }
+        }", ConflictStrategy::Abort);
+        match result {
+            Err(crate::result::Error::ImportConflict(secret)) => assert_eq!(secret, "foo (bar)"),
+            Err(e) => panic!("import failed with wrong error: {:#?}", e),
+            Ok(_) => panic!("import succeeded though it should have failed"),
+        }
+        let mut store = TotpStore::without_tpm(cfg).unwrap();
+        assert_eq!(store.list(None, None).unwrap().len(), 1);
+    }
+
     fn expect_import_to_fail(json: &str) {
         let (_tpm, _dir, cfg) = setup();
         let result = test_import_with_config(&cfg, json);
@@ -181,7 +497,7 @@ mod tests {
             Err(e) => panic!("import failed with wrong error: {:#?}", e),
         }
 
-        let store = TotpStore::without_tpm(cfg);
+        let mut store = TotpStore::without_tpm(cfg).unwrap();
         assert_eq!(0, store.list(None, None).unwrap().len());
     }
 
@@ -193,22 +509,13 @@ mod tests {
 
     fn test_import_with_config(cfg: &Config, json: &str) -> Result<TotpStore<WithTPM>, crate::result::Error> {
         TotpStore::init(cfg.clone()).unwrap();
-        let json_file = NamedTempFile::new().unwrap();
-        std::fs::write(json_file.path(), json).unwrap();
-        run(cfg.clone(), json_file.path())?;
+        import_json_string(cfg, json, ConflictStrategy::Abort)?;
         Ok(TotpStore::with_tpm(cfg.clone()).unwrap())
     }
-    
-    fn setup() -> (SwTpm, TempDir, Config) {
-        let tpm = SwTpm::new();
-        let dir = tempdir().unwrap();
-        let cfg = Config::default(
-            true,
-            tpm.tcti.clone(),
-            Some(dir.path().join("sys")),
-            Some(dir.path().join("user")),
-            Some(PresenceVerificationMethod::None)
-        );
-        (tpm, dir, cfg)
+
+    fn import_json_string(cfg: &Config, json: &str, on_conflict: ConflictStrategy) -> Result<(), crate::result::Error> {
+        let json_file = NamedTempFile::new().unwrap();
+        std::fs::write(json_file.path(), json).unwrap();
+        run(cfg.clone(), json_file.path(), SecretFormat::Auto, on_conflict, false)
     }
 }
\ No newline at end of file
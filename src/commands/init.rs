@@ -5,10 +5,14 @@ use std::{os::unix::fs::MetadataExt, path::{Path, PathBuf}, process::Command};
 use log::warn;
 use crate::{
     config::Config,
-    presence_verification::PresenceVerificationMethod,
-    privileges::{is_effective_user, is_root, with_uid_as_euid},
+    desktop,
+    presence_verification::{pin::write_pin_hash, ConstPresenceVerifier, PresenceVerificationMethod},
+    privileges::{is_effective_user, is_root, EuidSwapGuard},
     result::{Error, Result},
-    totp_store::TotpStore
+    system_user::SystemUser,
+    tcti::{check_tcti_loadable, validate_tcti, PROBE_CANDIDATES},
+    totp_store::TotpStore,
+    tpm::TPM,
 };
 
 const EXE_NAME: &str = "totpm";
@@ -16,11 +20,11 @@ const EXE_NAME: &str = "totpm";
 pub fn run(
     cfg_path: &Path,
     mut config: Config,
-    user: &str,
+    user: &SystemUser,
     local: bool,
     exe_install_dir: &Path,
 ) -> Result<()> {
-    if needs_root(cfg_path, &config, user, local, &exe_install_dir.join(EXE_NAME)) && !is_root() {
+    if needs_root(cfg_path, &config, &user.name, local, &exe_install_dir.join(EXE_NAME)) && !is_root() {
         return Err(Error::RootRequired);
     }
 
@@ -33,40 +37,104 @@ pub fn run(
     }
 
     log::info!("initializing secret store");
-    config.pv_method = PresenceVerificationMethod::None;
+    let uses_pin = config.pv_method.uses(PresenceVerificationMethod::Pin);
+    config.pv_method = PresenceVerificationMethod::None.into();
     TotpStore::init(config.clone())?;
+    if uses_pin {
+        set_pin(&config)?;
+    }
 
     if !local {
-        with_uid_as_euid(||{
-            install(&config, cfg_path, user, exe_install_dir)?;
-            Ok::<(), Error>(())
-        })?;
+        let _euid = EuidSwapGuard::real_user()?;
+        install(&config, cfg_path, user, exe_install_dir)?;
+    }
+
+    Ok(())
+}
+
+/// Asks for the PIN to use for presence verification, and stores its hash with the system data.
+fn set_pin(config: &Config) -> Result<()> {
+    let pin = super::read_pin("Enter new presence verification PIN: ")?;
+    if super::read_pin("Repeat PIN: ")? != pin {
+        return Err(Error::PinMismatch)
+    }
+    log::info!(
+        "writing PIN hash with permissions 0600 to {}",
+        config.pin_hash_path().to_str().unwrap(),
+    );
+    write_pin_hash(&config.pin_hash_path(), &pin)?;
+    let _ = std::fs::remove_file(config.pin_failures_path());
+    Ok(())
+}
+
+/// Installs a desktop entry and icon into the user's or the system's XDG data directory.
+pub fn install_desktop_entry(local: bool) -> Result<()> {
+    if !local && !is_root() {
+        return Err(Error::RootRequired);
     }
+    let entry_path = desktop::install(&desktop::data_dir(local))?;
+    println!("installed desktop entry {}", entry_path.to_str().unwrap());
+    Ok(())
+}
 
+/// Tries talking to the TPM through the given TCTI configuration, or each of the common ones if none is given,
+/// and reports which ones work.
+pub fn probe_tpm(tcti: Option<&str>) -> Result<()> {
+    let candidates = match tcti {
+        Some(tcti) => {
+            validate_tcti(tcti).map_err(|e| crate::totp_store::Error::from(crate::tpm::Error::from(e)))?;
+            vec![tcti]
+        },
+        None => PROBE_CANDIDATES.to_vec(),
+    };
+    let mut working = 0;
+    for tcti in candidates {
+        if let Err(missing) = check_tcti_loadable(tcti) {
+            println!("{}: unavailable; {} (from the {} package) is not installed", tcti, missing.library, missing.package);
+            continue;
+        }
+        let pv = Box::new(ConstPresenceVerifier::new(true));
+        match TPM::new(pv, tcti).and_then(|mut tpm| tpm.info()) {
+            Ok(info) => {
+                println!("{}: ok ({} {}, firmware {})", tcti, info.manufacturer, info.vendor, info.firmware_version);
+                working += 1;
+            },
+            Err(err) => println!("{}: unable to talk to the TPM: {:?}", tcti, err),
+        }
+    }
+    if working == 0 {
+        println!("no working TPM configuration found");
+    } else {
+        println!("use a working configuration with totpm init --tpm <configuration>");
+    }
+    Ok(())
+}
+
+/// Creates the primary key of a profile which has its own system data path,
+/// using the already installed configuration.
+pub fn run_profile(config: Config) -> Result<()> {
+    log::info!("initializing secret store for profile");
+    TotpStore::init(config)?;
     Ok(())
 }
 
 #[cfg(feature = "install")]
-fn install(config: &Config, cfg_path: &Path, user: &str, exe_install_dir: &Path) -> Result<u32> {
+fn install(config: &Config, cfg_path: &Path, user: &SystemUser, exe_install_dir: &Path) -> Result<u32> {
     log::info!("creating config parent directory at {}", cfg_path.parent().unwrap().to_str().unwrap());
     fs::create_dir_all(cfg_path.parent().unwrap())?;
 
     log::info!("writing config to {}", cfg_path.to_str().unwrap());
-    fs::write(cfg_path, toml::to_string(config)?)?;
-
-    log::info!("creating user '{}'", user);
-    let useradd_result = Command::new("/usr/sbin/useradd")
-        .arg("-r")
-        .arg(user)
-        .arg("-s")
-        .arg("/usr/sbin/nologin")
-        .output();
-    let uid = get_user_id(user)?;
-
-    match useradd_result {
-        Ok(_) => {},
-        Err(e) => { log::warn!("unable to create user '{}': {:#?}", user, e) },
+    crate::housekeeping::atomic_write(cfg_path, toml::to_string(config)?)?;
+
+    if get_user_id(&user.name).is_ok() {
+        log::info!("user '{}' already exists", user.name);
+    } else {
+        log::info!("creating user '{}'", user.name);
+        if let Err(e) = crate::system_user::create(user) {
+            log::warn!("unable to create user '{}': {}", user.name, e);
+        }
     }
+    let uid = get_user_id(&user.name)?;
 
     let executable_path = std::env::current_exe()?;
     let moved_executable_path = exe_install_dir.join(EXE_NAME);
@@ -83,8 +151,8 @@ fn install(config: &Config, cfg_path: &Path, user: &str, exe_install_dir: &Path)
 }
 
 #[cfg(not(feature = "install"))]
-fn install(_config: &Config, _cfg_path: &Path, user: &str, _exe_install_dir: &Path) -> Result<u32> {
-    get_user_id(user)
+fn install(_config: &Config, _cfg_path: &Path, user: &SystemUser, _exe_install_dir: &Path) -> Result<u32> {
+    get_user_id(&user.name)
 }
 
 fn needs_root(cfg_path: &Path, config: &Config, user: &str, local: bool, exe_install_path: &Path) -> bool {
@@ -92,7 +160,10 @@ fn needs_root(cfg_path: &Path, config: &Config, user: &str, local: bool, exe_ins
         log::info!("does not need root because we're doing local init");
         return false;
     }
-    let totpm_user_id = get_user_id(user).unwrap();
+    let Ok(totpm_user_id) = get_user_id(user) else {
+        log::info!("needs root because the totpm user doesn't exist yet");
+        return true;
+    };
     if !is_effective_user(totpm_user_id) {
         log::info!("needs root because we're not the totpm user");
         return true;
@@ -188,7 +259,7 @@ mod tests {
             Some(dir.path().join("user")),
             None,
         );
-        run(&cfg_path, config.clone(), &get_user_name(), false, dir.path()).unwrap();
+        run(&cfg_path, config.clone(), &SystemUser::new(&get_user_name()), false, dir.path()).unwrap();
 
         check_installed_exe(&dir);
         check_installed_config(&cfg_path);
@@ -216,7 +287,7 @@ mod tests {
             Some(dir.path().join("user")),
             None,
         );
-        run(&cfg_path, config.clone(), &get_user_name(), false, &PathBuf::from("/")).unwrap();
+        run(&cfg_path, config.clone(), &SystemUser::new(&get_user_name()), false, &PathBuf::from("/")).unwrap();
 
         assert!(config.auth_value_path().is_file());
         assert_eq!(config.auth_value_path().metadata().unwrap().permissions().mode(), 0o100600);
@@ -241,7 +312,7 @@ mod tests {
             Some(dir.path().join("user")),
             None,
         );
-        match run(&cfg_path, config, &get_user_name(), false, &PathBuf::from("/")).unwrap_err() {
+        match run(&cfg_path, config, &SystemUser::new(&get_user_name()), false, &PathBuf::from("/")).unwrap_err() {
             Error::RootRequired => {},
             err => panic!("wrong error: {:#?}", err),
         }
@@ -259,7 +330,7 @@ mod tests {
             Some(dir.path().join("user")),
             None,
         );
-        run(&cfg_path, config.clone(), &get_user_name(), true, dir.path()).unwrap();
+        run(&cfg_path, config.clone(), &SystemUser::new(&get_user_name()), true, dir.path()).unwrap();
 
         let installed_exe_path = dir.path().join(EXE_NAME);
         assert_eq!(installed_exe_path.is_file(), false);
@@ -0,0 +1,73 @@
+use std::{fs, path::Path};
+
+use crate::{config::Config, housekeeping::atomic_write, privileges::PrivilegeDropGuard, result::Result};
+
+/// Writes a tmpfiles.d entry creating the system data directory, owned by the given user, into the given
+/// configuration directory, e.g. /etc or /usr/lib. If built with the daemon feature, also writes a systemd
+/// user unit running the daemon, which serves the secrets store on the session bus.
+pub fn run(config: &Config, dir: &Path, user: &str) -> Result<()> {
+    PrivilegeDropGuard::new()?.forever()?;
+
+    let tmpfiles_path = dir.join("tmpfiles.d/totpm.conf");
+    write_unit(&tmpfiles_path, &tmpfiles_entry(config, user))?;
+
+    if cfg!(feature = "daemon") {
+        let exe = std::env::current_exe()?;
+        let unit_path = dir.join("systemd/user/totpm.service");
+        write_unit(&unit_path, &daemon_unit(&exe))?;
+        println!("enable the daemon for a user with 'systemctl --user enable --now totpm'");
+    }
+    Ok(())
+}
+
+fn write_unit(path: &Path, contents: &str) -> Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    log::info!("writing {}", path.to_str().unwrap());
+    atomic_write(path, contents)?;
+    println!("wrote {}", path.to_str().unwrap());
+    Ok(())
+}
+
+/// Returns a tmpfiles.d entry creating the system data directory with the permissions init gives it.
+fn tmpfiles_entry(config: &Config, user: &str) -> String {
+    format!(
+        "# Generated by totpm install-units.\nd {} 0700 {} {} -\n",
+        config.system_data_path.to_str().unwrap(),
+        user,
+        user,
+    )
+}
+
+/// Returns a systemd user unit running the daemon from the given executable.
+fn daemon_unit(exe: &Path) -> String {
+    format!(
+        "# Generated by totpm install-units.
+[Unit]
+Description=totpm secrets store on the session bus
+
+[Service]
+Type=dbus
+BusName=org.totpm.Manager
+ExecStart={} daemon
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+",
+        exe.to_str().unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn units_match_what_init_and_daemon_expect() {
+        let config = Config::default(false, "device".to_owned(), None, None, None);
+        assert_eq!(tmpfiles_entry(&config, "totpm").lines().last(), Some("d /var/lib/totpm 0700 totpm totpm -"));
+        assert!(daemon_unit(&PathBuf::from("/usr/bin/totpm")).contains("\nExecStart=/usr/bin/totpm daemon\n"));
+    }
+}
@@ -1,10 +1,178 @@
-use crate::{config::Config, totp_store::TotpStore, result::Result};
+use std::{io::{self, Write}, time::{SystemTime, UNIX_EPOCH}};
 
-pub fn run(config: Config, service: Option<&str>, account: Option<&str>) -> Result<()> {
-    log::info!("listing secrets for {} ({})", service.unwrap_or("(None)"), account.unwrap_or("None"));
-    let store = TotpStore::without_tpm(config);
-    for secret in store.list(service, account)? {
-        println!("{} ({})", secret.service, secret.account);
+use serde_derive::Serialize;
+
+use crate::{config::Config, db::{model::Secret, SecretFilter}, hygiene, term, totp_store::TotpStore, result::Result};
+
+/// A secret as printed by list --json, for frontends and scripts.
+#[derive(Serialize, Debug)]
+struct ListedSecret<'a> {
+    id: i64,
+    service: &'a str,
+    account: &'a str,
+    digits: u8,
+    interval: u32,
+    tags: &'a [String],
+    notes: Option<&'a str>,
+    url: Option<&'a str>,
+    icon: Option<&'a str>,
+    pinned: bool,
+}
+
+impl <'a> From<&'a Secret> for ListedSecret<'a> {
+    fn from(secret: &'a Secret) -> Self {
+        ListedSecret {
+            id: secret.id,
+            service: &secret.service,
+            account: &secret.account,
+            digits: secret.digits,
+            interval: secret.interval,
+            tags: &secret.tags,
+            notes: secret.notes.as_deref(),
+            url: secret.url.as_deref(),
+            icon: secret.icon.as_deref(),
+            pinned: secret.pinned,
+        }
+    }
+}
+
+pub fn run(config: Config, filter: &SecretFilter, long: bool, stale: bool, ids: bool, json: bool) -> Result<()> {
+    if config.encrypt_metadata {
+        let required = config.pv.require.list;
+        run_with_store(&mut super::open_store_for(config, required)?, filter, long, stale, ids, json)
+    } else {
+        super::verify_presence_if_required(&config, config.pv.require.list)?;
+        run_with_store(&mut TotpStore::without_tpm(config)?, filter, long, stale, ids, json)
+    }
+}
+
+/// Lists the secrets matching the given filter, or prints them as a single JSON array if json is true.
+pub fn run_with_store<P>(
+    store: &mut TotpStore<P>,
+    filter: &SecretFilter,
+    long: bool,
+    stale: bool,
+    ids: bool,
+    json: bool,
+) -> Result<()> {
+    log::info!("listing secrets for {} ({})", filter.service, filter.account);
+    let secrets = store.find(filter)?;
+    if json {
+        let mut stdout = io::stdout();
+        let listed = secrets.iter().map(ListedSecret::from).collect::<Vec<_>>();
+        serde_json::to_writer(&mut stdout, &listed).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+        return Ok(())
+    }
+    // Print everything at once, so that long listings can be paged
+    let mut out = Vec::new();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    if stale {
+        for (secret, issues) in hygiene::check(&secrets, now) {
+            print_secret(&mut out, secret, long, ids)?;
+            for issue in issues {
+                writeln!(out, "  ! {}", issue)?;
+            }
+        }
+    } else {
+        for secret in &secrets {
+            print_secret(&mut out, secret, long, ids)?;
+            if let Some(reminder) = super::rotation_reminder(secret, now) {
+                writeln!(out, "  ! {}", reminder)?;
+            }
+        }
+    }
+    term::page(&String::from_utf8_lossy(&out))?;
+    Ok(())
+}
+
+/// Prints the given secret, prefixed by its id if ids is true.
+fn print_secret(out: &mut impl Write, secret: &Secret, long: bool, ids: bool) -> io::Result<()> {
+    if ids {
+        write!(out, "{} ", secret.id)?;
+    }
+    if long {
+        print_long(out, secret)
+    } else if secret.tags.is_empty() {
+        writeln!(out, "{} ({})", secret.service, secret.account)
+    } else {
+        writeln!(out, "{} ({}) [{}]", secret.service, secret.account, secret.tags.join(", "))
+    }
+}
+
+fn print_long(out: &mut impl Write, secret: &Secret) -> io::Result<()> {
+    writeln!(out, "{} ({})", secret.service, secret.account)?;
+    writeln!(out, "  digits: {}", secret.digits)?;
+    writeln!(out, "  interval: {}", secret.interval)?;
+    if !secret.tags.is_empty() {
+        writeln!(out, "  tags: {}", secret.tags.join(", "))?;
+    }
+    if let Some(notes) = &secret.notes {
+        writeln!(out, "  notes: {}", notes)?;
+    }
+    if let Some(url) = &secret.url {
+        writeln!(out, "  url: {}", url)?;
+    }
+    if let Some(icon) = &secret.icon {
+        writeln!(out, "  icon: {}", icon)?;
+    }
+    if secret.pinned {
+        writeln!(out, "  pinned: yes")?;
+    }
+    match secret.last_used_at {
+        Some(last_used_at) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+            writeln!(out, "  last used: {}", format_age(now - last_used_at))?;
+        },
+        None => writeln!(out, "  last used: never")?,
+    }
+    writeln!(out, "  use count: {}", secret.use_count)?;
+    if let Some(days) = secret.rotate_after_days {
+        writeln!(out, "  rotate after: {} days", days)?;
+    }
+    if secret.offset_seconds != 0 {
+        writeln!(out, "  time offset: {:+} seconds", secret.offset_seconds)?;
+    }
+    if secret.t0 != 0 {
+        writeln!(out, "  t0: {}", secret.t0)?;
     }
     Ok(())
 }
+
+/// Formats the given number of seconds as a rough, human readable age.
+fn format_age(seconds: i64) -> String {
+    match seconds {
+        ..=59 => "just now".to_string(),
+        60..=3599 => format!("{} minutes ago", seconds / 60),
+        3600..=86399 => format!("{} hours ago", seconds / 3600),
+        _ => format!("{} days ago", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_secret_serializes_missing_fields_as_null() {
+        let mut secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![], vec![]);
+        secret.id = 3;
+        secret.icon = Some("svc-icon".to_owned());
+        assert_eq!(
+            serde_json::to_string(&ListedSecret::from(&secret)).unwrap(),
+            concat!(
+                r#"{"id":3,"service":"svc","account":"acct","digits":6,"interval":30,"tags":[],"#,
+                r#""notes":null,"url":null,"icon":"svc-icon","pinned":false}"#,
+            ),
+        );
+    }
+
+    #[test]
+    fn format_age_uses_largest_sensible_unit() {
+        assert_eq!(format_age(-5), "just now");
+        assert_eq!(format_age(59), "just now");
+        assert_eq!(format_age(120), "2 minutes ago");
+        assert_eq!(format_age(7200), "2 hours ago");
+        assert_eq!(format_age(86400 * 40), "40 days ago");
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+
+use serde::de::DeserializeOwned;
+
+use crate::{config::Config, housekeeping, migration::{Bundle, Target}, privileges::is_root, result::{Context, Error, Result}};
+
+/// First step, on the new machine: creates a migration target and writes its public part to the given file.
+pub fn target(config: Config, file: &Path) -> Result<()> {
+    let mut totp_store = super::open_store(config)?;
+    let target = totp_store.create_migration_target()?;
+    std::fs::write(file, toml::to_string(&target)?)?;
+    println!("wrote migration target to {}", file.to_str().unwrap());
+    println!("copy it to the old machine and have root run 'totpm migrate approve <target file>' there");
+    Ok(())
+}
+
+/// Second step, on the old machine: approves the target in the given file, adding the approval to the file.
+/// Only root may approve targets, since anyone able to would be able to export every migratable secret
+/// to a key of their own.
+pub fn approve(config: Config, target_file: &Path) -> Result<()> {
+    if !is_root() {
+        return Err(Error::RootRequired)
+    }
+    let mut totp_store = super::open_store(config)?;
+    let target: Target = read_toml(target_file)?;
+    let target = totp_store.approve_migration_target(target)?;
+    housekeeping::atomic_write(target_file, toml::to_string(&target)?)?;
+    println!("approved migration target {}", target_file.to_str().unwrap());
+    println!("run 'totpm migrate export <target file> <bundle file>' to export secrets to it");
+    Ok(())
+}
+
+/// Third step, on the old machine: duplicates all migratable secrets to the approved target in the given file,
+/// and writes them to a new bundle file.
+pub fn export(config: Config, target_file: &Path, bundle_file: &Path) -> Result<()> {
+    let mut totp_store = super::open_store(config)?;
+    let target: Target = read_toml(target_file)?;
+    let (bundle, skipped) = totp_store.export_migratable(target)?;
+    for secret in &skipped {
+        eprintln!("warning: the key of {} is bound to this TPM and can't be migrated", secret);
+    }
+
+    // The bundle is useless without the target TPM, but its metadata is still nobody else's business.
+    let mut out = OpenOptions::new().write(true).create_new(true).mode(0o600).open(bundle_file)
+        .context(|| format!("creating {}", bundle_file.to_str().unwrap()))?;
+    out.write_all(toml::to_string(&bundle)?.as_bytes())?;
+    println!("exported {} secrets to {}", bundle.secrets.len(), bundle_file.to_str().unwrap());
+    println!("copy it to the new machine and run 'totpm migrate import <bundle file>' there");
+    Ok(())
+}
+
+/// Last step, on the new machine: imports the secrets in the given bundle file.
+pub fn import(config: Config, bundle_file: &Path) -> Result<()> {
+    let mut totp_store = super::open_store(config)?;
+    let bundle: Bundle = read_toml(bundle_file)?;
+    let secrets = totp_store.import_migrated(bundle)?;
+    for secret in &secrets {
+        println!("imported {}", secret);
+    }
+    println!("imported {} secrets; the bundle file can now be deleted", secrets.len());
+    Ok(())
+}
+
+fn read_toml<T: DeserializeOwned>(file: &Path) -> Result<T> {
+    toml::from_str(&std::fs::read_to_string(file).context(|| format!("reading {}", file.to_str().unwrap()))?)
+        .map_err(|e| Error::ImportFormatError(format!("{}: {}", file.to_str().unwrap(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::{db, presence_verification::PresenceVerificationMethod, totp_store::TotpStore};
+
+    use super::*;
+
+    fn config(tpm: &SwTpm, dir: &Path, migratable_keys: bool) -> Config {
+        let mut cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.join("sys")),
+            Some(dir.join("user")),
+            Some(PresenceVerificationMethod::None)
+        );
+        cfg.migratable_keys = migratable_keys;
+        cfg
+    }
+
+    #[test]
+    fn migrate_moves_migratable_secrets_to_new_tpm() {
+        let (old_tpm, new_tpm) = (SwTpm::new(), SwTpm::new());
+        let (old_dir, new_dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let old_cfg = config(&old_tpm, old_dir.path(), false);
+        let new_cfg = config(&new_tpm, new_dir.path(), true);
+        TotpStore::init(old_cfg.clone()).unwrap();
+        TotpStore::init(new_cfg.clone()).unwrap();
+        TotpStore::with_tpm(old_cfg.clone()).unwrap()
+            .add("fixed", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+        let mut old_store = TotpStore::with_tpm(config(&old_tpm, old_dir.path(), true)).unwrap();
+        old_store.add("migratable", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        drop(old_store);
+
+        let target_file = new_dir.path().join("target.toml");
+        let bundle_file = new_dir.path().join("bundle.toml");
+        target(new_cfg.clone(), &target_file).unwrap();
+        approve_target(&old_cfg, &target_file);
+        export(old_cfg, &target_file, &bundle_file).unwrap();
+        import(new_cfg.clone(), &bundle_file).unwrap();
+
+        let secrets = db::with_db(new_cfg.secrets_db_path(), |db| db.list_secrets("", "")).unwrap();
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(secrets[0].service, "migratable");
+        let code = TotpStore::with_tpm(new_cfg).unwrap().gen(secrets[0].id, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(code, crate::reference::totp(&[0,0,0,0,0,0,0,0,0,0], std::time::UNIX_EPOCH, 30, 6));
+    }
+
+    #[test]
+    fn import_fails_without_migration_target() {
+        let (old_tpm, new_tpm) = (SwTpm::new(), SwTpm::new());
+        let (old_dir, new_dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let old_cfg = config(&old_tpm, old_dir.path(), true);
+        let new_cfg = config(&new_tpm, new_dir.path(), true);
+        TotpStore::init(old_cfg.clone()).unwrap();
+        TotpStore::init(new_cfg.clone()).unwrap();
+
+        let target_file = old_dir.path().join("target.toml");
+        let bundle_file = old_dir.path().join("bundle.toml");
+        target(old_cfg.clone(), &target_file).unwrap();
+        approve_target(&old_cfg, &target_file);
+        export(old_cfg, &target_file, &bundle_file).unwrap();
+        match import(new_cfg, &bundle_file).unwrap_err() {
+            Error::TotpStoreError(crate::totp_store::Error::MigrationTargetUnavailable) => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn export_fails_to_unapproved_target() {
+        let (old_tpm, new_tpm) = (SwTpm::new(), SwTpm::new());
+        let (old_dir, new_dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let old_cfg = config(&old_tpm, old_dir.path(), true);
+        let new_cfg = config(&new_tpm, new_dir.path(), true);
+        TotpStore::init(old_cfg.clone()).unwrap();
+        TotpStore::init(new_cfg.clone()).unwrap();
+        TotpStore::with_tpm(old_cfg.clone()).unwrap()
+            .add("migratable", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+
+        let target_file = new_dir.path().join("target.toml");
+        let bundle_file = new_dir.path().join("bundle.toml");
+        target(new_cfg, &target_file).unwrap();
+        match export(old_cfg, &target_file, &bundle_file).unwrap_err() {
+            Error::TotpStoreError(crate::totp_store::Error::MigrationTargetNotApproved) => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+        assert!(!bundle_file.exists());
+    }
+
+    /// Approves the target in the given file like approve, but without requiring root.
+    fn approve_target(cfg: &Config, target_file: &Path) {
+        let target: Target = read_toml(target_file).unwrap();
+        let target = TotpStore::with_tpm(cfg.clone()).unwrap().approve_migration_target(target).unwrap();
+        std::fs::write(target_file, toml::to_string(&target).unwrap()).unwrap();
+    }
+}
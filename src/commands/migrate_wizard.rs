@@ -0,0 +1,271 @@
+use std::{fmt::Display, io::{self, BufRead, Write}, path::{Path, PathBuf}, time::SystemTime};
+
+use crate::{base32, config::Config, db::model::Secret, result::{Error, Result}, term::{pick_one, prompt, IsATTY}};
+
+use super::import::import_json;
+
+/// Number of imported accounts whose codes the user is asked to compare with their old authenticator.
+const NUM_CODES_TO_VERIFY: usize = 3;
+
+/// Export formats the wizard knows how to read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// The JSON format read by the import command.
+    Json,
+    /// One otpauth:// URI per line, as exported by most phone authenticators.
+    OtpauthUris,
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Json => "totpm json",
+            Format::OtpauthUris => "otpauth uri list",
+        })
+    }
+}
+
+/// An account found in an export from another authenticator.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    service: String,
+    account: String,
+    secret: Vec<u8>,
+    digits: Option<u8>,
+    interval: Option<u32>,
+    url: Option<String>,
+    icon: Option<String>,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.service, self.account)
+    }
+}
+
+/// A file in a supported format, along with the accounts in it.
+#[derive(Debug)]
+struct Export {
+    path: PathBuf,
+    format: Format,
+    entries: Vec<Entry>,
+}
+
+impl Display for Export {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}, {} accounts)", self.path.to_str().unwrap(), self.format, self.entries.len())
+    }
+}
+
+/// Walks the user through moving their accounts from a phone authenticator, using the exports in the given directory.
+pub fn run(config: Config, dir: &Path) -> Result<()> {
+    let mut stdout = io::stdout();
+    walk(config, dir, &mut io::stdin().lock(), &mut stdout)
+}
+
+fn walk<In: BufRead, Out: Write + IsATTY>(config: Config, dir: &Path, inp: &mut In, out: &mut Out) -> Result<()> {
+    writeln!(out, "step 1/5: looking for exported accounts in {}", dir.to_str().unwrap())?;
+    let exports = detect_exports(dir)?;
+    if exports.is_empty() {
+        writeln!(out, "no exports found; export your accounts from your old authenticator as otpauth:// uris,")?;
+        writeln!(out, "one per line, and put the file in {}", dir.to_str().unwrap())?;
+        return Err(Error::ImportFormatError(format!("no importable files in {}", dir.to_str().unwrap())))
+    }
+    for export in &exports {
+        writeln!(out, "  found {}", export)?;
+    }
+    let export = pick_one(inp, out, "which export do you want to import?", exports.iter())
+        .ok_or(Error::SetupCancelled)?;
+
+    writeln!(out, "step 2/5: the following accounts will be imported:")?;
+    for entry in &export.entries {
+        writeln!(out, "  {}", entry)?;
+    }
+    if !prompt(inp, out, "continue? (y/n)", "n")?.eq_ignore_ascii_case("y") {
+        return Err(Error::SetupCancelled)
+    }
+
+    writeln!(out, "step 3/5: importing accounts")?;
+    let mut store = super::open_store(config)?;
+    let imported = store.batch(|store| {
+        export.entries.iter()
+            .map(|entry| {
+                let modify = |secret: &mut Secret| {
+                    secret.url = entry.url.clone();
+                    secret.icon = entry.icon.clone();
+                };
+                let secret = store.add_ex(&entry.service, &entry.account, entry.digits, entry.interval, &entry.secret, None, modify)?;
+                Ok((entry, secret.id))
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+    for (entry, _) in &imported {
+        writeln!(out, "  imported {}", entry)?;
+    }
+
+    writeln!(out, "step 4/5: comparing codes with your old authenticator")?;
+    let mut mismatches = 0;
+    for (entry, id) in imported.iter().take(NUM_CODES_TO_VERIFY) {
+        let code = store.gen(*id, SystemTime::now())?;
+        let answer = prompt(inp, out, &format!("does {} show {}? (y/n)", entry, code), "y")?;
+        if !answer.eq_ignore_ascii_case("y") {
+            mismatches += 1;
+        }
+    }
+    if mismatches > 0 {
+        writeln!(out, "warning: {} codes did not match; check that your clock is correct before going on,", mismatches)?;
+        writeln!(out, "and keep the accounts on your old device until you have logged in using totpm")?;
+    }
+
+    writeln!(out, "step 5/5: once you have logged in to each account using totpm, remove it from your old device:")?;
+    for entry in &export.entries {
+        writeln!(out, "  [ ] {}", entry)?;
+    }
+    writeln!(out, "finally, delete {}, since it contains your secrets in plaintext", export.path.to_str().unwrap())?;
+    Ok(())
+}
+
+/// Returns the files directly in the given directory that are in a supported format, sorted by name.
+/// Unreadable files and files in other formats are skipped.
+fn detect_exports(dir: &Path) -> Result<Vec<Export>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths.into_iter().filter_map(|path| read_export(path).ok().flatten()).collect())
+}
+
+/// Reads the given file if it's in a supported format, or returns None otherwise.
+fn read_export(path: PathBuf) -> Result<Option<Export>> {
+    if let Ok(imports) = import_json(&path) {
+        let entries = imports.into_iter()
+            .map(|(service, info)| Some(Entry {
+                service,
+                account: info.account,
+                secret: base32::decode(&info.secret)?,
+                digits: info.digits,
+                interval: info.interval,
+                url: info.url,
+                icon: info.icon,
+            }))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::SecretFormatError)?;
+        return Ok(Some(Export { path, format: Format::Json, entries }))
+    }
+
+    let contents = std::fs::read_to_string(&path)?;
+    let lines = contents.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>();
+    if lines.is_empty() {
+        return Ok(None)
+    }
+    let entries = lines.into_iter().map(parse_otpauth_uri).collect::<Option<Vec<_>>>();
+    Ok(entries.map(|entries| Export { path, format: Format::OtpauthUris, entries }))
+}
+
+/// Parses a URI of the form otpauth://totp/issuer:account?secret=...&issuer=...&digits=...&period=...&image=...
+/// where image is the URL of an icon for the service, as used by FreeOTP.
+/// Only TOTP with the default SHA-1 algorithm is supported.
+fn parse_otpauth_uri(uri: &str) -> Option<Entry> {
+    let rest = uri.strip_prefix("otpauth://totp/")?;
+    let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let label = percent_decode(label)?;
+    let (label_issuer, account) = match label.split_once(':') {
+        Some((issuer, account)) => (Some(issuer.trim().to_owned()), account.trim().to_owned()),
+        None => (None, label.trim().to_owned()),
+    };
+
+    let mut entry = Entry {
+        service: String::new(),
+        account,
+        secret: Vec::new(),
+        digits: None,
+        interval: None,
+        url: None,
+        icon: None,
+    };
+    let mut issuer = None;
+    let mut has_secret = false;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=')?;
+        let value = percent_decode(value)?;
+        match key {
+            "secret" => {
+                entry.secret = base32::decode(&value)?;
+                has_secret = true;
+            },
+            "issuer" => issuer = Some(value),
+            "digits" => entry.digits = Some(value.parse().ok()?),
+            "period" => entry.interval = Some(value.parse().ok()?),
+            "image" => entry.icon = Some(value),
+            "algorithm" if !value.eq_ignore_ascii_case("sha1") => return None,
+            _ => {},
+        }
+    }
+    entry.service = issuer.or(label_issuer)?;
+    has_secret.then_some(entry)
+}
+
+/// Decodes %XX escapes in the given URI component.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn parse_otpauth_uri_reads_label_and_parameters() {
+        assert_eq!(
+            parse_otpauth_uri("otpauth://totp/Example%20Co:alice%40example.com?secret=NBSWY3DP&digits=8&period=60"),
+            Some(Entry {
+                service: "Example Co".to_owned(),
+                account: "alice@example.com".to_owned(),
+                secret: b"hello".to_vec(),
+                digits: Some(8),
+                interval: Some(60),
+                url: None,
+                icon: None,
+            }),
+        );
+        let entry = parse_otpauth_uri("otpauth://totp/alice?secret=NBSWY3DP&issuer=Example").unwrap();
+        assert_eq!(entry.service, "Example");
+        assert_eq!(entry.account, "alice");
+        let entry = parse_otpauth_uri("otpauth://totp/Example:alice?secret=NBSWY3DP&image=https%3A%2F%2Fexample.com%2Ficon.png").unwrap();
+        assert_eq!(entry.icon.as_deref(), Some("https://example.com/icon.png"));
+        assert_eq!(parse_otpauth_uri("otpauth://totp/Example:alice"), None);
+        assert_eq!(parse_otpauth_uri("otpauth://hotp/Example:alice?secret=NBSWY3DP&counter=0"), None);
+        assert_eq!(parse_otpauth_uri("otpauth://totp/Example:alice?secret=NBSWY3DP&algorithm=SHA256"), None);
+    }
+
+    #[test]
+    fn detect_exports_finds_supported_files_only() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.json"), "{\"foo\": {\"account\": \"bar\", \"secret\": \"NBSWY3DP\"}}").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "otpauth://totp/Foo:bar?secret=NBSWY3DP\n\n").unwrap();
+        std::fs::write(dir.path().join("c.txt"), "shopping list\n").unwrap();
+        std::fs::create_dir(dir.path().join("d")).unwrap();
+
+        let exports = detect_exports(dir.path()).unwrap();
+        assert_eq!(exports.len(), 2);
+        assert_eq!(exports[0].format, Format::Json);
+        assert_eq!(exports[1].format, Format::OtpauthUris);
+        for export in exports {
+            assert_eq!(export.entries.len(), 1);
+            assert_eq!(export.entries[0].secret, b"hello".to_vec());
+        }
+    }
+}
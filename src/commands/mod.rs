@@ -1,8 +1,112 @@
 pub mod add;
+pub mod admin;
+pub mod backup;
+pub mod bugreport;
 pub mod init;
+pub mod install_units;
 pub mod list;
+pub mod migrate;
 pub mod gen;
 pub mod clear;
+pub mod config;
 pub mod del;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+pub mod doctor;
+pub mod export;
+pub mod setup;
+pub mod pam_helper;
+pub mod pin;
+pub mod shell;
+pub mod status;
+pub mod ssh_helper;
+pub mod undo;
+pub mod rekey;
+pub mod restore;
+pub mod verify;
+pub mod verify_store;
+pub mod version;
+#[cfg(test)]
+mod test_util;
 #[cfg(feature = "import")]
-pub mod import;
\ No newline at end of file
+pub mod import;
+#[cfg(feature = "import")]
+pub mod migrate_wizard;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rpassword::prompt_password;
+
+use crate::{
+    clock,
+    config::Config,
+    db::model::Secret,
+    observer::Observer,
+    presence_verification::factory::create_presence_verifier,
+    result::Result,
+    totp_store::{self, TotpStore, WithTPM},
+    tpm,
+};
+
+/// Reports what the TOTP store is doing on the terminal.
+struct TerminalObserver;
+
+impl Observer for TerminalObserver {
+    fn on_secret_used(&mut self, secret: &Secret) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        if let Some(reminder) = rotation_reminder(secret, now) {
+            eprintln!("warning: the secret for {} is {}", secret, reminder);
+        }
+    }
+}
+
+/// Opens the TOTP store for a command, reporting on the terminal.
+fn open_store(config: Config) -> Result<TotpStore<WithTPM>> {
+    Ok(TotpStore::with_tpm_observed(config, Box::new(TerminalObserver))?)
+}
+
+/// Like open_store, but doesn't verify presence if [pv.require] says the operation doesn't need it.
+fn open_store_for(config: Config, required: Option<bool>) -> Result<TotpStore<WithTPM>> {
+    match required {
+        Some(false) => Ok(TotpStore::with_tpm_unverified_observed(config, Box::new(TerminalObserver))?),
+        _ => open_store(config),
+    }
+}
+
+/// Verifies presence for an operation which doesn't use the TPM, if [pv.require] says it needs it anyway.
+/// Must be called before privileges are dropped, since some methods read system data.
+fn verify_presence_if_required(config: &Config, required: Option<bool>) -> Result<()> {
+    if required != Some(true) {
+        return Ok(())
+    }
+    let present = create_presence_verifier(config).owner_present()
+        .map_err(|e| totp_store::Error::TpmError(e.into()))?;
+    match present {
+        true => Ok(()),
+        false => Err(totp_store::Error::TpmError(tpm::Error::PresenceVerificationFailed).into()),
+    }
+}
+
+/// Prompts for a PIN on the tty, without echoing it.
+/// The prompt goes to the tty rather than stdout, so it doesn't end up in redirected output.
+fn read_pin(prompt: &str) -> Result<String> {
+    Ok(prompt_password(prompt)?)
+}
+
+/// Prints a warning to stderr for each sign that the system clock can't be trusted to generate valid codes
+/// for the given secret, checking against the configured NTP server, if any.
+fn warn_if_clock_unreliable(config: &Config, secret: &Secret) {
+    let ntp_issue = config.ntp_server.as_deref()
+        .and_then(|server| clock::check_ntp(server, secret.interval as f64 / 2.0));
+    for issue in clock::check().into_iter().chain(ntp_issue) {
+        eprintln!("warning: {}", issue);
+    }
+}
+
+/// Returns a reminder to rotate the given secret if it's older than its rotation window.
+fn rotation_reminder(secret: &Secret, now: i64) -> Option<String> {
+    secret.rotation_due(now).then(|| format!(
+        "{} days old and should be rotated every {} days",
+        secret.age_days(now).unwrap_or_default(),
+        secret.rotate_after_days.unwrap_or_default(),
+    ))
+}
@@ -0,0 +1,28 @@
+use std::io::{self, BufRead};
+
+use crate::{config::Config, result::Result};
+
+/// Reads a PAM prompt from stdin and prints the code for the matching secret to stdout,
+/// if the prompt asks for a one-time code.
+/// This lets e.g. a sudo askpass program answer the prompt of a TOTP PAM module from the TPM store.
+pub fn run(config: Config, service: &str, account: Option<&str>) -> Result<()> {
+    let prompt = read_prompt(&mut io::stdin().lock())?;
+    super::ssh_helper::run(config, service, account, Some(&prompt), false)
+}
+
+fn read_prompt<In: BufRead>(inp: &mut In) -> Result<String> {
+    let mut prompt = String::new();
+    inp.read_line(&mut prompt)?;
+    Ok(prompt.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_prompt_reads_first_line_only() {
+        let mut inp = "Verification code: \r\nsomething else\n".as_bytes();
+        assert_eq!(read_prompt(&mut inp).unwrap(), "Verification code: ");
+    }
+}
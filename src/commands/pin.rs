@@ -0,0 +1,35 @@
+use crate::{args::PinArgs, config::Config, db::SecretFilter, result::{Error, Result}, selection::{create_selector, Selector}, totp_store::TotpStore};
+
+/// Pins or unpins the secret matching the given arguments, so that it's listed and offered for selection first.
+/// Only metadata is touched, so the TPM is only needed if metadata is encrypted, as for list.
+pub fn run(config: Config, args: &PinArgs, pinned: bool) -> Result<()> {
+    let filter = &SecretFilter {
+        service: args.service.as_deref().unwrap_or_default(),
+        account: args.account.as_deref().unwrap_or_default(),
+        id: args.id,
+        exact: args.exact,
+        ..Default::default()
+    };
+    let mut selector = create_selector(config.selection);
+    if config.encrypt_metadata {
+        let required = config.pv.require.list;
+        set_pinned(&mut super::open_store_for(config, required)?, selector.as_mut(), filter, pinned)
+    } else {
+        super::verify_presence_if_required(&config, config.pv.require.list)?;
+        set_pinned(&mut TotpStore::without_tpm(config)?, selector.as_mut(), filter, pinned)
+    }
+}
+
+fn set_pinned<P>(store: &mut TotpStore<P>, selector: &mut dyn Selector, filter: &SecretFilter, pinned: bool) -> Result<()> {
+    let alternatives = store.find(filter)?;
+    if alternatives.is_empty() {
+        return Err(Error::SecretNotFound)
+    }
+    let secret = selector.select(
+        "found multiple matches for the given service/account combination",
+        &alternatives,
+    )?.ok_or(Error::AmbiguousSecret)?;
+    store.set_pinned(secret.id, pinned)?;
+    println!("{} {}", if pinned { "pinned" } else { "unpinned" }, secret);
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crate::{config::Config, result::Result, totp_store::TotpStore};
+
+/// Moves all secrets to a fresh primary key, evicting the old one from the TPM.
+pub fn run(config: Config, force: bool) -> Result<()> {
+    let count = TotpStore::rekey(config, force)?;
+    println!("re-wrapped {} secrets under a new primary key", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::{db, presence_verification::PresenceVerificationMethod, result::Error, totp_store};
+
+    use super::*;
+
+    fn config(tpm: &SwTpm, dir: &std::path::Path) -> Config {
+        let mut cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.join("sys")),
+            Some(dir.join("user")),
+            Some(PresenceVerificationMethod::None)
+        );
+        cfg.migratable_keys = true;
+        cfg.encrypt_metadata = true;
+        cfg
+    }
+
+    #[test]
+    fn rekey_keeps_secrets_usable_under_new_primary_key() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = config(&tpm, dir.path());
+        TotpStore::init(cfg.clone()).unwrap();
+        let secret = TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("service", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+        let old_handle = std::fs::read_to_string(cfg.primary_key_handle_path()).unwrap();
+
+        run(cfg.clone(), false).unwrap();
+
+        assert_ne!(std::fs::read_to_string(cfg.primary_key_handle_path()).unwrap(), old_handle);
+        let mut store = TotpStore::with_tpm(cfg).unwrap();
+        assert_eq!(store.list(None, None).unwrap()[0].service, "service");
+        assert!(store.verify_keys().unwrap().is_empty());
+        let code = store.gen(secret.id, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(code, crate::reference::totp(&[0,0,0,0,0,0,0,0,0,0], std::time::UNIX_EPOCH, 30, 6));
+    }
+
+    #[test]
+    fn interrupted_rekey_is_completed_when_store_is_opened() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = config(&tpm, dir.path());
+        TotpStore::init(cfg.clone()).unwrap();
+        let secret = TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("service", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+        let old_handle = std::fs::read(cfg.primary_key_handle_path()).unwrap();
+        let old_auth_value = std::fs::read(cfg.auth_value_path()).unwrap();
+        run(cfg.clone(), false).unwrap();
+
+        // Put things back the way they were right after the database was committed.
+        let journal = |path: std::path::PathBuf| std::path::PathBuf::from(format!("{}.rekey", path.display()));
+        std::fs::rename(cfg.primary_key_handle_path(), journal(cfg.primary_key_handle_path())).unwrap();
+        std::fs::rename(cfg.auth_value_path(), journal(cfg.auth_value_path())).unwrap();
+        std::fs::write(cfg.primary_key_handle_path(), &old_handle).unwrap();
+        std::fs::write(cfg.auth_value_path(), &old_auth_value).unwrap();
+
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        let code = store.gen(secret.id, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(code, crate::reference::totp(&[0,0,0,0,0,0,0,0,0,0], std::time::UNIX_EPOCH, 30, 6));
+        assert_ne!(std::fs::read(cfg.primary_key_handle_path()).unwrap(), old_handle);
+        assert!(!journal(cfg.primary_key_handle_path()).exists());
+        assert!(!journal(cfg.auth_value_path()).exists());
+    }
+
+    #[test]
+    fn interrupted_rekey_with_unusable_primary_key_is_discarded() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = config(&tpm, dir.path());
+        TotpStore::init(cfg.clone()).unwrap();
+        let secret = TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("service", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+        let old_handle = std::fs::read(cfg.primary_key_handle_path()).unwrap();
+        let journal = |path: std::path::PathBuf| std::path::PathBuf::from(format!("{}.rekey", path.display()));
+        std::fs::write(journal(cfg.primary_key_handle_path()), format!("{}", 0x81_00_ff_ffu32)).unwrap();
+        std::fs::write(journal(cfg.auth_value_path()), [0u8; 32]).unwrap();
+
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        assert!(store.gen(secret.id, std::time::UNIX_EPOCH).is_ok());
+        assert_eq!(std::fs::read(cfg.primary_key_handle_path()).unwrap(), old_handle);
+        assert!(!journal(cfg.primary_key_handle_path()).exists());
+        assert!(!journal(cfg.auth_value_path()).exists());
+    }
+
+    #[test]
+    fn rekey_changes_nothing_if_any_key_is_fixed() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let mut cfg = config(&tpm, dir.path());
+        cfg.migratable_keys = false;
+        TotpStore::init(cfg.clone()).unwrap();
+        TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("fixed", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+        let old_handle = std::fs::read_to_string(cfg.primary_key_handle_path()).unwrap();
+        let old_secrets = db::with_db(cfg.secrets_db_path(), |db| db.list_secrets("", "")).unwrap();
+
+        match run(cfg.clone(), false).unwrap_err() {
+            Error::TotpStoreError(totp_store::Error::KeysNotMigratable(secrets)) => {
+                assert_eq!(secrets.len(), 1);
+                assert_eq!(secrets[0].service, "fixed");
+            },
+            err => panic!("wrong error: {:#?}", err),
+        }
+        assert_eq!(std::fs::read_to_string(cfg.primary_key_handle_path()).unwrap(), old_handle);
+        assert_eq!(db::with_db(cfg.secrets_db_path(), |db| db.list_secrets("", "")).unwrap(), old_secrets);
+    }
+}
@@ -0,0 +1,280 @@
+use std::path::Path;
+
+use crate::{config::Config, db, housekeeping, migration::WrappedExport, privileges::EuidSwapGuard, result::{Context, Error, Result}, totp_store::{self, read_primary_key_persistent_handle}};
+
+use super::backup::{Manifest, BACKUP_DB_FILE, MANIFEST_FILE};
+
+/// Replaces the secrets database with the one in the given backup directory.
+/// The backup is staged and checked before the live database is touched:
+/// its schema must be no newer than ours, and every secret's key must load under the current primary key.
+/// A backup made under a different primary key handle is refused unless force is true.
+pub fn run(config: Config, path: &Path, force: bool) -> Result<()> {
+    let manifest = {
+        let _euid = EuidSwapGuard::real_user()?;
+        read_manifest(path)?
+    };
+    if manifest.schema_version > db::CURRENT_SCHEMA_VERSION {
+        return Err(Error::InvalidBackup(format!(
+            "backup has schema version {}, but this version of totpm only supports up to {}",
+            manifest.schema_version,
+            db::CURRENT_SCHEMA_VERSION,
+        )));
+    }
+
+    let current_handle = read_primary_key_persistent_handle(&config)
+        .or(Err(totp_store::Error::NotInitialized))?;
+    if manifest.primary_key_handle != current_handle {
+        if !force {
+            return Err(Error::BackupKeyHandleMismatch(manifest.primary_key_handle, current_handle));
+        }
+        log::warn!("restoring backup made under a different primary key handle");
+    }
+
+    let mut store = super::open_store(config.clone())?;
+    let db_path = config.secrets_db_path();
+    let staged_path = housekeeping::tmp_path(&db_path);
+    if staged_path.exists() {
+        std::fs::remove_file(&staged_path)?;
+    }
+
+    log::info!("staging backup at {}", staged_path.to_str().unwrap());
+    db::backup(path.join(BACKUP_DB_FILE), &staged_path, config.db_locking)?;
+    let problems = match store.verify_keys_in(&staged_path) {
+        Ok(problems) => problems,
+        Err(e) => {
+            std::fs::remove_file(&staged_path)?;
+            return Err(e.into());
+        },
+    };
+    if !problems.is_empty() {
+        for problem in &problems {
+            log::warn!("{}", problem);
+        }
+        std::fs::remove_file(&staged_path)?;
+        return Err(Error::BackupKeysNotLoadable(problems.len()));
+    }
+
+    if db_path.exists() {
+        let trash_path = housekeeping::move_to_trash(&db_path)?;
+        println!("moved previous secrets database to {}", trash_path.to_str().unwrap());
+    }
+    std::fs::rename(&staged_path, &db_path)?;
+    println!("restored {} secrets from {}", manifest.num_secrets, path.to_str().unwrap());
+    Ok(())
+}
+
+/// Adds the secrets in the given backup directory, made on a machine whose TPM is lost, to the secrets database
+/// of this machine. Only secrets enrolled with this machine's migration target as secondary_target are recovered.
+pub fn recover_secondary(config: Config, path: &Path) -> Result<()> {
+    let manifest = {
+        let _euid = EuidSwapGuard::real_user()?;
+        read_manifest(path)?
+    };
+    if manifest.schema_version > db::CURRENT_SCHEMA_VERSION {
+        return Err(Error::InvalidBackup(format!(
+            "backup has schema version {}, but this version of totpm only supports up to {}",
+            manifest.schema_version,
+            db::CURRENT_SCHEMA_VERSION,
+        )));
+    }
+
+    let mut store = super::open_store(config.clone())?;
+    let staged_path = housekeeping::tmp_path(&config.secrets_db_path());
+    if staged_path.exists() {
+        std::fs::remove_file(&staged_path)?;
+    }
+
+    log::info!("staging backup at {}", staged_path.to_str().unwrap());
+    db::backup(path.join(BACKUP_DB_FILE), &staged_path, config.db_locking)?;
+    let result = store.recover_secondary(&staged_path);
+    std::fs::remove_file(&staged_path)?;
+    let (secrets, skipped) = result?;
+    for secret in &secrets {
+        println!("recovered {}", secret);
+    }
+    if skipped > 0 {
+        eprintln!("warning: {} secrets have no secondary key for this machine and were not recovered", skipped);
+    }
+    println!("recovered {} secrets from {}", secrets.len(), path.to_str().unwrap());
+    Ok(())
+}
+
+/// Adds the secrets in the given file, written by export --wrapped, back to the secrets database,
+/// leaving out any that are still there. Nothing is added unless every key loads under the current primary key.
+/// An export made under a different primary key handle is refused unless force is true.
+pub fn wrapped(config: Config, file: &Path, force: bool) -> Result<()> {
+    let export: WrappedExport = {
+        let _euid = EuidSwapGuard::real_user()?;
+        toml::from_str(&std::fs::read_to_string(file).context(|| format!("reading {}", file.to_str().unwrap()))?)
+            .map_err(|e| Error::InvalidBackup(format!("malformed export: {}", e)))?
+    };
+    let current_handle = read_primary_key_persistent_handle(&config)
+        .or(Err(totp_store::Error::NotInitialized))?;
+    if export.primary_key_handle != current_handle {
+        if !force {
+            return Err(Error::BackupKeyHandleMismatch(export.primary_key_handle, current_handle));
+        }
+        log::warn!("restoring export made under a different primary key handle");
+    }
+
+    let secrets = export.secrets.iter()
+        .map(|secret| secret.to_secret().ok_or(Error::InvalidBackup(format!("malformed key data for {}", secret.service))))
+        .collect::<Result<Vec<_>>>()?;
+    let mut store = super::open_store(config)?;
+    let problems = store.check_wrapped_keys(&secrets)?;
+    if !problems.is_empty() {
+        for problem in &problems {
+            log::warn!("{}", problem);
+        }
+        return Err(Error::BackupKeysNotLoadable(problems.len()));
+    }
+
+    let (added, skipped) = store.add_wrapped(secrets)?;
+    for secret in &added {
+        println!("restored {}", secret);
+    }
+    if !skipped.is_empty() {
+        println!("{} secrets are already in the database and were left alone", skipped.len());
+    }
+    println!("restored {} secrets from {}", added.len(), file.to_str().unwrap());
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    let manifest_path = path.join(MANIFEST_FILE);
+    let manifest_str = std::fs::read_to_string(&manifest_path)
+        .context(|| format!("reading {}", manifest_path.to_str().unwrap()))?;
+    toml::from_str(&manifest_str).map_err(|e| Error::InvalidBackup(format!("malformed manifest: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{commands::{backup, test_util::setup}, db::model::Secret, housekeeping::TRASH_DIR, totp_store::TotpStore};
+
+    use super::*;
+
+    #[test]
+    fn restore_replaces_database_and_keeps_old_one_in_trash() {
+        let (_tpm, dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        let secret = store.add("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        let backup_path = dir.path().join("backup");
+        backup::run(cfg.clone(), &backup_path).unwrap();
+        store.add("baz", "qux", None, None, &[0,0,0,0,0,0,0,0,0,0]).unwrap();
+        drop(store);
+
+        run(cfg.clone(), &backup_path, false).unwrap();
+        let secrets = db::with_db(cfg.secrets_db_path(), |db| db.list_secrets("", "")).unwrap();
+        assert_eq!(secrets, vec![secret]);
+        let trash_dir = cfg.secrets_db_path().parent().unwrap().join(TRASH_DIR);
+        assert_eq!(std::fs::read_dir(trash_dir).unwrap().count(), 1);
+        TotpStore::with_tpm(cfg).unwrap().gen(secrets[0].id, std::time::SystemTime::now()).unwrap();
+    }
+
+    #[test]
+    fn restore_refuses_mismatched_key_handle_unless_forced() {
+        let (_tpm, dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let backup_path = dir.path().join("backup");
+        backup::run(cfg.clone(), &backup_path).unwrap();
+
+        let manifest_path = backup_path.join(MANIFEST_FILE);
+        let mut manifest = read_manifest(&backup_path).unwrap();
+        manifest.primary_key_handle += 1;
+        std::fs::write(&manifest_path, toml::to_string(&manifest).unwrap()).unwrap();
+
+        match run(cfg.clone(), &backup_path, false).unwrap_err() {
+            Error::BackupKeyHandleMismatch(..) => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+        run(cfg, &backup_path, true).unwrap();
+    }
+
+    #[test]
+    fn restore_refuses_backup_from_other_primary_key() {
+        let (_tpm, dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("foo", "bar", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+        let backup_path = dir.path().join("backup");
+        backup::run(cfg.clone(), &backup_path).unwrap();
+        TotpStore::clear(cfg.clone(), true).unwrap();
+        TotpStore::init(cfg.clone()).unwrap();
+
+        match run(cfg.clone(), &backup_path, true).unwrap_err() {
+            Error::BackupKeysNotLoadable(1) => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+        assert!(!housekeeping::tmp_path(&cfg.secrets_db_path()).exists());
+    }
+
+    #[test]
+    fn restore_refuses_newer_schema() {
+        let (_tpm, dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let backup_path = dir.path().join("backup");
+        backup::run(cfg.clone(), &backup_path).unwrap();
+
+        let mut manifest = read_manifest(&backup_path).unwrap();
+        manifest.schema_version = db::CURRENT_SCHEMA_VERSION + 1;
+        std::fs::write(backup_path.join(MANIFEST_FILE), toml::to_string(&manifest).unwrap()).unwrap();
+
+        match run(cfg, &backup_path, true).unwrap_err() {
+            Error::InvalidBackup(_) => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn wrapped_export_restores_lost_database() {
+        let (_tpm, dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg.clone()).unwrap();
+        let secret = store.add_ex("foo", "bar", None, None, &[1,2,3,4,5,6,7,8,9,10], None, |secret| {
+            secret.tags = vec!["work".to_owned()];
+            secret.offset_seconds = -45;
+        }).unwrap();
+        drop(store);
+        let export_path = dir.path().join("export.toml");
+        crate::commands::export::wrapped(cfg.clone(), &export_path).unwrap();
+        std::fs::remove_file(cfg.secrets_db_path()).unwrap();
+
+        wrapped(cfg.clone(), &export_path, false).unwrap();
+        wrapped(cfg.clone(), &export_path, false).unwrap();
+        let secrets = db::with_db(cfg.secrets_db_path(), |db| db.list_secrets("", "")).unwrap();
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(Secret { id: secret.id, ..secrets[0].clone() }, secret);
+        let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(100);
+        let code = TotpStore::with_tpm(cfg).unwrap().gen(secrets[0].id, now).unwrap();
+        assert_eq!(code, crate::reference::totp(&[1,2,3,4,5,6,7,8,9,10], now - std::time::Duration::from_secs(45), 30, 6));
+    }
+
+    #[test]
+    fn recover_secondary_adds_enrolled_secrets_on_backup_machine() {
+        let (_tpm, dir, mut cfg) = setup();
+        let (_backup_tpm, backup_dir, backup_cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        TotpStore::init(backup_cfg.clone()).unwrap();
+        TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("before", "alice", None, None, &[0,0,0,0,0,0,0,0,0,0])
+            .unwrap();
+
+        let target_file = backup_dir.path().join("target.toml");
+        crate::commands::migrate::target(backup_cfg.clone(), &target_file).unwrap();
+        cfg.secondary_target = Some(target_file);
+        TotpStore::with_tpm(cfg.clone()).unwrap()
+            .add("after", "alice", None, None, &[1,2,3,4,5,6,7,8,9,10])
+            .unwrap();
+        let backup_path = dir.path().join("backup");
+        backup::run(cfg, &backup_path).unwrap();
+
+        recover_secondary(backup_cfg.clone(), &backup_path).unwrap();
+        let secrets = db::with_db(backup_cfg.secrets_db_path(), |db| db.list_secrets("", "")).unwrap();
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(secrets[0].service, "after");
+        let code = TotpStore::with_tpm(backup_cfg).unwrap().gen(secrets[0].id, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(code, crate::reference::totp(&[1,2,3,4,5,6,7,8,9,10], std::time::UNIX_EPOCH, 30, 6));
+    }
+}
@@ -0,0 +1,169 @@
+use std::{fmt::Display, io::{self, BufRead, Write}, path::{Path, PathBuf}};
+
+use crate::{
+    commands::init,
+    config::Config,
+    housekeeping::atomic_write,
+    presence_verification::PresenceVerificationMethod,
+    privileges::is_root,
+    result::{Error, Result},
+    system_user::SystemUser,
+    tcti::{valid_forms, validate_tcti},
+    term::{pick_one, prompt, IsATTY}
+};
+
+/// TPM devices to look for, in order of preference.
+const TPM_DEVICES: [&str; 2] = ["/dev/tpmrm0", "/dev/tpm0"];
+
+/// A labelled alternative for the user to pick.
+struct Choice<T> {
+    label: &'static str,
+    value: T,
+}
+
+impl <T> Display for Choice<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.label)
+    }
+}
+
+/// Answers to the guided setup questions.
+#[derive(Debug, PartialEq)]
+struct Answers {
+    local: bool,
+    tpm: String,
+    pv_method: PresenceVerificationMethod,
+}
+
+/// Walks the user through setting up totpm, then initializes the store.
+/// Returns the path of the newly written config file.
+pub fn run(
+    explicit_config_path: Option<&Path>,
+    local_config_path: &Path,
+    system_config_path: &Path,
+    exe_install_dir: &Path,
+) -> Result<PathBuf> {
+    let answers = ask(
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+        detect_tpm().as_deref(),
+    )?.ok_or(Error::SetupCancelled)?;
+
+    if !answers.local && !is_root() {
+        eprintln!("a system-wide installation must be set up by running 'sudo totpm init'");
+        return Err(Error::RootRequired);
+    }
+
+    let config_path = explicit_config_path
+        .unwrap_or(if answers.local { local_config_path } else { system_config_path })
+        .to_owned();
+    let config = Config::default(answers.local, answers.tpm, None, None, Some(answers.pv_method));
+
+    // Only write the config once init has succeeded, so that a failed init doesn't leave one behind.
+    let contents = toml::to_string(&config)?;
+    init::run(&config_path, config, &SystemUser::new("totpm"), answers.local, exe_install_dir)?;
+
+    log::info!("writing config to {}", config_path.to_str().unwrap());
+    std::fs::create_dir_all(config_path.parent().unwrap())?;
+    atomic_write(&config_path, contents)?;
+    println!("setup complete; configuration written to {}", config_path.to_str().unwrap());
+    Ok(config_path)
+}
+
+fn ask<In: BufRead, Out: Write + IsATTY>(
+    inp: &mut In,
+    out: &mut Out,
+    detected_tpm: Option<&str>,
+) -> Result<Option<Answers>> {
+    out.write_fmt(format_args!("totpm has not been set up yet; answer a few questions to get started\n"))?;
+
+    let scopes = [
+        Choice { label: "local (only for the current user)", value: true },
+        Choice { label: "system (for all users; requires root)", value: false },
+    ];
+    let local = match pick_one(inp, out, "how should totpm be installed?", scopes.iter()) {
+        Some(choice) => choice.value,
+        None => return Ok(None),
+    };
+
+    let default_tpm = match detected_tpm {
+        Some(tpm) => tpm.to_owned(),
+        None => {
+            out.write_fmt(format_args!("no TPM device found; you may need to specify a TPM manually\n"))?;
+            format!("device:{}", TPM_DEVICES[0])
+        },
+    };
+    let tpm = loop {
+        let tpm = prompt(inp, out, "which TPM should totpm use?", &default_tpm)?;
+        match validate_tcti(&tpm) {
+            Ok(()) => break tpm,
+            Err(err) => out.write_fmt(format_args!("{}; valid forms are:\n{}\n", err, valid_forms()))?,
+        }
+    };
+
+    let pv_methods = [
+        Choice { label: "fprintd (fingerprint scan)", value: PresenceVerificationMethod::Fprintd },
+        Choice { label: "none (no presence verification)", value: PresenceVerificationMethod::None },
+        Choice { label: "pin (a PIN shared by all users)", value: PresenceVerificationMethod::Pin },
+    ];
+    let pv_method = match pick_one(inp, out, "how should user presence be verified?", pv_methods.iter()) {
+        Some(choice) => choice.value,
+        None => return Ok(None),
+    };
+
+    Ok(Some(Answers { local, tpm, pv_method }))
+}
+
+/// Returns a TCTI string for the first TPM device found on this machine, if any.
+fn detect_tpm() -> Option<String> {
+    TPM_DEVICES.iter()
+        .find(|dev| Path::new(dev).exists())
+        .map(|dev| format!("device:{}", dev))
+}
+
+#[cfg(test)]
+mod tests {
+    use testutil::term::MockTerminal;
+
+    use super::*;
+
+    #[test]
+    fn ask_collects_answers() {
+        let mut term = MockTerminal::new()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .write_stdin("1")
+            .expect_stdout("which TPM should totpm use? [device:/dev/tpmrm0]: ")
+            .write_stdin("")
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .write_stdin("2");
+        let (mut inp, mut out) = term.stdin_stdout();
+        assert_eq!(
+            ask(&mut inp, &mut out, Some("device:/dev/tpmrm0")).unwrap(),
+            Some(Answers {
+                local: true,
+                tpm: "device:/dev/tpmrm0".to_owned(),
+                pv_method: PresenceVerificationMethod::None,
+            }),
+        );
+    }
+
+    #[test]
+    fn ask_returns_none_on_cancel() {
+        let mut term = MockTerminal::new()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .write_stdin("0");
+        let (mut inp, mut out) = term.stdin_stdout();
+        assert_eq!(ask(&mut inp, &mut out, None).unwrap(), None);
+    }
+}
@@ -0,0 +1,172 @@
+use std::io::{self, BufRead, Write};
+
+use clap::Parser;
+
+use crate::{
+    args::{ShellCommand, ShellLine},
+    commands::{add, del, gen, list, undo},
+    config::Config,
+    db::SecretFilter,
+    result::{Error, Result},
+    selection::{create_selector, MruSelector},
+    tr,
+};
+
+const PROMPT: &str = "totpm> ";
+
+/// Runs an interactive session against a single TPM-backed store.
+/// Errors from individual commands are passed to `report_error` and do not end the session.
+pub fn run<F: Fn(Error)>(config: Config, report_error: F) -> Result<()> {
+    let mut selector = create_selector(config.selection);
+    let mut gen_selector = create_selector(config.gen_selection.unwrap_or(config.selection));
+    let mut store = super::open_store(config)?;
+    let stdin = io::stdin();
+    loop {
+        print!("{}", PROMPT);
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(())
+        }
+
+        let words = match split_words(&line) {
+            Some(words) => words,
+            None => {
+                eprintln!("{}", tr!("shell-unterminated-quote"));
+                continue
+            },
+        };
+        if words.is_empty() {
+            continue
+        }
+
+        let command = match ShellLine::try_parse_from(words) {
+            Ok(line) => line.command,
+            Err(e) => {
+                e.print()?;
+                continue
+            },
+        };
+
+        let result = match command {
+            ShellCommand::Add(args) => {
+                add::run_with_store(&mut store, selector.as_mut(), args)
+            },
+            ShellCommand::Del { service, account, yes } => {
+                del::run_with_store(&mut store, selector.as_mut(), &service, &account, yes)
+            },
+            ShellCommand::Gen { service, account, tag, mru } => {
+                let selector = if mru { &mut MruSelector } else { gen_selector.as_mut() };
+                gen::run_with_store(&mut store, selector, &service, account.as_deref(), tag.as_deref(), None, None)
+            },
+            ShellCommand::List { service, account, tag, long, stale } => {
+                let filter = SecretFilter {
+                    service: service.as_deref().unwrap_or_default(),
+                    account: account.as_deref().unwrap_or_default(),
+                    tag: tag.as_deref(),
+                    ..Default::default()
+                };
+                list::run_with_store(&mut store, &filter, long, stale, false, false)
+            },
+            ShellCommand::Undo => {
+                undo::run_with_store(&mut store)
+            },
+            ShellCommand::Exit => return Ok(()),
+        };
+        if let Err(e) = result {
+            report_error(e);
+        }
+    }
+}
+
+/// Splits a line into whitespace-separated words.
+/// Single or double quotes may be used to include whitespace in a word.
+/// Returns None if the line contains an unterminated quote.
+fn split_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                word.get_or_insert_with(String::new);
+                quote = Some(c);
+            },
+            (None, c) if c.is_whitespace() => {
+                if let Some(w) = word.take() {
+                    words.push(w);
+                }
+            },
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return None
+    }
+    if let Some(w) = word {
+        words.push(w);
+    }
+    Some(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_words_splits_on_whitespace() {
+        assert_eq!(
+            split_words("gen  foo\tbar\n"),
+            Some(vec!["gen".to_owned(), "foo".to_owned(), "bar".to_owned()]),
+        );
+        assert_eq!(split_words("   \n"), Some(vec![]));
+    }
+
+    #[test]
+    fn split_words_respects_quotes() {
+        assert_eq!(
+            split_words("gen \"my service\" 'an account'"),
+            Some(vec!["gen".to_owned(), "my service".to_owned(), "an account".to_owned()]),
+        );
+        assert_eq!(
+            split_words("gen \"\" x"),
+            Some(vec!["gen".to_owned(), "".to_owned(), "x".to_owned()]),
+        );
+        assert_eq!(
+            split_words("gen \"it's\""),
+            Some(vec!["gen".to_owned(), "it's".to_owned()]),
+        );
+    }
+
+    #[test]
+    fn split_words_fails_on_unterminated_quote() {
+        assert_eq!(split_words("gen \"foo"), None);
+        assert_eq!(split_words("gen 'foo"), None);
+    }
+
+    #[test]
+    fn shell_line_parses_commands() {
+        match ShellLine::try_parse_from(["gen", "foo"]).unwrap().command {
+            ShellCommand::Gen { service, account, tag, mru } => {
+                assert_eq!(service, "foo");
+                assert_eq!(account, None);
+                assert_eq!(tag, None);
+                assert!(!mru);
+            },
+            cmd => panic!("wrong command: {:#?}", cmd),
+        }
+        match ShellLine::try_parse_from(["quit"]).unwrap().command {
+            ShellCommand::Exit => {},
+            cmd => panic!("wrong command: {:#?}", cmd),
+        }
+        match ShellLine::try_parse_from(["undo"]).unwrap().command {
+            ShellCommand::Undo => {},
+            cmd => panic!("wrong command: {:#?}", cmd),
+        }
+        assert!(ShellLine::try_parse_from(["init"]).is_err());
+    }
+}
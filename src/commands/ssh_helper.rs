@@ -0,0 +1,59 @@
+use crate::{config::Config, db::SecretFilter, result::{Error, Result}, selection::FailFastSelector, totp_store::TotpStore};
+
+/// Words which, if any of them appears in an OpenSSH keyboard-interactive prompt,
+/// indicate that the server is asking for a one-time code rather than e.g. a password.
+const CODE_PROMPT_WORDS: &[&str] = &["verification", "one-time", "otp", "token", "authenticator", "code"];
+
+/// Prints the code for the matching secret, and nothing else, to stdout, for consumption by ssh.
+/// If the prompt ssh passed to its askpass program is given, nothing is printed unless it asks for a code,
+/// so that the code is never sent in response to e.g. a password prompt.
+/// Since there's nobody to ask, an error is returned if several secrets match.
+/// If check is true, no code is generated; instead the command succeeds iff exactly one secret matches,
+/// for use with Match exec.
+pub fn run(config: Config, service: &str, account: Option<&str>, prompt: Option<&str>, check: bool) -> Result<()> {
+    if check {
+        let filter = SecretFilter { service, account: account.unwrap_or(""), ..Default::default() };
+        let secrets = if config.encrypt_metadata {
+            super::open_store(config)?.find(&filter)?
+        } else {
+            TotpStore::without_tpm(config)?.find(&filter)?
+        };
+        return match secrets.len() {
+            0 => Err(Error::SecretNotFound),
+            1 => Ok(()),
+            _ => Err(Error::AmbiguousSecret),
+        }
+    }
+
+    if let Some(prompt) = prompt {
+        if !is_code_prompt(prompt) {
+            return Err(Error::UnexpectedPrompt(prompt.to_owned()));
+        }
+    }
+    let mut totp_store = super::open_store(config)?;
+    super::gen::run_with_store(&mut totp_store, &mut FailFastSelector, service, account, None, None, None)
+}
+
+fn is_code_prompt(prompt: &str) -> bool {
+    let prompt = prompt.to_lowercase();
+    prompt.split(|c: char| !c.is_alphanumeric() && c != '-')
+        .any(|word| CODE_PROMPT_WORDS.contains(&word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_code_prompt_recognizes_common_totp_prompts() {
+        assert!(is_code_prompt("Verification code: "));
+        assert!(is_code_prompt("(alice@example.com) Verification code: "));
+        assert!(is_code_prompt("One-time password (OATH) for `alice': "));
+        assert!(is_code_prompt("Enter your authenticator code:"));
+        assert!(is_code_prompt("OTP: "));
+        assert!(!is_code_prompt("Password: "));
+        assert!(!is_code_prompt("(alice@example.com) Password: "));
+        assert!(!is_code_prompt("Are you sure you want to continue connecting (yes/no/[fingerprint])? "));
+        assert!(!is_code_prompt("Enter passphrase for key '/home/alice/.ssh/id_ed25519': "));
+    }
+}
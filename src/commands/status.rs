@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use crate::{config::Config, db, privileges::PrivilegeDropGuard, result::Result, totp_store::read_primary_key_persistent_handle, units::ByteSize};
+
+/// Prints an overview of the totp store: whether it's initialized, which TPM and primary key it uses,
+/// how presence is verified, and how many secrets it holds. Neither the TPM nor presence verification is needed.
+pub fn run(config_path: &Path, config: Config) -> Result<()> {
+    let initialized = config.auth_value_path().is_file() && config.primary_key_handle_path().is_file();
+    let handle = read_primary_key_persistent_handle(&config).ok();
+    PrivilegeDropGuard::new()?.forever()?;
+
+    println!("config: {}", config_path.to_str().unwrap());
+    println!("initialized: {}", if initialized { "yes" } else { "no; run totpm init" });
+    println!("tpm: {}", config.tpm);
+    match handle {
+        Some(handle) => println!("primary key handle: {:#010x}", handle),
+        None => println!("primary key handle: none"),
+    }
+    println!("presence verification: {}", pv_method_name(&config));
+
+    let db_path = config.secrets_db_path();
+    match std::fs::metadata(&db_path) {
+        Ok(metadata) => {
+            let count = db::with_db_locking(&db_path, config.db_locking, |db| Ok(db.list_secrets("", "")?.len()))?;
+            println!("secrets: {}", count);
+            println!("database: {} ({})", db_path.to_str().unwrap(), ByteSize::from_bytes(metadata.len()));
+        },
+        Err(_) => {
+            println!("secrets: 0");
+            println!("database: {} (not created yet)", db_path.to_str().unwrap());
+        },
+    }
+    Ok(())
+}
+
+fn pv_method_name(config: &Config) -> String {
+    let method = config.pv_method.methods().iter()
+        .map(|method| format!("{:?}", method).to_lowercase())
+        .collect::<Vec<_>>()
+        .join(", falling back on ");
+    match &config.pv_policy {
+        Some(_) => format!("{}, enforced by the tpm", method),
+        None => method,
+    }
+}
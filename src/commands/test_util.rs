@@ -0,0 +1,19 @@
+use tempfile::{tempdir, TempDir};
+use testutil::tpm::SwTpm;
+
+use crate::{config::Config, presence_verification::PresenceVerificationMethod};
+
+/// Starts a software TPM and returns it together with a local config using it,
+/// which keeps its data in the returned temporary directory.
+pub fn setup() -> (SwTpm, TempDir, Config) {
+    let tpm = SwTpm::new();
+    let dir = tempdir().unwrap();
+    let cfg = Config::default(
+        true,
+        tpm.tcti.clone(),
+        Some(dir.path().join("sys")),
+        Some(dir.path().join("user")),
+        Some(PresenceVerificationMethod::None)
+    );
+    (tpm, dir, cfg)
+}
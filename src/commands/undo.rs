@@ -0,0 +1,17 @@
+use crate::{config::Config, db::Mutation, result::{Error, Result}, totp_store::{TotpStore, WithTPM}};
+
+/// Undoing a deletion makes a secret usable again, so the same presence verification as for add is required.
+pub fn run(config: Config) -> Result<()> {
+    let mut store = super::open_store(config)?;
+    run_with_store(&mut store)
+}
+
+pub fn run_with_store(store: &mut TotpStore<WithTPM>) -> Result<()> {
+    match store.undo()? {
+        Some((Mutation::Add(_), secret)) => println!("removed newly added secret {}", secret),
+        Some((Mutation::Del(_), secret)) => println!("restored deleted secret {}", secret),
+        Some((Mutation::Update { .. }, secret)) => println!("restored previous version of secret {}", secret),
+        None => return Err(Error::NothingToUndo),
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::time::{Duration, SystemTime};
+
+use crate::{config::Config, db::{model::Secret, SecretFilter}, result::{Error, Result}, selection::create_selector, totp_store::{TotpStore, WithTPM}};
+
+/// Checks the given code against the matching secret, accepting codes for up to window time steps
+/// before or after the current one. Fails with InvalidCode if the code doesn't match.
+pub fn run(
+    config: Config,
+    service: &str,
+    account: Option<&str>,
+    tag: Option<&str>,
+    code: &str,
+    window: u32,
+) -> Result<()> {
+    let mut selector = create_selector(config.gen_selection.unwrap_or(config.selection));
+    let mut store = super::open_store(config.clone())?;
+    let alternatives = store.find(&SecretFilter { service, account: account.unwrap_or(""), tag, ..Default::default() })?;
+    if alternatives.is_empty() {
+        return Err(Error::SecretNotFound)
+    }
+    let secret = selector.select("found multiple matches for the given service/account combination", &alternatives)?
+        .ok_or(Error::AmbiguousSecret)?;
+    super::warn_if_clock_unreliable(&config, secret);
+    match matching_step(&mut store, secret, code, window, SystemTime::now())? {
+        Some(0) => println!("valid"),
+        Some(step) if step < 0 => println!("valid, but {} time step(s) old", -step),
+        Some(step) => println!("valid, but {} time step(s) ahead", step),
+        None => return Err(Error::InvalidCode),
+    }
+    Ok(())
+}
+
+/// Returns the offset in time steps from now of the step whose code is the given one,
+/// looking at most window steps back and ahead, nearest steps first.
+/// The secret is only recorded as used if the code matches.
+fn matching_step(
+    store: &mut TotpStore<WithTPM>,
+    secret: &Secret,
+    code: &str,
+    window: u32,
+    now: SystemTime,
+) -> Result<Option<i64>> {
+    let pin = match secret.has_pin {
+        true => Some(super::read_pin(&format!("Enter PIN for {}: ", secret))?),
+        false => None,
+    };
+    let interval = Duration::from_secs(secret.interval as u64);
+    for distance in 0..=window {
+        for step in [-(distance as i64), distance as i64] {
+            let time = match step < 0 {
+                true => now.checked_sub(interval * distance),
+                false => now.checked_add(interval * distance),
+            };
+            let Some(time) = time else { continue };
+            if store.peek_with_pin(secret.id, time, pin.as_deref())? == code {
+                store.record_use(secret.id, now)?;
+                return Ok(Some(step))
+            }
+            if distance == 0 {
+                break
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commands::test_util::setup;
+
+    use super::*;
+
+    #[test]
+    fn matching_step_finds_codes_within_window() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg).unwrap();
+        store.add("foo", "bar", None, None, b"12345678901234567890").unwrap();
+        let secret = store.list(Some("foo"), Some("bar")).unwrap().remove(0);
+
+        // RFC 6238 test vectors, truncated to 6 digits, for the steps containing 59 and 1111111109
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1111111109);
+        assert_eq!(matching_step(&mut store, &secret, "081804", 0, now).unwrap(), Some(0));
+        assert_eq!(matching_step(&mut store, &secret, "081804", 1, now + Duration::from_secs(30)).unwrap(), Some(-1));
+        assert_eq!(matching_step(&mut store, &secret, "081804", 1, now - Duration::from_secs(30)).unwrap(), Some(1));
+        assert_eq!(matching_step(&mut store, &secret, "081804", 1, now + Duration::from_secs(60)).unwrap(), None);
+        assert_eq!(matching_step(&mut store, &secret, "287082", 1, now).unwrap(), None);
+    }
+
+    #[test]
+    fn matching_step_records_use_only_on_match() {
+        let (_tpm, _dir, cfg) = setup();
+        TotpStore::init(cfg.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(cfg).unwrap();
+        store.add("foo", "bar", None, None, b"12345678901234567890").unwrap();
+        let secret = store.list(Some("foo"), Some("bar")).unwrap().remove(0);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1111111109);
+
+        assert_eq!(matching_step(&mut store, &secret, "287082", 2, now).unwrap(), None);
+        assert_eq!(store.list(Some("foo"), Some("bar")).unwrap()[0].use_count, 0);
+        assert_eq!(matching_step(&mut store, &secret, "081804", 2, now + Duration::from_secs(60)).unwrap(), Some(-2));
+        let secret = store.list(Some("foo"), Some("bar")).unwrap().remove(0);
+        assert_eq!(secret.use_count, 1);
+        assert_eq!(secret.last_used_at, Some(1111111169));
+    }
+}
@@ -0,0 +1,27 @@
+use crate::{config::Config, result::{Error, Result}, totp_store::{Problem, TotpStore}};
+
+/// Checks the secrets database for corruption and malformed keys.
+/// If load_keys is true, also test-loads each key into the TPM, which requires presence verification.
+pub fn run(config: Config, load_keys: bool) -> Result<()> {
+    let problems = if load_keys {
+        let mut store = super::open_store(config)?;
+        let mut problems = store.verify()?;
+        problems.extend(store.verify_keys()?);
+        problems
+    } else {
+        TotpStore::without_tpm(config)?.verify()?
+    };
+    report(&problems)
+}
+
+fn report(problems: &[Problem]) -> Result<()> {
+    for problem in problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("no problems found");
+        Ok(())
+    } else {
+        Err(Error::StoreVerificationFailed(problems.len()))
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+
+use serde_derive::Serialize;
+
+use crate::{config::Config, db::CURRENT_SCHEMA_VERSION, presence_verification::ConstPresenceVerifier, result::Result, tpm::TPM};
+
+use super::bugreport::enabled_features;
+
+/// Version and capabilities of this totpm binary, for tooling that needs to gate behavior on them.
+#[derive(Serialize, Debug)]
+struct VersionInfo {
+    version: &'static str,
+    schema_version: u32,
+    import_formats: Vec<&'static str>,
+    export_formats: Vec<&'static str>,
+    features: Vec<&'static str>,
+    /// TPM specification revision reported by the configured TPM, if it could be queried.
+    tpm_spec_revision: Option<String>,
+}
+
+/// Prints the version and capabilities of totpm, either as text or as a single JSON object.
+pub fn run(config: Result<Config>, json: bool) -> Result<()> {
+    let info = VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        schema_version: CURRENT_SCHEMA_VERSION,
+        import_formats: import_formats(),
+        export_formats: vec!["backup", "migration-bundle", "wrapped-keys"],
+        features: enabled_features(),
+        tpm_spec_revision: config.ok().and_then(|config| tpm_spec_revision(&config)),
+    };
+    let mut stdout = io::stdout();
+    if json {
+        serde_json::to_writer(&mut stdout, &info).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+    } else {
+        writeln!(stdout, "totpm {}", info.version)?;
+        writeln!(stdout, "schema version: {}", info.schema_version)?;
+        writeln!(stdout, "import formats: {}", info.import_formats.join(", "))?;
+        writeln!(stdout, "export formats: {}", info.export_formats.join(", "))?;
+        writeln!(stdout, "features: {}", info.features.join(", "))?;
+        writeln!(stdout, "tpm spec revision: {}", info.tpm_spec_revision.as_deref().unwrap_or("unknown"))?;
+    }
+    Ok(())
+}
+
+/// Returns the formats secrets can be brought into totpm from.
+fn import_formats() -> Vec<&'static str> {
+    let mut formats = vec!["backup", "migration-bundle", "wrapped-keys"];
+    if cfg!(feature = "import") {
+        formats.extend(["totpm-json", "otpauth-uri-list"]);
+    }
+    formats
+}
+
+fn tpm_spec_revision(config: &Config) -> Option<String> {
+    let pv = Box::new(ConstPresenceVerifier::new(true));
+    match TPM::new(pv, &config.tpm).and_then(|mut tpm| tpm.info()) {
+        Ok(info) => Some(info.spec_revision),
+        Err(e) => {
+            log::info!("unable to query tpm: {:?}", e);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_info_serializes_to_json() {
+        let info = VersionInfo {
+            version: "1.2.3",
+            schema_version: 10,
+            import_formats: vec!["backup"],
+            export_formats: vec!["backup"],
+            features: vec![],
+            tpm_spec_revision: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&info).unwrap(),
+            "{\"version\":\"1.2.3\",\"schema_version\":10,\"import_formats\":[\"backup\"],\"export_formats\":[\"backup\"],\
+            \"features\":[],\"tpm_spec_revision\":null}",
+        );
+    }
+}
@@ -1,10 +1,20 @@
-use std::{env, path::Path};
+use std::{collections::BTreeMap, env, fs::Metadata, io, os::unix::fs::MetadataExt, path::Path, str::FromStr};
 #[allow(deprecated)]
 use std::{env::home_dir, path::PathBuf};
 
 use serde_derive::{Deserialize, Serialize};
 
-use crate::presence_verification::PresenceVerificationMethod;
+use crate::{autotype::AutoTypeMethod, db::locking::Locking, presence_verification::{signer::PolicyConfig, PresenceVerificationMethod, PresenceVerificationMethods, Prompts}, privileges::EuidSwapGuard, result::{Error, Result}, selection::SelectionMethod, tpm::{PersistentHandles, PrimaryKeyTemplate}, units::HumanDuration};
+
+/// Path of a user's overlay on top of the system config, relative to their home directory.
+pub const OVERLAY_PATH: &str = ".config/totpm-overlay.conf";
+
+/// Prefix of the environment variables overriding config keys; see env_overrides.
+pub const ENV_PREFIX: &str = "TOTPM_";
+
+/// Keys of the system config which users may override in their overlay. The others decide how secrets are protected
+/// and where they're kept, so they're up to whoever owns the system config.
+pub const OVERLAY_KEYS: [&str; 6] = ["pv_timeout", "pv_prompts", "selection", "gen_selection", "auto_type", "ntp_server"];
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
@@ -16,14 +26,171 @@ pub struct Config {
     // Must be interpreted relative to $HOME if relative.
     pub user_data_path: PathBuf,
 
-    /// Max number of seconds to wait for presence verification.
-    pub pv_timeout: u8,
+    /// Max time to wait for presence verification, e.g. "10s".
+    #[serde(default = "default_pv_timeout")]
+    pub pv_timeout: HumanDuration,
 
-    /// Method to use for presence verification.
+    /// Method to use for presence verification, or a list of methods to try in order,
+    /// falling back on the next one when a method fails or times out.
     /// Valid values are:
     /// - fprintd: ask for the user's fingerprint by calling fprintd over dbus
+    /// - pin: ask for the PIN set by init
     /// - none: don't verify user presence; only recommended for local installs
-    pub pv_method: PresenceVerificationMethod,
+    pub pv_method: PresenceVerificationMethods,
+
+    /// User whose fingerprints fprintd verifies. Defaults to the user fprintd sees as calling it, which
+    /// may be the totpm user rather than the user who ran totpm, depending on how totpm gets its privileges.
+    /// Claiming the reader for another user requires the net.reactivated.fprint.device.setusername polkit action.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fprintd_user: Option<String>,
+
+    /// Messages shown to the user during presence verification, e.g. to reword them.
+    /// Unless set, they're shown in the user's language.
+    #[serde(default, skip_serializing_if = "Prompts::is_default")]
+    pub pv_prompts: Prompts,
+
+    /// If given as a [pv_policy] section, the keys of secrets added from then on carry a TPM policy which requires
+    /// every code to be authorized by an external presence verifier, so that even a modified totpm can't skip
+    /// presence verification. The section has the keys:
+    /// - verifier_key: file containing the hex-encoded, marshalled TPM public area of the verifier's signing key
+    /// - signer: command which verifies presence and signs the hex-encoded digest on its standard input,
+    ///   printing the hex-encoded, marshalled signature
+    ///
+    /// Keys carrying the policy are never migratable, regardless of migratable_keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pv_policy: Option<PolicyConfig>,
+
+    /// Which operations require presence verification, given as a [pv.require] section with the boolean keys
+    /// add, gen, list and del. If true, presence is verified even if the operation doesn't use the TPM;
+    /// if false, it's not verified even if the operation does. Operations left out verify presence
+    /// whenever they use the TPM.
+    #[serde(default)]
+    pub pv: PvConfig,
+
+    /// If true, service names, account names, notes, urls and icons are encrypted in the secrets database,
+    /// using a key wrapped under the TPM primary key. Tags are not encrypted.
+    /// Secrets added before this was turned on are encrypted the next time the TPM is used.
+    /// Note that listing and deleting secrets then requires the TPM, and thus presence verification.
+    #[serde(default)]
+    pub encrypt_metadata: bool,
+
+    /// How to choose between several secrets matching the same service and account.
+    /// Valid values are:
+    /// - interactive: pick from a numbered list
+    /// - filter: narrow down the list by typing parts of the service and account names
+    /// - mru: pick the most recently used secret
+    /// - fail_fast: fail with an error; useful for scripts
+    #[serde(default)]
+    pub selection: SelectionMethod,
+
+    /// Selection method to use for gen, if different from selection.
+    /// Setting this to mru makes gen pick the most recently used of several matching secrets,
+    /// while still asking before deleting or replacing anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gen_selection: Option<SelectionMethod>,
+
+    /// Tool used by gen --type to type codes into the focused window.
+    /// Valid values are:
+    /// - auto: wtype or ydotool under Wayland, xdotool under X11, whichever is installed
+    /// - wtype, ydotool or xdotool: always use that tool
+    #[serde(default)]
+    pub auto_type: AutoTypeMethod,
+
+    /// NTP server to compare the system clock against before generating a code, e.g. pool.ntp.org.
+    /// If the clock is off by more than half the secret's interval, a warning is printed, since the code
+    /// will then likely be rejected. The server is not queried unless this is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ntp_server: Option<String>,
+
+    /// If true, secrets are added with keys that can later be moved to another machine's TPM
+    /// using the migrate command. Otherwise, keys are bound to this TPM for good.
+    /// Secrets added before this was turned on can't be migrated.
+    #[serde(default)]
+    pub migratable_keys: bool,
+
+    /// PCRs of the SHA-256 bank that the primary key is bound to, e.g. [7] for the secure boot state.
+    /// Codes can then only be generated while these PCRs have the values they had when the primary key was created.
+    /// Set using init --pcrs; changing this afterwards makes the primary key unusable.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pcrs: Vec<u8>,
+
+    /// Template of the primary key to create on init or rekey, given as a [primary_key] section with the keys:
+    /// - algorithm: aes (default) for an AES symmetric key, ecc for an ECC P-256 storage key as recommended by the TCG,
+    ///   or rsa for an RSA-2048 storage key
+    /// - name_hash: sha256 (default), sha384 or sha512
+    /// - symmetric: aes128_cfb or aes256_cfb; defaults to aes256_cfb for aes keys and aes128_cfb otherwise
+    /// - no_da: if true, exempts the primary key from the TPM's dictionary attack protection
+    ///
+    /// Changing this does not affect an existing primary key.
+    #[serde(default)]
+    pub primary_key: PrimaryKeyTemplate,
+
+    /// Persistent handle to store the primary key at on init or rekey, e.g. 0x81000100,
+    /// or an inclusive range of handles to use the first free one of, e.g. { first = 0x81010000, last = 0x810100ff }.
+    /// Defaults to the first free handle in 0x81000000-0x8100ffff.
+    /// The chosen handle is recorded in the system data directory. Rekeying needs a range of at least two handles,
+    /// since the new primary key is created before the old one is deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistent_handle: Option<PersistentHandles>,
+
+    /// How to keep concurrent totpm processes from corrupting the secrets database.
+    /// Valid values are:
+    /// - auto: use a lock file if the database is on NFS or a FUSE file system, and sqlite's locking otherwise
+    /// - file: always use a lock file next to the database
+    /// - sqlite: always use sqlite's locking, which is unreliable on NFS and FUSE
+    #[serde(default)]
+    pub db_locking: Locking,
+
+    /// If true, service and account names are stored in Unicode normalization form C, so that e.g. an 'é' typed
+    /// as 'e' followed by a combining accent is stored as one character. Names are matched regardless of
+    /// how they were typed either way.
+    #[serde(default)]
+    pub normalize_names: bool,
+
+    /// Migration target file written by 'totpm migrate target' on a backup machine.
+    /// If set, the key of each secret added or replaced from then on is also duplicated to that machine's TPM,
+    /// so that it can be recovered there with 'restore --from-secondary' if this machine's TPM dies.
+    /// Secrets with encrypted metadata can't be recovered this way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secondary_target: Option<PathBuf>,
+
+    /// Named stores, selected using --profile, e.g. to keep work and personal secrets separate.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Presence verification settings given as a [pv] section.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct PvConfig {
+    pub require: PvRequirements,
+}
+
+/// Whether each operation requires presence verification; None means only when it uses the TPM.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PvRequirements {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gen: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub del: Option<bool>,
+}
+
+/// A separate secrets store, overriding where secrets and optionally the primary key are kept.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Profile {
+    // Must be interpreted relative to $HOME if relative.
+    pub user_data_path: PathBuf,
+
+    /// Where to keep this profile's primary key handle and auth value, if it should have its own primary key.
+    /// The profile's primary key is created by running init with --profile.
+    /// Should always be absolute.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_data_path: Option<PathBuf>,
 }
 
 impl Config {
@@ -45,36 +212,169 @@ impl Config {
                 }
             ),
             user_data_path: user_data_path.unwrap_or(PathBuf::from(".local/state/totpm")),
-            pv_timeout: 10,
+            pv_timeout: default_pv_timeout(),
             pv_method: presence_verification.unwrap_or(
                 if local {
                     PresenceVerificationMethod::None
                 } else {
                     PresenceVerificationMethod::Fprintd
                 }                
-            )
+            ).into(),
+            fprintd_user: None,
+            pv_prompts: Prompts::default(),
+            pv_policy: None,
+            pv: PvConfig::default(),
+            encrypt_metadata: false,
+            selection: SelectionMethod::default(),
+            gen_selection: None,
+            auto_type: AutoTypeMethod::default(),
+            ntp_server: None,
+            migratable_keys: false,
+            pcrs: Vec::new(),
+            primary_key: PrimaryKeyTemplate::default(),
+            persistent_handle: None,
+            db_locking: Locking::default(),
+            normalize_names: false,
+            secondary_target: None,
+            profiles: BTreeMap::new(),
+        }
+    }
+
+    /// Returns this config with the data paths of the given profile.
+    pub fn with_profile(mut self, name: &str) -> Result<Self> {
+        let profile = self.profiles.get(name).ok_or(Error::ProfileNotFound(name.to_owned()))?;
+        self.user_data_path = profile.user_data_path.clone();
+        if let Some(system_data_path) = &profile.system_data_path {
+            self.system_data_path = system_data_path.clone();
         }
+        Ok(self)
+    }
+
+    /// Returns true if both configs refer to the same TPM and primary key.
+    pub fn same_primary_key(&self, other: &Config) -> bool {
+        self.tpm == other.tpm && self.primary_key_handle_path() == other.primary_key_handle_path()
     }
 
     pub fn auth_value_path(&self) -> PathBuf {
         self.system_data_path.join("auth_value")
     }
 
+    pub fn pin_hash_path(&self) -> PathBuf {
+        self.system_data_path.join("pin_hash")
+    }
+
+    pub fn pin_failures_path(&self) -> PathBuf {
+        self.system_data_path.join("pin_failures")
+    }
+
     pub fn primary_key_handle_path(&self) -> PathBuf {
         self.system_data_path.join("primary_key_handle")
     }
 
+    /// Returns the path to the migration approver, whose approval migratable keys need to be duplicated to another TPM.
+    pub fn migration_approver_path(&self) -> PathBuf {
+        self.system_data_path.join("migration_approver")
+    }
+
     pub fn secrets_db_path(&self) -> PathBuf {
+        #[allow(deprecated)]
+        self.secrets_db_path_in(&home_dir().unwrap())
+    }
+
+    /// Returns the path to the migration target created by migrate target, next to the secrets database.
+    pub fn migration_target_path(&self) -> PathBuf {
+        self.secrets_db_path().with_file_name("migration_target")
+    }
+
+    /// Returns the path to the migration approvers of other machines that secrets were imported from,
+    /// next to the secrets database.
+    pub fn migration_approvers_path(&self) -> PathBuf {
+        self.secrets_db_path().with_file_name("migration_approvers")
+    }
+
+    /// Returns the path to the secrets database of the user with the given home directory.
+    pub fn secrets_db_path_in(&self, home_dir: &Path) -> PathBuf {
         let secrets_db_file = "secrets.sqlite";
-        if self.user_data_path.is_absolute() {       
+        if self.user_data_path.is_absolute() {
             self.user_data_path.join(secrets_db_file)
         } else {
-            #[allow(deprecated)]
-            home_dir().unwrap().join(&self.user_data_path).join(secrets_db_file)
+            home_dir.join(&self.user_data_path).join(secrets_db_file)
         }
     }
 }
 
+/// Makes sure that the config file at the given path can't be changed by anyone but root or the given user,
+/// since a config file pointing system_data_path elsewhere would otherwise let any user take over the privileges
+/// of a setuid totpm. Group write access is only accepted from root's group.
+pub fn check_permissions(path: &Path, metadata: &Metadata, trusted_uid: u32) -> Result<()> {
+    let owner_is_trusted = metadata.uid() == 0 || metadata.uid() == trusted_uid;
+    let group_writable = metadata.mode() & 0o020 != 0 && metadata.gid() != 0;
+    let world_writable = metadata.mode() & 0o002 != 0;
+    if !owner_is_trusted || group_writable || world_writable {
+        return Err(Error::InsecureConfig(path.to_owned()))
+    }
+    Ok(())
+}
+
+/// Reads the calling user's overlay on top of the system config, if they have one.
+pub fn read_overlay() -> Result<Option<toml::Table>> {
+    let path = local_path(Path::new(OVERLAY_PATH));
+    let overlay_str = {
+        let _euid = EuidSwapGuard::real_user()?;
+        match std::fs::read_to_string(&path) {
+            Ok(overlay_str) => overlay_str,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+    };
+    Ok(Some(toml::Table::from_str(&overlay_str)?))
+}
+
+/// Merges the given overlay into the given config values. Sections, such as pv_prompts, are merged key by key.
+/// Keys users may not override are left out with a warning.
+pub fn apply_overlay(values: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        if !OVERLAY_KEYS.contains(&key.as_str()) {
+            log::warn!("ignoring {} in ~/{}; it can only be set in the system config", key, OVERLAY_PATH);
+            continue;
+        }
+        if let (Some(toml::Value::Table(section)), toml::Value::Table(overrides)) = (values.get_mut(&key), &value) {
+            section.extend(overrides.clone());
+            continue;
+        }
+        values.insert(key, value);
+    }
+}
+
+/// Returns the name of the environment variable overriding the given config key, e.g. TOTPM_PV_TIMEOUT for pv_timeout.
+pub fn env_var_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+/// Reads the overrides of config keys given in the environment. Like the overlay, the environment belongs to the user,
+/// so only the keys users may override in their overlay are read. Values are parsed as TOML, or taken as strings
+/// if they aren't valid TOML, so that e.g. both TOTPM_PV_TIMEOUT=30s and TOTPM_PV_TIMEOUT='"30s"' work.
+pub fn env_overrides() -> toml::Table {
+    env_overrides_from(|name| env::var(name).ok())
+}
+
+fn env_overrides_from(var: impl Fn(&str) -> Option<String>) -> toml::Table {
+    OVERLAY_KEYS.iter()
+        .filter_map(|key| {
+            let value = var(&env_var_name(key))?;
+            let value = toml::Table::from_str(&format!("value = {}", value))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or(toml::Value::String(value));
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+fn default_pv_timeout() -> HumanDuration {
+    HumanDuration::from_secs(10)
+}
+
 /// Makes the given path relative to the user's home directory.
 pub fn local_path(file: &Path) -> PathBuf {
     assert!(file.is_relative());
@@ -107,7 +407,23 @@ mod tests {
         assert!(cfg.auth_value_path().starts_with(&home_dir));
         assert!(cfg.primary_key_handle_path().starts_with(&home_dir));
         assert!(cfg.secrets_db_path().starts_with(&home_dir));
-        assert_eq!(cfg.pv_method, PresenceVerificationMethod::None);
+        assert_eq!(cfg.pv_method, PresenceVerificationMethod::None.into());
+    }
+
+    #[test]
+    fn same_primary_key_compares_tpm_and_system_data_path() {
+        let cfg = Config::default(false, "device".to_string(), None, None, None);
+        let mut other = Config::default(true, "device".to_string(), Some(PathBuf::from("/var/lib/totpm")), None, None);
+        assert!(cfg.same_primary_key(&other));
+
+        other.pv_timeout = HumanDuration::from_secs(30);
+        assert!(cfg.same_primary_key(&other));
+
+        other.tpm = "swtpm:host=127.0.0.1,port=2321".to_string();
+        assert!(!cfg.same_primary_key(&other));
+
+        let other = Config::default(true, "device".to_string(), None, None, None);
+        assert!(!cfg.same_primary_key(&other));
     }
 
     #[test]
@@ -121,6 +437,166 @@ mod tests {
         assert!(cfg.auth_value_path().starts_with("/var/lib"));
         assert!(cfg.primary_key_handle_path().starts_with("/var/lib"));
         assert!(cfg.secrets_db_path().starts_with(&home_dir));
-        assert_eq!(cfg.pv_method, PresenceVerificationMethod::Fprintd);
+        assert_eq!(cfg.pv_method, PresenceVerificationMethod::Fprintd.into());
+    }
+
+    #[test]
+    fn with_profile_overrides_data_paths() {
+        let cfg: Config = toml::from_str("
+            tpm = \"device\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+
+            [profiles.work]
+            user_data_path = \".local/state/totpm-work\"
+            system_data_path = \"/var/lib/totpm-work\"
+
+            [profiles.personal]
+            user_data_path = \".local/state/totpm-personal\"
+        ").unwrap();
+
+        let work = cfg.clone().with_profile("work").unwrap();
+        assert_eq!(work.user_data_path, PathBuf::from(".local/state/totpm-work"));
+        assert_eq!(work.system_data_path, PathBuf::from("/var/lib/totpm-work"));
+        assert!(!work.same_primary_key(&cfg));
+
+        let personal = cfg.clone().with_profile("personal").unwrap();
+        assert_eq!(personal.user_data_path, PathBuf::from(".local/state/totpm-personal"));
+        assert!(personal.same_primary_key(&cfg));
+
+        assert!(cfg.with_profile("play").is_err());
+    }
+
+    #[test]
+    fn pv_requirements_default_to_unset() {
+        let cfg: Config = toml::from_str("
+            tpm = \"device\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+
+            [pv.require]
+            gen = true
+            list = false
+        ").unwrap();
+        assert_eq!(cfg.pv.require, PvRequirements { gen: Some(true), list: Some(false), ..Default::default() });
+
+        let cfg = Config::default(false, "device".to_string(), None, None, None);
+        assert_eq!(cfg.pv.require, PvRequirements::default());
+        assert!(toml::from_str::<PvConfig>("[require]\nexport = true").is_err());
+    }
+
+    #[test]
+    fn unset_prompts_keep_their_defaults() {
+        let cfg: Config = toml::from_str("
+            tpm = \"device\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+
+            [pv_prompts]
+            no_match = \"Fingerabdruck nicht erkannt\"
+        ").unwrap();
+        assert_eq!(cfg.pv_prompts.no_match, "Fingerabdruck nicht erkannt");
+        assert_eq!(cfg.pv_prompts.place_finger, Prompts::default().place_finger);
+    }
+
+    #[test]
+    fn overlay_overrides_only_allowed_keys() {
+        let mut values = toml::Table::from_str("
+            tpm = \"device\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+            pv_timeout = \"10s\"
+
+            [pv_prompts]
+            no_match = \"Fingerabdruck nicht erkannt\"
+        ").unwrap();
+        let overlay = toml::Table::from_str("
+            tpm = \"swtpm:port=2321\"
+            pv_method = \"none\"
+            pv_timeout = \"30s\"
+
+            [pv_prompts]
+            retry = \"Nochmal\"
+        ").unwrap();
+
+        apply_overlay(&mut values, overlay);
+        let cfg: Config = toml::Value::Table(values).try_into().unwrap();
+        assert_eq!(cfg.tpm, "device");
+        assert!(cfg.pv_method.uses(PresenceVerificationMethod::Fprintd));
+        assert_eq!(cfg.pv_timeout, HumanDuration::from_secs(30));
+        assert_eq!(cfg.pv_prompts.no_match, "Fingerabdruck nicht erkannt");
+        assert_eq!(cfg.pv_prompts.retry, "Nochmal");
+    }
+
+    #[test]
+    fn env_overrides_reads_only_overlay_keys() {
+        let vars = BTreeMap::from([
+            ("TOTPM_PV_TIMEOUT", "30s"),
+            ("TOTPM_SELECTION", "\"mru\""),
+            ("TOTPM_PV_PROMPTS", "{ retry = \"Nochmal\" }"),
+            ("TOTPM_TPM", "swtpm:port=2321"),
+        ]);
+        let overrides = env_overrides_from(|name| vars.get(name).map(|value| value.to_string()));
+        assert_eq!(overrides.len(), 3);
+        assert_eq!(overrides["pv_timeout"], toml::Value::String("30s".to_owned()));
+        assert_eq!(overrides["selection"], toml::Value::String("mru".to_owned()));
+        assert_eq!(overrides["pv_prompts"]["retry"], toml::Value::String("Nochmal".to_owned()));
+    }
+
+    #[test]
+    fn check_permissions_refuses_writable_or_untrusted_config() {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let uid = crate::privileges::real_user_id();
+        let check = |mode| {
+            std::fs::set_permissions(file.path(), Permissions::from_mode(mode)).unwrap();
+            check_permissions(file.path(), &std::fs::metadata(file.path()).unwrap(), uid)
+        };
+        check(0o644).unwrap();
+        assert!(matches!(check(0o646), Err(Error::InsecureConfig(_))));
+        if uid != 0 {
+            let metadata = std::fs::metadata(file.path()).unwrap();
+            assert!(matches!(check_permissions(file.path(), &metadata, uid + 1), Err(Error::InsecureConfig(_))));
+        }
+    }
+
+    #[test]
+    fn primary_key_template_defaults_unset_keys() {
+        let cfg: Config = toml::from_str("
+            tpm = \"device\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+
+            [primary_key]
+            algorithm = \"ecc\"
+            no_da = true
+        ").unwrap();
+        assert_eq!(cfg.primary_key, PrimaryKeyTemplate {
+            algorithm: crate::tpm::PrimaryKeyAlgorithm::Ecc,
+            no_da: true,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn persistent_handle_accepts_single_handle_or_range() {
+        let config = |handle: &str| toml::from_str::<Config>(&format!("
+            tpm = \"device\"
+            system_data_path = \"/var/lib/totpm\"
+            user_data_path = \".local/state/totpm\"
+            pv_method = \"fprintd\"
+            persistent_handle = {}
+        ", handle)).unwrap();
+        assert_eq!(config("0x81000100").persistent_handle, Some(PersistentHandles::Fixed(0x81000100)));
+        assert_eq!(
+            config("{ first = 0x81010000, last = 0x810100ff }").persistent_handle,
+            Some(PersistentHandles::Range { first: 0x81010000, last: 0x810100ff }),
+        );
     }
 }
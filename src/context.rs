@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use serde_derive::Deserialize;
+
+use crate::result::{Error, Result};
+
+/// Name of the file specifying the secret to use within a directory and its subdirectories.
+pub const CONTEXT_FILE: &str = ".totpm";
+
+/// A directory context, e.g. specifying the second factor for a project repository.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Context {
+    pub service: String,
+    pub account: Option<String>,
+    pub tag: Option<String>,
+}
+
+/// Returns the context from the context file closest to the given directory, walking up towards the root.
+/// Returns None if no context file was found.
+pub fn find(dir: &Path) -> Result<Option<Context>> {
+    for ancestor in dir.ancestors() {
+        let path = ancestor.join(CONTEXT_FILE);
+        if path.is_file() {
+            log::info!("reading context from {}", path.to_str().unwrap());
+            let context_str = std::fs::read_to_string(&path)?;
+            let context = toml::from_str(&context_str)
+                .map_err(|e| Error::InvalidContextFile(path, e))?;
+            return Ok(Some(context));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn find_uses_closest_context_file() {
+        let dir = tempdir().unwrap();
+        let nested = dir.path().join("a").join("b");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find(&nested).unwrap(), None);
+
+        std::fs::write(dir.path().join(CONTEXT_FILE), "service = \"aws-prod\"\n").unwrap();
+        assert_eq!(find(&nested).unwrap(), Some(Context {
+            service: "aws-prod".to_owned(),
+            account: None,
+            tag: None,
+        }));
+
+        std::fs::write(dir.path().join("a").join(CONTEXT_FILE), "service = \"aws-dev\"\naccount = \"ci\"\n").unwrap();
+        assert_eq!(find(&nested).unwrap(), Some(Context {
+            service: "aws-dev".to_owned(),
+            account: Some("ci".to_owned()),
+            tag: None,
+        }));
+    }
+
+    #[test]
+    fn find_fails_on_malformed_context_file() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join(CONTEXT_FILE), "servcie = \"aws-prod\"\n").unwrap();
+        match find(dir.path()).unwrap_err() {
+            Error::InvalidContextFile(path, _) => assert_eq!(path, dir.path().join(CONTEXT_FILE)),
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+}
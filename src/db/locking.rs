@@ -0,0 +1,216 @@
+use std::{ffi::CString, fs::{File, OpenOptions}, io::{self, Write}, os::{fd::AsRawFd, unix::{ffi::OsStrExt, fs::OpenOptionsExt}}, path::{Path, PathBuf}, sync::Once, time::{Duration, Instant, SystemTime}};
+
+use serde_derive::{Deserialize, Serialize};
+
+#[link(name = "c")]
+extern "C" {
+    fn statfs(path: *const std::ffi::c_char, buf: *mut StatFs) -> i32;
+}
+
+/// The parts of struct statfs we care about, padded to the size of the whole struct on x86-64.
+#[repr(C)]
+struct StatFs {
+    f_type: i64,
+    _rest: [i64; 15],
+}
+
+const NFS_SUPER_MAGIC: i64 = 0x6969;
+const FUSE_SUPER_MAGIC: i64 = 0x65735546;
+
+/// How long to wait for another process to release the lock file or advisory lock before giving up.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lock files older than this are assumed to be left behind by a crashed process.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(60);
+
+/// How to keep concurrent totpm processes from corrupting the secrets database.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Locking {
+    /// Use a lock file if the database is on NFS or a FUSE file system, and SQLite's own locking otherwise.
+    #[default]
+    Auto,
+    /// Serialize access using a lock file next to the database.
+    File,
+    /// Use SQLite's byte-range locks, which are unreliable on NFS and most FUSE file systems,
+    /// along with an advisory lock which keeps whole operations, such as imports, from interleaving.
+    Sqlite,
+}
+
+static WARN_ONCE: Once = Once::new();
+
+impl Locking {
+    /// Returns the strategy to use for the database at the given path, detecting its file system if set to auto.
+    pub fn resolve(self, db_path: &Path) -> Locking {
+        match self {
+            Locking::Auto if has_unreliable_locks(db_path) => {
+                WARN_ONCE.call_once(|| {
+                    eprintln!(
+                        "warning: {} is on a network or FUSE file system; using a lock file instead of sqlite's locking",
+                        db_path.to_str().unwrap(),
+                    );
+                    eprintln!("set db_locking = \"file\" in your config file to silence this warning");
+                });
+                Locking::File
+            },
+            Locking::Auto => Locking::Sqlite,
+            locking => locking,
+        }
+    }
+}
+
+/// Returns true if the file at the given path is on a file system where SQLite's byte-range locks can't be trusted.
+fn has_unreliable_locks(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false
+    };
+    let mut buf = StatFs { f_type: 0, _rest: [0; 15] };
+    if unsafe { statfs(path.as_ptr(), &mut buf) } != 0 {
+        log::warn!("statfs failed: {}", io::Error::last_os_error());
+        return false
+    }
+    log::info!("secrets database is on file system type {:#x}", buf.f_type);
+    matches!(buf.f_type, NFS_SUPER_MAGIC | FUSE_SUPER_MAGIC)
+}
+
+/// Returns the path of the lock file serializing access to the database at the given path.
+pub fn lock_file_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Returns the path of the file which is flocked to serialize access to the database at the given path.
+pub fn advisory_lock_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".flock");
+    PathBuf::from(path)
+}
+
+/// An advisory lock (flock) on a file next to the database, held for a whole operation on the database.
+/// SQLite only serializes single transactions, so without it, concurrent adds, deletes and imports would
+/// interleave theirs. The kernel releases the lock when the process exits, so unlike a lock file,
+/// it can't go stale, and the file is left in place.
+#[derive(Debug)]
+pub struct AdvisoryLock {
+    _file: File,
+}
+
+impl AdvisoryLock {
+    /// Locks the file at the given path, creating it if needed, waiting for up to the given timeout
+    /// if another process holds the lock. Returns None if the lock could not be acquired in time.
+    pub fn acquire(path: &Path, timeout: Duration) -> io::Result<Option<Self>> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).mode(0o600).open(path)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                log::info!("acquired advisory lock {}", path.to_str().unwrap());
+                return Ok(Some(AdvisoryLock { _file: file }))
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err)
+            }
+            if Instant::now() >= deadline {
+                return Ok(None)
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// A lock file, which is removed when dropped.
+/// Creating files exclusively is atomic even on NFS, unlike byte-range locks.
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+}
+
+impl LockFile {
+    /// Creates the lock file at the given path, waiting for up to the given timeout if it already exists.
+    /// Returns None if the lock could not be acquired in time.
+    pub fn acquire(path: &Path, timeout: Duration) -> io::Result<Option<Self>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).mode(0o600).open(path) {
+                Ok(mut file) => {
+                    log::info!("acquired lock file {}", path.to_str().unwrap());
+                    file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+                    return Ok(Some(LockFile { path: path.to_owned() }))
+                },
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if is_stale(path) {
+                        log::warn!("removing stale lock file {}", path.to_str().unwrap());
+                        let _ = std::fs::remove_file(path);
+                        continue;
+                    }
+                    if Instant::now() >= deadline {
+                        return Ok(None)
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        log::info!("releasing lock file {}", self.path.to_str().unwrap());
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("unable to remove lock file {}: {}", self.path.to_str().unwrap(), e);
+        }
+    }
+}
+
+fn is_stale(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > STALE_LOCK_AGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_file_excludes_others_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = lock_file_path(&dir.path().join("secrets.sqlite"));
+        let lock = LockFile::acquire(&path, Duration::ZERO).unwrap().unwrap();
+        assert!(LockFile::acquire(&path, Duration::from_millis(100)).unwrap().is_none());
+        drop(lock);
+        assert!(!path.exists());
+        LockFile::acquire(&path, Duration::ZERO).unwrap().unwrap();
+    }
+
+    #[test]
+    fn advisory_lock_excludes_others_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = advisory_lock_path(&dir.path().join("secrets.sqlite"));
+        let lock = AdvisoryLock::acquire(&path, Duration::ZERO).unwrap().unwrap();
+        assert!(AdvisoryLock::acquire(&path, Duration::from_millis(100)).unwrap().is_none());
+        drop(lock);
+        assert!(path.exists());
+        AdvisoryLock::acquire(&path, Duration::ZERO).unwrap().unwrap();
+    }
+
+    #[test]
+    fn stale_lock_file_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = lock_file_path(&dir.path().join("secrets.sqlite"));
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_modified(SystemTime::now() - STALE_LOCK_AGE * 2).unwrap();
+        LockFile::acquire(&path, Duration::ZERO).unwrap().unwrap();
+    }
+
+    #[test]
+    fn explicit_locking_is_not_overridden() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Locking::File.resolve(dir.path()), Locking::File);
+        assert_eq!(Locking::Sqlite.resolve(dir.path()), Locking::Sqlite);
+    }
+}
@@ -1,14 +1,20 @@
+pub mod locking;
 pub mod model;
 
-use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::Path};
+use std::{fs::{OpenOptions, Permissions}, os::unix::fs::{OpenOptionsExt, PermissionsExt}, path::Path};
 
-use model::Secret;
-use rusqlite::{params, Connection, Row, Transaction};
+use locking::{advisory_lock_path, lock_file_path, AdvisoryLock, LockFile, Locking, LOCK_TIMEOUT};
+use model::{SecondaryKey, Secret};
+use rusqlite::{functions::FunctionFlags, params, types::{Value, ValueRef}, Connection, DatabaseName, OpenFlags, Row};
+use unicode_normalization::UnicodeNormalization;
 
-const CURRENT_SCHEMA_VERSION: u32 = 1;
+pub const CURRENT_SCHEMA_VERSION: u32 = 17;
+
+/// Columns to select in order to construct a Secret using to_secret.
+const SECRET_COLUMNS: &str = "id, service, account, digits, interval, public_data, private_data, notes, last_used_at, use_count, created_at, rotate_after_days, metadata_encrypted, has_pin, offset_seconds, t0, url, icon, pinned";
 
 pub struct DB<'a> {
-    transaction: Transaction<'a>
+    transaction: &'a Connection
 }
 
 #[derive(Debug)]
@@ -19,6 +25,8 @@ pub enum Error {
     DbDirIsNotADir,
     DbFileIsNotAFile,
     UnknownSchemaVersion(u32),
+    /// Another process held the lock file at the given path for too long.
+    Locked(std::path::PathBuf),
 }
 
 impl From<rusqlite::Error> for Error {
@@ -36,10 +44,77 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl Error {
+    /// Returns a stable, machine-readable code for the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::SqliteError(_) => "db.sqlite",
+            Error::IOError(_) => "db.io",
+            Error::NoSuchElement => "db.not-found",
+            Error::DbDirIsNotADir => "db.dir-not-a-dir",
+            Error::DbFileIsNotAFile => "db.file-not-a-file",
+            Error::UnknownSchemaVersion(_) => "db.unknown-schema-version",
+            Error::Locked(_) => "db.locked",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::SqliteError(e) => write!(f, "{}", e),
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::NoSuchElement => f.write_str("no such secret"),
+            Error::DbDirIsNotADir => f.write_str("the data directory is not a directory"),
+            Error::DbFileIsNotAFile => f.write_str("the secrets database is not a regular file"),
+            Error::UnknownSchemaVersion(version) => write!(
+                f,
+                "the secrets database was created by a newer version of totpm (schema version {}, but this version only supports up to {})",
+                version,
+                CURRENT_SCHEMA_VERSION,
+            ),
+            Error::Locked(path) => write!(f, "the secrets database is locked ({})", path.to_str().unwrap()),
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Criteria for selecting secrets.
+/// Service and account names match on substrings; an empty string matches everything.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SecretFilter<'a> {
+    pub service: &'a str,
+    pub account: &'a str,
+    /// If given, only secrets with this exact tag are matched.
+    pub tag: Option<&'a str>,
+    /// If given, only the secret with this id is matched.
+    pub id: Option<i64>,
+    /// If true, service and account names must match exactly rather than on substrings.
+    /// An empty string still matches everything.
+    pub exact: bool,
+    /// If true, service and account names are glob patterns which must match whole names, as matched by glob_matches.
+    /// Takes precedence over exact. An empty string still matches everything.
+    pub glob: bool,
+    /// If given, at most this many of the matching secrets are returned.
+    pub limit: Option<u32>,
+    /// Number of matching secrets to skip, in the order they are listed, before returning any.
+    pub offset: u32,
+}
+
+/// A mutation of the secrets database which can be undone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mutation {
+    /// The secret with the given id was added.
+    Add(i64),
+    /// The secret with the given id was deleted.
+    Del(i64),
+    /// The secret with id `old` was replaced by the one with id `new`.
+    Update { old: i64, new: i64 },
+}
+
 impl <'a> DB<'a> {
-    fn new(tx: Transaction<'a>) -> Self {
+    fn new(tx: &'a Connection) -> Self {
         DB {
             transaction: tx
         }
@@ -48,9 +123,9 @@ impl <'a> DB<'a> {
     pub fn add_secret(&self, mut secret: Secret) -> Result<Secret> {
         self.transaction.execute("
             INSERT INTO secrets
-                (service, account, digits, interval, public_data, private_data)
+                (service, account, digits, interval, public_data, private_data, notes, last_used_at, use_count, created_at, rotate_after_days, metadata_encrypted, has_pin, offset_seconds, t0, url, icon, pinned)
             VALUES
-                (?1, ?2, ?3, ?4, ?5, ?6)
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)
             ",
             params![
                 secret.service.as_str(),
@@ -59,13 +134,33 @@ impl <'a> DB<'a> {
                 secret.interval,
                 secret.public_data,
                 secret.private_data,
+                secret.notes,
+                secret.last_used_at,
+                secret.use_count,
+                secret.created_at,
+                secret.rotate_after_days,
+                secret.metadata_encrypted,
+                secret.has_pin,
+                secret.offset_seconds,
+                secret.t0,
+                secret.url,
+                secret.icon,
+                secret.pinned,
             ]
         )?;
         secret.id = self.transaction.last_insert_rowid();
+        for tag in &secret.tags {
+            self.transaction.execute(
+                "INSERT OR IGNORE INTO tags (secret_id, tag) VALUES (?1, ?2)",
+                params![secret.id, tag],
+            )?;
+        }
         Ok(secret)
     }
     
     pub fn del_secret(&self, secret_id: i64) -> Result<()> {
+        self.transaction.execute("DELETE FROM tags WHERE secret_id = ?1", [secret_id])?;
+        self.transaction.execute("DELETE FROM secondary_keys WHERE secret_id = ?1", [secret_id])?;
         let affected_rows = self.transaction.execute("DELETE FROM secrets WHERE id = ?1", [secret_id])?;
         if affected_rows != 1 {
             Err(Error::NoSuchElement)
@@ -75,47 +170,476 @@ impl <'a> DB<'a> {
     }
     
     pub fn list_secrets(&self, service: &str, account: &str) -> Result<Vec<Secret>> {
-        let mut stmt = self.transaction.prepare("
-            SELECT id, service, account, digits, interval, public_data, private_data
-            FROM secrets
-            WHERE service LIKE ('%' || ?1 || '%') AND account LIKE ('%' || ?2 || '%')
-            ORDER BY service, account ASC
-        ")?;
-        let secrets = stmt.query_map([service, account], to_secret)
+        self.find_secrets(&SecretFilter { service, account, ..Default::default() })
+    }
+
+    pub fn find_secrets(&self, filter: &SecretFilter) -> Result<Vec<Secret>> {
+        let (sql, service, account) = find_secrets_query(filter);
+        let mut stmt = self.transaction.prepare(&sql)?;
+        // A negative limit means no limit at all to SQLite
+        let limit = filter.limit.map_or(-1, i64::from);
+        let secrets = stmt.query_map(params![service, account, filter.tag, filter.id, limit, filter.offset], to_secret)
             ?.filter_map(core::result::Result::ok);
-        Ok(secrets.collect())
+        secrets.map(|secret| self.with_tags(secret)).collect()
     }
     
     pub fn get_secret(&self, secret_id: i64) -> Result<Secret> {
-        self.transaction.query_row(
-            "SELECT id, service, account, digits, interval, public_data, private_data FROM secrets WHERE id = ?1",
+        let secret = self.transaction.query_row(
+            &format!("SELECT {} FROM secrets WHERE id = ?1 AND NOT deleted", SECRET_COLUMNS),
             [secret_id],
             to_secret
-        ).map_err(From::from)
+        )?;
+        self.with_tags(secret)
+    }
+
+    /// Deletes the given secret in a way that can be undone, and records the deletion as the last mutation.
+    pub fn trash_secret(&self, secret_id: i64) -> Result<()> {
+        self.mark_deleted(secret_id)?;
+        self.set_last_mutation(Mutation::Del(secret_id))
+    }
+
+    /// Replaces the secret with the given id with a new one, in a way that can be undone.
+    /// Returns the new secret.
+    pub fn replace_secret(&self, old_secret_id: i64, secret: Secret) -> Result<Secret> {
+        self.mark_deleted(old_secret_id)?;
+        let secret = self.add_secret(secret)?;
+        self.set_last_mutation(Mutation::Update { old: old_secret_id, new: secret.id })?;
+        Ok(secret)
+    }
+
+    /// Records the given mutation as the one to reverse on the next call to undo.
+    /// Only the last mutation can be undone, so any other trashed secrets are permanently deleted.
+    pub fn set_last_mutation(&self, mutation: Mutation) -> Result<()> {
+        let (operation, secret_id, replaced_secret_id) = match mutation {
+            Mutation::Add(id) => ("add", id, None),
+            Mutation::Del(id) => ("del", id, None),
+            Mutation::Update { old, new } => ("update", new, Some(old)),
+        };
+        let keep = replaced_secret_id.unwrap_or(secret_id);
+        self.transaction.execute(
+            "DELETE FROM tags WHERE secret_id IN (SELECT id FROM secrets WHERE deleted AND id != ?1)",
+            [keep],
+        )?;
+        self.transaction.execute(
+            "DELETE FROM secondary_keys WHERE secret_id IN (SELECT id FROM secrets WHERE deleted AND id != ?1)",
+            [keep],
+        )?;
+        self.transaction.execute("DELETE FROM secrets WHERE deleted AND id != ?1", [keep])?;
+        self.transaction.execute(
+            "INSERT OR REPLACE INTO journal (id, operation, secret_id, replaced_secret_id) VALUES (1, ?1, ?2, ?3)",
+            params![operation, secret_id, replaced_secret_id],
+        )?;
+        Ok(())
+    }
+
+    /// Reverses the last recorded mutation.
+    /// Returns the mutation that was undone together with the affected secret,
+    /// or None if there is nothing to undo.
+    /// For updates, the affected secret is the restored old version.
+    pub fn undo(&self) -> Result<Option<(Mutation, Secret)>> {
+        let journal_entry = self.transaction.query_row(
+            "SELECT operation, secret_id, replaced_secret_id FROM journal WHERE id = 1",
+            (),
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<i64>>(2)?)),
+        );
+        let mutation = match journal_entry {
+            Ok((operation, secret_id, _)) if operation == "add" => Mutation::Add(secret_id),
+            Ok((operation, secret_id, Some(old))) if operation == "update" => Mutation::Update { old, new: secret_id },
+            Ok((_, secret_id, _)) => Mutation::Del(secret_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        self.transaction.execute("DELETE FROM journal", ())?;
+
+        let secret = match mutation {
+            Mutation::Add(secret_id) => {
+                let secret = self.get_secret(secret_id)?;
+                self.del_secret(secret_id)?;
+                secret
+            },
+            Mutation::Del(secret_id) => {
+                self.transaction.execute("UPDATE secrets SET deleted = 0 WHERE id = ?1", [secret_id])?;
+                self.get_secret(secret_id)?
+            },
+            Mutation::Update { old, new } => {
+                self.del_secret(new)?;
+                self.transaction.execute("UPDATE secrets SET deleted = 0 WHERE id = ?1", [old])?;
+                self.get_secret(old)?
+            },
+        };
+        Ok(Some((mutation, secret)))
+    }
+
+    fn mark_deleted(&self, secret_id: i64) -> Result<()> {
+        let affected_rows = self.transaction.execute(
+            "UPDATE secrets SET deleted = 1 WHERE id = ?1 AND NOT deleted",
+            [secret_id],
+        )?;
+        if affected_rows != 1 {
+            Err(Error::NoSuchElement)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records that a code was generated for the given secret at the given time (in seconds since the epoch).
+    pub fn record_use(&self, secret_id: i64, timestamp: i64) -> Result<()> {
+        let affected_rows = self.transaction.execute(
+            "UPDATE secrets SET last_used_at = ?2, use_count = use_count + 1 WHERE id = ?1",
+            params![secret_id, timestamp],
+        )?;
+        if affected_rows != 1 {
+            Err(Error::NoSuchElement)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sets whether the given secret is pinned, i.e. listed before secrets that aren't.
+    pub fn set_pinned(&self, secret_id: i64, pinned: bool) -> Result<()> {
+        let affected_rows = self.transaction.execute(
+            "UPDATE secrets SET pinned = ?2 WHERE id = ?1 AND NOT deleted",
+            params![secret_id, pinned],
+        )?;
+        if affected_rows != 1 {
+            Err(Error::NoSuchElement)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Permanently deletes all trashed secrets and forgets the last mutation, leaving nothing to undo.
+    pub fn clear_journal(&self) -> Result<()> {
+        self.transaction.execute("DELETE FROM tags WHERE secret_id IN (SELECT id FROM secrets WHERE deleted)", ())?;
+        self.transaction.execute("DELETE FROM secondary_keys WHERE secret_id IN (SELECT id FROM secrets WHERE deleted)", ())?;
+        self.transaction.execute("DELETE FROM secrets WHERE deleted", ())?;
+        self.transaction.execute("DELETE FROM journal", ())?;
+        Ok(())
+    }
+
+    /// Runs SQLite's integrity check. Returns the problems found, if any.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.transaction.prepare("PRAGMA integrity_check")?;
+        let messages = stmt.query_map((), |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(messages.into_iter().filter(|message| message != "ok").collect())
+    }
+
+    /// Overwrites the service, account, notes, url, icon and metadata_encrypted fields of the given secret.
+    pub fn update_metadata(&self, secret: &Secret) -> Result<()> {
+        let affected_rows = self.transaction.execute(
+            "UPDATE secrets SET service = ?2, account = ?3, notes = ?4, url = ?5, icon = ?6, metadata_encrypted = ?7 WHERE id = ?1",
+            params![secret.id, secret.service, secret.account, secret.notes, secret.url, secret.icon, secret.metadata_encrypted],
+        )?;
+        if affected_rows != 1 {
+            Err(Error::NoSuchElement)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Overwrites the private part of the given secret's wrapped key, e.g. after re-wrapping it under a new primary key.
+    pub fn update_private_data(&self, secret_id: i64, private_data: &[u8]) -> Result<()> {
+        let affected_rows = self.transaction.execute(
+            "UPDATE secrets SET private_data = ?2 WHERE id = ?1",
+            params![secret_id, private_data],
+        )?;
+        if affected_rows != 1 {
+            Err(Error::NoSuchElement)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the public and private parts of the wrapped metadata key, if one has been created.
+    pub fn get_metadata_key(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let key = self.transaction.query_row(
+            "SELECT public_data, private_data FROM metadata_key WHERE id = 1",
+            (),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        );
+        match key {
+            Ok(key) => Ok(Some(key)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores the wrapped metadata key. Fails if there already is one, since that would make
+    /// any metadata encrypted with the old key unreadable.
+    pub fn set_metadata_key(&self, public_data: &[u8], private_data: &[u8]) -> Result<()> {
+        self.transaction.execute(
+            "INSERT INTO metadata_key (id, public_data, private_data) VALUES (1, ?1, ?2)",
+            params![public_data, private_data],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the existing metadata key. The caller is responsible for re-encrypting all metadata
+    /// with the new key in the same transaction.
+    pub fn replace_metadata_key(&self, public_data: &[u8], private_data: &[u8]) -> Result<()> {
+        let affected_rows = self.transaction.execute(
+            "UPDATE metadata_key SET public_data = ?1, private_data = ?2 WHERE id = 1",
+            params![public_data, private_data],
+        )?;
+        if affected_rows != 1 {
+            Err(Error::NoSuchElement)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stores the secondary copy of a secret's key, replacing any previous one.
+    pub fn set_secondary_key(&self, key: &SecondaryKey) -> Result<()> {
+        self.transaction.execute(
+            "INSERT OR REPLACE INTO secondary_keys (secret_id, target, public_data, duplicate, seed) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![key.secret_id, key.target, key.public_data, key.duplicate, key.seed],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the secondary keys of all secrets that haven't been deleted.
+    pub fn list_secondary_keys(&self) -> Result<Vec<SecondaryKey>> {
+        let mut stmt = self.transaction.prepare("
+            SELECT secret_id, target, public_data, duplicate, seed
+            FROM secondary_keys
+            WHERE secret_id IN (SELECT id FROM secrets WHERE NOT deleted)
+            ORDER BY secret_id ASC
+        ")?;
+        let keys = stmt.query_map((), |row| Ok(SecondaryKey {
+            secret_id: row.get(0)?,
+            target: row.get(1)?,
+            public_data: row.get(2)?,
+            duplicate: row.get(3)?,
+            seed: row.get(4)?,
+        }))?.collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+
+    fn with_tags(&self, mut secret: Secret) -> Result<Secret> {
+        let mut stmt = self.transaction.prepare("SELECT tag FROM tags WHERE secret_id = ?1 ORDER BY tag ASC")?;
+        secret.tags = stmt.query_map([secret.id], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(secret)
+    }
+}
+
+/// Folds the case of the given service or account name and puts it in Unicode normalization form C,
+/// so that names differing only in case, in any script rather than just ASCII, or in how accented characters
+/// were typed, compare equal. Available in queries as fold().
+pub fn fold_case(name: &str) -> String {
+    name.to_lowercase().nfc().collect()
+}
+
+/// Returns the query find_secrets runs for the given filter, along with the service and account names to bind to it.
+/// The query takes the service, account, tag, id, limit and offset as parameters 1 through 6.
+fn find_secrets_query(filter: &SecretFilter) -> (String, String, String) {
+    let (names_match, service, account) = if filter.glob {
+        (
+            "(?1 = '' OR fold(service) GLOB fold(?1)) AND (?2 = '' OR fold(account) GLOB fold(?2))",
+            filter.service.to_owned(),
+            filter.account.to_owned(),
+        )
+    } else if filter.exact {
+        (
+            "(?1 = '' OR fold(service) = fold(?1)) AND (?2 = '' OR fold(account) = fold(?2))",
+            filter.service.to_owned(),
+            filter.account.to_owned(),
+        )
+    } else {
+        (
+            concat!(
+                "fold(service) LIKE ('%' || fold(?1) || '%') ESCAPE '\\' ",
+                "AND fold(account) LIKE ('%' || fold(?2) || '%') ESCAPE '\\'",
+            ),
+            escape_like(filter.service),
+            escape_like(filter.account),
+        )
+    };
+    let sql = format!("
+        SELECT {}
+        FROM secrets
+        WHERE {}
+            AND NOT deleted
+            AND (?3 IS NULL OR id IN (SELECT secret_id FROM tags WHERE tag = ?3))
+            AND (?4 IS NULL OR id = ?4)
+        ORDER BY pinned DESC, fold(service), fold(account) ASC
+        LIMIT ?5 OFFSET ?6
+    ", SECRET_COLUMNS, names_match);
+    (sql, service, account)
+}
+
+/// Escapes the wildcards of LIKE in the given string, so that it only matches itself when used with ESCAPE '\'.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Returns true if the given name matches the given glob pattern, the way SQLite's GLOB does: '*' matches
+/// any number of characters, '?' matches any one character, and '[...]' matches any one of the enclosed
+/// characters or ranges of characters such as a-z, or any character but those if the first one is '^'.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    glob_matches_chars(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+}
+
+fn glob_matches_chars(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, _) => name.is_empty(),
+        (Some('*'), _) => (0..=name.len()).any(|skip| glob_matches_chars(&pattern[1..], &name[skip..])),
+        (_, None) => false,
+        (Some('?'), _) => glob_matches_chars(&pattern[1..], &name[1..]),
+        (Some('['), Some(c)) => match match_class(&pattern[1..], *c) {
+            Some((true, rest)) => glob_matches_chars(rest, &name[1..]),
+            _ => false,
+        },
+        (Some(p), Some(c)) => p == c && glob_matches_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Matches the given character against the character class at the start of the given pattern, just after its [.
+/// Returns whether it matched and the rest of the pattern after the class, or None if the class is never closed.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negated, mut i) = if pattern.first() == Some(&'^') { (true, 1) } else { (false, 0) };
+    let first = i;
+    let mut matched = false;
+    loop {
+        let start = *pattern.get(i)?;
+        if start == ']' && i > first {
+            break;
+        }
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(end)) if *end != ']' => {
+                matched |= (start..=*end).contains(&c);
+                i += 3;
+            },
+            _ => {
+                matched |= start == c;
+                i += 1;
+            },
+        }
     }
+    Some((matched != negated, &pattern[i + 1..]))
 }
 
 pub fn with_db<P : AsRef<Path>, T, F: FnOnce(&DB) -> Result<T>>(db_path: P, f: F) -> Result<T> {
-    ensure_db_file_exists(&db_path)?;
-    log::info!("creating database {} with secure permissions", db_path.as_ref().to_str().unwrap());
-    log::info!("opening connection to database {}", db_path.as_ref().to_str().unwrap());
-    let mut db = Connection::open(&db_path)?;
+    with_db_locking(db_path, Locking::Auto, f)
+}
+
+/// Like with_db, but uses the given strategy to serialize access to the database.
+/// With file locking, SQLite's own locking is turned off, since it's what can't be trusted.
+pub fn with_db_locking<P : AsRef<Path>, T, F: FnOnce(&DB) -> Result<T>>(db_path: P, locking: Locking, f: F) -> Result<T> {
+    let (_lock, mut db) = open(db_path.as_ref(), locking)?;
 
     log::info!("starting transaction");
     let transaction = db.transaction()?;
     ensure_schema_is_up_to_date(&transaction)?;
-    let db = DB::new(transaction);
-    let result = f(&db);
+    let result = f(&DB::new(&transaction));
     if result.is_ok() {
         log::info!("committing transaction");
-        db.transaction.commit()?;
+        transaction.commit()?;
     } else {
         log::info!("rolling back transaction");
-        db.transaction.rollback()?;
+        transaction.rollback()?;
     }
     result
 }
 
+/// A connection to the database with a single transaction held open across many calls to with,
+/// so that making many changes, e.g. importing many secrets, doesn't reopen the database and commit every time.
+/// Nothing is committed until commit is called; dropping the batch rolls everything back.
+#[derive(Debug)]
+pub struct Batch {
+    connection: Connection,
+    _lock: DbLock,
+}
+
+impl Batch {
+    /// Opens the database and starts a transaction, serializing access to the database as with_db_locking does.
+    /// The database stays locked until the batch is committed or dropped.
+    pub fn begin<P: AsRef<Path>>(db_path: P, locking: Locking) -> Result<Self> {
+        let (_lock, connection) = open(db_path.as_ref(), locking)?;
+        log::info!("starting batch transaction");
+        connection.execute_batch("BEGIN IMMEDIATE")?;
+        ensure_schema_is_up_to_date(&connection)?;
+        Ok(Batch { connection, _lock })
+    }
+
+    /// Like with_db, but within the batch transaction.
+    /// If f fails, only the changes it made are rolled back.
+    pub fn with<T, F: FnOnce(&DB) -> Result<T>>(&self, f: F) -> Result<T> {
+        self.connection.execute_batch("SAVEPOINT batch_step")?;
+        let result = f(&DB::new(&self.connection));
+        if result.is_err() {
+            self.connection.execute_batch("ROLLBACK TO batch_step")?;
+        }
+        self.connection.execute_batch("RELEASE batch_step")?;
+        result
+    }
+
+    pub fn commit(self) -> Result<()> {
+        log::info!("committing batch transaction");
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+/// What keeps other processes out of the database while it's open. The lock is released when dropped.
+#[derive(Debug)]
+enum DbLock {
+    /// A lock file, with SQLite's own locking turned off.
+    File { _lock: LockFile },
+    /// An advisory lock, on top of SQLite's own locking.
+    Advisory { _lock: AdvisoryLock },
+}
+
+/// Opens a connection to the database at db_path, creating the database if needed,
+/// and locks it as the given locking strategy calls for.
+fn open(db_path: &Path, locking: Locking) -> Result<(DbLock, Connection)> {
+    ensure_db_file_exists(db_path)?;
+    log::info!("creating database {} with secure permissions", db_path.to_str().unwrap());
+    let lock = match locking.resolve(db_path) {
+        Locking::File => {
+            let lock_path = lock_file_path(db_path);
+            DbLock::File { _lock: LockFile::acquire(&lock_path, LOCK_TIMEOUT)?.ok_or(Error::Locked(lock_path))? }
+        },
+        _ => {
+            let lock_path = advisory_lock_path(db_path);
+            DbLock::Advisory { _lock: AdvisoryLock::acquire(&lock_path, LOCK_TIMEOUT)?.ok_or(Error::Locked(lock_path))? }
+        },
+    };
+    log::info!("opening connection to database {}", db_path.to_str().unwrap());
+    let connection = match lock {
+        DbLock::File { .. } => Connection::open_with_flags_and_vfs(db_path, OpenFlags::default(), "unix-none")?,
+        DbLock::Advisory { .. } => Connection::open(db_path)?,
+    };
+    connection.create_scalar_function(
+        "fold",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        // Anything but text, such as NULL, is left as it is
+        |ctx| Ok(match ctx.get_raw(0) {
+            ValueRef::Text(text) => Value::Text(fold_case(&String::from_utf8_lossy(text))),
+            value => Value::from(value),
+        }),
+    )?;
+    Ok((lock, connection))
+}
+
+/// Writes a consistent snapshot of the database at db_path to a new file at backup_path,
+/// using SQLite's online backup API so that concurrent writes can't leave the snapshot half-updated.
+/// The database is locked as the given locking strategy calls for while the snapshot is taken.
+/// The backup file is only readable by its owner.
+pub fn backup<P: AsRef<Path>, Q: AsRef<Path>>(db_path: P, backup_path: Q, locking: Locking) -> Result<()> {
+    log::info!("creating backup file {} with secure permissions", backup_path.as_ref().to_str().unwrap());
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&backup_path)?;
+    log::info!("backing up database {}", db_path.as_ref().to_str().unwrap());
+    let (_lock, connection) = open(db_path.as_ref(), locking)?;
+    connection.backup(DatabaseName::Main, backup_path, None)?;
+    Ok(())
+}
+
 fn ensure_db_file_exists<P : AsRef<Path>>(db_path: P) -> Result<()> {
     let db_dir = db_path.as_ref().parent().unwrap();
     if !db_dir.exists() {
@@ -145,17 +669,52 @@ fn to_secret(row: &Row) -> rusqlite::Result<Secret> {
         interval: row.get(4)?,
         public_data: row.get(5)?,
         private_data: row.get(6)?,
+        tags: Vec::new(),
+        notes: row.get(7)?,
+        last_used_at: row.get(8)?,
+        use_count: row.get(9)?,
+        created_at: row.get(10)?,
+        rotate_after_days: row.get(11)?,
+        metadata_encrypted: row.get(12)?,
+        has_pin: row.get(13)?,
+        offset_seconds: row.get(14)?,
+        t0: row.get(15)?,
+        url: row.get(16)?,
+        icon: row.get(17)?,
+        pinned: row.get(18)?,
     })
 }
 
-fn ensure_schema_is_up_to_date(tx: &Transaction) -> Result<()> {
+fn ensure_schema_is_up_to_date(tx: &Connection) -> Result<()> {
     let schema_version = schema_version(tx)?;
     if schema_version > CURRENT_SCHEMA_VERSION {
         return Err(Error::UnknownSchemaVersion(schema_version));
     }
+    if schema_version == CURRENT_SCHEMA_VERSION && user_version(tx)? == CURRENT_SCHEMA_VERSION {
+        return Ok(())
+    }
+    if schema_version > 0 && schema_version < CURRENT_SCHEMA_VERSION {
+        log::info!("upgrading secrets database from schema version {} to {}", schema_version, CURRENT_SCHEMA_VERSION);
+    }
     for v in schema_version .. CURRENT_SCHEMA_VERSION {
         match v {
             0 => create_secrets_table(tx)?,
+            1 => create_tags_table(tx)?,
+            2 => add_notes_column(tx)?,
+            3 => add_usage_columns(tx)?,
+            4 => add_rotation_columns(tx)?,
+            5 => create_journal_table(tx)?,
+            6 => add_journal_replaced_secret_column(tx)?,
+            7 => add_metadata_encryption(tx)?,
+            8 => add_pin_column(tx)?,
+            9 => create_secondary_keys_table(tx)?,
+            10 => add_offset_column(tx)?,
+            11 => add_t0_column(tx)?,
+            12 => create_name_and_tag_indexes(tx)?,
+            13 => make_name_index_case_insensitive(tx)?,
+            14 => add_url_and_icon_columns(tx)?,
+            15 => add_pinned_column(tx)?,
+            16 => index_folded_names(tx)?,
             _ => unreachable!(),
         }
     }
@@ -163,12 +722,21 @@ fn ensure_schema_is_up_to_date(tx: &Transaction) -> Result<()> {
     Ok(())
 }
 
-fn update_schema_version(tx: &Transaction, schema_version: u32) -> Result<()> {
+/// Records the schema version in SQLite's user_version, and in the __version table,
+/// which is all that versions of totpm from before user_version was used look at.
+fn update_schema_version(tx: &Connection, schema_version: u32) -> Result<()> {
+    tx.pragma_update(None, "user_version", schema_version)?;
     tx.execute("UPDATE __version SET version = ?1", params![schema_version])?;
     Ok(())
 }
 
-fn schema_version(tx: &Transaction) -> Result<u32> {
+/// Returns the schema version of the database, or 0 if it's empty.
+/// Databases written before the schema version was kept in user_version only have the __version table.
+fn schema_version(tx: &Connection) -> Result<u32> {
+    let version = user_version(tx)?;
+    if version > 0 {
+        return Ok(version)
+    }
     if let Ok(v) = tx.query_row("SELECT version FROM __version", (),|row| row.get(0)) {
         Ok(v)
     } else {
@@ -177,7 +745,11 @@ fn schema_version(tx: &Transaction) -> Result<u32> {
     }
 }
 
-fn create_secrets_table(tx: &Transaction) -> std::result::Result<(), Error> {
+fn user_version(tx: &Connection) -> Result<u32> {
+    Ok(tx.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+fn create_secrets_table(tx: &Connection) -> std::result::Result<(), Error> {
     tx.execute("
         CREATE TABLE IF NOT EXISTS secrets (
             id           INTEGER PRIMARY KEY,
@@ -193,7 +765,162 @@ fn create_secrets_table(tx: &Transaction) -> std::result::Result<(), Error> {
     Ok(())
 }
 
-fn create_version_table(tx: &Transaction) -> Result<()> {
+fn create_tags_table(tx: &Connection) -> Result<()> {
+    tx.execute("
+        CREATE TABLE IF NOT EXISTS tags (
+            secret_id INTEGER NOT NULL REFERENCES secrets(id),
+            tag       TEXT NOT NULL,
+            PRIMARY KEY (secret_id, tag)
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn add_notes_column(tx: &Connection) -> Result<()> {
+    tx.execute("ALTER TABLE secrets ADD COLUMN notes TEXT", ())?;
+    Ok(())
+}
+
+fn add_usage_columns(tx: &Connection) -> Result<()> {
+    tx.execute("ALTER TABLE secrets ADD COLUMN last_used_at INTEGER", ())?;
+    tx.execute("ALTER TABLE secrets ADD COLUMN use_count INTEGER NOT NULL DEFAULT 0", ())?;
+    Ok(())
+}
+
+/// Secrets created before this migration are considered to be created at the time of the migration,
+/// since their actual age is unknown.
+fn add_rotation_columns(tx: &Connection) -> Result<()> {
+    tx.execute("ALTER TABLE secrets ADD COLUMN created_at INTEGER", ())?;
+    tx.execute("UPDATE secrets SET created_at = CAST(strftime('%s', 'now') AS INTEGER)", ())?;
+    tx.execute("ALTER TABLE secrets ADD COLUMN rotate_after_days INTEGER", ())?;
+    Ok(())
+}
+
+/// The journal holds the last mutation, for undo.
+/// Deleted secrets are kept around, flagged as deleted, until the next mutation.
+fn create_journal_table(tx: &Connection) -> Result<()> {
+    tx.execute("
+        CREATE TABLE IF NOT EXISTS journal (
+            id        INTEGER PRIMARY KEY,
+            operation TEXT NOT NULL,
+            secret_id INTEGER NOT NULL,
+            CHECK(id = 1)
+        )",
+        (),
+    )?;
+    tx.execute("ALTER TABLE secrets ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0", ())?;
+    Ok(())
+}
+
+fn add_journal_replaced_secret_column(tx: &Connection) -> Result<()> {
+    tx.execute("ALTER TABLE journal ADD COLUMN replaced_secret_id INTEGER", ())?;
+    Ok(())
+}
+
+/// The metadata key is a TPM-wrapped symmetric key, shared by all secrets in the database.
+fn add_metadata_encryption(tx: &Connection) -> Result<()> {
+    tx.execute("
+        CREATE TABLE IF NOT EXISTS metadata_key (
+            id           INTEGER PRIMARY KEY,
+            public_data  BLOB NOT NULL,
+            private_data BLOB NOT NULL,
+            CHECK(id = 1)
+        )",
+        (),
+    )?;
+    tx.execute("ALTER TABLE secrets ADD COLUMN metadata_encrypted INTEGER NOT NULL DEFAULT 0", ())?;
+    Ok(())
+}
+
+/// Secrets with a PIN have an auth value on their HMAC key, which must be supplied to generate codes.
+fn add_pin_column(tx: &Connection) -> Result<()> {
+    tx.execute("ALTER TABLE secrets ADD COLUMN has_pin INTEGER NOT NULL DEFAULT 0", ())?;
+    Ok(())
+}
+
+/// Secondary keys are copies of HMAC keys duplicated to a backup machine's migration target,
+/// for recovering secrets if the TPM they were added with is lost.
+fn create_secondary_keys_table(tx: &Connection) -> Result<()> {
+    tx.execute("
+        CREATE TABLE IF NOT EXISTS secondary_keys (
+            secret_id   INTEGER PRIMARY KEY REFERENCES secrets(id),
+            target      TEXT NOT NULL,
+            public_data BLOB NOT NULL,
+            duplicate   BLOB NOT NULL,
+            seed        BLOB NOT NULL
+        )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn add_offset_column(tx: &Connection) -> Result<()> {
+    tx.execute("ALTER TABLE secrets ADD COLUMN offset_seconds INTEGER NOT NULL DEFAULT 0", ())?;
+    Ok(())
+}
+
+fn add_t0_column(tx: &Connection) -> Result<()> {
+    tx.execute("ALTER TABLE secrets ADD COLUMN t0 INTEGER NOT NULL DEFAULT 0", ())?;
+    Ok(())
+}
+
+/// Indexes secrets by service and account, so that listings come out sorted without a separate sort step.
+/// Name matches can't seek in the index, since an empty name matches everything, but still only need to look at
+/// the rows that aren't deleted, in order. Also indexes tags by tag for --tag.
+fn create_name_and_tag_indexes(tx: &Connection) -> Result<()> {
+    tx.execute_batch("
+        CREATE INDEX IF NOT EXISTS secrets_by_name ON secrets (service, account) WHERE NOT deleted;
+        CREATE INDEX IF NOT EXISTS tags_by_tag ON tags (tag, secret_id);
+    ")?;
+    Ok(())
+}
+
+/// Listings are sorted without regard to case, so that e.g. GitHub and github end up next to each other.
+/// The index is only on names as stored; matching folds case using fold(), which SQLite doesn't know about,
+/// so that the database can still be opened without it.
+fn make_name_index_case_insensitive(tx: &Connection) -> Result<()> {
+    tx.execute_batch("
+        DROP INDEX IF EXISTS secrets_by_name;
+        CREATE INDEX secrets_by_name ON secrets (service COLLATE NOCASE, account COLLATE NOCASE) WHERE NOT deleted;
+    ")?;
+    Ok(())
+}
+
+/// Adds the url of the service's login page and an icon to show for it, for frontends to render entries with.
+fn add_url_and_icon_columns(tx: &Connection) -> Result<()> {
+    tx.execute_batch("
+        ALTER TABLE secrets ADD COLUMN url TEXT;
+        ALTER TABLE secrets ADD COLUMN icon TEXT;
+    ")?;
+    Ok(())
+}
+
+/// Adds the pinned flag, and puts pinned secrets first in the name index so that listings, which show them first,
+/// still come out sorted without a separate sort step. Names are matched using fold(), which can't use the index
+/// anyway.
+fn add_pinned_column(tx: &Connection) -> Result<()> {
+    tx.execute_batch("
+        ALTER TABLE secrets ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+        DROP INDEX IF EXISTS secrets_by_name;
+        CREATE INDEX secrets_by_name ON secrets (pinned DESC, service COLLATE NOCASE, account COLLATE NOCASE)
+            WHERE NOT deleted;
+    ")?;
+    Ok(())
+}
+
+/// Sorts the name index the way listings are sorted, by names as folded by fold(), rather than by SQLite's NOCASE,
+/// which only folds ASCII. Since the index uses fold(), the secrets table can only be written to by connections
+/// which define it, which all of totpm's do.
+fn index_folded_names(tx: &Connection) -> Result<()> {
+    tx.execute_batch("
+        DROP INDEX IF EXISTS secrets_by_name;
+        CREATE INDEX secrets_by_name ON secrets (pinned DESC, fold(service), fold(account)) WHERE NOT deleted;
+    ")?;
+    Ok(())
+}
+
+fn create_version_table(tx: &Connection) -> Result<()> {
     tx.execute("
         CREATE TABLE IF NOT EXISTS __version (
             id      INTEGER PRIMARY KEY,
@@ -230,12 +957,75 @@ mod tests {
         );
 
         with_db(&db, |tx| {
-            assert_eq!(schema_version(&tx.transaction)?, CURRENT_SCHEMA_VERSION);
+            assert_eq!(schema_version(tx.transaction)?, CURRENT_SCHEMA_VERSION);
             assert_eq!(tx.list_secrets("", "")?, vec![]);
             Ok(())
         }).unwrap();
     }
 
+    #[test]
+    fn find_secrets_uses_name_and_tag_indexes() {
+        let dbdir = tempfile::tempdir().unwrap();
+        let db = dbdir.path().join("db.sqlite");
+        with_db(&db, |tx| {
+            let plan = |filter: SecretFilter| -> Result<String> {
+                let (sql, service, account) = find_secrets_query(&filter);
+                let mut stmt = tx.transaction.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+                let params = params![service, account, filter.tag, filter.id, -1, 0];
+                let details = stmt.query_map(params, |row| row.get::<_, String>(3))?.collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(details.join("\n"))
+            };
+            let filters = [
+                SecretFilter { service: "svc", account: "acct", exact: true, ..Default::default() },
+                SecretFilter { service: "s", ..Default::default() },
+                SecretFilter { service: "s*", glob: true, ..Default::default() },
+            ];
+            for filter in filters {
+                let sorted = plan(filter)?;
+                assert!(sorted.contains("USING INDEX secrets_by_name"), "{:?}: {}", filter, sorted);
+                assert!(!sorted.contains("TEMP B-TREE"), "{:?}: {}", filter, sorted);
+            }
+            let tagged = plan(SecretFilter { tag: Some("work"), ..Default::default() })?;
+            assert!(tagged.contains("tags_by_tag"), "{}", tagged);
+            Ok(())
+        }).unwrap();
+    }
+
+    fn user_version(db: &Path) -> u32 {
+        Connection::open(db).unwrap().pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn with_db_records_schema_version_in_user_version() {
+        let dbdir = tempfile::tempdir().unwrap();
+        let db = dbdir.path().join("db.sqlite");
+        with_db(&db, |_| Ok(())).unwrap();
+        assert_eq!(user_version(&db), CURRENT_SCHEMA_VERSION);
+
+        // Databases written by older versions only have the __version table
+        Connection::open(&db).unwrap().pragma_update(None, "user_version", 0).unwrap();
+        with_db(&db, |_| Ok(())).unwrap();
+        assert_eq!(user_version(&db), CURRENT_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn with_db_refuses_databases_created_by_newer_versions() {
+        let dbdir = tempfile::tempdir().unwrap();
+        let db = dbdir.path().join("db.sqlite");
+        with_db(&db, |_| Ok(())).unwrap();
+
+        Connection::open(&db).unwrap().pragma_update(None, "user_version", CURRENT_SCHEMA_VERSION + 1).unwrap();
+        let result = with_db(&db, |_| Ok(()));
+        assert!(matches!(result, Err(Error::UnknownSchemaVersion(v)) if v == CURRENT_SCHEMA_VERSION + 1));
+
+        let connection = Connection::open(&db).unwrap();
+        connection.pragma_update(None, "user_version", 0).unwrap();
+        connection.execute("UPDATE __version SET version = ?1", [CURRENT_SCHEMA_VERSION + 1]).unwrap();
+        drop(connection);
+        assert!(matches!(with_db(&db, |_| Ok(())), Err(Error::UnknownSchemaVersion(_))));
+        assert_eq!(user_version(&db), 0);
+    }
+
     #[test]
     fn db_file_always_has_secure_permissions() {
         let db = tempfile::NamedTempFile::new().unwrap();
@@ -247,6 +1037,19 @@ mod tests {
             interval: 30,
             public_data: vec![],
             private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
 
         with_db(&db, |_| Ok(())).unwrap();
@@ -282,6 +1085,59 @@ mod tests {
         assert!(&db.path().is_file());
     }
 
+    #[test]
+    fn with_db_file_locking_holds_lock_file_during_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("secrets.sqlite");
+        let lock_path = lock_file_path(&db);
+        with_db_locking(&db, Locking::File, |_| {
+            assert!(lock_path.exists());
+            Ok(())
+        }).unwrap();
+        assert!(!lock_path.exists());
+
+        let _lock = LockFile::acquire(&lock_path, std::time::Duration::ZERO).unwrap().unwrap();
+        with_db_locking(&db, Locking::Sqlite, |_| Ok(())).unwrap();
+    }
+
+    #[test]
+    fn with_db_sqlite_locking_holds_advisory_lock_during_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("secrets.sqlite");
+        let lock_path = advisory_lock_path(&db);
+        with_db_locking(&db, Locking::Sqlite, |_| {
+            assert!(AdvisoryLock::acquire(&lock_path, std::time::Duration::ZERO)?.is_none());
+            Ok(())
+        }).unwrap();
+        AdvisoryLock::acquire(&lock_path, std::time::Duration::ZERO).unwrap().unwrap();
+    }
+
+    #[test]
+    fn batch_commits_everything_at_once_and_rolls_back_failed_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("secrets.sqlite");
+        let secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+
+        let batch = Batch::begin(&db, Locking::File).unwrap();
+        assert!(lock_file_path(&db).exists());
+        batch.with(|tx| tx.add_secret(secret.clone())).unwrap();
+        let failed = batch.with(|tx| {
+            tx.add_secret(secret.clone())?;
+            tx.get_secret(-1)
+        });
+        assert!(matches!(failed, Err(Error::NoSuchElement)));
+        assert_eq!(batch.with(|tx| tx.list_secrets("", "")).unwrap().len(), 1);
+        drop(batch);
+        assert!(!lock_file_path(&db).exists());
+        assert_eq!(with_db(&db, |tx| tx.list_secrets("", "")).unwrap(), vec![]);
+
+        let batch = Batch::begin(&db, Locking::Auto).unwrap();
+        batch.with(|tx| tx.add_secret(secret.clone())).unwrap();
+        batch.with(|tx| tx.add_secret(secret.clone())).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(with_db(&db, |tx| tx.list_secrets("", "")).unwrap().len(), 2);
+    }
+
     #[test]
     fn with_db_fails_if_db_file_exists_but_is_not_a_file() {
         match with_db(Path::new("/dev/null"), |_| Ok(())) {
@@ -313,6 +1169,19 @@ mod tests {
             interval: 30,
             public_data: vec![],
             private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         let error = with_db(db.path(), |tx| {
@@ -338,6 +1207,19 @@ mod tests {
             interval: 30,
             public_data: vec![],
             private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         let inserted_secret = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap();
@@ -355,6 +1237,19 @@ mod tests {
             interval: 30,
             public_data: vec![],
             private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         let inserted_secret_1 = with_db(db.path(), |tx| tx.add_secret(secret.clone())).unwrap();
@@ -374,6 +1269,19 @@ mod tests {
             interval: 19,
             public_data: vec![123,4],
             private_data: vec![5,6,7,8],
+            tags: vec!["a".to_owned(), "b".to_owned()],
+            notes: Some("recovery codes are in the safe".to_owned()),
+            url: Some("https://example.com/login".to_owned()),
+            icon: Some("example".to_owned()),
+            pinned: false,
+            last_used_at: Some(1700000000),
+            use_count: 3,
+            created_at: Some(1600000000),
+            rotate_after_days: Some(365),
+            metadata_encrypted: false,
+            has_pin: true,
+            offset_seconds: -45,
+            t0: 1234,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         let inserted_secret = with_db(db.path(), |tx| tx.add_secret(secret.clone())).unwrap();
@@ -394,6 +1302,19 @@ mod tests {
             interval: 30,
             public_data: vec![],
             private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let other_secret = Secret {
             id: 0,
@@ -403,6 +1324,19 @@ mod tests {
             interval: 19,
             public_data: vec![123,4],
             private_data: vec![5,6,7,8],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         let expected_secret = with_db(db.path(), |tx| {
@@ -426,6 +1360,19 @@ mod tests {
             interval: 30,
             public_data: vec![],
             private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         with_db(db.path(), |tx| {
@@ -462,6 +1409,19 @@ mod tests {
             interval: 30,
             public_data: vec![],
             private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         let all_ids = with_db(db.path(), |tx| {
@@ -561,6 +1521,19 @@ mod tests {
             interval: 19,
             public_data: vec![123,4],
             private_data: vec![5,6,7,8],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
         };
         let db = tempfile::NamedTempFile::new().unwrap();
         let secret_id = with_db(db.path(), |tx| {
@@ -577,4 +1550,465 @@ mod tests {
         assert_eq!(result.iter().find(|x| x.service != "mame"), None);
         assert_eq!(result.iter().find(|x| x.id == secret_id), None);
     }
+
+    #[test]
+    fn add_secret_preserves_tags() {
+        let secret = Secret {
+            id: 0,
+            service: "svc".to_owned(),
+            account: "acct".to_owned(),
+            digits: 6,
+            interval: 30,
+            public_data: vec![],
+            private_data: vec![],
+            tags: vec!["work".to_owned(), "aws".to_owned()],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
+        };
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let inserted_secret = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap();
+        let stored_secret = with_db(db.path(), |tx| tx.get_secret(inserted_secret.id)).unwrap();
+        assert_eq!(stored_secret.tags, vec!["aws".to_owned(), "work".to_owned()]);
+    }
+
+    #[test]
+    fn find_secrets_filters_on_tag() {
+        let mut secret = Secret {
+            id: 0,
+            service: "svc".to_owned(),
+            account: "acct".to_owned(),
+            digits: 6,
+            interval: 30,
+            public_data: vec![],
+            private_data: vec![],
+            tags: vec![],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
+        };
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let (untagged, work, both) = with_db(db.path(), |tx| {
+            let untagged = tx.add_secret(secret.clone())?;
+            secret.tags = vec!["work".to_owned()];
+            let work = tx.add_secret(secret.clone())?;
+            secret.tags = vec!["work".to_owned(), "personal".to_owned()];
+            let both = tx.add_secret(secret.clone())?;
+            Ok((untagged.id, work.id, both.id))
+        }).unwrap();
+
+        let ids: HashSet<i64> = with_db(db.path(), |tx| tx.find_secrets(&SecretFilter::default()))
+            .unwrap().iter().map(|x| x.id).collect();
+        assert_eq!(ids, HashSet::from_iter([untagged, work, both]));
+
+        let filter = SecretFilter { tag: Some("work"), ..Default::default() };
+        let ids: HashSet<i64> = with_db(db.path(), |tx| tx.find_secrets(&filter))
+            .unwrap().iter().map(|x| x.id).collect();
+        assert_eq!(ids, HashSet::from_iter([work, both]));
+
+        let filter = SecretFilter { tag: Some("personal"), ..Default::default() };
+        let ids: HashSet<i64> = with_db(db.path(), |tx| tx.find_secrets(&filter))
+            .unwrap().iter().map(|x| x.id).collect();
+        assert_eq!(ids, HashSet::from_iter([both]));
+
+        /* tags match exactly, not on substrings */
+        let filter = SecretFilter { tag: Some("wor"), ..Default::default() };
+        let ids: HashSet<i64> = with_db(db.path(), |tx| tx.find_secrets(&filter))
+            .unwrap().iter().map(|x| x.id).collect();
+        assert_eq!(ids, HashSet::from_iter([]));
+
+        let filter = SecretFilter { id: Some(work), ..Default::default() };
+        let ids: HashSet<i64> = with_db(db.path(), |tx| tx.find_secrets(&filter))
+            .unwrap().iter().map(|x| x.id).collect();
+        assert_eq!(ids, HashSet::from_iter([work]));
+
+        let filter = SecretFilter { id: Some(work), tag: Some("personal"), ..Default::default() };
+        let ids: HashSet<i64> = with_db(db.path(), |tx| tx.find_secrets(&filter))
+            .unwrap().iter().map(|x| x.id).collect();
+        assert_eq!(ids, HashSet::from_iter([]));
+    }
+
+    #[test]
+    fn find_secrets_matches_names_exactly_if_asked_to() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let (github, gitlab) = with_db(db.path(), |tx| {
+            let github = tx.add_secret(Secret::new("github".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            let gitlab = tx.add_secret(Secret::new("gitlab".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            Ok((github.id, gitlab.id))
+        }).unwrap();
+        let find = |filter: SecretFilter| -> Vec<i64> {
+            with_db(db.path(), |tx| tx.find_secrets(&filter)).unwrap().iter().map(|x| x.id).collect()
+        };
+
+        assert_eq!(find(SecretFilter { service: "git", ..Default::default() }), vec![github, gitlab]);
+        assert_eq!(find(SecretFilter { service: "git", exact: true, ..Default::default() }), Vec::<i64>::new());
+        assert_eq!(find(SecretFilter { service: "github", exact: true, ..Default::default() }), vec![github]);
+        assert_eq!(find(SecretFilter { service: "gitlab", account: "alice", exact: true, ..Default::default() }), vec![gitlab]);
+        assert_eq!(find(SecretFilter { service: "gitlab", account: "ali", exact: true, ..Default::default() }), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn find_secrets_ignores_case_of_names() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let (github, svt) = with_db(db.path(), |tx| {
+            let svt = tx.add_secret(Secret::new("SVT Play".to_owned(), "Örjan".to_owned(), None, None, vec![], vec![]))?;
+            let github = tx.add_secret(Secret::new("GitHub".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            Ok((github.id, svt.id))
+        }).unwrap();
+        let find = |filter: SecretFilter| -> Vec<i64> {
+            with_db(db.path(), |tx| tx.find_secrets(&filter)).unwrap().iter().map(|x| x.id).collect()
+        };
+
+        assert_eq!(find(SecretFilter::default()), vec![github, svt]);
+        assert_eq!(find(SecretFilter { service: "github", ..Default::default() }), vec![github]);
+        assert_eq!(find(SecretFilter { service: "GITHUB", exact: true, ..Default::default() }), vec![github]);
+        assert_eq!(find(SecretFilter { account: "örj", ..Default::default() }), vec![svt]);
+        assert_eq!(find(SecretFilter { service: "svt play", account: "ÖRJAN", exact: true, ..Default::default() }), vec![svt]);
+    }
+
+    #[test]
+    fn find_secrets_sorts_and_matches_names_as_folded() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let (angstrom, alder, cafe) = with_db(db.path(), |tx| {
+            let angstrom = tx.add_secret(Secret::new("Ångström".to_owned(), "a".to_owned(), None, None, vec![], vec![]))?;
+            let alder = tx.add_secret(Secret::new("ålder".to_owned(), "a".to_owned(), None, None, vec![], vec![]))?;
+            // "Café", with the accent as a combining character
+            let cafe = tx.add_secret(Secret::new("Cafe\u{301}".to_owned(), "a".to_owned(), None, None, vec![], vec![]))?;
+            Ok((angstrom.id, alder.id, cafe.id))
+        }).unwrap();
+        let find = |filter: SecretFilter| -> Vec<i64> {
+            with_db(db.path(), |tx| tx.find_secrets(&filter)).unwrap().iter().map(|x| x.id).collect()
+        };
+
+        // NOCASE would put Ångström first, since it only folds ASCII
+        assert_eq!(find(SecretFilter::default()), vec![cafe, alder, angstrom]);
+        assert_eq!(find(SecretFilter { service: "café", ..Default::default() }), vec![cafe]);
+        assert_eq!(find(SecretFilter { service: "CAFÉ", exact: true, ..Default::default() }), vec![cafe]);
+    }
+
+    #[test]
+    fn fold_case_folds_case_and_normalizes() {
+        assert_eq!(fold_case("GitHub"), "github");
+        assert_eq!(fold_case("ÖRJAN"), "örjan");
+        assert_eq!(fold_case("Cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(fold_case("CAFE\u{301}"), fold_case("café"));
+    }
+
+    #[test]
+    fn find_secrets_matches_wildcards_literally_unless_globbing() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let (percent, underscore, other) = with_db(db.path(), |tx| {
+            let percent = tx.add_secret(Secret::new("100% Bank".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            let underscore = tx.add_secret(Secret::new("my_host".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            let other = tx.add_secret(Secret::new("myshost".to_owned(), "bob".to_owned(), None, None, vec![], vec![]))?;
+            Ok((percent.id, underscore.id, other.id))
+        }).unwrap();
+        let find = |filter: SecretFilter| -> Vec<i64> {
+            with_db(db.path(), |tx| tx.find_secrets(&filter)).unwrap().iter().map(|x| x.id).collect()
+        };
+
+        assert_eq!(find(SecretFilter { service: "%", ..Default::default() }), vec![percent]);
+        assert_eq!(find(SecretFilter { service: "y_h", ..Default::default() }), vec![underscore]);
+        assert_eq!(find(SecretFilter { service: "my*", ..Default::default() }), Vec::<i64>::new());
+        assert_eq!(find(SecretFilter { service: "my*", glob: true, ..Default::default() }), vec![underscore, other]);
+        assert_eq!(find(SecretFilter { service: "MY?HOST", account: "b*", glob: true, ..Default::default() }), vec![other]);
+        assert_eq!(find(SecretFilter { service: "my", glob: true, ..Default::default() }), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn find_secrets_applies_limit_and_offset_after_sorting() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let ids = with_db(db.path(), |tx| {
+            let mut ids = Vec::new();
+            for service in ["e", "d", "c", "b", "a"] {
+                ids.push(tx.add_secret(Secret::new(service.to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?.id);
+            }
+            ids.reverse();
+            Ok(ids)
+        }).unwrap();
+        let find = |filter: SecretFilter| -> Vec<i64> {
+            with_db(db.path(), |tx| tx.find_secrets(&filter)).unwrap().iter().map(|x| x.id).collect()
+        };
+
+        assert_eq!(find(SecretFilter { limit: Some(2), ..Default::default() }), ids[..2]);
+        assert_eq!(find(SecretFilter { limit: Some(2), offset: 2, ..Default::default() }), ids[2..4]);
+        assert_eq!(find(SecretFilter { offset: 3, ..Default::default() }), ids[3..]);
+        assert_eq!(find(SecretFilter { limit: Some(2), offset: 5, ..Default::default() }), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn glob_matches_like_sqlite() {
+        assert!(glob_matches("git*", "github"));
+        assert!(glob_matches("*hub", "github"));
+        assert!(glob_matches("g?t*", "gitlab"));
+        assert!(!glob_matches("git", "github"));
+        assert!(glob_matches("[gh]it*", "hithub"));
+        assert!(!glob_matches("[^g]*", "github"));
+        assert!(glob_matches("[a-h]*", "github"));
+        assert!(glob_matches("[]a]x", "]x"));
+        assert!(glob_matches("[a-]", "-"));
+        assert!(!glob_matches("git[", "git["));
+        assert!(glob_matches("", ""));
+    }
+
+    #[test]
+    fn del_secret_removes_tags() {
+        let secret = Secret {
+            id: 0,
+            service: "svc".to_owned(),
+            account: "acct".to_owned(),
+            digits: 6,
+            interval: 30,
+            public_data: vec![],
+            private_data: vec![],
+            tags: vec!["work".to_owned()],
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
+        };
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let secret_id = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap().id;
+        let num_tags: u32 = with_db(db.path(), |tx| {
+            tx.del_secret(secret_id)?;
+            Ok(tx.transaction.query_row("SELECT COUNT(*) FROM tags", (), |row| row.get(0))?)
+        }).unwrap();
+        assert_eq!(num_tags, 0);
+    }
+
+    #[test]
+    fn secondary_keys_are_listed_until_secret_is_deleted() {
+        let secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let secret = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap();
+        let key = SecondaryKey {
+            secret_id: secret.id,
+            target: "abcd".to_owned(),
+            public_data: vec![3],
+            duplicate: vec![4],
+            seed: vec![5],
+        };
+        with_db(db.path(), |tx| tx.set_secondary_key(&key)).unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.list_secondary_keys()).unwrap(), vec![key]);
+
+        with_db(db.path(), |tx| tx.trash_secret(secret.id)).unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.list_secondary_keys()).unwrap(), vec![]);
+        with_db(db.path(), |tx| tx.clear_journal()).unwrap();
+        let num_keys: u32 = with_db(db.path(), |tx| {
+            Ok(tx.transaction.query_row("SELECT COUNT(*) FROM secondary_keys", (), |row| row.get(0))?)
+        }).unwrap();
+        assert_eq!(num_keys, 0);
+    }
+
+    #[test]
+    fn record_use_updates_usage_statistics() {
+        let secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![], vec![]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let secret_id = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap().id;
+        with_db(db.path(), |tx| tx.record_use(secret_id, 100)).unwrap();
+        with_db(db.path(), |tx| tx.record_use(secret_id, 200)).unwrap();
+        let stored_secret = with_db(db.path(), |tx| tx.get_secret(secret_id)).unwrap();
+        assert_eq!(stored_secret.last_used_at, Some(200));
+        assert_eq!(stored_secret.use_count, 2);
+    }
+
+    #[test]
+    fn find_secrets_lists_pinned_secrets_first() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let (a, b, c) = with_db(db.path(), |tx| {
+            let a = tx.add_secret(Secret::new("a".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            let b = tx.add_secret(Secret::new("b".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            let c = tx.add_secret(Secret::new("c".to_owned(), "alice".to_owned(), None, None, vec![], vec![]))?;
+            Ok((a.id, b.id, c.id))
+        }).unwrap();
+        let list = || -> Vec<i64> {
+            with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap().iter().map(|x| x.id).collect()
+        };
+
+        with_db(db.path(), |tx| tx.set_pinned(c, true)).unwrap();
+        assert_eq!(list(), vec![c, a, b]);
+        assert!(with_db(db.path(), |tx| tx.get_secret(c)).unwrap().pinned);
+        with_db(db.path(), |tx| tx.set_pinned(b, true)).unwrap();
+        assert_eq!(list(), vec![b, c, a]);
+        with_db(db.path(), |tx| tx.set_pinned(c, false)).unwrap();
+        assert_eq!(list(), vec![b, a, c]);
+    }
+
+    #[test]
+    fn set_pinned_fails_on_nonexistent_secret() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let result = with_db(db.path(), |tx| tx.set_pinned(1, true));
+        assert!(matches!(result, Err(Error::NoSuchElement)));
+    }
+
+    #[test]
+    fn record_use_fails_on_nonexistent_secret() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let result = with_db(db.path(), |tx| tx.record_use(1, 100));
+        assert!(matches!(result, Err(Error::NoSuchElement)));
+    }
+
+    #[test]
+    fn undo_restores_trashed_secret() {
+        let mut secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        secret.tags = vec!["work".to_owned()];
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let secret = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap();
+        with_db(db.path(), |tx| tx.trash_secret(secret.id)).unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap(), vec![]);
+
+        let undone = with_db(db.path(), |tx| tx.undo()).unwrap();
+        assert_eq!(undone, Some((Mutation::Del(secret.id), secret.clone())));
+        assert_eq!(with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap(), vec![secret]);
+        assert_eq!(with_db(db.path(), |tx| tx.undo()).unwrap(), None);
+    }
+
+    #[test]
+    fn undo_removes_added_secret() {
+        let secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let secret = with_db(db.path(), |tx| {
+            let secret = tx.add_secret(secret)?;
+            tx.set_last_mutation(Mutation::Add(secret.id))?;
+            Ok(secret)
+        }).unwrap();
+
+        let undone = with_db(db.path(), |tx| tx.undo()).unwrap();
+        assert_eq!(undone, Some((Mutation::Add(secret.id), secret)));
+        assert_eq!(with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn later_mutation_purges_trashed_secret() {
+        let first = Secret::new("first".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let second = Secret::new("second".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let first = with_db(db.path(), |tx| tx.add_secret(first)).unwrap();
+        let second = with_db(db.path(), |tx| tx.add_secret(second)).unwrap();
+        with_db(db.path(), |tx| tx.trash_secret(first.id)).unwrap();
+        with_db(db.path(), |tx| tx.trash_secret(second.id)).unwrap();
+
+        let num_secrets: u32 = with_db(db.path(), |tx| {
+            Ok(tx.transaction.query_row("SELECT COUNT(*) FROM secrets", (), |row| row.get(0))?)
+        }).unwrap();
+        assert_eq!(num_secrets, 1);
+        assert_eq!(with_db(db.path(), |tx| tx.undo()).unwrap(), Some((Mutation::Del(second.id), second)));
+    }
+
+    #[test]
+    fn trash_secret_fails_on_nonexistent_secret() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let result = with_db(db.path(), |tx| tx.trash_secret(1));
+        assert!(matches!(result, Err(Error::NoSuchElement)));
+    }
+
+    #[test]
+    fn undo_reverts_replaced_secret() {
+        let old = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let new = Secret::new("svc".to_owned(), "acct".to_owned(), Some(8), None, vec![3], vec![4]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let old = with_db(db.path(), |tx| tx.add_secret(old)).unwrap();
+        let new = with_db(db.path(), |tx| tx.replace_secret(old.id, new)).unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap(), vec![new.clone()]);
+
+        let undone = with_db(db.path(), |tx| tx.undo()).unwrap();
+        assert_eq!(undone, Some((Mutation::Update { old: old.id, new: new.id }, old.clone())));
+        assert_eq!(with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap(), vec![old]);
+    }
+
+    #[test]
+    fn replace_secret_fails_atomically_on_nonexistent_secret() {
+        let new = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![3], vec![4]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        assert!(matches!(with_db(db.path(), |tx| tx.replace_secret(1, new)), Err(Error::NoSuchElement)));
+        assert_eq!(with_db(db.path(), |tx| tx.list_secrets("", "")).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn update_metadata_overwrites_only_metadata() {
+        let secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let mut secret = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap();
+        secret.service = "0011".to_owned();
+        secret.account = "2233".to_owned();
+        secret.notes = Some("4455".to_owned());
+        secret.metadata_encrypted = true;
+        with_db(db.path(), |tx| tx.update_metadata(&secret)).unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.get_secret(secret.id)).unwrap(), secret);
+    }
+
+    #[test]
+    fn backup_contains_all_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("secrets.sqlite");
+        let backup_path = dir.path().join("backup.sqlite");
+        let secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let secret = with_db(&db_path, |tx| tx.add_secret(secret)).unwrap();
+
+        backup(&db_path, &backup_path, Locking::Auto).unwrap();
+        assert_eq!(backup_path.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(with_db(&backup_path, |tx| tx.list_secrets("", "")).unwrap(), vec![secret]);
+        assert!(backup(&db_path, &backup_path, Locking::Auto).is_err());
+    }
+
+    #[test]
+    fn integrity_check_passes_on_fresh_database() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.integrity_check()).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn metadata_key_can_only_be_set_once() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.get_metadata_key()).unwrap(), None);
+        with_db(db.path(), |tx| tx.set_metadata_key(&[1], &[2])).unwrap();
+        assert!(with_db(db.path(), |tx| tx.set_metadata_key(&[3], &[4])).is_err());
+        assert_eq!(with_db(db.path(), |tx| tx.get_metadata_key()).unwrap(), Some((vec![1], vec![2])));
+    }
+
+    #[test]
+    fn replace_metadata_key_requires_existing_key() {
+        let db = tempfile::NamedTempFile::new().unwrap();
+        assert!(with_db(db.path(), |tx| tx.replace_metadata_key(&[1], &[2])).is_err());
+        with_db(db.path(), |tx| tx.set_metadata_key(&[1], &[2])).unwrap();
+        with_db(db.path(), |tx| tx.replace_metadata_key(&[3], &[4])).unwrap();
+        assert_eq!(with_db(db.path(), |tx| tx.get_metadata_key()).unwrap(), Some((vec![3], vec![4])));
+    }
+
+    #[test]
+    fn update_private_data_overwrites_only_private_data() {
+        let secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![1], vec![2]);
+        let db = tempfile::NamedTempFile::new().unwrap();
+        let mut secret = with_db(db.path(), |tx| tx.add_secret(secret)).unwrap();
+        with_db(db.path(), |tx| tx.update_private_data(secret.id, &[3])).unwrap();
+        secret.private_data = vec![3];
+        assert_eq!(with_db(db.path(), |tx| tx.get_secret(secret.id)).unwrap(), secret);
+        assert!(with_db(db.path(), |tx| tx.update_private_data(secret.id + 1, &[3])).is_err());
+    }
 }
@@ -1,4 +1,10 @@
-use std::fmt::Display;
+use std::{fmt::Display, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/// The largest time offset, in either direction, that a secret can be given: one year.
+pub const MAX_OFFSET_SECONDS: i64 = 366 * 24 * 60 * 60;
+
+/// The largest T0, in either direction, that a secret can be given: the end of the year 9999.
+pub const MAX_T0: i64 = 253_402_300_799;
 
 #[derive(Debug)]
 #[derive(Clone)]
@@ -11,6 +17,31 @@ pub struct Secret {
     pub interval: u32,
     pub public_data: Vec<u8>,
     pub private_data: Vec<u8>,
+    pub tags: Vec<String>,
+    pub notes: Option<String>,
+    /// URL of the service, e.g. its login page.
+    pub url: Option<String>,
+    /// Icon to show for the service, as a URL or a freedesktop icon name.
+    pub icon: Option<String>,
+    /// If true, the secret is listed and offered for selection before secrets that aren't pinned.
+    pub pinned: bool,
+    /// When a code was last generated for this secret, in seconds since the epoch.
+    pub last_used_at: Option<i64>,
+    /// Number of codes generated for this secret.
+    pub use_count: u32,
+    /// When this secret was added, in seconds since the epoch.
+    pub created_at: Option<i64>,
+    /// Number of days after which the user wants to be reminded to rotate this secret.
+    pub rotate_after_days: Option<u32>,
+    /// If true, service, account, notes, url and icon are encrypted with the metadata key, and must be
+    /// decrypted by the TOTP store before use.
+    pub metadata_encrypted: bool,
+    /// If true, the HMAC key is protected by a user-chosen PIN which must be given to generate codes.
+    pub has_pin: bool,
+    /// Seconds to add to the current time when generating codes, to make up for a service whose clock is off.
+    pub offset_seconds: i64,
+    /// Time in seconds since the epoch from which time steps are counted, called T0 in RFC 6238. Almost always 0.
+    pub t0: i64,
 }
 
 impl Secret {
@@ -30,8 +61,70 @@ impl Secret {
             interval: interval.unwrap_or(30),
             public_data,
             private_data,
+            tags: Vec::new(),
+            notes: None,
+            url: None,
+            icon: None,
+            pinned: false,
+            last_used_at: None,
+            use_count: 0,
+            created_at: None,
+            rotate_after_days: None,
+            metadata_encrypted: false,
+            has_pin: false,
+            offset_seconds: 0,
+            t0: 0,
+        }
+    }
+
+    /// Returns the age of this secret in whole days, if its creation time is known.
+    pub fn age_days(&self, now: i64) -> Option<i64> {
+        self.created_at.map(|created_at| (now - created_at) / 86400)
+    }
+
+    /// Returns true if this secret is older than its rotation window.
+    pub fn rotation_due(&self, now: i64) -> bool {
+        match (self.age_days(now), self.rotate_after_days) {
+            (Some(age), Some(days)) => age >= days as i64,
+            _ => false,
+        }
+    }
+
+    /// Returns the given time as the service sees it, i.e. adjusted by this secret's offset.
+    /// Times before the epoch are clamped to the epoch, and offsets that would overflow are ignored.
+    pub fn service_time(&self, time: SystemTime) -> SystemTime {
+        let offset = Duration::from_secs(self.offset_seconds.unsigned_abs());
+        if self.offset_seconds < 0 {
+            time.checked_sub(offset).unwrap_or(UNIX_EPOCH).max(UNIX_EPOCH)
+        } else {
+            time.checked_add(offset).unwrap_or(time)
         }
     }
+
+    /// Returns the number of the time step that the given time falls in, i.e. the counter to generate a code from.
+    pub fn time_step(&self, time: SystemTime) -> u64 {
+        let time = self.service_time(time).duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        (time.saturating_sub(self.t0).max(0) / self.interval as i64) as u64
+    }
+
+    /// Returns when the given time step starts, in the service's time, in seconds since the epoch.
+    /// Steps starting after the end of time start at i64::MAX.
+    pub fn time_step_start(&self, step: u64) -> i64 {
+        i64::try_from(step).unwrap_or(i64::MAX)
+            .saturating_mul(self.interval as i64)
+            .saturating_add(self.t0)
+    }
+}
+
+/// A copy of a secret's HMAC key, duplicated to the TPM of a backup machine when the secret was added.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecondaryKey {
+    pub secret_id: i64,
+    /// Hex-encoded, marshalled public area of the migration target the key was duplicated to.
+    pub target: String,
+    pub public_data: Vec<u8>,
+    pub duplicate: Vec<u8>,
+    pub seed: Vec<u8>,
 }
 
 impl Display for Secret {
@@ -39,3 +132,70 @@ impl Display for Secret {
         f.write_fmt(format_args!("{} ({})", self.service, self.account))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_is_due_once_rotation_window_has_passed() {
+        let mut secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![], vec![]);
+        secret.created_at = Some(0);
+        assert!(!secret.rotation_due(86400 * 1000));
+
+        secret.rotate_after_days = Some(30);
+        assert!(!secret.rotation_due(86400 * 29));
+        assert!(secret.rotation_due(86400 * 30));
+
+        secret.created_at = None;
+        assert!(!secret.rotation_due(86400 * 1000));
+    }
+
+    #[test]
+    fn service_time_is_offset_and_clamped_to_epoch() {
+        let mut secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![], vec![]);
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(secret.service_time(now), now);
+
+        secret.offset_seconds = 45;
+        assert_eq!(secret.service_time(now), UNIX_EPOCH + Duration::from_secs(1045));
+
+        secret.offset_seconds = -45;
+        assert_eq!(secret.service_time(now), UNIX_EPOCH + Duration::from_secs(955));
+
+        secret.offset_seconds = -2000;
+        assert_eq!(secret.service_time(now), UNIX_EPOCH);
+
+        secret.offset_seconds = i64::MAX;
+        assert_eq!(secret.service_time(now), now);
+        secret.offset_seconds = i64::MIN;
+        assert_eq!(secret.service_time(now), UNIX_EPOCH);
+    }
+
+    #[test]
+    fn time_steps_are_counted_from_t0() {
+        let mut secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![], vec![]);
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(secret.time_step(now), 33);
+        assert_eq!(secret.time_step_start(33), 990);
+
+        secret.t0 = 15;
+        assert_eq!(secret.time_step(now), 32);
+        assert_eq!(secret.time_step_start(32), 975);
+
+        secret.t0 = 2000;
+        assert_eq!(secret.time_step(now), 0);
+    }
+
+    #[test]
+    fn time_steps_saturate_with_extreme_t0() {
+        let mut secret = Secret::new("svc".to_owned(), "acct".to_owned(), None, None, vec![], vec![]);
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        secret.t0 = i64::MIN;
+        assert_eq!(secret.time_step(now), (i64::MAX / 30) as u64);
+        assert_eq!(secret.time_step_start(u64::MAX), -1);
+        secret.t0 = i64::MAX;
+        assert_eq!(secret.time_step(now), 0);
+        assert_eq!(secret.time_step_start(1), i64::MAX);
+    }
+}
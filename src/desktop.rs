@@ -0,0 +1,72 @@
+use std::{env, fs, io, path::{Path, PathBuf}};
+
+use crate::{config::local_path, housekeeping::atomic_write};
+
+/// Desktop entry launching the interactive shell in a terminal.
+const DESKTOP_ENTRY: &str = "[Desktop Entry]
+Type=Application
+Name=totpm
+GenericName=TOTP Authenticator
+Comment=Generate one-time codes using secrets stored in the TPM
+Exec=totpm shell
+Icon=totpm
+Terminal=true
+Categories=Utility;Security;
+Keywords=totp;otp;2fa;authenticator;tpm;
+";
+
+const ICON: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="64" height="64" viewBox="0 0 64 64">
+  <rect x="4" y="4" width="56" height="56" rx="10" fill="#2e3440"/>
+  <circle cx="32" cy="32" r="19" fill="none" stroke="#88c0d0" stroke-width="4"/>
+  <path d="M32 19v13l8 6" fill="none" stroke="#eceff4" stroke-width="4" stroke-linecap="round" stroke-linejoin="round"/>
+</svg>
+"##;
+
+/// Returns the XDG data directory to install desktop integration into:
+/// the user's data home for local installs, or /usr/local/share for system installs.
+pub fn data_dir(local: bool) -> PathBuf {
+    if !local {
+        return PathBuf::from("/usr/local/share");
+    }
+    match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => local_path(Path::new(".local/share")),
+    }
+}
+
+/// Writes the desktop entry and icon into the given XDG data directory, so that desktop environments
+/// can show totpm in their launchers. Returns the path of the desktop entry.
+pub fn install(data_dir: &Path) -> io::Result<PathBuf> {
+    let applications_dir = data_dir.join("applications");
+    let icons_dir = data_dir.join("icons/hicolor/scalable/apps");
+    fs::create_dir_all(&applications_dir)?;
+    fs::create_dir_all(&icons_dir)?;
+
+    let entry_path = applications_dir.join("totpm.desktop");
+    log::info!("writing desktop entry to {}", entry_path.to_str().unwrap());
+    atomic_write(&entry_path, DESKTOP_ENTRY)?;
+
+    let icon_path = icons_dir.join("totpm.svg");
+    log::info!("writing icon to {}", icon_path.to_str().unwrap());
+    atomic_write(&icon_path, ICON)?;
+    Ok(entry_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn install_writes_entry_and_icon() {
+        let dir = tempdir().unwrap();
+        let entry_path = install(dir.path()).unwrap();
+        assert_eq!(entry_path, dir.path().join("applications/totpm.desktop"));
+        assert!(fs::read_to_string(&entry_path).unwrap().starts_with("[Desktop Entry]\n"));
+        assert!(dir.path().join("icons/hicolor/scalable/apps/totpm.svg").is_file());
+
+        // Reinstalling overwrites the previous files.
+        install(dir.path()).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use std::{cell::RefCell, ffi::{c_char, CStr, CString, NulError}, fmt::Debug, ptr, str::Utf8Error, time::SystemTime};
+
+use crate::{config::Config, db, tpm, totp_store::{self, TotpStore, WithTPM}};
+
+/// Outcome of a call through the C API. When a call fails, totpm_last_error describes why.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TotpmStatus {
+    Ok,
+    /// A pointer was null, or a string wasn't valid UTF-8.
+    InvalidArgument,
+    /// totpm isn't initialized, or the config file can't be read.
+    NotInitialized,
+    /// There is no secret with the given id.
+    NotFound,
+    /// The user's presence could not be verified.
+    PresenceNotVerified,
+    /// Anything else.
+    Failed,
+}
+
+/// An open secrets store, opaque to C.
+pub struct TotpmStore {
+    store: TotpStore<WithTPM>,
+}
+
+/// A secret, as listed by totpm_list. Strings are owned by the list they're in.
+#[repr(C)]
+pub struct TotpmSecret {
+    pub id: i64,
+    pub service: *mut c_char,
+    pub account: *mut c_char,
+}
+
+/// Secrets listed by totpm_list, which must be freed using totpm_free_list.
+#[repr(C)]
+pub struct TotpmSecretList {
+    pub secrets: *mut TotpmSecret,
+    pub len: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail<E: Debug>(status: TotpmStatus, error: E) -> TotpmStatus {
+    let message = CString::new(format!("{:?}", error)).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+    status
+}
+
+fn store_error(error: totp_store::Error) -> TotpmStatus {
+    let status = match &error {
+        totp_store::Error::NotInitialized => TotpmStatus::NotInitialized,
+        totp_store::Error::DBError(db::Error::NoSuchElement) => TotpmStatus::NotFound,
+        totp_store::Error::TpmError(tpm::Error::PresenceVerificationFailed) => TotpmStatus::PresenceNotVerified,
+        _ => TotpmStatus::Failed,
+    };
+    fail(status, error)
+}
+
+/// Returns the given C string as a &str, or None if it's null or not UTF-8.
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Returns the given C string as a filter, which is None if the string is null.
+unsafe fn to_filter<'a>(s: *const c_char) -> Result<Option<&'a str>, Utf8Error> {
+    if s.is_null() {
+        return Ok(None)
+    }
+    CStr::from_ptr(s).to_str().map(Some)
+}
+
+/// Returns a description of the last failed call on this thread, or null if none has failed.
+/// The string is valid until the next failed call on this thread.
+#[no_mangle]
+pub extern "C" fn totpm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Opens the secrets store configured in the config file at the given path, and stores it in *store.
+/// Presence is not verified until a code is generated. The calling process must be able to read
+/// the system data directory, so for system installs, use the D-Bus daemon instead.
+///
+/// # Safety
+/// config_path must be a null-terminated string and store a valid pointer.
+/// The store must be closed using totpm_close.
+#[no_mangle]
+pub unsafe extern "C" fn totpm_open(config_path: *const c_char, store: *mut *mut TotpmStore) -> TotpmStatus {
+    let Some(config_path) = to_str(config_path) else {
+        return fail(TotpmStatus::InvalidArgument, "config_path is null or not UTF-8")
+    };
+    if store.is_null() {
+        return fail(TotpmStatus::InvalidArgument, "store is null")
+    }
+    let config_str = match std::fs::read_to_string(config_path) {
+        Ok(config_str) => config_str,
+        Err(e) => return fail(TotpmStatus::NotInitialized, e),
+    };
+    let config: Config = match toml::from_str(&config_str) {
+        Ok(config) => config,
+        Err(e) => return fail(TotpmStatus::NotInitialized, e),
+    };
+    match TotpStore::with_tpm_unverified(config) {
+        Ok(totp_store) => {
+            *store = Box::into_raw(Box::new(TotpmStore { store: totp_store }));
+            TotpmStatus::Ok
+        },
+        Err(e) => store_error(e),
+    }
+}
+
+/// Lists the secrets matching the given service and account, either of which may be null to match anything,
+/// and stores them in *list.
+///
+/// Fails with InvalidArgument if service or account isn't valid UTF-8, and with Failed if a listed name
+/// contains a null character.
+///
+/// # Safety
+/// store must have been opened by totpm_open, service and account must be null or null-terminated strings,
+/// and list must be a valid pointer. The list must be freed using totpm_free_list.
+#[no_mangle]
+pub unsafe extern "C" fn totpm_list(
+    store: *mut TotpmStore,
+    service: *const c_char,
+    account: *const c_char,
+    list: *mut TotpmSecretList,
+) -> TotpmStatus {
+    let (Some(store), false) = (store.as_mut(), list.is_null()) else {
+        return fail(TotpmStatus::InvalidArgument, "store or list is null")
+    };
+    let (service, account) = match (to_filter(service), to_filter(account)) {
+        (Ok(service), Ok(account)) => (service, account),
+        (Err(e), _) | (_, Err(e)) => return fail(TotpmStatus::InvalidArgument, e),
+    };
+    let secrets = match store.store.list(service, account) {
+        Ok(secrets) => secrets,
+        Err(e) => return store_error(e),
+    };
+    let names = secrets.into_iter()
+        .map(|secret| Ok((secret.id, CString::new(secret.service)?, CString::new(secret.account)?)))
+        .collect::<Result<Vec<_>, NulError>>();
+    let names = match names {
+        Ok(names) => names,
+        Err(e) => return fail(TotpmStatus::Failed, e),
+    };
+    let secrets = names.into_iter()
+        .map(|(id, service, account)| TotpmSecret { id, service: service.into_raw(), account: account.into_raw() })
+        .collect::<Box<[_]>>();
+    let len = secrets.len();
+    *list = TotpmSecretList { secrets: Box::into_raw(secrets) as *mut TotpmSecret, len };
+    TotpmStatus::Ok
+}
+
+/// Verifies presence and generates a code for the secret with the given id, storing it in *code.
+///
+/// # Safety
+/// store must have been opened by totpm_open, and code must be a valid pointer.
+/// The code must be freed using totpm_free_string.
+#[no_mangle]
+pub unsafe extern "C" fn totpm_gen(store: *mut TotpmStore, id: i64, code: *mut *mut c_char) -> TotpmStatus {
+    let (Some(store), false) = (store.as_mut(), code.is_null()) else {
+        return fail(TotpmStatus::InvalidArgument, "store or code is null")
+    };
+    let result = store.store.verify_presence()
+        .and_then(|_| store.store.gen(id, SystemTime::now()));
+    match result {
+        Ok(generated) => {
+            *code = CString::new(generated).unwrap_or_default().into_raw();
+            TotpmStatus::Ok
+        },
+        Err(e) => store_error(e),
+    }
+}
+
+/// Frees a string returned by totpm_gen. Does nothing if s is null.
+///
+/// # Safety
+/// s must be null or returned by totpm_gen, and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn totpm_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Frees the secrets in a list filled in by totpm_list, and empties the list.
+///
+/// # Safety
+/// list must be null or filled in by totpm_list, and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn totpm_free_list(list: *mut TotpmSecretList) {
+    let Some(list) = list.as_mut() else { return };
+    if list.secrets.is_null() {
+        return
+    }
+    let secrets = Box::from_raw(ptr::slice_from_raw_parts_mut(list.secrets, list.len));
+    for secret in secrets.iter() {
+        totpm_free_string(secret.service);
+        totpm_free_string(secret.account);
+    }
+    list.secrets = ptr::null_mut();
+    list.len = 0;
+}
+
+/// Closes a store opened by totpm_open. Does nothing if store is null.
+///
+/// # Safety
+/// store must be null or opened by totpm_open, and not closed before.
+#[no_mangle]
+pub unsafe extern "C" fn totpm_close(store: *mut TotpmStore) {
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::presence_verification::PresenceVerificationMethod;
+
+    use super::*;
+
+    #[test]
+    fn list_and_gen_through_c_api() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.path().join("sys")),
+            Some(dir.path().join("user")),
+            Some(PresenceVerificationMethod::None),
+        );
+        TotpStore::init(cfg.clone()).unwrap();
+        let secret = TotpStore::with_tpm(cfg.clone()).unwrap().add("foo", "bar", None, None, b"12345678901234567890").unwrap();
+        let cfg_path = CString::new(dir.path().join("totpm.conf").to_str().unwrap()).unwrap();
+        std::fs::write(dir.path().join("totpm.conf"), toml::to_string(&cfg).unwrap()).unwrap();
+
+        unsafe {
+            let mut store = ptr::null_mut();
+            assert_eq!(totpm_open(cfg_path.as_ptr(), &mut store), TotpmStatus::Ok);
+
+            let mut list = TotpmSecretList { secrets: ptr::null_mut(), len: 0 };
+            assert_eq!(totpm_list(store, c"fo".as_ptr(), ptr::null(), &mut list), TotpmStatus::Ok);
+            assert_eq!(list.len, 1);
+            let listed = &*list.secrets;
+            assert_eq!(listed.id, secret.id);
+            assert_eq!(CStr::from_ptr(listed.service).to_str(), Ok("foo"));
+            totpm_free_list(&mut list);
+            assert!(list.secrets.is_null());
+
+            let invalid = [0xff, 0];
+            assert_eq!(totpm_list(store, invalid.as_ptr() as *const c_char, ptr::null(), &mut list), TotpmStatus::InvalidArgument);
+            assert_eq!(totpm_list(store, ptr::null(), invalid.as_ptr() as *const c_char, &mut list), TotpmStatus::InvalidArgument);
+            assert!(list.secrets.is_null());
+
+            TotpStore::with_tpm(cfg.clone()).unwrap().add("nul\0service", "bar", None, None, b"12345678901234567890").unwrap();
+            assert_eq!(totpm_list(store, c"nul".as_ptr(), ptr::null(), &mut list), TotpmStatus::Failed);
+            assert!(list.secrets.is_null());
+            assert!(!totpm_last_error().is_null());
+
+            let mut code = ptr::null_mut();
+            assert_eq!(totpm_gen(store, secret.id, &mut code), TotpmStatus::Ok);
+            assert_eq!(CStr::from_ptr(code).to_str().unwrap().len(), 6);
+            totpm_free_string(code);
+
+            assert_eq!(totpm_gen(store, secret.id + 1, &mut code), TotpmStatus::NotFound);
+            assert!(!totpm_last_error().is_null());
+            totpm_close(store);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::{fs, io::{self, Write}, os::unix::fs::DirBuilderExt, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/// Suffix of temp files written by atomic_write.
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Suffix of lock files. Lock files contain the PID of the process holding the lock.
+pub const LOCK_SUFFIX: &str = ".lock";
+
+/// Name of the directory, relative to the user data directory, where replaced databases are kept for a while.
+pub const TRASH_DIR: &str = "trash";
+
+/// Temp files older than this are assumed to be left over from an interrupted write.
+const TMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How long to keep files in the trash directory.
+const TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Writes the given file atomically, by first writing to a temp file next to it and then moving it into place.
+/// The temp file is synced before it's moved, so that a crash can't leave an empty or partly written file in place.
+/// If interrupted, the temp file is cleaned up by a later call to clean.
+pub fn atomic_write<C: AsRef<[u8]>>(path: &Path, contents: C) -> io::Result<()> {
+    let tmp_path = tmp_path(path);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    fs::File::open(path.parent().unwrap())?.sync_all()
+}
+
+/// Moves the given file into the trash directory next to it, where it is kept for a while before being removed.
+/// Returns the file's new path.
+pub fn move_to_trash(path: &Path) -> io::Result<PathBuf> {
+    let trash_dir = path.parent().unwrap().join(TRASH_DIR);
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&trash_dir)?;
+    let now = SystemTime::now();
+    let mut file_name = path.file_name().unwrap().to_owned();
+    file_name.push(format!(".{}", now.duration_since(UNIX_EPOCH).unwrap().as_secs()));
+    let trash_path = trash_dir.join(file_name);
+    fs::rename(path, &trash_path)?;
+
+    // Renaming keeps the modification time, but retention should count from when the file was trashed.
+    fs::File::options().write(true).open(&trash_path)?.set_modified(now)?;
+    Ok(trash_path)
+}
+
+/// Removes stale lock files, orphaned temp files and expired trash from the given directory.
+/// Failures are logged but otherwise ignored, since housekeeping should never stop totpm from working.
+pub fn clean(dir: &Path) {
+    if let Err(e) = clean_at(dir, SystemTime::now()) {
+        log::warn!("housekeeping in {} failed: {}", dir.to_str().unwrap(), e);
+    }
+}
+
+fn clean_at(dir: &Path, now: SystemTime) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(())
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+        if name.ends_with(TMP_SUFFIX) && age(&path, now)? > TMP_MAX_AGE {
+            log::info!("removing orphaned temp file {}", path.to_str().unwrap());
+            fs::remove_file(&path)?;
+        } else if name.ends_with(LOCK_SUFFIX) && is_stale_lock(&path)? {
+            log::info!("removing stale lock file {}", path.to_str().unwrap());
+            fs::remove_file(&path)?;
+        }
+    }
+
+    let trash_dir = dir.join(TRASH_DIR);
+    if trash_dir.is_dir() {
+        for entry in fs::read_dir(&trash_dir)? {
+            let path = entry?.path();
+            if path.is_file() && age(&path, now)? > TRASH_RETENTION {
+                log::info!("removing expired trash {}", path.to_str().unwrap());
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A lock is stale if the process that created it no longer exists.
+/// Lock files that don't contain a PID are left alone.
+fn is_stale_lock(path: &Path) -> io::Result<bool> {
+    match fs::read_to_string(path)?.trim().parse::<u32>() {
+        Ok(pid) => Ok(!Path::new("/proc").join(pid.to_string()).exists()),
+        Err(_) => Ok(false),
+    }
+}
+
+fn age(path: &Path, now: SystemTime) -> io::Result<Duration> {
+    Ok(now.duration_since(path.metadata()?.modified()?).unwrap_or_default())
+}
+
+/// Returns the path of the temp file used to write the given file.
+/// Temp files are removed by clean if left behind.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap().to_owned();
+    file_name.push(TMP_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn atomic_write_leaves_no_temp_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("totpm.conf");
+        atomic_write(&path, "foo").unwrap();
+        atomic_write(&path, "bar").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bar");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn clean_removes_only_old_temp_files() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a.tmp"), "").unwrap();
+        fs::write(dir.path().join("secrets.sqlite"), "").unwrap();
+
+        clean_at(dir.path(), SystemTime::now()).unwrap();
+        assert!(dir.path().join("a.tmp").exists());
+
+        clean_at(dir.path(), SystemTime::now() + TMP_MAX_AGE * 2).unwrap();
+        assert!(!dir.path().join("a.tmp").exists());
+        assert!(dir.path().join("secrets.sqlite").exists());
+    }
+
+    #[test]
+    fn clean_removes_locks_held_by_dead_processes() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("live.lock"), std::process::id().to_string()).unwrap();
+        fs::write(dir.path().join("dead.lock"), u32::MAX.to_string()).unwrap();
+        fs::write(dir.path().join("unknown.lock"), "").unwrap();
+
+        clean_at(dir.path(), SystemTime::now()).unwrap();
+        assert!(dir.path().join("live.lock").exists());
+        assert!(!dir.path().join("dead.lock").exists());
+        assert!(dir.path().join("unknown.lock").exists());
+    }
+
+    #[test]
+    fn clean_removes_expired_trash() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join(TRASH_DIR)).unwrap();
+        fs::write(dir.path().join(TRASH_DIR).join("secrets.sqlite"), "").unwrap();
+
+        clean_at(dir.path(), SystemTime::now()).unwrap();
+        assert!(dir.path().join(TRASH_DIR).join("secrets.sqlite").exists());
+
+        clean_at(dir.path(), SystemTime::now() + TRASH_RETENTION * 2).unwrap();
+        assert!(!dir.path().join(TRASH_DIR).join("secrets.sqlite").exists());
+    }
+
+    #[test]
+    fn move_to_trash_restarts_retention_period() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("secrets.sqlite");
+        fs::write(&path, "foo").unwrap();
+        fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() - TRASH_RETENTION * 2)
+            .unwrap();
+
+        let trash_path = move_to_trash(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(trash_path.parent().unwrap(), dir.path().join(TRASH_DIR));
+        assert_eq!(fs::read_to_string(&trash_path).unwrap(), "foo");
+
+        clean_at(dir.path(), SystemTime::now()).unwrap();
+        assert!(trash_path.exists());
+    }
+
+    #[test]
+    fn clean_ignores_missing_directory() {
+        let dir = tempdir().unwrap();
+        clean_at(&dir.path().join("nonexistent"), SystemTime::now()).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::Display;
+
+use crate::db::model::Secret;
+
+/// Secrets unused for this many days are considered stale.
+pub const STALE_AFTER_DAYS: i64 = 365;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// A potential problem with a stored secret, which the user may want to clean up.
+#[derive(Debug, PartialEq)]
+pub enum Issue {
+    /// The secret has not been used for the given number of days.
+    Unused(i64),
+    /// The secret has never been used, and was added the given number of days ago.
+    NeverUsed(i64),
+    /// Another secret has the same service and account.
+    Duplicate,
+    /// A secret in the given other profile has the same service and account.
+    DuplicateInProfile(String),
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::Unused(days) => write!(
+                f,
+                "not used in {} days; if the account no longer exists, delete the secret",
+                days,
+            ),
+            Issue::NeverUsed(days) => write!(
+                f,
+                "never used since it was added {} days ago; if it's not needed, delete it",
+                days,
+            ),
+            Issue::Duplicate => f.write_str(
+                "same service and account as another secret; delete the one that no longer produces valid codes",
+            ),
+            Issue::DuplicateInProfile(profile) => write!(
+                f,
+                "same service and account as a secret in profile {}; keep it in only one of them",
+                profile,
+            ),
+        }
+    }
+}
+
+/// Checks the given secrets for hygiene issues.
+/// Returns each secret that has at least one issue, together with its issues.
+pub fn check(secrets: &[Secret], now: i64) -> Vec<(&Secret, Vec<Issue>)> {
+    check_with_profiles(secrets, &[], now)
+}
+
+/// Like check, but also flags secrets with the same service and account as a secret in one of the given
+/// other profiles, given by name.
+pub fn check_with_profiles<'a>(
+    secrets: &'a [Secret],
+    other_profiles: &[(&str, &[Secret])],
+    now: i64,
+) -> Vec<(&'a Secret, Vec<Issue>)> {
+    secrets.iter()
+        .map(|secret| {
+            let mut issues = issues(secret, secrets, now);
+            for (profile, other_secrets) in other_profiles {
+                if other_secrets.iter().any(|other| other.service == secret.service && other.account == secret.account) {
+                    issues.push(Issue::DuplicateInProfile(profile.to_string()));
+                }
+            }
+            (secret, issues)
+        })
+        .filter(|(_, issues)| !issues.is_empty())
+        .collect()
+}
+
+// Weak algorithms aren't flagged: every secret uses HMAC-SHA1, the only algorithm totpm supports, and the
+// database doesn't record which algorithms a service offers.
+fn issues(secret: &Secret, all_secrets: &[Secret], now: i64) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    match (secret.last_used_at, secret.created_at) {
+        (Some(last_used_at), _) => {
+            let days = (now - last_used_at) / SECONDS_PER_DAY;
+            if days >= STALE_AFTER_DAYS {
+                issues.push(Issue::Unused(days));
+            }
+        },
+        (None, Some(created_at)) => {
+            let days = (now - created_at) / SECONDS_PER_DAY;
+            if days >= STALE_AFTER_DAYS {
+                issues.push(Issue::NeverUsed(days));
+            }
+        },
+        (None, None) => {},
+    }
+    let is_duplicate = all_secrets.iter().any(|other| {
+        other.id != secret.id && other.service == secret.service && other.account == secret.account
+    });
+    if is_duplicate {
+        issues.push(Issue::Duplicate);
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret(id: i64, service: &str, last_used_at: Option<i64>, created_at: Option<i64>) -> Secret {
+        let mut secret = Secret::new(service.to_owned(), "acct".to_owned(), None, None, vec![], vec![]);
+        secret.id = id;
+        secret.last_used_at = last_used_at;
+        secret.created_at = created_at;
+        secret
+    }
+
+    #[test]
+    fn check_flags_secrets_unused_for_a_year() {
+        let now = 1000 * SECONDS_PER_DAY;
+        let secrets = vec![
+            secret(1, "recent", Some(now - SECONDS_PER_DAY), Some(0)),
+            secret(2, "old", Some(now - 400 * SECONDS_PER_DAY), Some(0)),
+            secret(3, "never", None, Some(now - 365 * SECONDS_PER_DAY)),
+            secret(4, "unknown", None, None),
+        ];
+        let flagged = check(&secrets, now);
+        assert_eq!(flagged.len(), 2);
+        assert_eq!(flagged[0].0.service, "old");
+        assert_eq!(flagged[0].1, vec![Issue::Unused(400)]);
+        assert_eq!(flagged[1].0.service, "never");
+        assert_eq!(flagged[1].1, vec![Issue::NeverUsed(365)]);
+    }
+
+    #[test]
+    fn check_flags_duplicates() {
+        let secrets = vec![
+            secret(1, "svc", Some(0), Some(0)),
+            secret(2, "svc", Some(0), Some(0)),
+            secret(3, "other", Some(0), Some(0)),
+        ];
+        let flagged = check(&secrets, 0);
+        assert_eq!(flagged.len(), 2);
+        assert!(flagged.iter().all(|(secret, issues)| secret.service == "svc" && *issues == vec![Issue::Duplicate]));
+    }
+
+    #[test]
+    fn check_with_profiles_flags_secrets_duplicated_in_other_profiles() {
+        let secrets = vec![secret(1, "svc", Some(0), Some(0)), secret(2, "other", Some(0), Some(0))];
+        let work = vec![secret(1, "svc", Some(0), Some(0))];
+        let home = vec![secret(1, "svc", Some(0), Some(0)), secret(2, "third", Some(0), Some(0))];
+        let flagged = check_with_profiles(&secrets, &[("work", &work), ("home", &home)], 0);
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].0.service, "svc");
+        assert_eq!(
+            flagged[0].1,
+            vec![Issue::DuplicateInProfile("work".to_owned()), Issue::DuplicateInProfile("home".to_owned())],
+        );
+    }
+}
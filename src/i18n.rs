@@ -0,0 +1,148 @@
+use std::sync::OnceLock;
+
+use fluent_bundle::{concurrent::FluentBundle, FluentArgs, FluentResource};
+use unic_langid::LanguageIdentifier;
+
+pub use fluent_bundle::FluentValue;
+
+/// Messages for each supported language, in Fluent syntax. English comes first and is the fallback.
+const LOCALES: [(&str, &str); 2] = [
+    ("en", include_str!("../locales/en.ftl")),
+    ("sv", include_str!("../locales/sv.ftl")),
+];
+
+struct Messages {
+    /// Messages in the user's language, if it's supported and isn't English.
+    localized: Option<FluentBundle<FluentResource>>,
+    english: FluentBundle<FluentResource>,
+}
+
+static MESSAGES: OnceLock<Messages> = OnceLock::new();
+
+/// Returns the message with the given id in the user's language, falling back to English if there's no
+/// translation for it, and to the id itself if there's no such message at all.
+/// Use the tr! macro rather than calling this directly.
+pub fn translate(id: &str, args: &[(&str, FluentValue)]) -> String {
+    let messages = MESSAGES.get_or_init(|| {
+        // Tests check messages in English, whatever the locale of the machine running them
+        let language = if cfg!(test) { None } else { language_from_env() };
+        Messages {
+            localized: language.filter(|language| language.language.as_str() != "en").and_then(bundle),
+            english: bundle(LanguageIdentifier::from_bytes(b"en").unwrap()).unwrap(),
+        }
+    });
+    let args = args.iter().cloned().collect::<FluentArgs>();
+    messages.localized.iter().chain([&messages.english])
+        .find_map(|bundle| format(bundle, id, &args))
+        .unwrap_or_else(|| {
+            log::warn!("no such message: {}", id);
+            id.to_owned()
+        })
+}
+
+/// Returns the message with the given id in the user's language, with the given arguments, e.g.
+/// tr!("user-not-found", user = name). Arguments must convert into a FluentValue, i.e. be strings or numbers.
+#[macro_export]
+macro_rules! tr {
+    ($id:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::i18n::translate($id, &[$((stringify!($name), $crate::i18n::FluentValue::from($value))),*])
+    };
+}
+
+fn format(bundle: &FluentBundle<FluentResource>, id: &str, args: &FluentArgs) -> Option<String> {
+    let pattern = bundle.get_message(id)?.value()?;
+    let mut errors = Vec::new();
+    let message = bundle.format_pattern(pattern, Some(args), &mut errors);
+    for error in errors {
+        log::warn!("unable to format message {}: {}", id, error);
+    }
+    Some(message.into_owned())
+}
+
+/// Returns a bundle of the messages for the given language, if it's supported.
+fn bundle(language: LanguageIdentifier) -> Option<FluentBundle<FluentResource>> {
+    let (_, source) = LOCALES.iter().find(|(locale, _)| *locale == language.language.as_str())?;
+    let resource = FluentResource::try_new(source.to_string())
+        .unwrap_or_else(|(resource, errors)| {
+            log::warn!("unable to parse messages for {}: {:?}", language, errors);
+            resource
+        });
+    let mut bundle = FluentBundle::new_concurrent(vec![language]);
+    // Unicode isolation marks around arguments only show up as garbage in most terminals
+    bundle.set_use_isolating(false);
+    bundle.add_resource(resource).ok()?;
+    Some(bundle)
+}
+
+/// Returns the first supported language the user asked for, looking at LANGUAGE, LC_ALL, LC_MESSAGES and LANG
+/// in that order, like gettext does. Returns None if none is supported, or if the locale is C or POSIX.
+fn language_from_env() -> Option<LanguageIdentifier> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+        .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()))?;
+    let language = std::env::var("LANGUAGE").unwrap_or_default();
+    select_language(&language, &locale)
+}
+
+/// Returns the first supported language in the given colon-separated LANGUAGE priority list,
+/// or the language of the given POSIX locale, e.g. sv_SE.UTF-8. LANGUAGE is ignored for the C locale.
+fn select_language(language: &str, locale: &str) -> Option<LanguageIdentifier> {
+    let locale = parse_locale(locale)?;
+    language.split(':')
+        .filter_map(parse_locale)
+        .chain([locale])
+        .find(|candidate| LOCALES.iter().any(|(supported, _)| *supported == candidate.language.as_str()))
+}
+
+/// Parses a POSIX locale name such as sv_SE.UTF-8@euro, ignoring its encoding and modifier.
+fn parse_locale(locale: &str) -> Option<LanguageIdentifier> {
+    let name = locale.split(['.', '@']).next()?;
+    match name {
+        "" | "C" | "POSIX" => None,
+        name => name.replace('_', "-").parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lang(s: &str) -> Option<LanguageIdentifier> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn select_language_follows_gettext_precedence() {
+        assert_eq!(select_language("", "sv_SE.UTF-8"), lang("sv-SE"));
+        assert_eq!(select_language("", "de_DE.UTF-8@euro"), None);
+        assert_eq!(select_language("de:sv", "en_US.UTF-8"), lang("sv"));
+        assert_eq!(select_language("sv", "C"), None);
+        assert_eq!(select_language("sv", "POSIX"), None);
+    }
+
+    #[test]
+    fn every_message_is_translated() {
+        let ids = |source: &str| {
+            let mut ids = source.lines()
+                .filter_map(|line| line.split_once(" = "))
+                .map(|(id, _)| id.to_owned())
+                .filter(|id| !id.starts_with(' ') && !id.starts_with('#'))
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let english = ids(LOCALES[0].1);
+        for (locale, source) in &LOCALES[1..] {
+            assert_eq!(ids(source), english, "messages for {} don't match the English ones", locale);
+            let bundle = bundle(locale.parse().unwrap()).unwrap();
+            assert!(english.iter().all(|id| bundle.has_message(id)));
+        }
+    }
+
+    #[test]
+    fn translate_fills_in_arguments_and_falls_back_to_id() {
+        assert_eq!(tr!("user-not-found", user = "alice"), "user does not exist: alice");
+        assert_eq!(tr!("store-verification-failed", count = 1), "found 1 problem in the secrets store");
+        assert_eq!(tr!("store-verification-failed", count = 3), "found 3 problems in the secrets store");
+        assert_eq!(tr!("no-such-message"), "no-such-message");
+    }
+}
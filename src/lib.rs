@@ -1,4 +1,5 @@
 pub mod tpm;
+pub mod tcti;
 pub mod presence_verification;
 pub mod totp_store;
 pub mod args;
@@ -9,4 +10,25 @@ pub mod privileges;
 pub mod result;
 pub mod tpm_config;
 pub mod base32;
-pub mod term;
\ No newline at end of file
+pub mod term;
+pub mod housekeeping;
+pub mod units;
+pub mod hygiene;
+pub mod reference;
+pub mod selection;
+pub mod context;
+pub mod desktop;
+pub mod migration;
+pub mod clock;
+pub mod observer;
+pub mod rng;
+pub mod autotype;
+pub mod logging;
+pub mod secret_format;
+pub mod system_user;
+pub mod async_store;
+pub mod i18n;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+pub mod python;
\ No newline at end of file
@@ -0,0 +1,45 @@
+use std::env;
+
+/// Environment variable controlling the log output of the tpm2-tss libraries.
+const TSS2_LOG_ENV_VAR: &str = "TSS2_LOG";
+
+/// Log levels of totpm and of the tpm2-tss libraries for each number of -v flags given.
+/// Anything beyond the last entry gets the last entry's levels.
+const LEVELS: [(log::Level, &str); 4] = [
+    (log::Level::Warn, "all+none"),
+    (log::Level::Info, "all+error"),
+    (log::Level::Debug, "all+warning"),
+    (log::Level::Trace, "all+trace"),
+];
+
+/// Returns the totpm and tpm2-tss log levels for the given verbosity.
+fn levels(verbosity: u8) -> (log::Level, &'static str) {
+    LEVELS[usize::from(verbosity).min(LEVELS.len() - 1)]
+}
+
+/// Starts logging to stderr at the given verbosity, i.e. number of -v flags,
+/// and makes the tpm2-tss libraries log at a matching level, unless TSS2_LOG is already set.
+/// Must be called before talking to the TPM, since tpm2-tss reads TSS2_LOG on its first log message.
+pub fn init(verbosity: u8) {
+    let (level, tss2_level) = levels(verbosity);
+    if env::var_os(TSS2_LOG_ENV_VAR).is_none() {
+        env::set_var(TSS2_LOG_ENV_VAR, tss2_level);
+    }
+    stderrlog::new()
+        .verbosity(level)
+        .init()
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_grow_with_verbosity_and_saturate() {
+        assert_eq!(levels(0), (log::Level::Warn, "all+none"));
+        assert_eq!(levels(1).0, log::Level::Info);
+        assert_eq!(levels(3), (log::Level::Trace, "all+trace"));
+        assert_eq!(levels(200), levels(3));
+    }
+}
@@ -1,19 +1,21 @@
-use std::{path::{Path, PathBuf}, process::exit, str::FromStr};
+use std::{io::{IsTerminal, Read}, os::unix::fs::OpenOptionsExt, path::{Path, PathBuf}, process::exit, str::FromStr};
 
 use clap::Parser;
 use serde::Deserialize;
-use totpm::{args::Opts, config::{absolute_path, local_path, Config}, presence_verification::PresenceVerificationMethod, result::Result};
+use totpm::{args::Opts, config::{absolute_path, local_path, Config}, context, db::SecretFilter, presence_verification::PresenceVerificationMethod, privileges::{elevated_user_id, restrict_capabilities, EuidSwapGuard}, result::{Context, Result}, selection::SelectionMethod, system_user::SystemUser, tr, units::HumanDuration};
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/totpm.conf";
+const LOCAL_CONFIG_PATH: &str = ".config/totpm.conf";
 
 fn main() {
     let opts = Opts::parse();
-    if opts.debug {
-        stderrlog::new()
-            .verbosity(log::Level::Trace)
-            .init()
-            .unwrap();
-    }
+    totpm::logging::init(opts.verbose);
 
-    let config_path = resolve_config_path(false, opts.config.as_deref());
+    let config_path = resolve_config_path(
+        opts.local_config,
+        opts.system_config,
+        opts.config.as_deref(),
+    );
     match run_command(opts, &config_path) {
         Ok(_) => (),
         Err(e) => fail(e),
@@ -21,161 +23,716 @@ fn main() {
 }
 
 fn fail(e: totpm::result::Error) {
+    totpm::commands::bugreport::record_error(&e);
+    let code = e.code();
+    print_error(e);
+    eprintln!("{}", tr!("error-code", code = code));
+    exit(1);
+}
+
+fn print_error(e: totpm::result::Error) {
     match e {
         totpm::result::Error::IOError(e) => {
-            eprintln!("an io operation failed: {:#?}", e);
-            eprintln!("try re-running the command with the --debug flag for more information");
+            eprintln!("{}", tr!("io-failed", error = e.to_string()));
+            eprintln!("{}", tr!("rerun-with-debug"));
         },
         totpm::result::Error::ConfigReadError(e) => {
-            eprintln!("unable to parse configuration file: {:#?}", e);
+            eprintln!("{}", tr!("config-unreadable", error = e.to_string()));
         },
         totpm::result::Error::ConfigWriteError(e) => {
-            eprintln!("unable to write default configuration to file: {:#?}", e);
+            eprintln!("{}", tr!("config-unwritable", error = e.to_string()));
         },
         totpm::result::Error::TotpStoreError(e) => {
             print_totp_store_error(e);
         },
         totpm::result::Error::UserNotFoundError(user) => {
-            eprintln!("user does not exist: {}", user);
+            eprintln!("{}", tr!("user-not-found", user = user));
         },
         totpm::result::Error::SecretFormatError => {
-            eprintln!("unable to decode secret");
+            eprintln!("{}", tr!("secret-undecodable"));
+        },
+        totpm::result::Error::InsecureSecretFile(path) => {
+            eprintln!("{}", tr!("secret-file-insecure", path = path.to_str().unwrap()));
+            eprintln!("{}", tr!("secret-file-insecure-hint", path = path.to_str().unwrap()));
+        },
+        totpm::result::Error::InvalidSecret(e) => {
+            eprintln!("{}", tr!("secret-invalid", reason = e.to_string()));
+        },
+        totpm::result::Error::InvalidContextFile(path, e) => {
+            eprintln!("{}", tr!("context-file-invalid", path = path.to_str().unwrap(), error = e.to_string()));
+        },
+        totpm::result::Error::UnexpectedPrompt(prompt) => {
+            eprintln!("{}", tr!("unexpected-prompt", prompt = prompt.trim()));
+        },
+        totpm::result::Error::NoContext => {
+            eprintln!("{}", tr!("no-context", file = context::CONTEXT_FILE));
         },
         totpm::result::Error::InvalidPVMethod(method) => {
-            eprintln!("invalid presence verification method: {}", method);
+            eprintln!("{}", tr!("invalid-pv-method", method = method));
+        },
+        totpm::result::Error::InvalidSelectionMethod(method) => {
+            eprintln!("{}", tr!("invalid-selection-method", method = method));
+            eprintln!("{}", tr!("invalid-selection-method-hint"));
+        },
+        totpm::result::Error::ProfileNotFound(name) => {
+            eprintln!("{}", tr!("profile-not-found", name = name));
         },
         totpm::result::Error::RootRequired => {
-            eprintln!("root permissions required");
+            eprintln!("{}", tr!("root-required"));
         },
         totpm::result::Error::SecretNotFound => {
-            eprintln!("service/account combination not found");
+            eprintln!("{}", tr!("secret-not-found"));
         },
         totpm::result::Error::AmbiguousSecret => {
-            eprintln!("more than one secret matched the given parameters");
+            eprintln!("{}", tr!("secret-ambiguous"));
         },
         totpm::result::Error::ImportFormatError(e) => {
-            eprintln!("unable to import secrets: {}", e);
+            eprintln!("{}", tr!("import-failed", error = e));
+        },
+        totpm::result::Error::ImportConflict(secret) => {
+            eprintln!("{}", tr!("import-conflict", secret = secret));
+            eprintln!("{}", tr!("import-conflict-hint"));
+        },
+        totpm::result::Error::SetupCancelled => {
+            eprintln!("{}", tr!("setup-cancelled"));
+        },
+        totpm::result::Error::DeletionCancelled => {
+            eprintln!("{}", tr!("deletion-cancelled"));
+            eprintln!("{}", tr!("deletion-cancelled-hint"));
+        },
+        totpm::result::Error::InvalidCode => {
+            eprintln!("{}", tr!("invalid-code"));
+        },
+        totpm::result::Error::StoreVerificationFailed(num_problems) => {
+            eprintln!("{}", tr!("store-verification-failed", count = num_problems));
+        },
+        totpm::result::Error::DoctorChecksFailed(num_failed) => {
+            eprintln!("{}", tr!("doctor-checks-failed", count = num_failed));
+        },
+        totpm::result::Error::AutoTypeUnavailable => {
+            eprintln!("{}", tr!("auto-type-unavailable"));
+            eprintln!("{}", tr!("auto-type-unavailable-hint"));
+        },
+        totpm::result::Error::ConfigKeyNotFound(key) => {
+            eprintln!("{}", tr!("config-key-not-found", key = key));
+        },
+        totpm::result::Error::InvalidBackup(reason) => {
+            eprintln!("{}", tr!("backup-invalid", reason = reason));
+        },
+        totpm::result::Error::BackupKeyHandleMismatch(backup_handle, current_handle) => {
+            eprintln!("{}", tr!(
+                "backup-key-handle-mismatch",
+                backup_handle = format!("{:#x}", backup_handle),
+                current_handle = format!("{:#x}", current_handle),
+            ));
+            eprintln!("{}", tr!("backup-key-handle-mismatch-hint"));
+        },
+        totpm::result::Error::BackupKeysNotLoadable(num_secrets) => {
+            eprintln!("{}", tr!("backup-keys-not-loadable", count = num_secrets));
+            eprintln!("{}", tr!("backup-keys-not-loadable-hint"));
+        },
+        totpm::result::Error::NothingToUndo => {
+            eprintln!("{}", tr!("nothing-to-undo"));
+        },
+        totpm::result::Error::PcrsNotConfigured(path) => {
+            eprintln!("{}", tr!("pcrs-not-configured"));
+            eprintln!("{}", tr!("pcrs-not-configured-hint", path = path.to_str().unwrap()));
+        },
+        totpm::result::Error::Context(context, e) => {
+            eprintln!("{}", tr!("error-context", context = context));
+            print_error(*e);
+        },
+        totpm::result::Error::ReferenceMismatch(code, reference_code) => {
+            eprintln!("{}", tr!("reference-mismatch", code = code, reference = reference_code));
+        },
+        totpm::result::Error::TestModeUnavailable => {
+            eprintln!("{}", tr!("test-mode-unavailable"));
+        },
+        totpm::result::Error::SecretMismatch => {
+            eprintln!("{}", tr!("secret-mismatch"));
+        },
+        totpm::result::Error::PinMismatch => {
+            eprintln!("{}", tr!("pin-mismatch"));
+        },
+        totpm::result::Error::InsecureConfig(path) => {
+            eprintln!("{}", tr!("config-insecure", path = path.to_str().unwrap()));
+            eprintln!("{}", tr!("config-insecure-hint"));
         },
     };
-    exit(1);
 }
 
 fn print_totp_store_error(error: totpm::totp_store::Error) {
     match error {
         totpm::totp_store::Error::NotInitialized => {
-            eprintln!("the totp store is not initialized");
-            eprintln!("initialize it by running 'totpm init' and then re-run the command");
+            eprintln!("{}", tr!("store-not-initialized"));
+            eprintln!("{}", tr!("store-not-initialized-hint"));
         },
         totpm::totp_store::Error::AlreadyInitialized => {
-            eprintln!("the totp store is already initialized");
+            eprintln!("{}", tr!("store-already-initialized"));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::TctiNotLoadable(missing)) => {
+            eprintln!("{}", tr!("tcti-not-loadable", library = missing.library));
+            eprintln!("{}", tr!("tcti-not-loadable-hint", package = missing.package));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::InvalidTcti(invalid)) => {
+            eprintln!("{}", invalid);
+            eprintln!("{}", tr!("invalid-tcti-forms", forms = totpm::tcti::valid_forms()));
+            eprintln!("{}", tr!("invalid-tcti-hint"));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::NoFreePersistentHandle(handles)) => {
+            eprintln!("{}", tr!("no-free-persistent-handle", handles = handles.to_string()));
+            eprintln!("{}", tr!("no-free-persistent-handle-hint"));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::PcrPolicyFailed) => {
+            eprintln!("{}", tr!("pcr-policy-failed"));
+            eprintln!("{}", tr!("pcr-policy-failed-warning"));
+            eprintln!("{}", tr!("pcr-policy-failed-hint"));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::AuthFailed) => {
+            eprintln!("{}", tr!("wrong-pin"));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::LockedOut) => {
+            eprintln!("{}", tr!("tpm-locked-out"));
+            eprintln!("{}", tr!("tpm-locked-out-hint"));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::PvPolicyUnavailable) => {
+            eprintln!("{}", tr!("pv-policy-unavailable"));
+            eprintln!("{}", tr!("pv-policy-unavailable-hint"));
+        },
+        totpm::totp_store::Error::TpmError(totpm::tpm::Error::PvPolicyFailed) => {
+            eprintln!("{}", tr!("pv-policy-failed"));
+            eprintln!("{}", tr!("pv-policy-failed-hint"));
         },
         totpm::totp_store::Error::TpmError(e) => {
-            eprintln!("a tpm operation failed: {:#?}", e);
-            eprintln!("try re-running the command with the --debug flag for more information");
+            eprintln!("{}", tr!("tpm-failed", error = e.to_string()));
+            eprintln!("{}", tr!("rerun-with-debug"));
         },
         totpm::totp_store::Error::IOError(e) => {
-            eprintln!("an io operation failed: {:#?}", e);
-            eprintln!("try re-running the command with the --debug flag for more information");
+            eprintln!("{}", tr!("io-failed", error = e.to_string()));
+            eprintln!("{}", tr!("rerun-with-debug"));
+        },
+        totpm::totp_store::Error::DBError(totpm::db::Error::Locked(path)) => {
+            eprintln!("{}", tr!("db-locked"));
+            eprintln!("{}", tr!("db-locked-hint", path = path.to_str().unwrap()));
+        },
+        totpm::totp_store::Error::DBError(totpm::db::Error::UnknownSchemaVersion(version)) => {
+            eprintln!("{}", tr!("db-too-new", version = version, supported = totpm::db::CURRENT_SCHEMA_VERSION));
+            eprintln!("{}", tr!("db-too-new-hint"));
         },
         totpm::totp_store::Error::DBError(e) => {
-            eprintln!("an sqlite operation failed: {:#?}", e);
-            eprintln!("try re-running the command with the --debug flag for more information");
+            eprintln!("{}", tr!("db-failed", error = e.to_string()));
+            eprintln!("{}", tr!("rerun-with-debug"));
         },
         totpm::totp_store::Error::KeyHandleError => {
-            eprintln!("the primary key handle is corrupted and your secrets are permanently lost");
-            eprintln!("you can reset the password store by running 'totpm clear' followed by 'totpm init'");
+            eprintln!("{}", tr!("key-handle-corrupted"));
+            eprintln!("{}", tr!("key-handle-corrupted-hint"));
+        },
+        totpm::totp_store::Error::MetadataKeyUnavailable => {
+            eprintln!("{}", tr!("metadata-key-unavailable"));
+            eprintln!("{}", tr!("metadata-key-unavailable-hint"));
+        },
+        totpm::totp_store::Error::MigrationTargetUnavailable => {
+            eprintln!("{}", tr!("migration-target-unavailable"));
+            eprintln!("{}", tr!("migration-target-unavailable-hint"));
+        },
+        totpm::totp_store::Error::MigrationTargetMismatch => {
+            eprintln!("{}", tr!("migration-target-mismatch"));
+            eprintln!("{}", tr!("migration-target-mismatch-hint"));
+        },
+        totpm::totp_store::Error::MigrationTargetNotApproved => {
+            eprintln!("{}", tr!("migration-target-not-approved"));
+            eprintln!("{}", tr!("migration-target-not-approved-hint"));
+        },
+        totpm::totp_store::Error::MalformedMigrationData => {
+            eprintln!("{}", tr!("migration-data-malformed"));
+        },
+        totpm::totp_store::Error::KeysNotMigratable(secrets) => {
+            eprintln!("{}", tr!("keys-not-migratable"));
+            for secret in secrets {
+                eprintln!("  {}", secret);
+            }
+            eprintln!("{}", tr!("keys-not-migratable-hint"));
+        },
+        totpm::totp_store::Error::SharedPrimaryKey => {
+            eprintln!("{}", tr!("shared-primary-key"));
+            eprintln!("{}", tr!("shared-primary-key-hint"));
+        },
+        totpm::totp_store::Error::MetadataNotRecoverable => {
+            eprintln!("{}", tr!("metadata-not-recoverable"));
+            eprintln!("{}", tr!("metadata-not-recoverable-hint"));
+        },
+        totpm::totp_store::Error::PinRequired => {
+            eprintln!("{}", tr!("pin-required"));
+        },
+        totpm::totp_store::Error::MetadataCorrupted => {
+            eprintln!("{}", tr!("metadata-corrupted"));
+            eprintln!("{}", tr!("rerun-with-debug"));
+        },
+        totpm::totp_store::Error::WorkerStopped => {
+            eprintln!("{}", tr!("worker-stopped"));
+            eprintln!("{}", tr!("rerun-with-debug"));
         },
     }
 }
 
 fn run_command(opts: Opts, config_path: &Path) -> Result<()> {
+    restrict_capabilities()?;
+    if let Some(seed) = opts.test_seed {
+        totpm::rng::set_test_seed(seed)?;
+    }
+    let config_path = &first_run_setup(&opts, config_path)?;
+    let selection = opts.select.as_deref().map(SelectionMethod::from_str).transpose()?;
+    let profile = opts.profile.as_deref();
     match opts.command {
-        totpm::args::Command::Add { service, account, digits, interval, secret_on_stdin } => {
-            totpm::commands::add::run(
-                load_config(config_path)?,
-                &service,
-                &account,
-                digits,
-                interval,
-                secret_on_stdin,
-            )
+        totpm::args::Command::Add(args) => {
+            let config = with_pv_timeout(with_selection(load_profile(config_path, profile)?, selection), args.pv_timeout);
+            totpm::commands::add::run(config, args)
         },
-        totpm::args::Command::Del { service, account } => {
-            totpm::commands::del::run(
-                load_config(config_path)?,
-                &service,
-                &account,
-            )
+        totpm::args::Command::Del { service, account, id, exact, glob, all, yes } => {
+            let filter = SecretFilter {
+                service: service.as_deref().unwrap_or_default(),
+                account: account.as_deref().unwrap_or_default(),
+                tag: None,
+                id,
+                exact,
+                glob,
+                limit: None,
+                offset: 0,
+            };
+            if all {
+                totpm::commands::del::run_all(load_profile(config_path, profile)?, &filter, yes)
+            } else {
+                totpm::commands::del::run(with_selection(load_profile(config_path, profile)?, selection), &filter, yes)
+            }
         },
-        totpm::args::Command::Gen { service, account } => {
-            totpm::commands::gen::run(
-                load_config(config_path)?,
-                &service,
-                account.as_deref(),
-            )
+        totpm::args::Command::Pin(args) => {
+            totpm::commands::pin::run(with_selection(load_profile(config_path, profile)?, selection), &args, true)
         },
-        totpm::args::Command::List { service, account } => {
-            totpm::commands::list::run(
-                load_config(config_path)?,
-                service.as_deref(),
-                account.as_deref(),
-            )
+        totpm::args::Command::Unpin(args) => {
+            totpm::commands::pin::run(with_selection(load_profile(config_path, profile)?, selection), &args, false)
+        },
+        totpm::args::Command::Gen { service, account, tag, id, exact, glob, mru, all, type_code, window, pv_timeout, reference_check } => {
+            let mut config = with_pv_timeout(with_selection(load_profile(config_path, profile)?, selection), pv_timeout);
+            if mru {
+                config.gen_selection = Some(SelectionMethod::Mru);
+            }
+            let (service, account, tag) = match service {
+                Some(service) => (service, account, tag),
+                None if id.is_some() => (String::new(), None, None),
+                None if all => (String::new(), None, tag),
+                None => {
+                    let context = {
+                        let _euid = EuidSwapGuard::real_user()?;
+                        context::find(&std::env::current_dir()?)?
+                    }.ok_or(totpm::result::Error::NoContext)?;
+                    (context.service, context.account, tag.or(context.tag))
+                },
+            };
+            let filter = SecretFilter {
+                service: &service,
+                account: account.as_deref().unwrap_or_default(),
+                tag: tag.as_deref(),
+                id,
+                exact,
+                glob,
+                limit: None,
+                offset: 0,
+            };
+            if all {
+                totpm::commands::gen::run_all(config, &filter)
+            } else {
+                totpm::commands::gen::run(config, &filter, type_code, window, reference_check)
+            }
+        },
+        totpm::args::Command::List { service, account, tag, long, stale, glob, limit, offset, ids, json } => {
+            let filter = SecretFilter {
+                service: service.as_deref().unwrap_or_default(),
+                account: account.as_deref().unwrap_or_default(),
+                tag: tag.as_deref(),
+                glob,
+                limit,
+                offset,
+                ..Default::default()
+            };
+            totpm::commands::list::run(load_profile(config_path, profile)?, &filter, long, stale, ids, json)
         },
         #[cfg(feature = "import")]
-        totpm::args::Command::Import { file } => {
+        totpm::args::Command::Import { file, secret_format, on_conflict, dry_run } => {
             totpm::commands::import::run(
-                load_config(config_path)?,
-                &file
+                load_profile(config_path, profile)?,
+                &file,
+                secret_format,
+                on_conflict,
+                dry_run,
             )
         },
-        totpm::args::Command::Init { tpm, system_data_path, user_data_path, user, presence_verification, local } => {
-            let config_path = resolve_config_path(local, opts.config.as_deref());
-            let user_name = user.as_deref().unwrap_or("totpm");
+        totpm::args::Command::Init { probe_tpm: true, tpm, .. } => {
+            totpm::commands::init::probe_tpm(tpm.as_deref())
+        },
+        totpm::args::Command::Init { .. } if profile.is_some() => {
+            totpm::commands::init::run_profile(load_profile(config_path, profile)?)
+        },
+        totpm::args::Command::Init {
+            tpm,
+            system_data_path,
+            user_data_path,
+            user,
+            user_home,
+            user_uid_range,
+            user_groups,
+            presence_verification,
+            local,
+            pcrs,
+            install_desktop_entry,
+            probe_tpm: _,
+        } => {
+            let config_path = resolve_config_path(
+                local || opts.local_config,
+                opts.system_config,
+                opts.config.as_deref(),
+            );
+            let system_user = SystemUser {
+                name: user.unwrap_or("totpm".to_owned()),
+                home: user_home,
+                uid_range: user_uid_range,
+                groups: user_groups,
+            };
             let pv = presence_verification.map(|x| PresenceVerificationMethod::from_str(&x)).transpose()?;
             let config = if cfg!(feature = "install") {
-                Config::default(local, tpm, system_data_path, user_data_path, pv)
+                let tpm = tpm.unwrap_or_else(|| totpm::tcti::DEFAULT_TCTI.to_owned());
+                let mut config = Config::default(local, tpm, system_data_path, user_data_path, pv);
+                config.pcrs = pcrs;
+                config
             } else {
-                load_config(&config_path)?
+                let config = load_config(&config_path)?;
+                if !pcrs.is_empty() && pcrs != config.pcrs {
+                    return Err(totpm::result::Error::PcrsNotConfigured(config_path));
+                }
+                config
             };
-            totpm::commands::init::run(
+            let result = totpm::commands::init::run(
                 &config_path,
                 config,
-                user_name,
+                &system_user,
                 local,
                 &PathBuf::from("/usr/local/bin"),
+            );
+            match result {
+                Err(totpm::result::Error::TotpStoreError(totpm::totp_store::Error::AlreadyInitialized)) if install_desktop_entry => {
+                    log::info!("totp store is already initialized; only installing desktop entry");
+                },
+                result => result?,
+            }
+            if install_desktop_entry {
+                totpm::commands::init::install_desktop_entry(local)?;
+            }
+            Ok(())
+        },
+        #[cfg(feature = "import")]
+        totpm::args::Command::MigrateWizard { dir } => {
+            totpm::commands::migrate_wizard::run(load_profile(config_path, profile)?, &dir)
+        },
+        totpm::args::Command::Migrate { command } => {
+            let config = load_profile(config_path, profile)?;
+            match command {
+                totpm::args::MigrateCommand::Target { file } => {
+                    totpm::commands::migrate::target(config, &file)
+                },
+                totpm::args::MigrateCommand::Approve { target_file } => {
+                    totpm::commands::migrate::approve(config, &target_file)
+                },
+                totpm::args::MigrateCommand::Export { target_file, bundle_file } => {
+                    totpm::commands::migrate::export(config, &target_file, &bundle_file)
+                },
+                totpm::args::MigrateCommand::Import { bundle_file } => {
+                    totpm::commands::migrate::import(config, &bundle_file)
+                },
+            }
+        },
+        totpm::args::Command::Config { command } => {
+            match command {
+                totpm::args::ConfigCommand::Effective => {
+                    let config = load_config(config_path)?;
+                    let flags = flag_keys(&config, selection.is_some(), profile);
+                    let config = match profile {
+                        Some(name) => config.with_profile(name)?,
+                        None => config,
+                    };
+                    totpm::commands::config::effective(
+                        config_path,
+                        &with_selection(config, selection),
+                        read_overlay(config_path)?.as_ref(),
+                        &totpm::config::env_overrides(),
+                        &flags,
+                    )
+                },
+                totpm::args::ConfigCommand::Get { key } => {
+                    totpm::commands::config::get(&load_profile(config_path, profile)?, &key)
+                },
+                totpm::args::ConfigCommand::Set { key, value } => {
+                    totpm::commands::config::set(config_path, &key, &value)
+                },
+                totpm::args::ConfigCommand::Upgrade => {
+                    totpm::commands::config::upgrade(
+                        config_path,
+                        *config_path == local_path(Path::new(LOCAL_CONFIG_PATH)),
+                    )
+                },
+            }
+        },
+        totpm::args::Command::Shell => {
+            totpm::commands::shell::run(
+                with_selection(load_profile(config_path, profile)?, selection),
+                print_error,
+            )
+        },
+        totpm::args::Command::Undo => {
+            totpm::commands::undo::run(load_profile(config_path, profile)?)
+        },
+        totpm::args::Command::Admin { command } => {
+            match command {
+                totpm::args::AdminCommand::Transfer { from, to, service, account, tag } => {
+                    totpm::commands::admin::transfer(
+                        load_profile(config_path, profile)?,
+                        &from,
+                        &to,
+                        &SecretFilter {
+                            service: service.as_deref().unwrap_or(""),
+                            account: account.as_deref().unwrap_or(""),
+                            tag: tag.as_deref(),
+                            id: None,
+                            exact: false,
+                            glob: false,
+                            limit: None,
+                            offset: 0,
+                        },
+                    )
+                },
+            }
+        },
+        totpm::args::Command::Bugreport => {
+            totpm::commands::bugreport::run(
+                config_path,
+                load_config(config_path),
+            )
+        },
+        #[cfg(feature = "daemon")]
+        totpm::args::Command::Daemon => {
+            totpm::commands::daemon::run(load_profile(config_path, profile)?)
+        },
+        totpm::args::Command::InstallUnits { dir, user } => {
+            totpm::commands::install_units::run(&load_profile(config_path, profile)?, &dir, &user)
+        },
+        totpm::args::Command::Status => {
+            totpm::commands::status::run(config_path, load_config(config_path)?)
+        },
+        totpm::args::Command::Doctor { hygiene } => {
+            totpm::commands::doctor::run(config_path, load_config(config_path), hygiene)
+        },
+        totpm::args::Command::Version { json } => {
+            totpm::commands::version::run(load_config(config_path), json)
+        },
+        totpm::args::Command::Backup { path } => {
+            totpm::commands::backup::run(load_profile(config_path, profile)?, &path)
+        },
+        totpm::args::Command::PamHelper { service, account } => {
+            totpm::commands::pam_helper::run(
+                load_profile(config_path, profile)?,
+                &service,
+                account.as_deref(),
+            )
+        },
+        totpm::args::Command::SshHelper { service, account, prompt, check } => {
+            totpm::commands::ssh_helper::run(
+                load_profile(config_path, profile)?,
+                &service,
+                account.as_deref(),
+                prompt.as_deref(),
+                check,
+            )
+        },
+        totpm::args::Command::Export { wrapped } => {
+            totpm::commands::export::wrapped(load_profile(config_path, profile)?, &wrapped)
+        },
+        totpm::args::Command::Restore { path, force, from_secondary, wrapped } => {
+            if from_secondary {
+                totpm::commands::restore::recover_secondary(load_profile(config_path, profile)?, &path)
+            } else if wrapped {
+                totpm::commands::restore::wrapped(load_profile(config_path, profile)?, &path, force)
+            } else {
+                totpm::commands::restore::run(load_profile(config_path, profile)?, &path, force)
+            }
+        },
+        totpm::args::Command::Rekey { force } => {
+            totpm::commands::rekey::run(load_profile(config_path, profile)?, force)
+        },
+        totpm::args::Command::Verify { service, mut account_and_code, tag, window } => {
+            let code = account_and_code.pop().unwrap();
+            totpm::commands::verify::run(
+                load_profile(config_path, profile)?,
+                &service,
+                account_and_code.first().map(String::as_str),
+                tag.as_deref(),
+                &code,
+                window,
             )
         },
-        totpm::args::Command::Clear { yes_i_know_what_i_am_doing, system } => {
+        totpm::args::Command::VerifyStore { load_keys } => {
+            totpm::commands::verify_store::run(load_profile(config_path, profile)?, load_keys)
+        },
+        totpm::args::Command::Clear { yes_i_know_what_i_am_doing, system, service, tag, yes } => {
             totpm::commands::clear::run(
-                load_config(config_path)?,
+                load_profile(config_path, profile)?,
                 system,
                 yes_i_know_what_i_am_doing,
+                yes,
+                service.as_deref(),
+                tag.as_deref(),
             )
         },
     }
 }
 
-/// Loads a config from the given path.
+/// Runs guided setup if the config file does not exist, the command is not init or bugreport,
+/// and we're running interactively.
+/// Returns the path to the config file to use for the rest of the command.
+fn first_run_setup(opts: &Opts, config_path: &Path) -> Result<PathBuf> {
+    let interactive = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    if config_path.exists() || !interactive || matches!(opts.command, totpm::args::Command::Init { .. } | totpm::args::Command::Bugreport | totpm::args::Command::Doctor { .. } | totpm::args::Command::Version { .. }) {
+        return Ok(config_path.to_owned())
+    }
+    totpm::commands::setup::run(
+        opts.config.as_deref(),
+        &local_path(Path::new(LOCAL_CONFIG_PATH)),
+        Path::new(SYSTEM_CONFIG_PATH),
+        &PathBuf::from("/usr/local/bin"),
+    )
+}
+
+/// Loads a config from the given path, with the calling user's overlay and environment overrides applied.
 fn load_config(config_path: &Path) -> Result<Config> {
-    let config_str = std::fs::read_to_string(config_path)?;
-    Ok(Config::deserialize(toml::Deserializer::new(&config_str))?)
+    let reading = || format!("reading {}", config_path.to_str().unwrap());
+    // With elevated privileges, the file is checked and read through the same descriptor, and never through a symlink,
+    // so that it can't be swapped for another one after its permissions were checked.
+    let trusted_uid = elevated_user_id();
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    if trusted_uid.is_some() {
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options.open(config_path).context(reading)?;
+    if let Some(trusted_uid) = trusted_uid {
+        totpm::config::check_permissions(config_path, &file.metadata()?, trusted_uid)?;
+    }
+    let mut config_str = String::new();
+    file.read_to_string(&mut config_str).context(reading)?;
+    let overlay = read_overlay(config_path)?;
+    let env = totpm::config::env_overrides();
+    if overlay.is_none() && env.is_empty() {
+        return Config::deserialize(toml::Deserializer::new(&config_str)).context(reading)
+    }
+    let mut values = toml::Table::from_str(&config_str).context(reading)?;
+    if let Some(overlay) = overlay {
+        totpm::config::apply_overlay(&mut values, overlay);
+    }
+    totpm::config::apply_overlay(&mut values, env);
+    toml::Value::Table(values).try_into().context(reading)
+}
+
+/// Reads the calling user's overlay, unless the given config is their own local config.
+fn read_overlay(config_path: &Path) -> Result<Option<toml::Table>> {
+    if *config_path == local_path(Path::new(LOCAL_CONFIG_PATH)) {
+        return Ok(None)
+    }
+    totpm::config::read_overlay()
+}
+
+/// Loads the config at the given path, with the data paths of the given profile if any.
+fn load_profile(config_path: &Path, profile: Option<&str>) -> Result<Config> {
+    match profile {
+        Some(name) => load_config(config_path)?.with_profile(name),
+        None => load_config(config_path),
+    }
+}
+
+/// Returns the config keys overridden by --select, if given, and by the given profile, if any.
+fn flag_keys(config: &Config, select: bool, profile: Option<&str>) -> Vec<&'static str> {
+    let mut keys = Vec::new();
+    if select {
+        keys.extend(["selection", "gen_selection"]);
+    }
+    if let Some(profile) = profile.and_then(|name| config.profiles.get(name)) {
+        keys.push("user_data_path");
+        if profile.system_data_path.is_some() {
+            keys.push("system_data_path");
+        }
+    }
+    keys
+}
+
+/// Overrides the configured selection methods, if one was given on the command line.
+fn with_selection(mut config: Config, selection: Option<SelectionMethod>) -> Config {
+    if let Some(selection) = selection {
+        config.selection = selection;
+        config.gen_selection = None;
+    }
+    config
+}
+
+/// Overrides the configured presence verification timeout, if one was given on the command line.
+fn with_pv_timeout(mut config: Config, pv_timeout: Option<HumanDuration>) -> Config {
+    if let Some(pv_timeout) = pv_timeout {
+        config.pv_timeout = pv_timeout;
+    }
+    config
 }
 
 /// Returns the path to the totpm configuration file, according to the following rules:
 /// - if config is not Some(p), then p is returned
 /// - if force_local is true, then the path to the user-local config is returned
+/// - if force_system is true, then the path to the system-wide config is returned
 /// - if the user-local config exists, then its path is returned
 /// - otherwise the path to the system-wide config is returned
-fn resolve_config_path(force_local: bool, config: Option<&Path>) -> PathBuf {
+///
+/// If the user-local config is picked implicitly while a system-wide config also exists,
+/// a warning is printed if the two configs disagree about which TPM and primary key to use.
+fn resolve_config_path(force_local: bool, force_system: bool, config: Option<&Path>) -> PathBuf {
     match config {
         Some(cfg) => absolute_path(cfg),
         None => {
-          let local_config = local_path(Path::new(".config/totpm.conf"));
-          if force_local || local_config.is_file() {
+          let local_config = local_path(Path::new(LOCAL_CONFIG_PATH));
+          let system_config = PathBuf::from(SYSTEM_CONFIG_PATH);
+          if force_local {
+              local_config
+          } else if force_system {
+              system_config
+          } else if local_config.is_file() {
+              if system_config.is_file() {
+                  warn_on_config_conflict(&local_config, &system_config);
+              }
               local_config
           } else {
-             "/etc/totpm.conf".into()
+              system_config
           }
         },
     }
 }
+
+/// Prints a warning if the given local and system configs refer to different TPMs or primary keys.
+fn warn_on_config_conflict(local_config: &Path, system_config: &Path) {
+    match (load_config(local_config), load_config(system_config)) {
+        (Ok(local), Ok(system)) => {
+            if !local.same_primary_key(&system) {
+                eprintln!("{}", tr!(
+                    "config-conflict",
+                    local = local_config.to_str().unwrap(),
+                    system = system_config.to_str().unwrap(),
+                ));
+                eprintln!("{}", tr!("config-conflict-hint"));
+            }
+        },
+        _ => {
+            log::info!("unable to load both local and system config; skipping conflict check");
+        },
+    }
+}
@@ -0,0 +1,181 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{db::model::Secret, totp_store::{hex_decode, hex_encode}};
+
+/// The public part of a migration target, sent from the new machine to the old one.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    /// Hex-encoded, marshalled public area of the migration target key.
+    pub public: String,
+    /// Hex-encoded, marshalled signature by the old machine's migration approver, approving the target.
+    /// Added on the old machine by migrate approve.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approval: Option<String>,
+}
+
+/// Secrets duplicated to a migration target, sent from the old machine to the new one.
+/// Key material is only readable by the TPM holding the migration target, but metadata is in plaintext.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bundle {
+    /// The migration target the secrets were duplicated to.
+    pub target: Target,
+    /// Hex-encoded, marshalled public area of the old machine's migration approver, which the new machine
+    /// needs to know of to move the secrets' keys to another primary key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approver: Option<String>,
+    pub secrets: Vec<MigratedSecret>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MigratedSecret {
+    pub service: String,
+    pub account: String,
+    pub digits: u8,
+    pub interval: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotate_after_days: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub has_pin: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset_seconds: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub t0: i64,
+    /// Hex-encoded, marshalled public area of the HMAC key.
+    pub public: String,
+    /// Hex-encoded duplicate of the HMAC key's private area.
+    pub duplicate: String,
+    /// Hex-encoded seed protecting the duplicate, encrypted to the migration target.
+    pub seed: String,
+}
+
+impl MigratedSecret {
+    /// Returns the metadata of this secret as a new secret, without any key.
+    pub fn to_secret(&self) -> Secret {
+        let mut secret = Secret::new(
+            self.service.clone(),
+            self.account.clone(),
+            Some(self.digits),
+            Some(self.interval),
+            Vec::new(),
+            Vec::new(),
+        );
+        secret.tags = self.tags.clone();
+        secret.notes = self.notes.clone();
+        secret.url = self.url.clone();
+        secret.icon = self.icon.clone();
+        secret.pinned = self.pinned;
+        secret.created_at = self.created_at;
+        secret.rotate_after_days = self.rotate_after_days;
+        secret.has_pin = self.has_pin;
+        secret.offset_seconds = self.offset_seconds;
+        secret.t0 = self.t0;
+        secret
+    }
+}
+
+/// Secrets whose keys are still wrapped under the primary key they were exported from, written by export --wrapped.
+/// Key material is only usable with that primary key, on the TPM it lives in, but metadata is in plaintext.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct WrappedExport {
+    /// TPM the secrets are bound to.
+    pub tpm: String,
+    /// Persistent handle of the primary key the secrets are wrapped under.
+    pub primary_key_handle: u32,
+    pub secrets: Vec<WrappedSecret>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct WrappedSecret {
+    pub service: String,
+    pub account: String,
+    pub digits: u8,
+    pub interval: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotate_after_days: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub has_pin: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset_seconds: i64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub t0: i64,
+    /// Hex-encoded, marshalled public area of the HMAC key.
+    pub public: String,
+    /// Hex-encoded private area of the HMAC key, encrypted under the primary key.
+    pub private: String,
+}
+
+impl WrappedSecret {
+    /// Returns this secret as a new secret with its wrapped key, or None if the key isn't valid hex.
+    pub fn to_secret(&self) -> Option<Secret> {
+        let mut secret = Secret::new(
+            self.service.clone(),
+            self.account.clone(),
+            Some(self.digits),
+            Some(self.interval),
+            hex_decode(&self.public)?,
+            hex_decode(&self.private)?,
+        );
+        secret.tags = self.tags.clone();
+        secret.notes = self.notes.clone();
+        secret.url = self.url.clone();
+        secret.icon = self.icon.clone();
+        secret.pinned = self.pinned;
+        secret.created_at = self.created_at;
+        secret.rotate_after_days = self.rotate_after_days;
+        secret.has_pin = self.has_pin;
+        secret.offset_seconds = self.offset_seconds;
+        secret.t0 = self.t0;
+        Some(secret)
+    }
+}
+
+impl From<Secret> for WrappedSecret {
+    fn from(secret: Secret) -> Self {
+        WrappedSecret {
+            public: hex_encode(&secret.public_data),
+            private: hex_encode(&secret.private_data),
+            service: secret.service,
+            account: secret.account,
+            digits: secret.digits,
+            interval: secret.interval,
+            tags: secret.tags,
+            notes: secret.notes,
+            url: secret.url,
+            icon: secret.icon,
+            pinned: secret.pinned,
+            created_at: secret.created_at,
+            rotate_after_days: secret.rotate_after_days,
+            has_pin: secret.has_pin,
+            offset_seconds: secret.offset_seconds,
+            t0: secret.t0,
+        }
+    }
+}
+
+fn is_zero(n: &i64) -> bool {
+    *n == 0
+}
@@ -0,0 +1,52 @@
+use std::fmt::Display;
+
+use crate::db::model::Secret;
+
+/// A TPM operation performed by a TOTP store on behalf of the user.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TpmOperation {
+    LoadPrimaryKey,
+    CreateKey,
+    GenerateCode,
+    DuplicateKey,
+    ImportKey,
+}
+
+impl Display for TpmOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TpmOperation::LoadPrimaryKey => "loading primary key",
+            TpmOperation::CreateKey => "creating key",
+            TpmOperation::GenerateCode => "generating code",
+            TpmOperation::DuplicateKey => "duplicating key",
+            TpmOperation::ImportKey => "importing key",
+        })
+    }
+}
+
+/// Receives notifications about what a TOTP store is doing, so that embedders can show progress
+/// and prompts in their own user interface. All methods do nothing by default.
+pub trait Observer {
+    /// Presence verification has started, and the user should e.g. be asked to scan their finger.
+    fn on_pv_started(&mut self) {}
+
+    /// Presence verification has finished, successfully or not.
+    fn on_pv_finished(&mut self, _verified: bool) {}
+
+    /// The TPM is about to perform the given operation.
+    fn on_tpm_op(&mut self, _op: TpmOperation) {}
+
+    /// A code was generated for the given secret.
+    fn on_secret_used(&mut self, _secret: &Secret) {}
+}
+
+impl std::fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+/// An observer which ignores all notifications.
+pub struct NoObserver;
+
+impl Observer for NoObserver {}
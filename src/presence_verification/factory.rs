@@ -1,13 +1,60 @@
-use super::{fprintd::FprintdPresenceVerifier, ConstPresenceVerifier, PresenceVerifier, PresenceVerificationMethod};
+#[cfg(feature = "fprintd")]
+use super::fprintd::FprintdPresenceVerifier;
+use crate::config::Config;
 
-pub(crate) fn create_presence_verifier(
+use super::{
+    pin::PinPresenceVerifier,
+    ConstPresenceVerifier,
+    FirstOfPresenceVerifier,
+    PresenceVerifier,
+    PresenceVerificationMethod,
+    PresenceVerificationMethods,
+};
+
+pub(crate) fn create_presence_verifier(config: &Config) -> Box<dyn PresenceVerifier> {
+    match &config.pv_method {
+        PresenceVerificationMethods::One(method) => create_method_verifier(config, *method),
+        PresenceVerificationMethods::FirstOf(methods) => Box::new(FirstOfPresenceVerifier::new(
+            methods.iter().map(|method| create_method_verifier(config, *method)).collect()
+        )),
+    }
+}
+
+fn create_method_verifier(
+    #[allow(unused_variables)]
+    config: &Config,
     method: PresenceVerificationMethod,
-    timeout_secs: u8
 ) -> Box<dyn PresenceVerifier> {
     match method {
-        PresenceVerificationMethod::Fprintd => Box::new(FprintdPresenceVerifier::new(timeout_secs)),
+        #[cfg(feature = "fprintd")]
+        PresenceVerificationMethod::Fprintd => Box::new(
+            FprintdPresenceVerifier::new(
+                config.pv_timeout.as_duration(),
+                config.pv_prompts.clone(),
+                config.fprintd_user.clone(),
+            )
+        ),
+        #[cfg(not(feature = "fprintd"))]
+        PresenceVerificationMethod::Fprintd => Box::new(UnavailablePresenceVerifier("fprintd")),
+        PresenceVerificationMethod::Pin => Box::new(
+            PinPresenceVerifier::new(config.pin_hash_path(), config.pin_failures_path(), config.pv_prompts.clone())
+        ),
         PresenceVerificationMethod::None => Box::new(ConstPresenceVerifier::new(true)),
         #[cfg(test)]
         PresenceVerificationMethod::AlwaysFail => Box::new(ConstPresenceVerifier::new(false))
     }
 }
+
+/// Stand-in for presence verification methods which were not enabled at build time.
+/// Always fails with an error.
+#[cfg(not(feature = "fprintd"))]
+struct UnavailablePresenceVerifier(&'static str);
+
+#[cfg(not(feature = "fprintd"))]
+impl PresenceVerifier for UnavailablePresenceVerifier {
+    fn owner_present(&mut self) -> super::Result<bool> {
+        Err(super::Error::ImplementationSpecificError(
+            format!("{}: support for this method was not enabled when totpm was built", self.0)
+        ))
+    }
+}
@@ -1,14 +1,17 @@
-use std::{fmt::Display, str::FromStr, sync::{Arc, Mutex}, time::{self, Duration}};
+use std::{fmt::Display, sync::{Arc, Mutex}, time::{self, Duration}};
 
 use dbus::{arg::ReadAll, blocking::{Connection, Proxy}, message::SignalArgs, Message, Path};
 
-use crate::privileges::with_uid_as_euid;
+use crate::{privileges::EuidSwapGuard, tr};
 
-use super::PresenceVerifier;
+use super::{notification::Notification, PresenceVerifier, Prompts};
 
 pub struct FprintdPresenceVerifier {
     use_system_bus: bool,
     timeout: Duration,
+    prompts: Prompts,
+    /// User whose fingerprints to verify; empty for the calling user.
+    username: String,
 }
 
 const FPRINTD_BUS_NAME: &str = "net.reactivated.Fprint";
@@ -26,7 +29,7 @@ struct VerifyStatus {
 }
 
 /// All possible fprintd verification statuses.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 enum Status {
     Match,
     NoMatch,
@@ -34,24 +37,26 @@ enum Status {
     SwipeTooShort,
     FingerNotCentered,
     RemoveAndRetry,
+    TooFast,
     Disconnected,
     UnknownError,
+    /// A status not known to totpm; treated as a request to retry the scan.
+    Unrecognized(String),
 }
 
-impl FromStr for Status {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl From<&str> for Status {
+    fn from(s: &str) -> Self {
         match s {
-            "verify-match" => Ok(Status::Match),
-            "verify-no-match" => Ok(Status::NoMatch),
-            "verify-retry-scan" => Ok(Status::RetryScan),
-            "verify-swipe-too-short" => Ok(Status::SwipeTooShort),
-            "verify-finger-not-entered" => Ok(Status::FingerNotCentered),
-            "verify-remove-and-retry" => Ok(Status::RemoveAndRetry),
-            "verify-disconnected" => Ok(Status::Disconnected),
-            "verify-unknown-error" => Ok(Status::UnknownError),
-            _ => Err(format!("unknown status: {}", s))
+            "verify-match" => Status::Match,
+            "verify-no-match" => Status::NoMatch,
+            "verify-retry-scan" => Status::RetryScan,
+            "verify-swipe-too-short" => Status::SwipeTooShort,
+            "verify-finger-not-centered" | "verify-finger-not-entered" => Status::FingerNotCentered,
+            "verify-remove-and-retry" => Status::RemoveAndRetry,
+            "verify-too-fast" => Status::TooFast,
+            "verify-disconnected" => Status::Disconnected,
+            "verify-unknown-error" => Status::UnknownError,
+            _ => Status::Unrecognized(s.to_owned()),
         }
     }
 }
@@ -63,10 +68,12 @@ impl Display for Status {
             Status::NoMatch => f.write_str("verify-no-match"),
             Status::RetryScan => f.write_str("verify-retry-scan"),
             Status::SwipeTooShort => f.write_str("verify-swipe-too-short"),
-            Status::FingerNotCentered => f.write_str("verify-finger-not-entered"),
+            Status::FingerNotCentered => f.write_str("verify-finger-not-centered"),
             Status::RemoveAndRetry => f.write_str("verify-remove-and-retry"),
+            Status::TooFast => f.write_str("verify-too-fast"),
             Status::Disconnected => f.write_str("verify-disconnected"),
             Status::UnknownError => f.write_str("verify-unknown-error"),
+            Status::Unrecognized(s) => f.write_str(s),
         }
     }
 }
@@ -74,7 +81,7 @@ impl Display for Status {
 impl ReadAll for VerifyStatus {
     fn read(i: &mut dbus::arg::Iter) -> Result<Self, dbus::arg::TypeMismatchError> {
         Ok(VerifyStatus {
-            status: Status::from_str(i.read::<&str>()?).unwrap(),
+            status: Status::from(i.read::<&str>()?),
             done: i.read()?,
         })
     }
@@ -105,99 +112,137 @@ fn fail<T>(reason: &str) -> super::Result<T> {
     Err(super::Error::ImplementationSpecificError(reason.to_owned()))
 }
 
+/// Shows a prompt on stderr, and in the desktop notification if there is one.
+fn prompt(notification: &mut Option<Notification>, message: &str) {
+    eprintln!("{}", message);
+    if let Some(notification) = notification {
+        notification.update(message);
+    }
+}
+
 impl <'a> FprintDevice<'a> {
-    fn verify(&self, timeout: &Duration) -> super::Result<bool> {
+    fn verify(&self, timeout: &Duration, prompts: &Prompts) -> super::Result<bool> {
         let scan_status = Arc::new(Mutex::new(None));
         let scan_status_clone = scan_status.clone();
         self.proxy.match_signal(move |status: VerifyStatus, _: &Connection, _: &Message| {
             *scan_status.lock().unwrap() = Some(status.status);
             true
-        }).or(fail("fprintd: unable to listen for signal"))?;
+        }).or(fail(&tr!("fprintd-listen-failed")))?;
 
         self.proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "VerifyStart", ("any",))
-            .or(fail("fprintd: unable to start fingerprint verification"))?;
+            .or(fail(&tr!("fprintd-start-failed")))?;
 
-        eprintln!("place your finger on the fingerprint reader");
+        // Without a terminal (e.g. when started from a launcher), stderr goes nowhere;
+        // the notification is closed when verification finishes, one way or another
+        eprintln!("{}", prompts.place_finger);
+        let mut notification = Notification::unless_terminal(&prompts.place_finger);
         let mut time_left = timeout.as_millis() as i64;
         while time_left > 0 {
             let t0 = time::Instant::now();
             self.connection.process(Duration::from_millis(time_left as u64))
-                .or(fail("fprintd: unable to process incoming signals"))?;
+                .or(fail(&tr!("fprintd-process-failed")))?;
             let t1 = time::Instant::now();
             time_left -= (t1 - t0).as_millis() as i64;
 
-            if let Some(status) = *scan_status_clone.lock().unwrap() {
+            if let Some(status) = scan_status_clone.lock().unwrap().take() {
                 match status {
                     Status::Match => {
                         self.proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "VerifyStop", ())
-                            .or(fail("fprintd: unable to stop fingerprint verification"))?;
+                            .or(fail(&tr!("fprintd-stop-failed")))?;
                         return Ok(true)
                     },
                     Status::NoMatch => {
-                        eprintln!("fingerprint not recognized, try again");
+                        prompt(&mut notification, &prompts.no_match);
                         self.proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "VerifyStop", ())
-                            .or(fail("fprintd: unable to stop fingerprint verification"))?;
+                            .or(fail(&tr!("fprintd-stop-failed")))?;
                         self.proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "VerifyStart", ("any",))
-                            .or(fail("fprintd: unable to restart fingerprint verification"))?;
+                            .or(fail(&tr!("fprintd-restart-failed")))?;
                     },
-                    Status::RetryScan | Status::SwipeTooShort | Status::FingerNotCentered | Status::RemoveAndRetry => {
-                        eprintln!("fingerprint not recognized, try again")
-                        // scan is still ongoing, keep waiting for status updates
+                    // For the retry statuses, the scan is still ongoing; keep waiting for status updates
+                    Status::RetryScan => prompt(&mut notification, &prompts.retry),
+                    Status::SwipeTooShort => prompt(&mut notification, &prompts.swipe_too_short),
+                    Status::FingerNotCentered => prompt(&mut notification, &prompts.finger_not_centered),
+                    Status::RemoveAndRetry => prompt(&mut notification, &prompts.remove_and_retry),
+                    Status::TooFast => prompt(&mut notification, &prompts.too_fast),
+                    Status::Unrecognized(status) => {
+                        log::warn!("fprintd: unrecognized verification status '{}'; retrying", status);
+                        prompt(&mut notification, &prompts.retry)
                     },
                     Status::Disconnected => {
-                        return fail("fprintd: fingerprint reader disconnected")
+                        return fail(&tr!("fprintd-disconnected"))
                     },
                     Status::UnknownError => {
                         self.proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "VerifyStop", ())
-                            .or(fail("fprintd: unable to stop fingerprint verification"))?;
-                        return fail("fprintd: fingerprint scan failed with unknown error")
+                            .or(fail(&tr!("fprintd-stop-failed")))?;
+                        return fail(&tr!("fprintd-unknown-error"))
                     },
                 }
     
             }
         }
         self.proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "VerifyStop", ())
-            .or(fail("fprintd: unable to stop fingerprint verification"))?;
+            .or(fail(&tr!("fprintd-stop-failed")))?;
         Ok(false)
     }
 
-    /// Finds the default fingerprint scanner, claims it, and returns a release-on-drop proxy object for it.
-    fn claim_default_device(conn: &'a Connection) -> super::Result<Self> {
-        let mgr_proxy = conn.with_proxy(
-            FPRINTD_BUS_NAME,
-            FPRINTD_MANAGER_PATH,
-            Duration::from_secs(10),
-        );
-        let (device_path,): (Path,) = mgr_proxy.method_call(FPRINTD_MANAGER_IFACE, "GetDefaultDevice", ())
-            .or(Err(super::Error::ImplementationSpecificError("fprintd: couldn't get default device".to_owned())))?;
+    /// Finds the default fingerprint scanner, claims it for the given user (the calling user if empty),
+    /// and returns a release-on-drop proxy object for it.
+    fn claim_default_device(conn: &'a Connection, username: &str) -> super::Result<Self> {
+        let device_path = default_device_path(conn)?;
         let proxy = conn.with_proxy(
             FPRINTD_BUS_NAME,
             device_path,
             Duration::from_secs(10),
         );
-        proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "Claim", ("",))
-            .or(Err(super::Error::ImplementationSpecificError("fprintd: unable to claim device".to_owned())))?;
+        proxy.method_call::<(), _, _, _>(FPRINTD_DEVICE_IFACE, "Claim", (username,))
+            .or(Err(super::Error::ImplementationSpecificError(tr!("fprintd-claim-failed"))))?;
         Ok(FprintDevice { proxy, connection: conn })
     }    
 }
 
+/// Returns the object path of the default fingerprint scanner.
+fn default_device_path(conn: &Connection) -> super::Result<Path<'static>> {
+    let mgr_proxy = conn.with_proxy(
+        FPRINTD_BUS_NAME,
+        FPRINTD_MANAGER_PATH,
+        Duration::from_secs(10),
+    );
+    let (device_path,): (Path,) = mgr_proxy.method_call(FPRINTD_MANAGER_IFACE, "GetDefaultDevice", ())
+        .or(Err(super::Error::ImplementationSpecificError(tr!("fprintd-no-default-device"))))?;
+    Ok(device_path)
+}
+
+/// Returns the fingers the given user (the calling user if empty) has enrolled on the default fingerprint scanner.
+pub fn enrolled_fingers(username: &str) -> super::Result<Vec<String>> {
+    let _euid = EuidSwapGuard::real_user()
+        .map_err(|e| super::Error::ImplementationSpecificError(format!("fprintd: {}", e)))?;
+    let conn = Connection::new_system()
+        .or(Err(super::Error::ImplementationSpecificError(tr!("fprintd-bus-unavailable"))))?;
+    let proxy = conn.with_proxy(FPRINTD_BUS_NAME, default_device_path(&conn)?, Duration::from_secs(10));
+    match proxy.method_call::<(Vec<String>,), _, _, _>(FPRINTD_DEVICE_IFACE, "ListEnrolledFingers", (username,)) {
+        Ok((fingers,)) => Ok(fingers),
+        Err(e) if e.name() == Some("net.reactivated.Fprint.Error.NoEnrolledPrints") => Ok(Vec::new()),
+        Err(e) => fail(&tr!("fprintd-list-fingers-failed", error = e.to_string())),
+    }
+}
+
 impl PresenceVerifier for FprintdPresenceVerifier {
     fn owner_present(&mut self) -> super::Result<bool> {
-        with_uid_as_euid(|| {
-            let conn = if self.use_system_bus {
-                Connection::new_system()
-            } else {
-                Connection::new_session()
-            }.or(Err(super::Error::ImplementationSpecificError("fprintd: couldn't connect to bus".to_owned())))?;
-            let dev = FprintDevice::claim_default_device(&conn)?;
-            dev.verify(&self.timeout)
-        })
+        let _euid = EuidSwapGuard::real_user()
+            .map_err(|e| super::Error::ImplementationSpecificError(format!("fprintd: {}", e)))?;
+        let conn = if self.use_system_bus {
+            Connection::new_system()
+        } else {
+            Connection::new_session()
+        }.or(Err(super::Error::ImplementationSpecificError(tr!("fprintd-bus-unavailable"))))?;
+        let dev = FprintDevice::claim_default_device(&conn, &self.username)?;
+        dev.verify(&self.timeout, &self.prompts)
     }
 }
 
 impl FprintdPresenceVerifier {
-    pub fn new(timeout_secs: u8) -> Self {
-        FprintdPresenceVerifier { use_system_bus: true, timeout: Duration::from_secs(timeout_secs as u64) }
+    pub fn new(timeout: Duration, prompts: Prompts, username: Option<String>) -> Self {
+        FprintdPresenceVerifier { use_system_bus: true, timeout, prompts, username: username.unwrap_or_default() }
     }
 }
 
@@ -214,6 +259,8 @@ mod tests {
         FprintdPresenceVerifier {
             use_system_bus: false,
             timeout: Duration::from_secs(1),
+            prompts: Prompts::default(),
+            username: String::new(),
         }
     }
 
@@ -240,6 +287,22 @@ mod tests {
         assert_eq!(error, presence_verification::Error::ImplementationSpecificError("fprintd: unable to claim device".to_owned()))
     }
 
+    #[test]
+    #[serial]
+    fn configured_user_is_claimed_for() {
+        let _mock = FprintdMockBuilder::new()
+            .expect_method(FprintdMethod::GetDefaultDevice(Ok(DEVICE_PATH.to_owned())))
+            .expect_method(FprintdMethod::Claim("alice".to_owned(), Ok(())))
+            .expect_method(FprintdMethod::VerifyStart("any".to_owned(), Ok(())))
+            .wait(Duration::from_millis(100))
+            .send_status(Status::Match, true)
+            .expect_method(FprintdMethod::VerifyStop(Ok(())))
+            .expect_method(FprintdMethod::Release(Ok(())))
+            .build();
+        let mut pv = FprintdPresenceVerifier { username: "alice".to_owned(), ..new_session_verifier() };
+        assert!(pv.owner_present().unwrap());
+    }
+
     #[test]
     #[serial]
     fn failed_verifystart_fails_presence_verification() {
@@ -322,6 +385,31 @@ mod tests {
         assert_eq!(pv.owner_present().unwrap(), true);
     }
 
+    #[test]
+    #[serial]
+    fn unrecognized_status_followed_by_match_makes_presence_verification_succeed() {
+        let _mock = FprintdMockBuilder::new()
+            .expect_method(FprintdMethod::GetDefaultDevice(Ok(DEVICE_PATH.to_owned())))
+            .expect_method(FprintdMethod::Claim("".to_owned(), Ok(())))
+            .expect_method(FprintdMethod::VerifyStart("any".to_owned(), Ok(())))
+            .wait(Duration::from_millis(100))
+            .send_status(Status::Unrecognized("verify-something-new".to_owned()), false)
+            .wait(Duration::from_millis(100))
+            .send_status(Status::Match, true)
+            .expect_method(FprintdMethod::VerifyStop(Ok(())))
+            .expect_method(FprintdMethod::Release(Ok(())))
+            .build();
+        let mut pv = new_session_verifier();
+        assert!(pv.owner_present().unwrap());
+    }
+
+    #[test]
+    fn status_parses_known_and_unknown_strings() {
+        assert!(matches!(Status::from("verify-too-fast"), Status::TooFast));
+        assert!(matches!(Status::from("verify-finger-not-centered"), Status::FingerNotCentered));
+        assert!(matches!(Status::from("verify-whatever"), Status::Unrecognized(s) if s == "verify-whatever"));
+    }
+
     #[test]
     #[serial]
     fn disconnected_makes_presence_verification_fail() {
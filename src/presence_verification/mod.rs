@@ -2,8 +2,15 @@ use std::str::FromStr;
 
 use serde::{de::IntoDeserializer, Deserialize, Serialize};
 
+use crate::tr;
+
+#[cfg(feature = "fprintd")]
 pub mod fprintd;
+#[cfg(feature = "fprintd")]
+mod notification;
 pub mod factory;
+pub mod pin;
+pub mod signer;
 
 #[derive(Debug)]
 #[derive(PartialEq)]
@@ -17,6 +24,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[serde(rename_all = "snake_case")]
 pub enum PresenceVerificationMethod {
     Fprintd,
+    /// A PIN, whose salted hash is kept with the system data.
+    Pin,
     None,
     #[cfg(test)]
     AlwaysFail,
@@ -31,6 +40,76 @@ impl FromStr for PresenceVerificationMethod {
     type Err = crate::result::Error;
 }
 
+/// The presence verification method(s) to use.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PresenceVerificationMethods {
+    /// A single method.
+    One(PresenceVerificationMethod),
+    /// Methods to try in order, falling back on the next one when a method fails or times out.
+    FirstOf(Vec<PresenceVerificationMethod>),
+}
+
+impl PresenceVerificationMethods {
+    pub fn methods(&self) -> &[PresenceVerificationMethod] {
+        match self {
+            PresenceVerificationMethods::One(method) => std::slice::from_ref(method),
+            PresenceVerificationMethods::FirstOf(methods) => methods,
+        }
+    }
+
+    /// Returns true if the given method is one of the methods tried.
+    pub fn uses(&self, method: PresenceVerificationMethod) -> bool {
+        self.methods().contains(&method)
+    }
+}
+
+impl From<PresenceVerificationMethod> for PresenceVerificationMethods {
+    fn from(method: PresenceVerificationMethod) -> Self {
+        PresenceVerificationMethods::One(method)
+    }
+}
+
+/// User-facing messages shown during presence verification.
+/// Any message not set in the config file keeps its default, in the user's language.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Prompts {
+    pub place_finger: String,
+    pub no_match: String,
+    pub retry: String,
+    pub swipe_too_short: String,
+    pub finger_not_centered: String,
+    pub remove_and_retry: String,
+    pub too_fast: String,
+    pub enter_pin: String,
+    pub wrong_pin: String,
+}
+
+impl Default for Prompts {
+    fn default() -> Self {
+        Prompts {
+            place_finger: tr!("pv-place-finger"),
+            no_match: tr!("pv-no-match"),
+            retry: tr!("pv-retry"),
+            swipe_too_short: tr!("pv-swipe-too-short"),
+            finger_not_centered: tr!("pv-finger-not-centered"),
+            remove_and_retry: tr!("pv-remove-and-retry"),
+            too_fast: tr!("pv-too-fast"),
+            enter_pin: tr!("pv-enter-pin"),
+            wrong_pin: tr!("pv-wrong-pin"),
+        }
+    }
+}
+
+impl Prompts {
+    /// Returns true if no message differs from its default. Default messages aren't written to config files,
+    /// so that every user sees them in their own language.
+    pub fn is_default(&self) -> bool {
+        *self == Prompts::default()
+    }
+}
+
 pub trait PresenceVerifier {
     fn owner_present(&mut self) -> Result<bool>;
 }
@@ -49,6 +128,32 @@ impl PresenceVerifier for ConstPresenceVerifier {
     }
 }
 
+/// Tries each of its verifiers in order until one of them verifies presence.
+/// A verifier which fails with an error, e.g. because its device is missing, is skipped.
+/// If no verifier succeeds, the result of the last one is returned.
+pub struct FirstOfPresenceVerifier(Vec<Box<dyn PresenceVerifier>>);
+
+impl FirstOfPresenceVerifier {
+    pub fn new(verifiers: Vec<Box<dyn PresenceVerifier>>) -> Self {
+        FirstOfPresenceVerifier(verifiers)
+    }
+}
+
+impl PresenceVerifier for FirstOfPresenceVerifier {
+    fn owner_present(&mut self) -> Result<bool> {
+        let mut result = Ok(false);
+        for verifier in self.0.iter_mut() {
+            result = verifier.owner_present();
+            match &result {
+                Ok(true) => break,
+                Ok(false) => log::info!("presence verification failed; trying next method"),
+                Err(e) => log::warn!("presence verification error: {:?}; trying next method", e),
+            }
+        }
+        result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,6 +161,7 @@ mod tests {
     #[test]
     fn pv_method_deserializes_correctly() {
         assert_eq!(PresenceVerificationMethod::from_str("fprintd").unwrap(), PresenceVerificationMethod::Fprintd);
+        assert_eq!(PresenceVerificationMethod::from_str("pin").unwrap(), PresenceVerificationMethod::Pin);
         assert_eq!(PresenceVerificationMethod::from_str("none").unwrap(), PresenceVerificationMethod::None);
         let invalid_values = vec!["FPRINTD", "", "fprintd ", " fprintd", " fprintd ", "no"];
         for v in invalid_values {
@@ -66,4 +172,38 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn pv_methods_deserialize_from_one_method_or_list() {
+        #[derive(Deserialize)]
+        struct Cfg {
+            pv_method: PresenceVerificationMethods,
+        }
+        let one: Cfg = toml::from_str("pv_method = \"pin\"").unwrap();
+        assert_eq!(one.pv_method.methods(), &[PresenceVerificationMethod::Pin]);
+        let chain: Cfg = toml::from_str("pv_method = [\"fprintd\", \"pin\"]").unwrap();
+        assert_eq!(chain.pv_method.methods(), &[PresenceVerificationMethod::Fprintd, PresenceVerificationMethod::Pin]);
+        assert!(chain.pv_method.uses(PresenceVerificationMethod::Pin));
+        assert!(!chain.pv_method.uses(PresenceVerificationMethod::None));
+        assert!(toml::from_str::<Cfg>("pv_method = [\"fprintd\", \"face\"]").is_err());
+    }
+
+    #[test]
+    fn first_of_falls_back_on_failure_or_error() {
+        struct Failing;
+        impl PresenceVerifier for Failing {
+            fn owner_present(&mut self) -> Result<bool> {
+                Err(Error::ImplementationSpecificError("no reader".to_owned()))
+            }
+        }
+        let mut pv = FirstOfPresenceVerifier::new(vec![Box::new(Failing), Box::new(ConstPresenceVerifier::new(true))]);
+        assert!(pv.owner_present().unwrap());
+        let mut pv = FirstOfPresenceVerifier::new(vec![Box::new(ConstPresenceVerifier::new(false)), Box::new(ConstPresenceVerifier::new(true))]);
+        assert!(pv.owner_present().unwrap());
+        let mut pv = FirstOfPresenceVerifier::new(vec![Box::new(ConstPresenceVerifier::new(true)), Box::new(Failing)]);
+        assert!(pv.owner_present().unwrap());
+        let mut pv = FirstOfPresenceVerifier::new(vec![Box::new(ConstPresenceVerifier::new(false)), Box::new(Failing)]);
+        assert!(pv.owner_present().is_err());
+        assert!(!FirstOfPresenceVerifier::new(Vec::new()).owner_present().unwrap());
+    }
 }
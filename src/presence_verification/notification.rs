@@ -0,0 +1,77 @@
+use std::{collections::HashMap, io::IsTerminal, time::Duration};
+
+use dbus::{arg::{PropMap, Variant}, blocking::Connection};
+
+use crate::privileges::EuidSwapGuard;
+
+const NOTIFICATIONS_BUS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+const NOTIFICATIONS_IFACE: &str = "org.freedesktop.Notifications";
+
+/// Notification urgency hint; critical notifications aren't expired by the notification server.
+const URGENCY_CRITICAL: u8 = 2;
+
+/// A freedesktop desktop notification, which is closed when dropped.
+pub struct Notification {
+    connection: Connection,
+    id: u32,
+}
+
+impl Notification {
+    /// Shows a notification with the given body, unless stderr is a terminal, where prompts are already visible.
+    /// Failing to show the notification is not an error; there may not be any notification server running.
+    pub fn unless_terminal(body: &str) -> Option<Self> {
+        if std::io::stderr().is_terminal() {
+            return None
+        }
+        // Presence is verified before privileges are dropped, but the session bus belongs to the user who ran totpm
+        let connection = match EuidSwapGuard::real_user().map_err(|e| e.to_string())
+            .and_then(|_euid| Connection::new_session().map_err(|e| e.to_string()))
+        {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::debug!("unable to connect to session bus for notifications: {}", e);
+                return None
+            },
+        };
+        let mut notification = Notification { connection, id: 0 };
+        match notification.notify(body) {
+            Ok(()) => Some(notification),
+            Err(e) => {
+                log::debug!("unable to show notification: {}", e);
+                None
+            },
+        }
+    }
+
+    /// Replaces the body of the notification.
+    pub fn update(&mut self, body: &str) {
+        if let Err(e) = self.notify(body) {
+            log::debug!("unable to update notification: {}", e);
+        }
+    }
+
+    fn notify(&mut self, body: &str) -> Result<(), dbus::Error> {
+        let mut hints: PropMap = HashMap::new();
+        hints.insert("urgency".to_owned(), Variant(Box::new(URGENCY_CRITICAL)));
+        let (id,): (u32,) = self.proxy().method_call(
+            NOTIFICATIONS_IFACE,
+            "Notify",
+            ("totpm", self.id, "fingerprint", "totpm", body, Vec::<&str>::new(), hints, -1i32),
+        )?;
+        self.id = id;
+        Ok(())
+    }
+
+    fn proxy(&self) -> dbus::blocking::Proxy<'_, &Connection> {
+        self.connection.with_proxy(NOTIFICATIONS_BUS_NAME, NOTIFICATIONS_PATH, Duration::from_secs(5))
+    }
+}
+
+impl Drop for Notification {
+    fn drop(&mut self) {
+        if let Err(e) = self.proxy().method_call::<(), _, _, _>(NOTIFICATIONS_IFACE, "CloseNotification", (self.id,)) {
+            log::debug!("unable to close notification: {}", e);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    env,
+    fs::Permissions,
+    io::{self, IsTerminal, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use argon2::{password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
+
+use crate::{privileges::EuidSwapGuard, rng};
+
+use super::{PresenceVerifier, Prompts};
+
+/// Environment variable naming a program to ask for the PIN when there's no terminal, like SSH_ASKPASS.
+/// The program is given the prompt as its only argument, and should print the PIN on stdout.
+pub const ASKPASS_ENV_VAR: &str = "TOTPM_ASKPASS";
+
+/// Number of wrong PINs in a row after which each further try has to wait.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Wait after the first wrong PIN past MAX_ATTEMPTS, doubling with every further wrong PIN.
+const BACKOFF_BASE: Duration = Duration::from_secs(30);
+
+/// Longest wait between tries.
+const BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// Verifies presence by asking for a PIN, which is checked against its argon2 hash.
+/// Wrong PINs are counted in a file next to the hash, so that the backoff survives across invocations.
+pub struct PinPresenceVerifier {
+    hash_path: PathBuf,
+    failures_path: PathBuf,
+    prompts: Prompts,
+}
+
+fn fail<T>(reason: String) -> super::Result<T> {
+    Err(super::Error::ImplementationSpecificError(reason))
+}
+
+impl PinPresenceVerifier {
+    pub fn new(hash_path: PathBuf, failures_path: PathBuf, prompts: Prompts) -> Self {
+        PinPresenceVerifier { hash_path, failures_path, prompts }
+    }
+
+    /// Returns the number of wrong PINs in a row and when the last one was entered.
+    fn failures(&self) -> (u32, SystemTime) {
+        let failures = std::fs::read_to_string(&self.failures_path).ok().and_then(|failures| {
+            let (count, last) = failures.trim().split_once(' ')?;
+            Some((count.parse().ok()?, UNIX_EPOCH + Duration::from_secs(last.parse().ok()?)))
+        });
+        failures.unwrap_or((0, UNIX_EPOCH))
+    }
+
+    fn record_failure(&self, count: u32) -> super::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.failures_path)
+            .and_then(|mut file| file.write_all(format!("{} {}\n", count, now).as_bytes()));
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => fail(format!("pin: unable to write {}: {}", self.failures_path.to_str().unwrap(), e)),
+        }
+    }
+}
+
+/// Returns how long to wait after the given number of wrong PINs in a row.
+fn backoff(failures: u32) -> Duration {
+    match failures.checked_sub(MAX_ATTEMPTS) {
+        None => Duration::ZERO,
+        Some(excess) => BACKOFF_BASE.checked_mul(1 << excess.min(16)).unwrap_or(BACKOFF_MAX).min(BACKOFF_MAX),
+    }
+}
+
+impl PresenceVerifier for PinPresenceVerifier {
+    fn owner_present(&mut self) -> super::Result<bool> {
+        let hash = match std::fs::read_to_string(&self.hash_path) {
+            Ok(hash) => hash,
+            Err(e) => return fail(format!("pin: unable to read {}: {}", self.hash_path.to_str().unwrap(), e)),
+        };
+        for attempt in 0..MAX_ATTEMPTS {
+            if attempt > 0 {
+                eprintln!("{}", self.prompts.wrong_pin);
+            }
+            let (failures, last_failure) = self.failures();
+            let wait = (last_failure + backoff(failures))
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            if !wait.is_zero() {
+                return fail(format!("pin: too many wrong PINs; try again in {} seconds", wait.as_secs() + 1))
+            }
+            let pin = match read_pin(&self.prompts.enter_pin) {
+                Ok(pin) => pin,
+                Err(e) => return fail(format!("pin: unable to read PIN: {}", e)),
+            };
+            if verify_pin(&hash, &pin)? {
+                if failures > 0 {
+                    let _ = std::fs::remove_file(&self.failures_path);
+                }
+                return Ok(true)
+            }
+            self.record_failure(failures + 1)?;
+        }
+        Ok(false)
+    }
+}
+
+/// Reads a PIN from the terminal, or using the askpass program if there is no terminal and one is set.
+fn read_pin(prompt: &str) -> io::Result<String> {
+    match env::var_os(ASKPASS_ENV_VAR) {
+        Some(askpass) if !io::stdin().is_terminal() => {
+            // The askpass program is chosen by the user, so it must not run with our privileges
+            let _euid = EuidSwapGuard::real_user()?;
+            let output = Command::new(askpass).arg(prompt).output()?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!("{} failed: {}", ASKPASS_ENV_VAR, output.status)))
+            }
+            let pin = String::from_utf8(output.stdout).map_err(io::Error::other)?;
+            Ok(pin.trim_end_matches(['\r', '\n']).to_owned())
+        },
+        _ => {
+            io::stderr().flush()?;
+            rpassword::prompt_password(prompt)
+        },
+    }
+}
+
+/// Hashes the given PIN with a random salt, returning the hash in PHC string format.
+pub fn hash_pin(pin: &str) -> super::Result<String> {
+    let mut salt = [0u8; 16];
+    rng::fill_bytes(&mut salt);
+    let salt = match SaltString::encode_b64(&salt) {
+        Ok(salt) => salt,
+        Err(e) => return fail(format!("pin: unable to encode salt: {}", e)),
+    };
+    match Argon2::default().hash_password(pin.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => fail(format!("pin: unable to hash PIN: {}", e)),
+    }
+}
+
+fn verify_pin(hash: &str, pin: &str) -> super::Result<bool> {
+    let hash = match PasswordHash::new(hash.trim()) {
+        Ok(hash) => hash,
+        Err(e) => return fail(format!("pin: invalid PIN hash: {}", e)),
+    };
+    Ok(Argon2::default().verify_password(pin.as_bytes(), &hash).is_ok())
+}
+
+/// Hashes the given PIN and writes the hash to the given path, readable only by its owner.
+pub fn write_pin_hash(path: &Path, pin: &str) -> io::Result<()> {
+    let hash = hash_pin(pin).map_err(|super::Error::ImplementationSpecificError(e)| io::Error::other(e))?;
+    let mut file = std::fs::File::create(path)?;
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(hash.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn hashed_pin_verifies_only_against_same_pin() {
+        let hash = hash_pin("1234").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_pin(&hash, "1234").unwrap());
+        assert!(!verify_pin(&hash, "4321").unwrap());
+        assert!(!verify_pin(&hash, "").unwrap());
+    }
+
+    #[test]
+    fn same_pin_gets_different_salts() {
+        assert_ne!(hash_pin("1234").unwrap(), hash_pin("1234").unwrap());
+    }
+
+    #[test]
+    fn missing_hash_file_fails_presence_verification() {
+        let dir = tempdir().unwrap();
+        let mut pv = PinPresenceVerifier::new(dir.path().join("pin_hash"), dir.path().join("pin_failures"), Prompts::default());
+        assert!(pv.owner_present().is_err());
+    }
+
+    #[test]
+    fn backoff_starts_after_max_attempts_and_is_capped() {
+        assert_eq!(backoff(0), Duration::ZERO);
+        assert_eq!(backoff(MAX_ATTEMPTS - 1), Duration::ZERO);
+        assert_eq!(backoff(MAX_ATTEMPTS), BACKOFF_BASE);
+        assert_eq!(backoff(MAX_ATTEMPTS + 1), BACKOFF_BASE * 2);
+        assert_eq!(backoff(MAX_ATTEMPTS + 100), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn failures_persist_across_verifiers_and_lock_out_further_tries() {
+        let dir = tempdir().unwrap();
+        write_pin_hash(&dir.path().join("pin_hash"), "1234").unwrap();
+        let pv = || PinPresenceVerifier::new(dir.path().join("pin_hash"), dir.path().join("pin_failures"), Prompts::default());
+        for count in 1..=MAX_ATTEMPTS {
+            pv().record_failure(count).unwrap();
+        }
+        assert_eq!(pv().failures().0, MAX_ATTEMPTS);
+        assert_eq!(dir.path().join("pin_failures").metadata().unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Locked out before the PIN is even asked for
+        match pv().owner_present() {
+            Err(super::super::Error::ImplementationSpecificError(e)) => assert!(e.contains("too many wrong PINs"), "{}", e),
+            result => panic!("wrong result: {:#?}", result),
+        }
+    }
+
+    #[test]
+    fn pin_hash_is_only_readable_by_owner() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pin_hash");
+        write_pin_hash(&path, "1234").unwrap();
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(verify_pin(&std::fs::read_to_string(&path).unwrap(), "1234").unwrap());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{io::Write, path::PathBuf, process::{Command, Stdio}};
+
+use serde_derive::{Deserialize, Serialize};
+use tss_esapi::{structures::{Digest, Public, Signature}, traits::UnMarshall};
+
+use crate::totp_store::{hex_decode, hex_encode};
+
+use super::{Error, Result};
+
+/// Verifies user presence outside of totpm, and proves it to the TPM by signing an authorization
+/// with a key that totpm has no access to.
+pub trait PresenceSigner {
+    /// Verifies the user's presence, then signs the given digest. Returns None if the user is not present.
+    fn sign(&mut self, digest: &Digest) -> Result<Option<Signature>>;
+}
+
+impl std::fmt::Debug for dyn PresenceSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PresenceSigner")
+    }
+}
+
+/// Presence verification enforced by the TPM, configured using a [pv_policy] section.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PolicyConfig {
+    /// File containing the hex-encoded, marshalled public area of the verifier's signing key.
+    pub verifier_key: PathBuf,
+
+    /// Command, with arguments, which verifies user presence and signs the hex-encoded SHA-256 digest
+    /// given on its standard input. It should print the hex-encoded, marshalled signature and exit with
+    /// status 0 if the user is present, and exit with any other status otherwise.
+    pub signer: Vec<String>,
+}
+
+impl PolicyConfig {
+    /// Reads the public key of the verifier.
+    pub fn read_verifier_key(&self) -> Result<Public> {
+        let contents = std::fs::read_to_string(&self.verifier_key).map_err(|e| Error::ImplementationSpecificError(
+            format!("unable to read verifier key {}: {}", self.verifier_key.to_str().unwrap(), e)
+        ))?;
+        hex_decode(contents.trim())
+            .and_then(|data| Public::unmarshall(&data).ok())
+            .ok_or(Error::ImplementationSpecificError(
+                format!("malformed verifier key in {}", self.verifier_key.to_str().unwrap())
+            ))
+    }
+
+    /// Returns a signer running the configured command.
+    pub fn signer(&self) -> CommandSigner {
+        CommandSigner { command: self.signer.clone() }
+    }
+}
+
+/// Gets authorizations signed by running an external command.
+pub struct CommandSigner {
+    command: Vec<String>,
+}
+
+impl PresenceSigner for CommandSigner {
+    fn sign(&mut self, digest: &Digest) -> Result<Option<Signature>> {
+        let (program, args) = self.command.split_first()
+            .ok_or(Error::ImplementationSpecificError("no signer command configured".to_owned()))?;
+        log::info!("running presence signer {}", program);
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::ImplementationSpecificError(format!("unable to run {}: {}", program, e)))?;
+        let mut stdin = child.stdin.take().unwrap();
+        let written = writeln!(stdin, "{}", hex_encode(digest.value()));
+        drop(stdin);
+        let output = child.wait_with_output()
+            .map_err(|e| Error::ImplementationSpecificError(format!("{} failed: {}", program, e)))?;
+        if !output.status.success() {
+            log::warn!("{} did not verify presence: {}", program, output.status);
+            return Ok(None)
+        }
+        written.map_err(|e| Error::ImplementationSpecificError(format!("unable to write to {}: {}", program, e)))?;
+        String::from_utf8(output.stdout).ok()
+            .and_then(|stdout| hex_decode(stdout.trim()))
+            .and_then(|data| Signature::unmarshall(&data).ok())
+            .map(Some)
+            .ok_or(Error::ImplementationSpecificError(format!("{} printed a malformed signature", program)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_signer_reports_absent_user_on_failure() {
+        let mut signer = CommandSigner { command: vec!["false".to_owned()] };
+        assert_eq!(signer.sign(&Digest::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn command_signer_rejects_malformed_signature() {
+        let mut signer = CommandSigner { command: vec!["echo".to_owned(), "xyz".to_owned()] };
+        assert!(signer.sign(&Digest::default()).is_err());
+    }
+}
@@ -1,46 +1,283 @@
+use std::io;
+
 #[link(name = "c")]
 extern "C" {
-    fn setresuid(ruid: u32, euid: u32, suid: u32) -> u32;
+    fn setresuid(ruid: u32, euid: u32, suid: u32) -> i32;
     fn getuid() -> u32;
     fn getgid() -> u32;
     fn geteuid() -> u32;
-    fn seteuid(uid: u32) -> u32;
-    fn setegid(uid: u32) -> u32;
+    fn getegid() -> u32;
+    fn seteuid(uid: u32) -> i32;
+    fn setegid(gid: u32) -> i32;
+    fn capget(header: *mut CapHeader, data: *mut CapData) -> i32;
+    fn capset(header: *mut CapHeader, data: *const CapData) -> i32;
+    fn prctl(option: i32, ...) -> i32;
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+const PR_CAP_AMBIENT: i32 = 47;
+const PR_CAP_AMBIENT_CLEAR_ALL: u64 = 4;
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+
+/// Capabilities needed to read the system data directory and open the TPM device without being setuid.
+const NEEDED_CAPABILITIES: &[u32] = &[CAP_DAC_OVERRIDE, CAP_DAC_READ_SEARCH];
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
 }
 
-/// Set all UIDs to our real UID, dropping any SUID-acquired privileges.
-/// Returns true if dropping privileges succeeded, otherwise false.
-pub fn drop_privileges() -> bool {
-    log::info!("permanently dropping privileges");
+/// Capability sets of the current thread; capabilities 0-31 in the first element, 32-63 in the second.
+type Capabilities = [CapData; 2];
+
+/// Turns the return value of a system call into an error, if it failed.
+fn check(syscall: &str, result: i32) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        let e = io::Error::last_os_error();
+        Err(io::Error::new(e.kind(), format!("{} failed: {}", syscall, e)))
+    }
+}
+
+/// Makes a UID or GID changing system call, unless a test has made it fail.
+fn call(syscall: &'static str, f: impl FnOnce() -> i32) -> io::Result<()> {
+    #[cfg(test)]
+    if tests::is_failing(syscall) {
+        let e = io::Error::from_raw_os_error(libc::EPERM);
+        return Err(io::Error::new(e.kind(), format!("{} failed: {}", syscall, e)))
+    }
+    check(syscall, f())
+}
+
+fn get_capabilities() -> io::Result<Capabilities> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut caps = Capabilities::default();
     unsafe {
-        let euid = geteuid();
+        check("capget", capget(&mut header, caps.as_mut_ptr()))?;
+    }
+    Ok(caps)
+}
 
-        // Drop privileges
-        let gid = getgid();
-        let uid = getuid();
-        setegid(gid);
-        setresuid(uid, uid, uid);
-        
-        // Ensure we can't change back to our old EUID
-        seteuid(euid);
-        geteuid() == uid
+fn set_capabilities(caps: &Capabilities) -> io::Result<()> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    unsafe {
+        check("capset", capset(&mut header, caps.as_ptr()))
+    }
+}
+
+/// Returns the given capability sets with everything but the given capabilities removed.
+/// Nothing is kept in the inheritable set, so that child processes don't get any capabilities.
+fn retain(caps: &Capabilities, keep: &[u32]) -> Capabilities {
+    let mut mask = [0u32; 2];
+    for cap in keep {
+        mask[(*cap / 32) as usize] |= 1 << (cap % 32);
+    }
+    let mut result = *caps;
+    for (data, mask) in result.iter_mut().zip(mask) {
+        data.effective &= mask;
+        data.permitted &= mask;
+        data.inheritable = 0;
     }
+    result
 }
 
-/// Temporarily assume our real UID as our effective UID.
-pub fn with_uid_as_euid<T, F: FnOnce() -> T>(f: F) -> T {
+/// Returns the given capability sets with an empty effective set, which is enough to act as an ordinary user
+/// while keeping the permitted capabilities around.
+fn without_effective(caps: &Capabilities) -> Capabilities {
+    caps.map(|data| CapData { effective: 0, ..data })
+}
+
+/// Returns true if we get our privileges from capabilities, e.g. granted using systemd's AmbientCapabilities,
+/// rather than from being setuid. Root is considered to be running with full privileges rather than capabilities.
+pub fn uses_capabilities() -> bool {
     unsafe {
-        let uid = getuid();
-        let euid = geteuid();
-        log::info!("setting euid to {} (was {})", uid, euid);
-        seteuid(uid);
-        let result = f();
-        log::info!("restoring euid to {} (was {})", euid, uid);
-        seteuid(euid);
-        result
+        if getuid() == 0 || getuid() != geteuid() {
+            return false
+        }
+    }
+    get_capabilities().is_ok_and(|caps| caps.iter().any(|data| data.permitted != 0))
+}
+
+/// If we get our privileges from capabilities, drops all but those needed to read the system data directory
+/// and open the TPM device, and makes sure they're not passed on to child processes.
+/// Should be called before doing anything else.
+pub fn restrict_capabilities() -> io::Result<()> {
+    if !uses_capabilities() {
+        return Ok(())
+    }
+    log::info!("restricting capabilities to what is needed to access the tpm");
+    set_capabilities(&retain(&get_capabilities()?, NEEDED_CAPABILITIES))?;
+    unsafe {
+        check("prctl", prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, 0u64, 0u64, 0u64))
+    }
+}
+
+/// Assumes our real UID and GID as our effective ones, dropping any SUID-acquired privileges
+/// until the guard is dropped. If we get our privileges from capabilities, the effective capability set
+/// is cleared instead. Use forever to make the drop irreversible.
+#[must_use]
+#[derive(Debug)]
+pub struct PrivilegeDropGuard {
+    euid: u32,
+    egid: u32,
+    capabilities: Option<Capabilities>,
+}
+
+impl PrivilegeDropGuard {
+    pub fn new() -> io::Result<Self> {
+        unsafe {
+            let mut guard = PrivilegeDropGuard { euid: geteuid(), egid: getegid(), capabilities: None };
+            if uses_capabilities() {
+                log::info!("dropping effective capabilities");
+                let caps = get_capabilities()?;
+                set_capabilities(&without_effective(&caps))?;
+                guard.capabilities = Some(caps);
+                return Ok(guard)
+            }
+            log::info!("dropping privileges (euid was {})", guard.euid);
+            call("setegid", || setegid(getgid()))?;
+            call("seteuid", || seteuid(getuid()))?;
+            Ok(guard)
+        }
+    }
+
+    /// Sets all UIDs to our real UID, or clears all capability sets, so that the dropped privileges
+    /// can never be regained.
+    pub fn forever(self) -> io::Result<()> {
+        log::info!("permanently dropping privileges");
+        let euid = self.euid;
+        let capabilities = self.capabilities;
+        std::mem::forget(self);
+        if capabilities.is_some() {
+            return set_capabilities(&Capabilities::default())
+        }
+        unsafe {
+            let uid = getuid();
+            call("setresuid", || setresuid(uid, uid, uid))?;
+
+            // Ensure we can't change back to our old EUID
+            if uid != euid && seteuid(euid) == 0 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "privileges could be regained after dropping them"))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PrivilegeDropGuard {
+    fn restore(&self) -> io::Result<()> {
+        match &self.capabilities {
+            Some(caps) => {
+                log::info!("restoring effective capabilities");
+                set_capabilities(caps)
+            },
+            None => {
+                log::info!("restoring privileges (euid {})", self.euid);
+                unsafe {
+                    call("seteuid", || seteuid(self.euid)).and_then(|_| call("setegid", || setegid(self.egid)))
+                }
+            },
+        }
+    }
+}
+
+impl Drop for PrivilegeDropGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore() {
+            log::warn!("unable to restore privileges: {}", e);
+        }
     }
 }
 
+/// Temporarily assumes the given UID as our effective UID, restoring the previous one when dropped.
+/// Assuming any UID other than the real one is only possible when running as root.
+/// If we get our privileges from capabilities, assuming the real UID also clears the effective capability set,
+/// so that files are accessed with the user's permissions.
+#[must_use]
+#[derive(Debug)]
+pub struct EuidSwapGuard {
+    euid: u32,
+    capabilities: Option<Capabilities>,
+}
+
+impl EuidSwapGuard {
+    pub fn new(uid: u32) -> io::Result<Self> {
+        unsafe {
+            let mut guard = EuidSwapGuard { euid: geteuid(), capabilities: None };
+            if uid == guard.euid && uses_capabilities() {
+                log::info!("dropping effective capabilities to act as uid {}", uid);
+                let caps = get_capabilities()?;
+                set_capabilities(&without_effective(&caps))?;
+                guard.capabilities = Some(caps);
+                return Ok(guard)
+            }
+            log::info!("setting euid to {} (was {})", uid, guard.euid);
+            call("seteuid", || seteuid(uid))?;
+            Ok(guard)
+        }
+    }
+
+    /// Assumes our real UID, i.e. the UID of the user who ran totpm.
+    pub fn real_user() -> io::Result<Self> {
+        Self::new(real_user_id())
+    }
+}
+
+impl EuidSwapGuard {
+    fn restore(&self) -> io::Result<()> {
+        match &self.capabilities {
+            Some(caps) => {
+                log::info!("restoring effective capabilities");
+                set_capabilities(caps)
+            },
+            None => {
+                log::info!("restoring euid to {}", self.euid);
+                unsafe {
+                    call("seteuid", || seteuid(self.euid))
+                }
+            },
+        }
+    }
+}
+
+impl Drop for EuidSwapGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore() {
+            log::warn!("unable to restore euid: {}", e);
+        }
+    }
+}
+
+/// Returns our real UID, i.e. the UID of the user who ran totpm.
+pub fn real_user_id() -> u32 {
+    unsafe {
+        getuid()
+    }
+}
+
+/// Returns the user whose files, besides root's, can be trusted while running with elevated privileges:
+/// the effective user if we're setuid, or only root if we get our privileges from capabilities.
+/// Returns None if we're running without elevated privileges.
+pub fn elevated_user_id() -> Option<u32> {
+    unsafe {
+        if getuid() != geteuid() {
+            return Some(geteuid())
+        }
+    }
+    uses_capabilities().then_some(0)
+}
+
 pub fn is_root() -> bool {
     unsafe {
         getuid() == 0
@@ -52,3 +289,108 @@ pub fn is_effective_user(uid: u32) -> bool {
         geteuid() == uid
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    thread_local! {
+        static FAILING_SYSCALLS: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(super) fn is_failing(syscall: &str) -> bool {
+        FAILING_SYSCALLS.with(|failing| failing.borrow().contains(&syscall))
+    }
+
+    /// Makes the given system calls fail with EPERM on this thread while running f.
+    fn failing<T>(syscalls: &[&'static str], f: impl FnOnce() -> T) -> T {
+        FAILING_SYSCALLS.with(|failing| failing.borrow_mut().extend(syscalls));
+        let result = f();
+        FAILING_SYSCALLS.with(|failing| failing.borrow_mut().clear());
+        result
+    }
+
+    fn assert_failed(result: io::Result<()>, syscall: &str) {
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(e.to_string().starts_with(&format!("{} failed: ", syscall)), "wrong error: {}", e);
+    }
+
+    #[test]
+    fn check_reports_failed_syscall() {
+        check("seteuid", 0).unwrap();
+        let e = check("seteuid", -1).unwrap_err();
+        assert!(e.to_string().starts_with("seteuid failed: "));
+    }
+
+    #[test]
+    fn retain_keeps_only_given_capabilities() {
+        let all = CapData { effective: u32::MAX, permitted: u32::MAX, inheritable: u32::MAX };
+        let caps = retain(&[all, all], &[CAP_DAC_OVERRIDE, 33]);
+        assert_eq!(caps[0], CapData { effective: 1 << CAP_DAC_OVERRIDE, permitted: 1 << CAP_DAC_OVERRIDE, inheritable: 0 });
+        assert_eq!(caps[1], CapData { effective: 1 << 1, permitted: 1 << 1, inheritable: 0 });
+    }
+
+    #[test]
+    fn without_effective_keeps_permitted_capabilities() {
+        let caps = [CapData { effective: 6, permitted: 6, inheritable: 0 }, CapData::default()];
+        assert_eq!(without_effective(&caps)[0], CapData { effective: 0, permitted: 6, inheritable: 0 });
+    }
+
+    #[test]
+    fn privilege_drop_guard_reports_failure_to_drop() {
+        if uses_capabilities() {
+            return
+        }
+        assert_failed(failing(&["setegid"], PrivilegeDropGuard::new).map(|_| ()), "setegid");
+        assert_failed(failing(&["seteuid"], PrivilegeDropGuard::new).map(|_| ()), "seteuid");
+    }
+
+    #[test]
+    fn privilege_drop_guard_reports_failure_to_drop_forever() {
+        if uses_capabilities() {
+            return
+        }
+        let guard = PrivilegeDropGuard::new().unwrap();
+        assert_failed(failing(&["setresuid"], || guard.forever()), "setresuid");
+    }
+
+    #[test]
+    fn privilege_drop_guard_reports_failure_to_restore() {
+        if uses_capabilities() {
+            return
+        }
+        let guard = PrivilegeDropGuard::new().unwrap();
+        assert_failed(failing(&["seteuid"], || guard.restore()), "seteuid");
+        assert_failed(failing(&["setegid"], || guard.restore()), "setegid");
+    }
+
+    #[test]
+    fn euid_swap_guard_reports_failure_to_swap() {
+        if uses_capabilities() {
+            return
+        }
+        assert_failed(failing(&["seteuid"], EuidSwapGuard::real_user).map(|_| ()), "seteuid");
+    }
+
+    #[test]
+    fn euid_swap_guard_reports_failure_to_restore() {
+        if uses_capabilities() {
+            return
+        }
+        let guard = EuidSwapGuard::real_user().unwrap();
+        assert_failed(failing(&["seteuid"], || guard.restore()), "seteuid");
+    }
+
+    #[test]
+    fn euid_swap_guard_restores_euid() {
+        let euid = unsafe { geteuid() };
+        {
+            let _guard = EuidSwapGuard::real_user().unwrap();
+            assert!(is_effective_user(real_user_id()));
+        }
+        assert!(is_effective_user(euid));
+    }
+}
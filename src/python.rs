@@ -0,0 +1,175 @@
+use std::time::SystemTime;
+
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+
+use crate::{base32, commands::add::find_exact, config::Config, db::model, totp_store::{TotpStore, WithTPM}};
+
+create_exception!(totpm, TotpmError, PyException, "Raised when a secrets store operation fails.");
+
+fn to_py_err<E: std::fmt::Debug>(error: E) -> PyErr {
+    TotpmError::new_err(format!("{:?}", error))
+}
+
+/// A secret in the store. The HMAC key itself never leaves the TPM.
+#[pyclass(name = "Secret", module = "totpm", get_all)]
+#[derive(Clone)]
+pub struct PySecret {
+    id: i64,
+    service: String,
+    account: String,
+    digits: u8,
+    interval: u32,
+    tags: Vec<String>,
+    notes: Option<String>,
+    url: Option<String>,
+    icon: Option<String>,
+}
+
+impl From<model::Secret> for PySecret {
+    fn from(secret: model::Secret) -> Self {
+        PySecret {
+            id: secret.id,
+            service: secret.service,
+            account: secret.account,
+            digits: secret.digits,
+            interval: secret.interval,
+            tags: secret.tags,
+            notes: secret.notes,
+            url: secret.url,
+            icon: secret.icon,
+        }
+    }
+}
+
+#[pymethods]
+impl PySecret {
+    fn __repr__(&self) -> String {
+        format!("Secret(id={}, service={:?}, account={:?})", self.id, self.service, self.account)
+    }
+}
+
+/// An open secrets store. Presence is verified when the store is opened, and again before each code is generated
+/// or secret is added after the first.
+#[pyclass(name = "Store", module = "totpm", unsendable)]
+pub struct PyStore {
+    store: TotpStore<WithTPM>,
+    /// True until presence verified when opening the store has been used for an operation.
+    presence_verified: bool,
+}
+
+#[pymethods]
+impl PyStore {
+    /// Verifies presence and opens the secrets store configured in the config file at the given path.
+    #[new]
+    fn new(config_path: &str) -> PyResult<Self> {
+        let config_str = std::fs::read_to_string(config_path).map_err(to_py_err)?;
+        let config: Config = toml::from_str(&config_str).map_err(to_py_err)?;
+        let store = TotpStore::with_tpm(config).map_err(to_py_err)?;
+        Ok(PyStore { store, presence_verified: true })
+    }
+
+    /// Lists the secrets matching the given service and account.
+    #[pyo3(signature = (service=None, account=None))]
+    fn list(&mut self, service: Option<&str>, account: Option<&str>) -> PyResult<Vec<PySecret>> {
+        let secrets = self.store.list(service, account).map_err(to_py_err)?;
+        Ok(secrets.into_iter().map(PySecret::from).collect())
+    }
+
+    /// Verifies presence and generates a code for the secret with the given id.
+    fn gen(&mut self, id: i64) -> PyResult<String> {
+        self.verify_presence()?;
+        self.store.gen(id, SystemTime::now()).map_err(to_py_err)
+    }
+
+    /// Verifies presence and adds a secret, given as a base32 string, for the given service and account.
+    /// Fails if there already is a secret for them, unless replace is true, in which case that secret is replaced.
+    #[pyo3(signature = (service, account, secret, digits=None, interval=None, replace=false))]
+    fn add(
+        &mut self,
+        service: &str,
+        account: &str,
+        secret: &str,
+        digits: Option<u8>,
+        interval: Option<u32>,
+        replace: bool,
+    ) -> PyResult<PySecret> {
+        let secret = base32::decode_strict(secret).map_err(|e| TotpmError::new_err(e.to_string()))?;
+        let existing = find_exact(&mut self.store, service, account).map_err(to_py_err)?;
+        let old_secret_id = match existing.as_slice() {
+            [] => None,
+            [old] if replace => Some(old.id),
+            [_] => return Err(TotpmError::new_err(format!(
+                "there already is a secret for {}/{}; pass replace=True to replace it", service, account,
+            ))),
+            _ => return Err(TotpmError::new_err(format!(
+                "there are several secrets for {}/{}; use the totpm command to pick one to replace", service, account,
+            ))),
+        };
+        self.verify_presence()?;
+        let secret = match old_secret_id {
+            Some(id) => self.store.replace_ex(id, service, account, digits, interval, &secret, None, |_| {}),
+            None => self.store.add(service, account, digits, interval, &secret),
+        };
+        Ok(secret.map_err(to_py_err)?.into())
+    }
+}
+
+impl PyStore {
+    /// Verifies presence, unless it was just verified when opening the store.
+    fn verify_presence(&mut self) -> PyResult<()> {
+        if std::mem::take(&mut self.presence_verified) {
+            return Ok(())
+        }
+        self.store.verify_presence().map_err(to_py_err)
+    }
+}
+
+/// Python bindings for the TPM-backed secrets store.
+#[pymodule]
+fn totpm(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyStore>()?;
+    m.add_class::<PySecret>()?;
+    m.add("TotpmError", m.py().get_type::<TotpmError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use testutil::tpm::SwTpm;
+
+    use crate::presence_verification::PresenceVerificationMethod;
+
+    use super::*;
+
+    #[test]
+    fn add_refuses_existing_secrets_unless_replacing() {
+        let tpm = SwTpm::new();
+        let dir = tempdir().unwrap();
+        let cfg = Config::default(
+            true,
+            tpm.tcti.clone(),
+            Some(dir.path().join("sys")),
+            Some(dir.path().join("user")),
+            Some(PresenceVerificationMethod::None),
+        );
+        TotpStore::init(cfg.clone()).unwrap();
+        let cfg_path = dir.path().join("totpm.conf");
+        std::fs::write(&cfg_path, toml::to_string(&cfg).unwrap()).unwrap();
+
+        let mut store = PyStore::new(cfg_path.to_str().unwrap()).unwrap();
+        let added = store.add("foo", "bar", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", None, None, false).unwrap();
+        assert!(store.add("foo", "bar", "GEZDGNBVGY3TQOJQ", None, None, false).is_err());
+        assert_eq!(store.list(None, None).unwrap().len(), 1);
+
+        let replaced = store.add("foo", "bar", "GEZDGNBVGY3TQOJQ", Some(8), None, true).unwrap();
+        let listed = store.list(Some("foo"), None).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, replaced.id);
+        assert_ne!(replaced.id, added.id);
+        assert_eq!(store.gen(replaced.id).unwrap().len(), 8);
+
+        assert!(store.add("baz", "bar", "not base32!", None, None, false).is_err());
+        assert_eq!(store.list(None, None).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SHA1_BLOCK_SIZE: usize = 64;
+
+/// Computes the TOTP code for the given secret and time, as specified by RFC 6238 using HMAC-SHA1.
+/// This is a software reference implementation, used to cross-check codes generated by the TPM.
+/// It deliberately shares no code with the TPM code path; never use it with real secrets.
+pub fn totp(secret: &[u8], time: SystemTime, interval: u32, digits: u8) -> String {
+    let counter = time.duration_since(UNIX_EPOCH).unwrap().as_secs() / interval as u64;
+    hotp(secret, counter, digits)
+}
+
+/// Computes the HOTP code for the given secret and counter, as specified by RFC 4226.
+pub fn hotp(secret: &[u8], counter: u64, digits: u8) -> String {
+    let mac = hmac_sha1(secret, &counter.to_be_bytes());
+    let offset = (mac[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([mac[offset], mac[offset + 1], mac[offset + 2], mac[offset + 3]]) & 0x7fff_ffff;
+    let code = binary as u64 % 10u64.pow(digits as u32);
+    format!("{:0width$}", code, width = digits as usize)
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block_key = [0u8; SHA1_BLOCK_SIZE];
+    if key.len() > SHA1_BLOCK_SIZE {
+        block_key[..20].copy_from_slice(&sha1(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = block_key.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>();
+    inner.extend_from_slice(message);
+    let mut outer = block_key.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>();
+    outer.extend_from_slice(&sha1(&inner));
+    sha1(&outer)
+}
+
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % SHA1_BLOCK_SIZE != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+    for chunk in padded.chunks(SHA1_BLOCK_SIZE) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([chunk[4 * i], chunk[4 * i + 1], chunk[4 * i + 2], chunk[4 * i + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, x) in h.iter().enumerate() {
+        digest[4 * i..4 * i + 4].copy_from_slice(&x.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha1_matches_known_digests() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+        );
+    }
+
+    #[test]
+    fn hmac_sha1_matches_rfc_2202() {
+        assert_eq!(hex(&hmac_sha1(&[0x0b; 20], b"Hi There")), "b617318655057264e28bc0b6fb378c8ef146be00");
+        assert_eq!(hex(&hmac_sha1(b"Jefe", b"what do ya want for nothing?")), "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+        assert_eq!(
+            hex(&hmac_sha1(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112",
+        );
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = ["755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871", "520489"];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(b"12345678901234567890", counter as u64, 6), *code);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238() {
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(totp(b"12345678901234567890", UNIX_EPOCH + Duration::from_secs(time), 30, 8), code);
+        }
+    }
+}
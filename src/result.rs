@@ -1,4 +1,6 @@
-use crate::totp_store;
+use std::fmt::Display;
+
+use crate::{db, rng, secret_format, totp_store};
 
 #[derive(Debug)]
 pub enum Error {
@@ -7,12 +9,119 @@ pub enum Error {
     ConfigWriteError(toml::ser::Error),
     TotpStoreError(totp_store::Error),
     ImportFormatError(String),
+    /// A secret to import has the same service and account, given as "service (account)", as an existing one.
+    ImportConflict(String),
     UserNotFoundError(String),
     SecretFormatError,
+    /// The secret file at the given path could be accessed by other users than the one who ran totpm.
+    InsecureSecretFile(std::path::PathBuf),
+    /// The secret given to add isn't valid in the given or detected format.
+    InvalidSecret(secret_format::DecodeError),
+    /// The context file at the given path could not be parsed.
+    InvalidContextFile(std::path::PathBuf, toml::de::Error),
+    /// No service was given and no context file was found.
+    NoContext,
+    /// ssh-helper was given a prompt that doesn't ask for a one-time code.
+    UnexpectedPrompt(String),
+    /// config get or set was given a key which isn't set, or isn't a config key.
+    ConfigKeyNotFound(String),
     InvalidPVMethod(String),
+    InvalidSelectionMethod(String),
+    ProfileNotFound(String),
+    /// The config file at the given path could be changed by other users than root and the one totpm runs as,
+    /// and can't be trusted while running with elevated privileges.
+    InsecureConfig(std::path::PathBuf),
     RootRequired,
     SecretNotFound,
     AmbiguousSecret,
+    SetupCancelled,
+    /// The user didn't confirm a deletion, or there was no one to ask.
+    DeletionCancelled,
+    NothingToUndo,
+    /// verify was given a code which doesn't match the secret.
+    InvalidCode,
+    /// verify-store found the given number of problems.
+    StoreVerificationFailed(usize),
+    /// gen --type was given, but none of the tools it can use is installed.
+    AutoTypeUnavailable,
+    /// doctor found the given number of failed checks.
+    DoctorChecksFailed(usize),
+    /// The backup is unreadable or can't be restored by this version of totpm.
+    InvalidBackup(String),
+    /// The backup was made under a different primary key handle: (backup handle, current handle).
+    BackupKeyHandleMismatch(u32, u32),
+    /// The keys of the given number of secrets in the backup can't be loaded under the current primary key.
+    BackupKeysNotLoadable(usize),
+    /// init was given PCRs to bind the primary key to, but they're not in the configuration file at the given path,
+    /// which init doesn't write when built without the install feature.
+    PcrsNotConfigured(std::path::PathBuf),
+    /// The secret and its confirmation given when adding a secret were different.
+    SecretMismatch,
+    /// The PIN and its confirmation given when adding a secret or setting the presence verification PIN were different.
+    PinMismatch,
+    /// --test-seed was given to a release build.
+    TestModeUnavailable,
+    /// The TPM generated a different code than the reference implementation: (tpm code, reference code).
+    ReferenceMismatch(String, String),
+    /// The given error happened while doing what the string describes, e.g. "reading /etc/totpm.conf".
+    /// Errors may have several layers of context, outermost first.
+    Context(String, Box<Error>),
+}
+
+impl Error {
+    /// Returns a stable, machine-readable code for the error, ignoring any context.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::IOError(_) => "io",
+            Error::ConfigReadError(_) => "config.unreadable",
+            Error::ConfigWriteError(_) => "config.unwritable",
+            Error::TotpStoreError(e) => e.code(),
+            Error::ImportFormatError(_) => "import.format",
+            Error::ImportConflict(_) => "import.conflict",
+            Error::UserNotFoundError(_) => "user.not-found",
+            Error::SecretFormatError => "secret.format",
+            Error::InsecureSecretFile(_) => "secret.insecure-file",
+            Error::InvalidSecret(_) => "secret.invalid",
+            Error::InvalidContextFile(_, _) => "context.invalid",
+            Error::NoContext => "context.not-found",
+            Error::UnexpectedPrompt(_) => "ssh-helper.unexpected-prompt",
+            Error::ConfigKeyNotFound(_) => "config.key-not-found",
+            Error::InvalidPVMethod(_) => "config.invalid-pv-method",
+            Error::InvalidSelectionMethod(_) => "config.invalid-selection-method",
+            Error::ProfileNotFound(_) => "config.profile-not-found",
+            Error::InsecureConfig(_) => "config.insecure",
+            Error::RootRequired => "root-required",
+            Error::SecretNotFound => "secret.not-found",
+            Error::AmbiguousSecret => "secret.ambiguous",
+            Error::SetupCancelled => "setup.cancelled",
+            Error::DeletionCancelled => "del.cancelled",
+            Error::NothingToUndo => "undo.nothing-to-undo",
+            Error::InvalidCode => "verify.invalid-code",
+            Error::StoreVerificationFailed(_) => "verify-store.failed",
+            Error::AutoTypeUnavailable => "auto-type.unavailable",
+            Error::DoctorChecksFailed(_) => "doctor.failed",
+            Error::InvalidBackup(_) => "backup.invalid",
+            Error::BackupKeyHandleMismatch(_, _) => "backup.key-handle-mismatch",
+            Error::BackupKeysNotLoadable(_) => "backup.keys-not-loadable",
+            Error::PcrsNotConfigured(_) => "init.pcrs-not-configured",
+            Error::SecretMismatch => "secret.mismatch",
+            Error::PinMismatch => "pin.mismatch",
+            Error::TestModeUnavailable => "test-mode.unavailable",
+            Error::ReferenceMismatch(_, _) => "reference.mismatch",
+            Error::Context(_, e) => e.code(),
+        }
+    }
+}
+
+/// Adds context to the error of a result, describing what was being done when it happened.
+pub trait Context<T> {
+    fn context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl <T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|e| Error::Context(context().to_string(), Box::new(e.into())))
+    }
 }
 
 impl From<toml::ser::Error> for Error {
@@ -33,10 +142,41 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<rng::TestModeUnavailable> for Error {
+    fn from(_: rng::TestModeUnavailable) -> Self {
+        Self::TestModeUnavailable
+    }
+}
+
 impl From<totp_store::Error> for Error {
     fn from(value: totp_store::Error) -> Self {
         Self::TotpStoreError(value)
     }
 }
 
+impl From<db::Error> for Error {
+    fn from(value: db::Error) -> Self {
+        Self::TotpStoreError(totp_store::Error::DBError(value))
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_is_layered_outermost_first_and_keeps_code() {
+        let result: Result<()> = Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+            .context(|| "reading /etc/totpm.conf")
+            .context(|| format!("loading profile {}", "work"));
+        let Err(Error::Context(outer, inner)) = result else { panic!("expected context") };
+        assert_eq!(outer, "loading profile work");
+        assert!(matches!(*inner, Error::Context(ref ctx, ref e) if ctx == "reading /etc/totpm.conf" && matches!(**e, Error::IOError(_))));
+        assert_eq!(inner.code(), "io");
+
+        let error: Error = totp_store::Error::DBError(db::Error::NoSuchElement).into();
+        assert_eq!(error.code(), "db.not-found");
+    }
+}
@@ -0,0 +1,52 @@
+use std::cell::RefCell;
+
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+thread_local! {
+    static TEST_RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+}
+
+/// Test mode is only available in debug builds.
+#[derive(Debug, PartialEq)]
+pub struct TestModeUnavailable;
+
+/// Makes all randomness used by totpm on the current thread, i.e. auth values, IVs and primary key templates,
+/// deterministic, for reproducible integration tests.
+/// Refuses to do so in release builds, since the generated keys and auth values are then predictable.
+pub fn set_test_seed(seed: u64) -> Result<(), TestModeUnavailable> {
+    if !cfg!(debug_assertions) {
+        return Err(TestModeUnavailable)
+    }
+    log::warn!("using test seed {}; keys and auth values are predictable", seed);
+    TEST_RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha20Rng::seed_from_u64(seed)));
+    Ok(())
+}
+
+/// Fills the given buffer with random bytes, from the seeded test RNG if one is set.
+pub fn fill_bytes(buf: &mut [u8]) {
+    TEST_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => rng.fill_bytes(buf),
+        None => rand::thread_rng().fill_bytes(buf),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_bytes() {
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        set_test_seed(42).unwrap();
+        fill_bytes(&mut first);
+        set_test_seed(42).unwrap();
+        fill_bytes(&mut second);
+        assert_eq!(first, second);
+
+        set_test_seed(43).unwrap();
+        fill_bytes(&mut second);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::base32;
+
+/// How a secret given to add or import is encoded.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum SecretFormat {
+    /// Hex if the secret has a 0x prefix, or consists of hex digits including any of 0, 1, 8 and 9,
+    /// which aren't part of the base32 alphabet. Base32 otherwise.
+    #[default]
+    Auto,
+    /// RFC 4648 base32, as used by most providers and otpauth:// URIs.
+    Base32,
+    /// Hexadecimal, optionally prefixed with 0x.
+    Hex,
+    /// The secret bytes themselves, as read from a file.
+    Raw,
+}
+
+impl FromStr for SecretFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(SecretFormat::Auto),
+            "base32" => Ok(SecretFormat::Base32),
+            "hex" => Ok(SecretFormat::Hex),
+            "raw" => Ok(SecretFormat::Raw),
+            _ => Err(format!("invalid secret format: '{}' (expected auto, base32, hex or raw)", s)),
+        }
+    }
+}
+
+/// Why a secret couldn't be decoded. Positions are 0-based character offsets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    Base32(base32::DecodeError),
+    InvalidHexChar { position: usize, char: char },
+    OddHexLength(usize),
+    /// The secret isn't UTF-8 text, so it can only be used as raw bytes.
+    NotText,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Base32(e) => write!(f, "not valid base32: {}", e),
+            DecodeError::InvalidHexChar { position, char } => {
+                write!(f, "not valid hex: invalid character '{}' at position {}", char, position + 1)
+            },
+            DecodeError::OddHexLength(len) => {
+                write!(f, "not valid hex: {} digits is not a whole number of bytes; the secret is probably truncated", len)
+            },
+            DecodeError::NotText => f.write_str("not text; use --secret-format raw for binary secrets"),
+        }
+    }
+}
+
+impl SecretFormat {
+    /// Decodes the given secret, which is expected to be in this format.
+    /// Base32 that isn't strictly valid, but which earlier versions accepted, is decoded with a warning.
+    pub fn decode(self, secret: &str) -> Result<Vec<u8>, DecodeError> {
+        match self.detect(secret) {
+            SecretFormat::Hex => decode_hex(secret.strip_prefix("0x").unwrap_or(secret)),
+            SecretFormat::Raw => Ok(secret.as_bytes().to_vec()),
+            _ => base32::decode_strict(secret).or_else(|e| match base32::decode(secret) {
+                // Earlier versions took anything made up of base32 characters, so keep doing that, with a warning
+                Some(bytes) => {
+                    log::warn!("the secret is not valid base32 ({}); decoding it leniently, as earlier versions did", e);
+                    Ok(bytes)
+                },
+                None => Err(DecodeError::Base32(e)),
+            }),
+        }
+    }
+
+    /// Decodes the given contents of a secret file. Unless the format is Raw,
+    /// the contents must be text, and surrounding whitespace such as a trailing newline is ignored.
+    pub fn decode_bytes(self, secret: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        match self {
+            SecretFormat::Raw => Ok(secret.to_vec()),
+            _ => self.decode(std::str::from_utf8(secret).map_err(|_| DecodeError::NotText)?.trim()),
+        }
+    }
+
+    /// Returns the format of the given secret: this format, unless it's Auto.
+    fn detect(self, secret: &str) -> SecretFormat {
+        match self {
+            SecretFormat::Auto => {
+                let hex_only = secret.strip_prefix("0x").is_some()
+                    || (secret.chars().all(|c| c.is_ascii_hexdigit()) && secret.contains(['0', '1', '8', '9']));
+                if hex_only { SecretFormat::Hex } else { SecretFormat::Base32 }
+            },
+            format => format,
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, DecodeError> {
+    if let Some((position, char)) = hex.chars().enumerate().find(|(_, c)| !c.is_ascii_hexdigit()) {
+        return Err(DecodeError::InvalidHexChar { position, char })
+    }
+    if !hex.len().is_multiple_of(2) {
+        return Err(DecodeError::OddHexLength(hex.len()))
+    }
+    Ok(crate::totp_store::hex_decode(hex).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_detects_hex_only_when_unambiguous() {
+        assert_eq!(SecretFormat::Auto.detect("3132333435363738393031323334353637383930"), SecretFormat::Hex);
+        assert_eq!(SecretFormat::Auto.detect("0xabcdef"), SecretFormat::Hex);
+        assert_eq!(SecretFormat::Auto.detect("ABCDEF234567"), SecretFormat::Base32);
+        assert_eq!(SecretFormat::Auto.detect("NBSWY3DP"), SecretFormat::Base32);
+        assert_eq!(SecretFormat::Base32.detect("3132"), SecretFormat::Base32);
+    }
+
+    #[test]
+    fn decode_decodes_in_detected_format() {
+        assert_eq!(SecretFormat::Auto.decode("3132333435363738393031323334353637383930"), Ok(b"12345678901234567890".to_vec()));
+        assert_eq!(SecretFormat::Hex.decode("0x6869"), Ok(b"hi".to_vec()));
+        assert_eq!(SecretFormat::Hex.decode("ABCDEF"), Ok(vec![0xab, 0xcd, 0xef]));
+        assert_eq!(SecretFormat::Auto.decode("NBSWY3DP"), Ok(b"hello".to_vec()));
+        assert_eq!(SecretFormat::Hex.decode("123"), Err(DecodeError::OddHexLength(3)));
+        assert_eq!(SecretFormat::Hex.decode("12g4"), Err(DecodeError::InvalidHexChar { position: 2, char: 'g' }));
+        assert!(matches!(SecretFormat::Base32.decode("3132"), Err(DecodeError::Base32(_))));
+    }
+
+    #[test]
+    fn decode_falls_back_to_lenient_base32() {
+        // Non-zero trailing bits, a length no encoding produces, and truncated padding
+        assert_eq!(SecretFormat::Base32.decode("MFRB"), Ok(b"ab".to_vec()));
+        assert_eq!(SecretFormat::Base32.decode("NBSWY3DPA"), Ok(b"hello".to_vec()));
+        assert_eq!(SecretFormat::Auto.decode("MFRA=="), Ok(b"ab".to_vec()));
+        assert!(matches!(SecretFormat::Base32.decode("NBSWY3D!"), Err(DecodeError::Base32(_))));
+    }
+
+    #[test]
+    fn decode_bytes_trims_text_but_not_raw_bytes() {
+        assert_eq!(SecretFormat::Auto.decode_bytes(b"NBSWY3DP\n"), Ok(b"hello".to_vec()));
+        assert_eq!(SecretFormat::Raw.decode_bytes(b"\xff hi\n"), Ok(b"\xff hi\n".to_vec()));
+        assert_eq!(SecretFormat::Hex.decode_bytes(b"\xff"), Err(DecodeError::NotText));
+    }
+}
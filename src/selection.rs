@@ -0,0 +1,252 @@
+use std::{io::{BufRead, Write}, str::FromStr};
+
+use serde::{de::IntoDeserializer, Deserialize, Serialize};
+
+use crate::{db::model::Secret, result::{Error, Result}, term::{pick_one, IsATTY}};
+
+/// How to choose between several secrets matching the same query.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionMethod {
+    /// Ask the user to pick from a numbered list.
+    #[default]
+    Interactive,
+    /// Let the user narrow down the list by typing parts of the service and account names.
+    Filter,
+    /// Pick the secret that was most recently used to generate a code.
+    Mru,
+    /// Fail, so that scripts never act on the wrong secret.
+    FailFast,
+}
+
+impl FromStr for SelectionMethod {
+    fn from_str(s: &str) -> Result<Self> {
+        Self::deserialize(s.into_deserializer())
+            .map_err(|_: serde::de::value::Error| Error::InvalidSelectionMethod(s.to_string()))
+    }
+
+    type Err = Error;
+}
+
+/// A strategy for disambiguating between secrets.
+/// Frontends other than the terminal can provide their own implementation.
+pub trait Selector {
+    /// Chooses one of the given secrets, of which there are at least two.
+    /// Returns None if the user cancelled the selection.
+    fn choose<'a>(&mut self, msg: &str, secrets: &'a [Secret]) -> Result<Option<&'a Secret>>;
+
+    /// Returns the given secret if there's only one, or lets the strategy choose if there are several.
+    fn select<'a>(&mut self, msg: &str, secrets: &'a [Secret]) -> Result<Option<&'a Secret>> {
+        match secrets.len() {
+            0 => Ok(None),
+            1 => Ok(Some(&secrets[0])),
+            _ => self.choose(msg, secrets),
+        }
+    }
+}
+
+pub fn create_selector(method: SelectionMethod) -> Box<dyn Selector> {
+    match method {
+        SelectionMethod::Interactive => Box::new(InteractiveSelector),
+        SelectionMethod::Filter => Box::new(FilterSelector),
+        SelectionMethod::Mru => Box::new(MruSelector),
+        SelectionMethod::FailFast => Box::new(FailFastSelector),
+    }
+}
+
+pub struct InteractiveSelector;
+
+impl Selector for InteractiveSelector {
+    fn choose<'a>(&mut self, msg: &str, secrets: &'a [Secret]) -> Result<Option<&'a Secret>> {
+        Ok(pick_one(&mut std::io::stdin().lock(), &mut std::io::stdout(), msg, secrets.iter()))
+    }
+}
+
+pub struct FilterSelector;
+
+impl Selector for FilterSelector {
+    fn choose<'a>(&mut self, msg: &str, secrets: &'a [Secret]) -> Result<Option<&'a Secret>> {
+        Ok(filter_one(&mut std::io::stdin().lock(), &mut std::io::stdout(), msg, secrets)?)
+    }
+}
+
+pub struct MruSelector;
+
+impl Selector for MruSelector {
+    /// Fails if none of the secrets has been used yet, since there's nothing to go by.
+    fn choose<'a>(&mut self, _msg: &str, secrets: &'a [Secret]) -> Result<Option<&'a Secret>> {
+        let mut most_recent: Option<&Secret> = None;
+        for secret in secrets {
+            if secret.last_used_at > most_recent.and_then(|x| x.last_used_at) {
+                most_recent = Some(secret);
+            }
+        }
+        match most_recent {
+            Some(secret) => {
+                eprintln!("picked most recently used secret: {}", secret);
+                Ok(Some(secret))
+            },
+            None => Err(Error::AmbiguousSecret),
+        }
+    }
+}
+
+pub struct FailFastSelector;
+
+impl Selector for FailFastSelector {
+    fn choose<'a>(&mut self, _msg: &str, _secrets: &'a [Secret]) -> Result<Option<&'a Secret>> {
+        Err(Error::AmbiguousSecret)
+    }
+}
+
+/// Lets the user narrow down the given secrets by typing a filter, until only one remains
+/// or the user picks one by number. An empty line cancels.
+fn filter_one<'a, In: BufRead, Out: Write + IsATTY>(
+    inp: &mut In,
+    out: &mut Out,
+    msg: &str,
+    secrets: &'a [Secret],
+) -> std::io::Result<Option<&'a Secret>> {
+    if !out.isatty() {
+        return Ok(None)
+    }
+    out.write_fmt(format_args!("{}\n", msg))?;
+    out.write_fmt(format_args!("type to filter, enter a number to choose, or nothing to cancel\n"))?;
+    let mut candidates = secrets.iter().collect::<Vec<_>>();
+    loop {
+        for (i, secret) in candidates.iter().enumerate() {
+            out.write_fmt(format_args!("{}:\t{}\n", i + 1, secret))?;
+        }
+        out.write_fmt(format_args!("filter> "))?;
+        out.flush()?;
+
+        let mut response = String::new();
+        inp.read_line(&mut response)?;
+        let response = response.trim();
+        if response.is_empty() {
+            log::info!("selection cancelled");
+            return Ok(None)
+        }
+        if let Ok(ix) = response.parse::<usize>() {
+            if ix >= 1 && ix <= candidates.len() {
+                return Ok(Some(candidates[ix - 1]))
+            }
+        }
+
+        let matching = candidates.iter()
+            .copied()
+            .filter(|secret| fuzzy_match(response, &secret.to_string()))
+            .collect::<Vec<_>>();
+        match matching.len() {
+            0 => out.write_fmt(format_args!("no matches\n"))?,
+            1 => return Ok(Some(matching[0])),
+            _ => candidates = matching,
+        }
+    }
+}
+
+/// Returns true if all characters of the pattern appear in the text, in order, ignoring case.
+fn fuzzy_match(pattern: &str, text: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    pattern.chars()
+        .flat_map(char::to_lowercase)
+        .filter(|c| !c.is_whitespace())
+        .all(|c| text.any(|x| x == c))
+}
+
+#[cfg(test)]
+mod tests {
+    use testutil::term::MockTerminal;
+
+    use super::*;
+
+    fn secret(service: &str, account: &str, last_used_at: Option<i64>) -> Secret {
+        let mut secret = Secret::new(service.to_owned(), account.to_owned(), None, None, vec![], vec![]);
+        secret.last_used_at = last_used_at;
+        secret
+    }
+
+    #[test]
+    fn selection_method_parses_from_string() {
+        assert_eq!(SelectionMethod::from_str("fail_fast").unwrap(), SelectionMethod::FailFast);
+        assert_eq!(SelectionMethod::from_str("mru").unwrap(), SelectionMethod::Mru);
+        assert!(SelectionMethod::from_str("random").is_err());
+    }
+
+    #[test]
+    fn select_never_asks_strategy_about_a_single_secret() {
+        let secrets = vec![secret("github", "alice", None)];
+        assert_eq!(FailFastSelector.select("", &secrets).unwrap(), Some(&secrets[0]));
+        assert_eq!(FailFastSelector.select("", &[]).unwrap(), None);
+        assert!(FailFastSelector.select("", &[secrets[0].clone(), secrets[0].clone()]).is_err());
+    }
+
+    #[test]
+    fn mru_picks_most_recently_used_secret() {
+        let secrets = vec![
+            secret("github", "alice", Some(100)),
+            secret("github", "bob", Some(200)),
+            secret("github", "carol", None),
+        ];
+        assert_eq!(MruSelector.select("", &secrets).unwrap(), Some(&secrets[1]));
+        assert!(MruSelector.select("", &[secrets[2].clone(), secrets[2].clone()]).is_err());
+    }
+
+    #[test]
+    fn fuzzy_match_matches_subsequences() {
+        assert!(fuzzy_match("gh bob", "github (bob)"));
+        assert!(fuzzy_match("GHB", "github (bob)"));
+        assert!(!fuzzy_match("bg", "github (bob)"));
+        assert!(fuzzy_match("", "github (bob)"));
+    }
+
+    #[test]
+    fn filter_one_narrows_down_until_one_remains() {
+        let secrets = vec![
+            secret("github", "alice", None),
+            secret("github", "bob", None),
+            secret("gitlab", "alice", None),
+        ];
+        let mut term = MockTerminal::new()
+            .expect_stdout("hello\n")
+            .wait_stdout()
+            .expect_stdout("1:\tgithub (alice)\n")
+            .expect_stdout("2:\tgithub (bob)\n")
+            .expect_stdout("3:\tgitlab (alice)\n")
+            .expect_stdout("filter> ")
+            .write_stdin("xyz")
+            .expect_stdout("no matches\n")
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .expect_stdout("filter> ")
+            .write_stdin("alice")
+            .expect_stdout("1:\tgithub (alice)\n")
+            .expect_stdout("2:\tgitlab (alice)\n")
+            .expect_stdout("filter> ")
+            .write_stdin("lab");
+        let (mut inp, mut out) = term.stdin_stdout();
+        assert_eq!(filter_one(&mut inp, &mut out, "hello", &secrets).unwrap(), Some(&secrets[2]));
+    }
+
+    #[test]
+    fn filter_one_accepts_numbers_and_cancels_on_empty_input() {
+        let secrets = vec![secret("github", "alice", None), secret("github", "bob", None)];
+        let mut term = MockTerminal::new()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .write_stdin("2")
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .wait_stdout()
+            .write_stdin("");
+        let (mut inp, mut out) = term.stdin_stdout();
+        assert_eq!(filter_one(&mut inp, &mut out, "hello", &secrets).unwrap(), Some(&secrets[1]));
+        assert_eq!(filter_one(&mut inp, &mut out, "hello", &secrets).unwrap(), None);
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fmt::Display, io, path::{Path, PathBuf}, process::Command, str::FromStr};
+
+/// Where useradd may be installed, in order of preference.
+const USERADD_PATHS: [&str; 3] = ["/usr/sbin/useradd", "/sbin/useradd", "/usr/bin/useradd"];
+
+/// Where adduser may be installed, e.g. on Debian or as part of BusyBox, in order of preference.
+const ADDUSER_PATHS: [&str; 3] = ["/usr/sbin/adduser", "/sbin/adduser", "/usr/bin/adduser"];
+
+/// Shells which refuse logins, in order of preference. /bin/false is used if none exists.
+const NOLOGIN_PATHS: [&str; 2] = ["/usr/sbin/nologin", "/sbin/nologin"];
+
+/// The user owning system-wide data, and how to create it if it doesn't exist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemUser {
+    pub name: String,
+    /// Home directory of the user. The home directory is not created.
+    /// Defaults to whatever the user creation tool picks.
+    pub home: Option<PathBuf>,
+    /// Range to pick the user's id from. Defaults to the user creation tool's range for system users.
+    pub uid_range: Option<UidRange>,
+    /// Supplementary groups to add the user to, e.g. tss for TPM access.
+    pub groups: Vec<String>,
+}
+
+impl SystemUser {
+    /// Returns a user with the given name, to be created with the user creation tool's defaults.
+    pub fn new(name: &str) -> Self {
+        SystemUser { name: name.to_owned(), home: None, uid_range: None, groups: Vec::new() }
+    }
+}
+
+/// Inclusive range of user ids, given as e.g. 800-899.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct UidRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl FromStr for UidRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid uid range: '{}' (expected e.g. 800-899)", s);
+        let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+        let first = first.trim().parse().map_err(|_| invalid())?;
+        let last = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid())
+        }
+        Ok(UidRange { first, last })
+    }
+}
+
+impl Display for UidRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// A program which can create system users.
+#[derive(Debug, Clone, PartialEq)]
+enum Tool {
+    /// useradd from shadow-utils, as found on most distributions.
+    Useradd(PathBuf),
+    /// Debian's adduser.
+    Adduser(PathBuf),
+    /// BusyBox's adduser, as found on e.g. Alpine. It can't pick user ids from a range.
+    BusyboxAdduser(PathBuf),
+}
+
+/// Creates the given user as a system user which can't log in, using whichever of useradd and adduser is installed.
+pub fn create(user: &SystemUser) -> io::Result<()> {
+    let tool = find_tool()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "neither useradd nor adduser is installed"))?;
+    if matches!(tool, Tool::BusyboxAdduser(_)) && user.uid_range.is_some() {
+        log::warn!("busybox adduser can't pick user ids from a range; ignoring the uid range");
+    }
+    for mut command in create_commands(&tool, user, &nologin_shell()) {
+        log::info!("running {:?}", command);
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{} failed with {}: {}",
+                command.get_program().to_string_lossy(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim(),
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Returns the commands to run to create the given user using the given tool.
+fn create_commands(tool: &Tool, user: &SystemUser, shell: &Path) -> Vec<Command> {
+    let mut commands = Vec::new();
+    match tool {
+        Tool::Useradd(path) => {
+            let mut useradd = Command::new(path);
+            useradd.arg("-r").arg("-s").arg(shell);
+            if let Some(home) = &user.home {
+                useradd.arg("-d").arg(home);
+            }
+            if let Some(range) = user.uid_range {
+                useradd.arg("-K").arg(format!("SYS_UID_MIN={}", range.first));
+                useradd.arg("-K").arg(format!("SYS_UID_MAX={}", range.last));
+            }
+            if !user.groups.is_empty() {
+                useradd.arg("-G").arg(user.groups.join(","));
+            }
+            useradd.arg(&user.name);
+            commands.push(useradd);
+        },
+        Tool::Adduser(path) => {
+            let mut adduser = Command::new(path);
+            adduser.arg("--system").arg("--shell").arg(shell);
+            if let Some(home) = &user.home {
+                adduser.arg("--home").arg(home).arg("--no-create-home");
+            }
+            if let Some(range) = user.uid_range {
+                adduser.arg("--firstuid").arg(range.first.to_string());
+                adduser.arg("--lastuid").arg(range.last.to_string());
+            }
+            adduser.arg(&user.name);
+            commands.push(adduser);
+            for group in &user.groups {
+                let mut add_to_group = Command::new(path);
+                add_to_group.arg(&user.name).arg(group);
+                commands.push(add_to_group);
+            }
+        },
+        Tool::BusyboxAdduser(path) => {
+            let mut adduser = Command::new(path);
+            adduser.arg("-S").arg("-D").arg("-H").arg("-s").arg(shell);
+            if let Some(home) = &user.home {
+                adduser.arg("-h").arg(home);
+            }
+            adduser.arg(&user.name);
+            commands.push(adduser);
+            for group in &user.groups {
+                let mut addgroup = Command::new(path.with_file_name("addgroup"));
+                addgroup.arg(&user.name).arg(group);
+                commands.push(addgroup);
+            }
+        },
+    }
+    commands
+}
+
+/// Finds the tool to create users with, preferring useradd.
+fn find_tool() -> Option<Tool> {
+    if let Some(path) = first_existing(&USERADD_PATHS) {
+        return Some(Tool::Useradd(path))
+    }
+    let path = first_existing(&ADDUSER_PATHS)?;
+    let is_busybox = std::fs::canonicalize(&path)
+        .map(|target| target.file_name().is_some_and(|name| name == "busybox"))
+        .unwrap_or(false);
+    match is_busybox {
+        true => Some(Tool::BusyboxAdduser(path)),
+        false => Some(Tool::Adduser(path)),
+    }
+}
+
+fn nologin_shell() -> PathBuf {
+    first_existing(&NOLOGIN_PATHS).unwrap_or(PathBuf::from("/bin/false"))
+}
+
+fn first_existing(paths: &[&str]) -> Option<PathBuf> {
+    paths.iter().map(PathBuf::from).find(|path| path.exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    fn args(command: &Command) -> Vec<&str> {
+        command.get_args().map(|arg| arg.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn uid_range_parses_inclusive_ranges() {
+        assert_eq!("800-899".parse(), Ok(UidRange { first: 800, last: 899 }));
+        assert_eq!("800-800".parse(), Ok(UidRange { first: 800, last: 800 }));
+        assert!("899-800".parse::<UidRange>().is_err());
+        assert!("800".parse::<UidRange>().is_err());
+        assert!("a-b".parse::<UidRange>().is_err());
+    }
+
+    #[test]
+    fn create_commands_pass_options_in_each_tools_syntax() {
+        let shell = Path::new("/sbin/nologin");
+        let user = SystemUser {
+            name: "totpm".to_owned(),
+            home: Some(PathBuf::from("/var/lib/totpm")),
+            uid_range: Some(UidRange { first: 800, last: 899 }),
+            groups: vec!["tss".to_owned(), "wheel".to_owned()],
+        };
+
+        let commands = create_commands(&Tool::Useradd(PathBuf::from("/usr/sbin/useradd")), &user, shell);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(args(&commands[0]), [
+            "-r", "-s", "/sbin/nologin", "-d", "/var/lib/totpm",
+            "-K", "SYS_UID_MIN=800", "-K", "SYS_UID_MAX=899", "-G", "tss,wheel", "totpm",
+        ]);
+
+        let commands = create_commands(&Tool::Adduser(PathBuf::from("/usr/sbin/adduser")), &user, shell);
+        assert_eq!(commands.len(), 3);
+        assert_eq!(args(&commands[0]), [
+            "--system", "--shell", "/sbin/nologin", "--home", "/var/lib/totpm", "--no-create-home",
+            "--firstuid", "800", "--lastuid", "899", "totpm",
+        ]);
+        assert_eq!(args(&commands[2]), ["totpm", "wheel"]);
+
+        let commands = create_commands(&Tool::BusyboxAdduser(PathBuf::from("/usr/sbin/adduser")), &user, shell);
+        assert_eq!(args(&commands[0]), ["-S", "-D", "-H", "-s", "/sbin/nologin", "-h", "/var/lib/totpm", "totpm"]);
+        assert_eq!(commands[1].get_program(), OsStr::new("/usr/sbin/addgroup"));
+        assert_eq!(args(&commands[1]), ["totpm", "tss"]);
+    }
+
+    #[test]
+    fn create_commands_use_tool_defaults_unless_told_otherwise() {
+        let commands = create_commands(
+            &Tool::Useradd(PathBuf::from("/usr/sbin/useradd")),
+            &SystemUser::new("totpm"),
+            Path::new("/usr/sbin/nologin"),
+        );
+        assert_eq!(args(&commands[0]), ["-r", "-s", "/usr/sbin/nologin", "totpm"]);
+    }
+}
@@ -0,0 +1,217 @@
+use std::{ffi::{c_char, c_int, c_void, CString}, fmt::Display, path::Path};
+
+#[link(name = "dl")]
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlclose(handle: *mut c_void) -> c_int;
+}
+
+const RTLD_NOW: c_int = 2;
+
+/// A TCTI shared library which could not be loaded.
+#[derive(Debug, PartialEq)]
+pub struct MissingTcti {
+    /// File name of the missing library.
+    pub library: String,
+
+    /// Name of the package which typically provides the library.
+    pub package: &'static str,
+}
+
+/// TCTIs supported by totpm, with examples of the configuration strings they accept.
+pub const SUPPORTED_TCTIS: [(&str, &str); 4] = [
+    ("device", "\"device\" or \"device:/dev/tpmrm0\""),
+    ("tabrmd", "\"tabrmd\" or \"tabrmd:bus_name=com.intel.tss2.Tabrmd,bus_type=system\""),
+    ("swtpm", "\"swtpm\" or \"swtpm:host=localhost,port=2321\""),
+    ("mssim", "\"mssim\" or \"mssim:host=localhost,port=2321\""),
+];
+
+/// TCTI configuration used by init unless another is given: the kernel's resource manager.
+pub const DEFAULT_TCTI: &str = "device:/dev/tpmrm0";
+
+/// TCTI configurations tried by init --probe-tpm, in order of preference.
+pub const PROBE_CANDIDATES: [&str; 5] = [
+    "device:/dev/tpmrm0",
+    "tabrmd",
+    "device:/dev/tpm0",
+    "swtpm:host=localhost,port=2321",
+    "mssim:host=localhost,port=2321",
+];
+
+/// A TCTI configuration string which is malformed or uses an unsupported TCTI.
+#[derive(Debug, PartialEq)]
+pub struct InvalidTcti {
+    pub tcti: String,
+    pub reason: String,
+}
+
+impl Display for InvalidTcti {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid tpm \"{}\": {}", self.tcti, self.reason)
+    }
+}
+
+/// Returns the valid forms of TCTI configuration strings, one per line.
+pub fn valid_forms() -> String {
+    SUPPORTED_TCTIS.iter()
+        .map(|(name, examples)| format!("  {}: {}", name, examples))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Checks that the given TCTI configuration string uses a supported TCTI, with options that TCTI understands.
+pub fn validate_tcti(tcti: &str) -> Result<(), InvalidTcti> {
+    let invalid = |reason: String| InvalidTcti { tcti: tcti.to_owned(), reason };
+    let (name, conf) = tcti.split_once(':').unwrap_or((tcti, ""));
+    match name {
+        "device" if !conf.is_empty() && !conf.starts_with('/') => {
+            Err(invalid(format!("device path must be absolute, but was {}", conf)))
+        },
+        "device" => Ok(()),
+        "swtpm" | "mssim" => {
+            for (key, value) in parse_options(conf).map_err(invalid)? {
+                match key {
+                    "host" => {},
+                    "port" if value.parse::<u16>().is_err() => return Err(invalid(format!("invalid port {}", value))),
+                    "port" => {},
+                    _ => return Err(invalid(format!("unknown option {}; valid options are host and port", key))),
+                }
+            }
+            Ok(())
+        },
+        "tabrmd" => {
+            for (key, value) in parse_options(conf).map_err(invalid)? {
+                match key {
+                    "bus_name" => {},
+                    "bus_type" if value != "system" && value != "session" => {
+                        return Err(invalid(format!("bus_type must be system or session, but was {}", value)))
+                    },
+                    "bus_type" => {},
+                    _ => return Err(invalid(format!("unknown option {}; valid options are bus_name and bus_type", key))),
+                }
+            }
+            Ok(())
+        },
+        _ => Err(invalid(format!("unsupported tcti {}", name))),
+    }
+}
+
+/// Splits a TCTI configuration of the form key=value,key=value into its options.
+fn parse_options(conf: &str) -> Result<Vec<(&str, &str)>, String> {
+    conf.split(',')
+        .filter(|option| !option.is_empty())
+        .map(|option| option.split_once('=').ok_or(format!("option {} is not of the form key=value", option)))
+        .collect()
+}
+
+/// Returns the name of the TCTI used by the given TCTI configuration string.
+/// E.g. "device:/dev/tpmrm0" uses the "device" TCTI.
+pub fn tcti_name(tcti: &str) -> &str {
+    tcti.split_once(':').map(|(name, _)| name).unwrap_or(tcti)
+}
+
+/// Returns true if the given TCTI configuration string talks to the TPM through a resource manager,
+/// i.e. the kernel's (/dev/tpmrm*) or tpm2-abrmd. The resource manager owns the TPM's lifecycle,
+/// so its clients must not start up or shut down the TPM. A bare "device" counts as using one, since the device
+/// TCTI of tpm2-tss 3.0 and later tries /dev/tpmrm0 before falling back on /dev/tpm0.
+pub fn uses_resource_manager(tcti: &str) -> bool {
+    match tcti.split_once(':').unwrap_or((tcti, "")) {
+        ("tabrmd", _) | ("device", "") => true,
+        ("device", path) => Path::new(path).file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("tpmrm")),
+        _ => false,
+    }
+}
+
+/// Returns the file name of the shared library implementing the given TCTI.
+/// tss2-tctildr loads this library at runtime, even if totpm itself was statically linked.
+pub fn tcti_library(tcti: &str) -> String {
+    format!("libtss2-tcti-{}.so.0", tcti_name(tcti))
+}
+
+/// Checks that the shared library required by the given TCTI configuration can be loaded.
+pub fn check_tcti_loadable(tcti: &str) -> Result<(), MissingTcti> {
+    let library = tcti_library(tcti);
+    let c_library = CString::new(library.as_str()).unwrap();
+    log::info!("checking that {} can be loaded", library);
+    unsafe {
+        let handle = dlopen(c_library.as_ptr(), RTLD_NOW);
+        if handle.is_null() {
+            return Err(MissingTcti { library, package: tcti_package(tcti) })
+        }
+        dlclose(handle);
+    }
+    Ok(())
+}
+
+fn tcti_package(tcti: &str) -> &'static str {
+    match tcti_name(tcti) {
+        "tabrmd" => "tpm2-abrmd",
+        _ => "tpm2-tss",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcti_library_is_derived_from_tcti_name() {
+        assert_eq!(tcti_library("device:/dev/tpmrm0"), "libtss2-tcti-device.so.0");
+        assert_eq!(tcti_library("device"), "libtss2-tcti-device.so.0");
+        assert_eq!(tcti_library("swtpm:host=127.0.0.1,port=2321"), "libtss2-tcti-swtpm.so.0");
+        assert_eq!(tcti_library("tabrmd:bus_type=system"), "libtss2-tcti-tabrmd.so.0");
+    }
+
+    #[test]
+    fn validate_tcti_accepts_supported_forms() {
+        for tcti in PROBE_CANDIDATES {
+            validate_tcti(tcti).unwrap();
+        }
+        validate_tcti("device").unwrap();
+        validate_tcti("tabrmd:bus_name=com.intel.tss2.Tabrmd,bus_type=session").unwrap();
+    }
+
+    #[test]
+    fn validate_tcti_rejects_malformed_and_unsupported_forms() {
+        for tcti in [
+            "potato",
+            "device:dev/tpm0",
+            "swtpm:host=localhost,port=potato",
+            "mssim:hostname=localhost",
+            "swtpm:localhost",
+            "tabrmd:bus_type=bus",
+        ] {
+            assert_eq!(validate_tcti(tcti).unwrap_err().tcti, tcti);
+        }
+    }
+
+    #[test]
+    fn uses_resource_manager_only_for_tpmrm_devices_and_tabrmd() {
+        assert!(uses_resource_manager("device:/dev/tpmrm0"));
+        assert!(uses_resource_manager("device"));
+        assert!(uses_resource_manager("tabrmd"));
+        assert!(uses_resource_manager("tabrmd:bus_type=session"));
+        assert!(!uses_resource_manager("device:/dev/tpm0"));
+        assert!(!uses_resource_manager("swtpm:host=localhost,port=2321"));
+        assert!(!uses_resource_manager("mssim"));
+    }
+
+    #[test]
+    fn check_tcti_loadable_succeeds_for_swtpm() {
+        check_tcti_loadable("swtpm:host=127.0.0.1,port=2321").unwrap();
+    }
+
+    #[test]
+    fn check_tcti_loadable_names_missing_library() {
+        assert_eq!(
+            check_tcti_loadable("potato:foo").unwrap_err(),
+            MissingTcti { library: "libtss2-tcti-potato.so.0".to_owned(), package: "tpm2-tss" },
+        );
+        assert_eq!(
+            check_tcti_loadable("potato").unwrap_err().library,
+            "libtss2-tcti-potato.so.0",
+        );
+    }
+}
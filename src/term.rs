@@ -1,4 +1,6 @@
-use std::{fmt::Display, io::{BufRead, IsTerminal, Stdout, Write}};
+use std::{fmt::Display, io::{BufRead, ErrorKind, IsTerminal, Stdout, Write}, process::{Command, Stdio}};
+
+use crate::tr;
 
 
 /// Since we can't implement IsTerminal, we need a custom trait
@@ -30,7 +32,7 @@ pub fn pick_one<'a, T: Display, I: Iterator<Item = &'a T>, In: BufRead, Out: Wri
                 return None
             }
             out.write_fmt(format_args!("{}\n", msg)).unwrap();
-            out.write_fmt(format_args!("0:\t[cancel]\n")).unwrap();
+            out.write_fmt(format_args!("0:\t{}\n", tr!("term-cancel"))).unwrap();
             for (i, item) in alts.iter().enumerate() {
                 out.write_fmt(format_args!("{}:\t{}\n", i + 1, item)).unwrap();
             }
@@ -46,13 +48,87 @@ pub fn pick_one<'a, T: Display, I: Iterator<Item = &'a T>, In: BufRead, Out: Wri
                 } else if ix <= num_alts {
                     return Some(alts[ix - 1])
                 } else {
-                    out.write_fmt(format_args!("invalid selection\n")).unwrap()
+                    out.write_fmt(format_args!("{}\n", tr!("term-invalid-selection"))).unwrap()
                 }
             }        
         }
     }
 }
 
+/// Number of lines assumed to fit in the terminal, unless LINES says otherwise.
+const DEFAULT_TERMINAL_LINES: usize = 24;
+
+/// Writes the given text to stdout, through the user's pager if stdout is a terminal and the text doesn't fit in it.
+/// The pager is taken from PAGER, defaulting to less; an empty PAGER or cat disables paging.
+pub fn page(text: &str) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    let lines = std::env::var("LINES").ok().and_then(|lines| lines.parse().ok()).unwrap_or(DEFAULT_TERMINAL_LINES);
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_owned());
+    if !needs_paging(text, stdout.isatty(), lines) || matches!(pager.trim(), "" | "cat") {
+        return stdout.write_all(text.as_bytes())
+    }
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&pager).stdin(Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        // Like git: quit if the text fits on screen after all, pass colors through and don't clear the screen
+        command.env("LESS", "FRX");
+    }
+    let mut child = command.spawn()?;
+    if let Err(e) = child.stdin.take().unwrap().write_all(text.as_bytes()) {
+        // The user quit the pager before reading everything
+        if e.kind() != ErrorKind::BrokenPipe {
+            return Err(e)
+        }
+    }
+    if child.wait()?.code() == Some(127) {
+        log::warn!("unable to run pager: {}", pager);
+        return stdout.write_all(text.as_bytes())
+    }
+    Ok(())
+}
+
+/// Returns true if the given text is too long to fit in a terminal of the given number of lines.
+fn needs_paging(text: &str, isatty: bool, terminal_lines: usize) -> bool {
+    // Leave room for the prompt, so that the first line doesn't scroll away
+    isatty && text.lines().count() >= terminal_lines
+}
+
+/// Asks the user a question and returns their answer.
+/// If the answer is empty, the given default is returned instead.
+pub fn prompt<In: BufRead, Out: Write>(
+    inp: &mut In,
+    out: &mut Out,
+    msg: &str,
+    default: &str,
+) -> std::io::Result<String> {
+    if default.is_empty() {
+        out.write_fmt(format_args!("{}: ", msg))?;
+    } else {
+        out.write_fmt(format_args!("{} [{}]: ", msg, default))?;
+    }
+    out.flush()?;
+    let mut response = String::new();
+    inp.read_line(&mut response)?;
+    let response = response.trim();
+    if response.is_empty() {
+        Ok(default.to_owned())
+    } else {
+        Ok(response.to_owned())
+    }
+}
+
+/// Asks the user a yes/no question, returning true only if they answer yes.
+/// No answer, e.g. at the end of input, counts as no.
+pub fn confirm<In: BufRead, Out: Write>(inp: &mut In, out: &mut Out, msg: &str) -> std::io::Result<bool> {
+    out.write_fmt(format_args!("{} {} ", msg, tr!("term-confirm-choices")))?;
+    out.flush()?;
+    let mut response = String::new();
+    inp.read_line(&mut response)?;
+    let response = response.trim().to_lowercase();
+    // English answers are always accepted, since users of translations often answer in English anyway
+    Ok(matches!(response.as_str(), "y" | "yes") || tr!("term-yes-answers").split_whitespace().any(|yes| yes == response))
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::VecDeque;
@@ -175,4 +251,59 @@ mod tests {
             Some(&2u32),
         );
     }
+
+    #[test]
+    fn prompt_returns_answer() {
+        let mut term = MockTerminal::new()
+            .expect_stdout("question [default]: ")
+            .write_stdin("answer");
+        let (mut inp, mut out) = term.stdin_stdout();
+        assert_eq!(
+            prompt(&mut inp, &mut out, "question", "default").unwrap(),
+            "answer",
+        );
+    }
+
+    #[test]
+    fn prompt_returns_default_on_empty_answer() {
+        let mut term = MockTerminal::new()
+            .expect_stdout("question [default]: ")
+            .write_stdin("");
+        let (mut inp, mut out) = term.stdin_stdout();
+        assert_eq!(
+            prompt(&mut inp, &mut out, "question", "default").unwrap(),
+            "default",
+        );
+    }
+
+    #[test]
+    fn confirm_accepts_only_yes() {
+        for (answer, expected) in [("y", true), ("YES", true), ("n", false), ("", false), ("yep", false)] {
+            let mut term = MockTerminal::new()
+                .expect_stdout("sure? [y/N] ")
+                .write_stdin(answer);
+            let (mut inp, mut out) = term.stdin_stdout();
+            assert_eq!(confirm(&mut inp, &mut out, "sure?").unwrap(), expected, "answer: {}", answer);
+        }
+    }
+
+    #[test]
+    fn needs_paging_only_if_text_does_not_fit_in_terminal() {
+        let text = "line\n".repeat(23);
+        assert!(!needs_paging(&text, true, 24));
+        assert!(needs_paging(&(text.clone() + "line\n"), true, 24));
+        assert!(!needs_paging(&(text + "line\n"), false, 24));
+    }
+
+    #[test]
+    fn prompt_omits_empty_default() {
+        let mut term = MockTerminal::new()
+            .expect_stdout("question: ")
+            .write_stdin("answer");
+        let (mut inp, mut out) = term.stdin_stdout();
+        assert_eq!(
+            prompt(&mut inp, &mut out, "question", "").unwrap(),
+            "answer",
+        );
+    }
 }
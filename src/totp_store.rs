@@ -1,9 +1,9 @@
-use std::{fs::Permissions, io::Write, marker::PhantomData, os::unix::fs::PermissionsExt, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::HashSet, fmt::Display, fs::Permissions, io::Write, marker::PhantomData, os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
-use rand::RngCore;
-use tss_esapi::{handles::KeyHandle, structures::{Digest, Public}, traits::{Marshall, UnMarshall}};
+use tss_esapi::{handles::KeyHandle, interface_types::dynamic_handles::Persistent, structures::{Auth, Digest, EncryptedSecret, Private, Public, Signature}, traits::{Marshall, UnMarshall}};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::{config::Config, db::{self, model::Secret}, presence_verification::{factory::create_presence_verifier, PresenceVerifier}, privileges::{drop_privileges, with_uid_as_euid}, tpm::{self, HmacKey, TPM}};
+use crate::{config::Config, housekeeping, db::{self, fold_case, glob_matches, model::{SecondaryKey, Secret}, Mutation, SecretFilter}, migration::{Bundle, MigratedSecret, Target, WrappedSecret}, observer::{NoObserver, Observer, TpmOperation}, presence_verification::{self, factory::create_presence_verifier, ConstPresenceVerifier, PresenceVerifier}, privileges::{real_user_id, EuidSwapGuard, PrivilegeDropGuard}, rng, tpm::{self, HmacKey, MigrationBranch, SymmetricKey, TPM}};
 
 #[derive(Debug)]
 pub enum Error {
@@ -13,13 +13,65 @@ pub enum Error {
     IOError(std::io::Error),
     DBError(db::Error),
     KeyHandleError,
+    /// Some secrets have encrypted metadata, but the metadata key could not be used,
+    /// either because the store was opened without the TPM or because the key is missing.
+    MetadataKeyUnavailable,
+    /// Encrypted metadata could not be decoded or decrypted.
+    MetadataCorrupted,
+    /// There is no migration target on this machine to import secrets with.
+    MigrationTargetUnavailable,
+    /// The secrets to import were duplicated to another machine's migration target.
+    MigrationTargetMismatch,
+    /// A migration target or duplicated secret could not be decoded.
+    MalformedMigrationData,
+    /// The migration target to export secrets to has not been approved by this machine's migration approver.
+    MigrationTargetNotApproved,
+    /// The keys of the given secrets are bound to the primary key, so the store can't be rekeyed.
+    KeysNotMigratable(Vec<Secret>),
+    /// The primary key is shared with other users, whose secrets would become unusable by rekeying.
+    SharedPrimaryKey,
+    /// The secret is protected by a PIN, but none was given.
+    PinRequired,
+    /// Secrets to recover from their secondary keys have encrypted metadata,
+    /// which can only be decrypted using the TPM they were added with.
+    MetadataNotRecoverable,
+    /// The worker thread of an AsyncStore has stopped, because opening the store or an earlier request failed.
+    WorkerStopped,
 }
 
+impl Error {
+    /// Returns a stable, machine-readable code for the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotInitialized => "store.not-initialized",
+            Error::AlreadyInitialized => "store.already-initialized",
+            Error::TpmError(e) => e.code(),
+            Error::IOError(_) => "io",
+            Error::DBError(e) => e.code(),
+            Error::KeyHandleError => "store.key-handle-corrupted",
+            Error::MetadataKeyUnavailable => "store.metadata-key-unavailable",
+            Error::MetadataCorrupted => "store.metadata-corrupted",
+            Error::MigrationTargetUnavailable => "migration.target-unavailable",
+            Error::MigrationTargetMismatch => "migration.target-mismatch",
+            Error::MalformedMigrationData => "migration.malformed",
+            Error::MigrationTargetNotApproved => "migration.target-not-approved",
+            Error::KeysNotMigratable(_) => "store.keys-not-migratable",
+            Error::SharedPrimaryKey => "store.shared-primary-key",
+            Error::PinRequired => "store.pin-required",
+            Error::MetadataNotRecoverable => "store.metadata-not-recoverable",
+            Error::WorkerStopped => "store.worker-stopped",
+        }
+    }
+}
+
+/// Size of the random IV stored in front of each encrypted metadata field.
+const METADATA_IV_SIZE: usize = 16;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 impl From<tss_esapi::Error> for Error {
     fn from(value: tss_esapi::Error) -> Self {
-        Error::TpmError(tpm::Error::TpmError(value))
+        Error::TpmError(tpm::Error::from(value))
     }
 }
 
@@ -41,11 +93,36 @@ impl From<db::Error> for Error {
     }
 }
 
+/// A problem with the store, found by verify or verify_keys.
+#[derive(Debug)]
+pub enum Problem {
+    /// SQLite's integrity check reported the given message.
+    Database(String),
+    /// The wrapped HMAC key of the secret can not be decoded.
+    MalformedKey(Secret),
+    /// The wrapped HMAC key of the secret can not be loaded under the primary key.
+    KeyNotLoadable(Secret, String),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Database(message) => write!(f, "database: {}", message),
+            Problem::MalformedKey(secret) => write!(f, "{}: malformed key data", describe(secret)),
+            Problem::KeyNotLoadable(secret, error) => write!(f, "{}: key can not be loaded: {}", describe(secret), error),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TotpStore<T> {
     config: Config,
     tpm: Option<TPM>,
     primary_key: Option<KeyHandle>,
+    metadata_key: Option<SymmetricKey>,
+    /// The transaction database accesses are made in while running batch, if any.
+    batch: Option<db::Batch>,
+    observer: Box<dyn Observer>,
     phantom: PhantomData<T>,
 }
 
@@ -56,36 +133,218 @@ pub struct WithTPM;
 pub struct WithoutTPM;
 
 impl <P> TotpStore<P> {
+    /// Returns the configuration the store was opened with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Sets the observer to notify about what the store is doing.
+    pub fn set_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observer = observer;
+    }
+
+    /// Deletes the given secret. The deletion can be reversed using undo, until the next add or del.
     pub fn del(&mut self, secret_id: i64) -> Result<()> {
         self.with_db(|db| {
-            db.del_secret(secret_id)
+            db.trash_secret(secret_id)
+        })?;
+        Ok(())
+    }
+
+    /// Sets whether the given secret is pinned, i.e. listed and offered for selection before secrets that aren't.
+    pub fn set_pinned(&mut self, secret_id: i64, pinned: bool) -> Result<()> {
+        self.with_db(|db| {
+            db.set_pinned(secret_id, pinned)
         })?;
         Ok(())
     }
 
-    pub fn list(&self, service: Option<&str>, account: Option<&str>) -> Result<Vec<Secret>> {
-        let result = self.with_db(|db| {
-            db.list_secrets(service.unwrap_or(""), account.unwrap_or(""))
+    /// Permanently deletes all of the given secrets in a single transaction.
+    /// Unlike del, this can't be undone, and whatever could be undone before can't be afterwards.
+    pub fn del_all(&mut self, secret_ids: &[i64]) -> Result<()> {
+        self.with_db(|db| {
+            db.clear_journal()?;
+            for secret_id in secret_ids {
+                db.del_secret(*secret_id)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    pub fn list(&mut self, service: Option<&str>, account: Option<&str>) -> Result<Vec<Secret>> {
+        self.find(&SecretFilter {
+            service: service.unwrap_or(""),
+            account: account.unwrap_or(""),
+            ..Default::default()
+        })
+    }
+
+    /// Returns all secrets matching the given filter, with their metadata decrypted.
+    /// Service and account names match regardless of case and Unicode normalization.
+    /// Once a metadata key exists, the database can no longer match on service and account,
+    /// so all secrets with the given tag are decrypted and matched, and the limit and offset applied, here instead.
+    pub fn find(&mut self, filter: &SecretFilter) -> Result<Vec<Secret>> {
+        let (has_metadata_key, secrets) = self.with_db(|db| {
+            if db.get_metadata_key()?.is_some() {
+                Ok((true, db.find_secrets(&SecretFilter { tag: filter.tag, id: filter.id, ..Default::default() })?))
+            } else {
+                Ok((false, db.find_secrets(filter)?))
+            }
         })?;
-        Ok(result)
+        if !has_metadata_key {
+            return Ok(secrets)
+        }
+
+        let mut result = Vec::new();
+        for secret in secrets {
+            let secret = self.decrypt_metadata(secret)?;
+            let names_match = if filter.glob {
+                (filter.service.is_empty() || glob_matches(&fold_case(filter.service), &fold_case(&secret.service)))
+                    && (filter.account.is_empty() || glob_matches(&fold_case(filter.account), &fold_case(&secret.account)))
+            } else if filter.exact {
+                (filter.service.is_empty() || fold_case(&secret.service) == fold_case(filter.service))
+                    && (filter.account.is_empty() || fold_case(&secret.account) == fold_case(filter.account))
+            } else {
+                contains_ignore_case(&secret.service, filter.service)
+                    && contains_ignore_case(&secret.account, filter.account)
+            };
+            if names_match {
+                result.push(secret);
+            }
+        }
+        result.sort_by_cached_key(|secret| (!secret.pinned, fold_case(&secret.service), fold_case(&secret.account)));
+        let limit = filter.limit.map_or(usize::MAX, |limit| limit as usize);
+        Ok(result.into_iter().skip(filter.offset as usize).take(limit).collect())
+    }
+
+    /// Runs SQLite's integrity check, and checks that the wrapped HMAC key of each secret can be decoded.
+    /// Does not touch the TPM, except to decrypt metadata if available.
+    pub fn verify(&mut self) -> Result<Vec<Problem>> {
+        let (messages, secrets) = self.with_db(|db| Ok((db.integrity_check()?, db.list_secrets("", "")?)))?;
+        let mut problems = messages.into_iter().map(Problem::Database).collect::<Vec<_>>();
+        for secret in secrets {
+            if wrapped_key(&secret).is_none() {
+                problems.push(Problem::MalformedKey(self.decrypt_metadata_if_possible(secret)));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Like decrypt_metadata, but returns the secret as-is if its metadata can't be decrypted.
+    fn decrypt_metadata_if_possible(&mut self, secret: Secret) -> Secret {
+        self.decrypt_metadata(secret.clone()).unwrap_or(secret)
+    }
+
+    /// Decrypts the metadata of the given secret, if it's encrypted.
+    fn decrypt_metadata(&mut self, mut secret: Secret) -> Result<Secret> {
+        if !secret.metadata_encrypted {
+            return Ok(secret)
+        }
+        let key = self.metadata_key(false)?;
+        secret.service = self.decrypt_string(&key, &secret.service)?;
+        secret.account = self.decrypt_string(&key, &secret.account)?;
+        for field in [&mut secret.notes, &mut secret.url, &mut secret.icon] {
+            if let Some(value) = field.take() {
+                *field = Some(self.decrypt_string(&key, &value)?);
+            }
+        }
+        secret.metadata_encrypted = false;
+        Ok(secret)
+    }
+
+    fn decrypt_string(&mut self, key: &SymmetricKey, encoded: &str) -> Result<String> {
+        let data = hex_decode(encoded)
+            .filter(|x| x.len() >= METADATA_IV_SIZE)
+            .ok_or(Error::MetadataCorrupted)?;
+        let (iv, ciphertext) = data.split_at(METADATA_IV_SIZE);
+        let tpm = self.tpm.as_mut().ok_or(Error::MetadataKeyUnavailable)?;
+        let plaintext = tpm.encrypt_decrypt(key, true, iv, ciphertext)?;
+        String::from_utf8(plaintext).or(Err(Error::MetadataCorrupted))
+    }
+
+    /// Returns the metadata key, loading it from the database if needed.
+    /// If there is no metadata key yet and create is true, a new one is generated.
+    fn metadata_key(&mut self, create: bool) -> Result<SymmetricKey> {
+        if let Some(key) = &self.metadata_key {
+            return Ok(key.clone())
+        }
+        let primary_key = self.primary_key.ok_or(Error::MetadataKeyUnavailable)?;
+        let key = match self.with_db(|db| db.get_metadata_key())? {
+            Some((public_data, private_data)) => SymmetricKey::new(
+                primary_key,
+                Public::unmarshall(&public_data)?,
+                private_data.try_into()?,
+            ),
+            None if create => {
+                log::info!("generating metadata key");
+                let tpm = self.tpm.as_mut().ok_or(Error::MetadataKeyUnavailable)?;
+                let key = tpm.create_symmetric_key(primary_key)?;
+                let public_data = key.public.marshall()?;
+                self.with_db(|db| db.set_metadata_key(&public_data, &key.private))?;
+                key
+            },
+            None => return Err(Error::MetadataKeyUnavailable),
+        };
+        self.metadata_key = Some(key.clone());
+        Ok(key)
+    }
+
+    /// Runs f, making all of its database accesses in a single transaction, which is committed if f succeeds
+    /// and rolled back otherwise. Use this when making many changes at once, e.g. importing secrets,
+    /// to avoid reopening the database and committing for every change.
+    /// The database stays locked until f returns. Calls made while already in a batch join the outer batch.
+    pub fn batch<T, E, F>(&mut self, f: F) -> std::result::Result<T, E>
+    where
+        E: From<Error>,
+        F: FnOnce(&mut Self) -> std::result::Result<T, E>,
+    {
+        if self.batch.is_some() {
+            return f(self)
+        }
+        let batch = db::Batch::begin(self.config.secrets_db_path(), self.config.db_locking).map_err(Error::from)?;
+        self.batch = Some(batch);
+        let result = f(self);
+        let batch = self.batch.take().unwrap();
+        if result.is_ok() {
+            batch.commit().map_err(Error::from)?;
+        }
+        result
+    }
+
+    /// Returns the given service or account name in Unicode normalization form C if normalize_names is set,
+    /// so that names which look the same are stored the same way, however they were typed.
+    fn normalize_name(&self, name: &str) -> String {
+        if self.config.normalize_names {
+            name.nfc().collect()
+        } else {
+            name.to_owned()
+        }
     }
 
     fn with_db<T, F: FnOnce(&db::DB) -> db::Result<T>>(&self, f: F) -> db::Result<T> {
-        db::with_db(self.config.secrets_db_path(), f)
+        match &self.batch {
+            Some(batch) => batch.with(f),
+            None => db::with_db_locking(self.config.secrets_db_path(), self.config.db_locking, f),
+        }
     }
 }
 
 impl TotpStore<WithoutTPM> {
     /// Creates a TOTP store client which does not access the TPM.
     /// Immediately drops privileges.
-    pub fn without_tpm(config: Config) -> TotpStore<WithoutTPM> {
-        drop_privileges();
-        TotpStore {
+    pub fn without_tpm(config: Config) -> Result<TotpStore<WithoutTPM>> {
+        PrivilegeDropGuard::new()?.forever()?;
+        housekeeping::clean(config.secrets_db_path().parent().unwrap());
+        Ok(TotpStore {
             config,
             tpm: None,
             primary_key: None,
+            metadata_key: None,
+            batch: None,
+            observer: Box::new(NoObserver),
             phantom: PhantomData,
-        }
+        })
     }
 
     /// Initializes a secret store.
@@ -93,8 +352,8 @@ impl TotpStore<WithoutTPM> {
         if config.auth_value_path().is_file() || config.primary_key_handle_path().is_file() {
             return Err(Error::AlreadyInitialized);
         }
-        let pv = create_presence_verifier(config.pv_method, config.pv_timeout);
-        let mut tpm = TPM::new(pv, &config.tpm)?;
+        let pv = create_presence_verifier(&config);
+        let mut tpm = open_tpm(pv, &config, &mut NoObserver)?;
 
         log::info!(
             "creating system data directory with permissions 0700 at {}",
@@ -103,31 +362,38 @@ impl TotpStore<WithoutTPM> {
         std::fs::create_dir_all(&config.system_data_path)?;
         std::fs::set_permissions(&config.system_data_path, Permissions::from_mode(0o700))?;
 
-        log::info!(
-            "creating auth value file with permissions 0600 at {}",
-            config.auth_value_path().to_str().unwrap(),
-        );
-        let mut auth_value_file = std::fs::File::create(config.auth_value_path())?;
-        auth_value_file.set_permissions(Permissions::from_mode(0o600))?;
-
         let mut auth_value = vec![0u8; 32];
-        rand::thread_rng().fill_bytes(&mut auth_value);
-        auth_value_file.write_all(&auth_value)?;
-        drop(auth_value_file);
+        rng::fill_bytes(&mut auth_value);
 
+        // Nothing is written until the primary key exists, and everything is undone if any later step fails,
+        // so that a failed init can simply be retried.
         log::info!("creating primary key");
-        let key_handle = tpm.create_persistent_primary(auth_value.try_into()?)?;
-        let handle_u32: u32 = match key_handle {
-            tss_esapi::interface_types::dynamic_handles::Persistent::Persistent(persistent_tpm_handle) => {
-                persistent_tpm_handle.into()
-            },
-        };
-        log::info!(
-            "persisting primary key handle {} at {}",
-            handle_u32,
-            config.primary_key_handle_path().to_str().unwrap(),
-        );
-        std::fs::write(config.primary_key_handle_path(), handle_u32.to_string())?;
+        let handle_u32 = persistent_handle_to_u32(tpm.create_persistent_primary(auth_value.clone().try_into()?)?);
+        let result = tpm.get_persistent_primary(handle_u32, auth_value.clone().try_into()?)
+            .map_err(Error::from)
+            .and_then(|primary_key| {
+                create_migration_approver(&config, &mut tpm, primary_key)?;
+                log::info!(
+                    "persisting primary key handle {} at {}",
+                    handle_u32,
+                    config.primary_key_handle_path().to_str().unwrap(),
+                );
+                write_private(&config.primary_key_handle_path(), handle_u32.to_string().as_bytes())?;
+                log::info!(
+                    "creating auth value file with permissions 0600 at {}",
+                    config.auth_value_path().to_str().unwrap(),
+                );
+                write_private(&config.auth_value_path(), &auth_value)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            log::info!("initialization failed; deleting primary key");
+            tpm.delete_persistent_primary(handle_u32, auth_value.try_into()?)?;
+            let _ = std::fs::remove_file(config.migration_approver_path());
+            let _ = std::fs::remove_file(config.primary_key_handle_path());
+            let _ = std::fs::remove_file(config.auth_value_path());
+            return Err(e)
+        }
         Ok(())
     }
 
@@ -135,9 +401,11 @@ impl TotpStore<WithoutTPM> {
     /// If system is true, also removes all system data.
     pub fn clear(config: Config, system: bool) -> Result<()> {
         if system {
-            let pv = create_presence_verifier(config.pv_method, config.pv_timeout);
+            let pv = create_presence_verifier(&config);
             let mut tpm = TPM::new(pv, &config.tpm)?;
 
+            // Only a handle we recorded ourselves is evicted; whatever sits at the configured handle
+            // may well belong to someone else.
             if config.auth_value_path().is_file() && config.primary_key_handle_path().is_file() {
                 let pk_handle = read_primary_key_persistent_handle(&config)?;
                 let auth_value = read_auth_value(&config)?;
@@ -161,17 +429,20 @@ impl TotpStore<WithoutTPM> {
             } else {
                 log::info!("no primary key handle file to remove");
             }
-        }
 
-        with_uid_as_euid(||{
-            if config.secrets_db_path().is_file() {
-                log::info!("removing secrets database at {}", config.secrets_db_path().to_str().unwrap());
-                std::fs::remove_file(config.secrets_db_path())
-            } else {
-                log::info!("no secrets database to remove");
-                Ok(())
+            if config.migration_approver_path().is_file() {
+                log::info!("removing migration approver at {}", config.migration_approver_path().to_str().unwrap());
+                std::fs::remove_file(config.migration_approver_path())?;
             }
-        })?;
+        }
+
+        let _euid = EuidSwapGuard::real_user()?;
+        if config.secrets_db_path().is_file() {
+            log::info!("removing secrets database at {}", config.secrets_db_path().to_str().unwrap());
+            std::fs::remove_file(config.secrets_db_path())?;
+        } else {
+            log::info!("no secrets database to remove");
+        }
 
         Ok(())
     }
@@ -181,33 +452,222 @@ impl TotpStore<WithTPM> {
     /// Creates a TOTP store client which uses the TPM.
     /// Drops privileges immediately after reading the auth value.
     pub fn with_tpm(config: Config) -> Result<Self> {
-        let pv = create_presence_verifier(config.pv_method, config.pv_timeout);
-        Self::with_tpm_ex(pv, config)
+        Self::with_tpm_observed(config, Box::new(NoObserver))
     }
 
-    fn with_tpm_ex(pv: Box<dyn PresenceVerifier>, config: Config) -> Result<Self> {
+    /// Like with_tpm, but notifies the given observer about presence verification and everything after it.
+    pub fn with_tpm_observed(config: Config, observer: Box<dyn Observer>) -> Result<Self> {
+        let pv = create_presence_verifier(&config);
+        Self::with_tpm_ex(pv, config, observer)
+    }
+
+    /// Like with_tpm, but doesn't verify presence. Long-running processes serving several requests, such as the
+    /// daemon, must instead call verify_presence before every operation which needs it.
+    pub fn with_tpm_unverified(config: Config) -> Result<Self> {
+        Self::with_tpm_unverified_observed(config, Box::new(NoObserver))
+    }
+
+    /// Like with_tpm_unverified, but notifies the given observer about everything after opening the TPM.
+    pub fn with_tpm_unverified_observed(config: Config, observer: Box<dyn Observer>) -> Result<Self> {
+        Self::with_tpm_ex(Box::new(ConstPresenceVerifier::new(true)), config, observer)
+    }
+
+    /// Verifies that the user is present, using the configured method.
+    pub fn verify_presence(&mut self) -> Result<()> {
+        let pv = create_presence_verifier(&self.config);
+        let result = ObservedPresenceVerifier { pv, observer: self.observer.as_mut() }.owner_present();
+        match result.map_err(tpm::Error::from)? {
+            true => Ok(()),
+            false => Err(Error::TpmError(tpm::Error::PresenceVerificationFailed)),
+        }
+    }
+
+    fn with_tpm_ex(pv: Box<dyn PresenceVerifier>, config: Config, mut observer: Box<dyn Observer>) -> Result<Self> {
         log::info!("Creating TOTP store with the following settings:");
         log::info!("- auth value path: {}", config.auth_value_path().to_str().unwrap());
         log::info!("- primary key handle path: {}", config.primary_key_handle_path().to_str().unwrap());
         log::info!("- secrets db path: {}", config.secrets_db_path().to_str().unwrap());
 
         log::info!("reading auth value");
-        let auth_value = read_auth_value(&config).or(Err(Error::NotInitialized))?;
+        let mut auth_value = read_auth_value(&config).or(Err(Error::NotInitialized))?;
 
         log::info!("reading primary key persistent handle");
-        let handle = read_primary_key_persistent_handle(&config).or(Err(Error::NotInitialized))?;
+        let mut handle = read_primary_key_persistent_handle(&config).or(Err(Error::NotInitialized))?;
 
-        let mut tpm = TPM::new(pv, &config.tpm)?;
+        let mut tpm = open_tpm(pv, &config, observer.as_mut())?;
+        if rekey_journal_path(&config.auth_value_path()).exists() {
+            recover_interrupted_rekey(&config, &mut tpm)?;
+            auth_value = read_auth_value(&config)?;
+            handle = read_primary_key_persistent_handle(&config)?;
+        }
+        if let Some(handles) = config.persistent_handle.filter(|handles| !handles.contains(handle)) {
+            log::warn!("primary key is stored at {:#010x}, outside of the configured persistent handles {}", handle, handles);
+        }
+        observer.on_tpm_op(TpmOperation::LoadPrimaryKey);
         let primary_key = tpm.get_persistent_primary(handle, auth_value.try_into()?)?;
+        load_migration_approver(&config, &mut tpm, primary_key);
 
-        drop_privileges();
+        PrivilegeDropGuard::new()?.forever()?;
+        housekeeping::clean(config.secrets_db_path().parent().unwrap());
 
-        Ok(TotpStore {
+        let mut store = TotpStore {
             config,
             tpm: Some(tpm),
             primary_key: Some(primary_key),
+            metadata_key: None,
+            batch: None,
+            observer,
             phantom: PhantomData,
-        })
+        };
+        store.add_known_migration_branches();
+        if store.config.encrypt_metadata {
+            store.encrypt_existing_metadata()?;
+        }
+        Ok(store)
+    }
+
+    /// Creates a new primary key and re-wraps the keys of all secrets under it in a single transaction,
+    /// then evicts the old primary key from the TPM. Returns the number of re-wrapped secrets.
+    /// Nothing is changed if the key of any secret is bound to the old primary key.
+    /// Since other users' secrets become unusable, the store is only rekeyed if the current user owns
+    /// the system data directory, unless force is true.
+    pub fn rekey(config: Config, force: bool) -> Result<usize> {
+        let owner = std::fs::metadata(&config.system_data_path).or(Err(Error::NotInitialized))?.uid();
+        if owner != real_user_id() && !force {
+            return Err(Error::SharedPrimaryKey)
+        }
+
+        read_auth_value(&config).or(Err(Error::NotInitialized))?;
+        let pv = create_presence_verifier(&config);
+        let mut tpm = open_tpm(pv, &config, &mut NoObserver)?;
+        if rekey_journal_path(&config.auth_value_path()).exists() {
+            recover_interrupted_rekey(&config, &mut tpm)?;
+        }
+
+        log::info!("reading auth value");
+        let old_auth_value = read_auth_value(&config).or(Err(Error::NotInitialized))?;
+        log::info!("reading primary key persistent handle");
+        let old_handle = read_primary_key_persistent_handle(&config).or(Err(Error::NotInitialized))?;
+        let old_primary_key = tpm.get_persistent_primary(old_handle, old_auth_value.clone().try_into()?)?;
+        let approver = read_migration_approver(&config).ok();
+        if let Some((public, _)) = &approver {
+            tpm.set_migration_approver(public.clone());
+        }
+        let mut store = TotpStore {
+            config: config.clone(),
+            tpm: Some(tpm),
+            primary_key: Some(old_primary_key),
+            metadata_key: None,
+            batch: None,
+            observer: Box::new(NoObserver),
+            phantom: PhantomData,
+        };
+
+        let fixed = {
+            let _euid = EuidSwapGuard::real_user()?;
+            store.add_known_migration_branches();
+            store.fixed_secrets()?
+        };
+        if !fixed.is_empty() {
+            return Err(Error::KeysNotMigratable(fixed))
+        }
+
+        let mut auth_value = vec![0u8; 32];
+        rng::fill_bytes(&mut auth_value);
+        log::info!("creating new primary key");
+        let handle = persistent_handle_to_u32(store.tpm().create_persistent_primary(auth_value.clone().try_into()?)?);
+        let result = store.tpm().get_persistent_primary(handle, auth_value.clone().try_into()?)
+            .map_err(Error::from)
+            .and_then(|new_primary_key| {
+                // The new handle, approver and auth value are journaled before the database is committed,
+                // so that a rekey interrupted after the commit can be completed the next time the store is opened.
+                // The auth value goes last, since its journal marks a rekey as in progress.
+                write_private(&rekey_journal_path(&config.primary_key_handle_path()), handle.to_string().as_bytes())?;
+                if let Some((public, private)) = &approver {
+                    log::info!("re-wrapping migration approver");
+                    let rewrapped = store.tpm().rewrap_hmac_key(
+                        &HmacKey::new(old_primary_key, public.clone(), private.clone()),
+                        new_primary_key,
+                    )?;
+                    write_migration_approver(&rekey_journal_path(&config.migration_approver_path()), public, &rewrapped.private)?;
+                }
+                write_private(&rekey_journal_path(&config.auth_value_path()), &auth_value)?;
+                let _euid = EuidSwapGuard::real_user()?;
+                store.rewrap_all(new_primary_key)
+            });
+        let count = match result {
+            Ok(count) => count,
+            Err(e) => {
+                log::info!("rekeying failed; deleting new primary key");
+                store.tpm().delete_persistent_primary(handle, auth_value.try_into()?)?;
+                remove_rekey_journal(&config);
+                return Err(e)
+            },
+        };
+
+        log::info!("persisting new primary key handle {}", handle);
+        finish_rekey(&config, store.tpm(), Some((old_handle, old_auth_value)))?;
+        Ok(count)
+    }
+
+    /// Returns all secrets whose keys can't be moved to another primary key.
+    fn fixed_secrets(&mut self) -> Result<Vec<Secret>> {
+        let secrets = self.with_db(|db| db.list_secrets("", ""))?;
+        Ok(secrets.into_iter()
+            .filter(|secret| match wrapped_key(secret) {
+                Some((public, _)) => public.object_attributes().fixed_tpm(),
+                None => true,
+            })
+            .map(|secret| self.decrypt_metadata_if_possible(secret))
+            .collect())
+    }
+
+    /// Re-wraps the keys of all secrets under the given primary key, along with a new metadata key
+    /// if there is one, in a single transaction. Anything that could be undone is permanently deleted,
+    /// since its key is still wrapped under the old primary key.
+    fn rewrap_all(&mut self, new_primary_key: KeyHandle) -> Result<usize> {
+        let old_primary_key = *self.primary_key();
+        let secrets = self.with_db(|db| db.list_secrets("", ""))?;
+        let mut rewrapped = Vec::new();
+        for secret in secrets {
+            let (public, private) = wrapped_key(&secret).ok_or(Error::KeyHandleError)?;
+            log::info!("re-wrapping key of secret {}", secret.id);
+            let hmac_key = self.tpm().rewrap_hmac_key(&HmacKey::new(old_primary_key, public, private), new_primary_key)?;
+            let was_encrypted = secret.metadata_encrypted;
+            rewrapped.push((was_encrypted, self.decrypt_metadata(secret)?, hmac_key.private));
+        }
+
+        // The metadata key can't leave the old primary key, so metadata is re-encrypted under a new one.
+        let metadata_key = match self.with_db(|db| db.get_metadata_key())? {
+            Some(_) => {
+                log::info!("generating new metadata key");
+                let key = self.tpm().create_symmetric_key(new_primary_key)?;
+                Some((key.public.marshall()?, key))
+            },
+            None => None,
+        };
+        self.primary_key = Some(new_primary_key);
+        self.metadata_key = metadata_key.as_ref().map(|(_, key)| key.clone());
+        let mut reencrypted = Vec::new();
+        for (was_encrypted, secret, _) in &rewrapped {
+            if *was_encrypted {
+                reencrypted.push(self.protect_metadata(secret)?);
+            }
+        }
+
+        self.with_db(|db| {
+            for (_, secret, private) in &rewrapped {
+                db.update_private_data(secret.id, private)?;
+            }
+            for secret in &reencrypted {
+                db.update_metadata(secret)?;
+            }
+            if let Some((public_data, key)) = &metadata_key {
+                db.replace_metadata_key(public_data, &key.private)?;
+            }
+            db.clear_journal()
+        })?;
+        Ok(rewrapped.len())
     }
 
     pub fn add(
@@ -217,44 +677,535 @@ impl TotpStore<WithTPM> {
         digits: Option<u8>,
         interval: Option<u32>,
         secret: &[u8]
+    ) -> Result<Secret> {
+        self.add_ex(service, account, digits, interval, secret, None, |_| {})
+    }
+
+    /// Like add, but lets the caller fill in additional fields of the secret before it's stored.
+    /// If a PIN is given, it will be needed to generate codes for the secret.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_ex<F: FnOnce(&mut Secret)>(
+        &mut self,
+        service: &str,
+        account: &str,
+        digits: Option<u8>,
+        interval: Option<u32>,
+        secret: &[u8],
+        pin: Option<&str>,
+        modify: F,
+    ) -> Result<Secret> {
+        let secondary_key = self.enroll_secondary(secret, pin)?;
+        let mut secret = self.new_secret(service, account, digits, interval, secret, pin)?;
+        modify(&mut secret);
+        let stored_secret = self.protect_metadata(&secret)?;
+
+        log::info!("adding secret to database");
+        secret.id = self.with_db(|db| {
+            let stored_secret = db.add_secret(stored_secret)?;
+            if let Some(mut secondary_key) = secondary_key {
+                secondary_key.secret_id = stored_secret.id;
+                db.set_secondary_key(&secondary_key)?;
+            }
+            db.set_last_mutation(Mutation::Add(stored_secret.id))?;
+            Ok(stored_secret.id)
+        })?;
+        Ok(secret)
+    }
+
+    /// Like add_ex, but atomically replaces the secret with the given id.
+    /// Tags, notes, url, icon, pinning, rotation window, time offset, T0 and usage statistics are carried over from the old secret
+    /// before `modify` is called.
+    #[allow(clippy::too_many_arguments)]
+    pub fn replace_ex<F: FnOnce(&mut Secret)>(
+        &mut self,
+        old_secret_id: i64,
+        service: &str,
+        account: &str,
+        digits: Option<u8>,
+        interval: Option<u32>,
+        secret: &[u8],
+        pin: Option<&str>,
+        modify: F,
+    ) -> Result<Secret> {
+        let secondary_key = self.enroll_secondary(secret, pin)?;
+        let mut secret = self.new_secret(service, account, digits, interval, secret, pin)?;
+        let old_secret = self.with_db(|db| db.get_secret(old_secret_id))?;
+        let old_secret = self.decrypt_metadata(old_secret)?;
+        secret.tags = old_secret.tags;
+        secret.notes = old_secret.notes;
+        secret.url = old_secret.url;
+        secret.icon = old_secret.icon;
+        secret.pinned = old_secret.pinned;
+        secret.rotate_after_days = old_secret.rotate_after_days;
+        secret.offset_seconds = old_secret.offset_seconds;
+        secret.t0 = old_secret.t0;
+        secret.last_used_at = old_secret.last_used_at;
+        secret.use_count = old_secret.use_count;
+        modify(&mut secret);
+        let stored_secret = self.protect_metadata(&secret)?;
+
+        log::info!("replacing secret in database");
+        secret.id = self.with_db(|db| {
+            let stored_secret = db.replace_secret(old_secret_id, stored_secret)?;
+            if let Some(mut secondary_key) = secondary_key {
+                secondary_key.secret_id = stored_secret.id;
+                db.set_secondary_key(&secondary_key)?;
+            }
+            Ok(stored_secret.id)
+        })?;
+        Ok(secret)
+    }
+
+    /// Wraps the given secret under the primary key, returning a secret that's ready to be stored.
+    fn new_secret(
+        &mut self,
+        service: &str,
+        account: &str,
+        digits: Option<u8>,
+        interval: Option<u32>,
+        secret: &[u8],
+        pin: Option<&str>,
     ) -> Result<Secret> {
         let primary_key = *self.primary_key();
 
         log::info!("generating secret hmac key");
-        let hmac_key = self.tpm().create_hmac_key(primary_key, secret)?;
-        let secret = Secret::new(
-            service.to_owned(),
-            account.to_owned(),
+        let migratable = self.config.migratable_keys && self.config.pv_policy.is_none();
+        if self.config.migratable_keys && !migratable {
+            log::warn!("keys carrying a presence verification policy can't be migratable");
+        }
+        let auth_value = pin.map(pin_to_auth).transpose()?;
+        self.observer.on_tpm_op(TpmOperation::CreateKey);
+        let hmac_key = self.tpm().create_hmac_key_with_auth(primary_key, secret, migratable, auth_value)?;
+        let mut secret = Secret::new(
+            self.normalize_name(service),
+            self.normalize_name(account),
             digits,
             interval,
             hmac_key.public.marshall()?,
             hmac_key.private.to_vec(),
         );
+        secret.created_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64);
+        secret.has_pin = pin.is_some();
+        Ok(secret)
+    }
 
-        log::info!("adding secret to database");
-        let added_secret = self.with_db( |db| db.add_secret(secret))?;
-        Ok(added_secret)
+    /// Duplicates a copy of the given key material to the configured secondary target, if any.
+    /// The copy can only be duplicated to the secondary target, and is protected by the same PIN as the key itself.
+    /// The returned key still has to be assigned the id of its secret.
+    fn enroll_secondary(&mut self, secret: &[u8], pin: Option<&str>) -> Result<Option<SecondaryKey>> {
+        let Some(target) = self.secondary_target()? else {
+            return Ok(None)
+        };
+        let target_public = hex_decode(&target.public)
+            .and_then(|data| Public::unmarshall(&data).ok())
+            .ok_or(Error::MalformedMigrationData)?;
+        let primary_key = *self.primary_key();
+
+        log::info!("generating secondary hmac key");
+        let auth_value = pin.map(pin_to_auth).transpose()?;
+        self.observer.on_tpm_op(TpmOperation::CreateKey);
+        let hmac_key = self.tpm().create_hmac_key_for_new_parent(primary_key, secret, target_public.clone(), auth_value)?;
+        log::info!("duplicating secondary hmac key to secondary target");
+        self.observer.on_tpm_op(TpmOperation::DuplicateKey);
+        let (duplicate, seed) = self.tpm().duplicate_hmac_key(&hmac_key, target_public, None)?;
+        Ok(Some(SecondaryKey {
+            secret_id: 0,
+            target: target.public,
+            public_data: hmac_key.public.marshall()?,
+            duplicate: duplicate.to_vec(),
+            seed: seed.value().to_vec(),
+        }))
+    }
+
+    /// Reads the migration target configured as secondary_target, if any.
+    fn secondary_target(&self) -> Result<Option<Target>> {
+        let Some(path) = &self.config.secondary_target else {
+            return Ok(None)
+        };
+        let contents = std::fs::read_to_string(path)?;
+        toml::from_str(&contents).map(Some).or(Err(Error::MalformedMigrationData))
+    }
+
+    /// Returns the given secret as it should be stored, i.e. with its metadata encrypted
+    /// if metadata encryption is enabled.
+    fn protect_metadata(&mut self, secret: &Secret) -> Result<Secret> {
+        let mut secret = secret.clone();
+        if !self.config.encrypt_metadata {
+            return Ok(secret)
+        }
+        let key = self.metadata_key(true)?;
+        secret.service = self.encrypt_string(&key, &secret.service)?;
+        secret.account = self.encrypt_string(&key, &secret.account)?;
+        for field in [&mut secret.notes, &mut secret.url, &mut secret.icon] {
+            if let Some(value) = field.take() {
+                *field = Some(self.encrypt_string(&key, &value)?);
+            }
+        }
+        secret.metadata_encrypted = true;
+        Ok(secret)
+    }
+
+    /// Encrypts the metadata of secrets stored before metadata encryption was enabled.
+    fn encrypt_existing_metadata(&mut self) -> Result<()> {
+        let secrets = self.with_db(|db| db.list_secrets("", ""))?
+            .into_iter()
+            .filter(|secret| !secret.metadata_encrypted)
+            .collect::<Vec<_>>();
+        if secrets.is_empty() {
+            return Ok(())
+        }
+
+        log::info!("encrypting metadata of {} existing secrets", secrets.len());
+        let encrypted_secrets = secrets.iter()
+            .map(|secret| self.protect_metadata(secret))
+            .collect::<Result<Vec<_>>>()?;
+        self.with_db(|db| {
+            for secret in &encrypted_secrets {
+                db.update_metadata(secret)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn encrypt_string(&mut self, key: &SymmetricKey, plaintext: &str) -> Result<String> {
+        let mut iv = [0u8; METADATA_IV_SIZE];
+        rng::fill_bytes(&mut iv);
+        let ciphertext = self.tpm().encrypt_decrypt(key, false, &iv, plaintext.as_bytes())?;
+        Ok(hex_encode(&[iv.as_slice(), &ciphertext].concat()))
+    }
+
+    /// Reverses the last add, del or replace.
+    /// Returns the undone mutation and the affected secret, or None if there was nothing to undo.
+    pub fn undo(&mut self) -> Result<Option<(Mutation, Secret)>> {
+        match self.with_db(|db| db.undo())? {
+            Some((mutation, secret)) => Ok(Some((mutation, self.decrypt_metadata(secret)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Test-loads the HMAC key of each secret under the primary key.
+    /// Secrets whose keys can't be decoded at all are skipped; use verify to find those.
+    pub fn verify_keys(&mut self) -> Result<Vec<Problem>> {
+        let db_path = self.config.secrets_db_path();
+        self.verify_keys_in(db_path)
+    }
+
+    /// Like verify_keys, but checks the secrets in the database at the given path,
+    /// e.g. to make sure that a backup is usable before restoring it.
+    pub fn verify_keys_in<Q: AsRef<Path>>(&mut self, db_path: Q) -> Result<Vec<Problem>> {
+        let primary_key = *self.primary_key();
+        let secrets = db::with_db_locking(db_path, self.config.db_locking, |db| db.list_secrets("", ""))?;
+        let mut problems = Vec::new();
+        for secret in secrets {
+            if let Some((public, private)) = wrapped_key(&secret) {
+                log::info!("loading key of secret {}", secret.id);
+                let hmac_key = HmacKey::new(primary_key, public, private);
+                if let Err(e) = self.tpm().check_hmac_key(&hmac_key) {
+                    let secret = self.decrypt_metadata_if_possible(secret);
+                    problems.push(Problem::KeyNotLoadable(secret, e.to_string()));
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Creates a migration target for moving secrets from another machine to this one,
+    /// replacing any previous one. Its public part is passed to export_migratable on the other machine.
+    pub fn create_migration_target(&mut self) -> Result<Target> {
+        let primary_key = *self.primary_key();
+        log::info!("creating migration target");
+        let (public, private) = self.tpm().create_migration_target(primary_key)?;
+        let target = Target { public: hex_encode(&public.marshall()?), approval: None };
+        let path = self.config.migration_target_path();
+        log::info!("writing migration target to {}", path.to_str().unwrap());
+        housekeeping::atomic_write(&path, format!("{}\n{}\n", target.public, hex_encode(&private)))?;
+        Ok(target)
+    }
+
+    /// Approves duplicating the keys of migratable secrets to the given migration target, created by another machine,
+    /// by adding the signature of this machine's migration approver to it.
+    /// The migration approver is only readable with the privileges of the system data directory's owner.
+    pub fn approve_migration_target(&mut self, target: Target) -> Result<Target> {
+        let target_public = hex_decode(&target.public)
+            .and_then(|data| Public::unmarshall(&data).ok())
+            .ok_or(Error::MalformedMigrationData)?;
+        let approver = read_migration_approver(&self.config)?;
+        let primary_key = *self.primary_key();
+        log::info!("approving migration target");
+        let signature = self.tpm().approve_new_parent(primary_key, approver, target_public)?;
+        Ok(Target { approval: Some(hex_encode(&signature.marshall()?)), ..target })
+    }
+
+    /// Duplicates the keys of all migratable secrets to the given migration target, which must have been approved
+    /// using approve_migration_target. Returns the duplicated secrets, and the secrets that were left out
+    /// because their keys are bound to this TPM.
+    pub fn export_migratable(&mut self, target: Target) -> Result<(Bundle, Vec<Secret>)> {
+        let target_public = hex_decode(&target.public)
+            .and_then(|data| Public::unmarshall(&data).ok())
+            .ok_or(Error::MalformedMigrationData)?;
+        let approval = match &target.approval {
+            Some(approval) => hex_decode(approval)
+                .and_then(|data| Signature::unmarshall(&data).ok())
+                .ok_or(Error::MalformedMigrationData)?,
+            None => return Err(Error::MigrationTargetNotApproved),
+        };
+        let primary_key = *self.primary_key();
+        let secrets = self.with_db(|db| db.list_secrets("", ""))?;
+        let mut migrated = Vec::new();
+        let mut skipped = Vec::new();
+        for secret in secrets {
+            let secret = self.decrypt_metadata(secret)?;
+            let (public, private) = match wrapped_key(&secret) {
+                Some((public, private)) if !public.object_attributes().fixed_tpm() => (public, private),
+                _ => {
+                    skipped.push(secret);
+                    continue;
+                },
+            };
+            log::info!("duplicating key of secret {}", secret.id);
+            let hmac_key = HmacKey::new(primary_key, public, private);
+            self.observer.on_tpm_op(TpmOperation::DuplicateKey);
+            let (duplicate, seed) = self.tpm()
+                .duplicate_hmac_key(&hmac_key, target_public.clone(), Some(approval.clone()))
+                .map_err(|e| match e {
+                    tpm::Error::MigrationNotApproved => Error::MigrationTargetNotApproved,
+                    e => Error::from(e),
+                })?;
+            let public_data = secret.public_data.clone();
+            migrated.push(migrated_secret(secret, &public_data, &duplicate, seed.value()));
+        }
+        let approver = match self.tpm().migration_approver() {
+            Some(approver) => Some(hex_encode(&approver.marshall()?)),
+            None => None,
+        };
+        Ok((Bundle { target, approver, secrets: migrated }, skipped))
+    }
+
+    /// Recovers the secrets in the database at the given path, e.g. a backup made on a machine whose TPM is lost,
+    /// from the secondary keys that were duplicated to this machine's migration target.
+    /// Returns the recovered secrets, and the number of secrets left out because they have no secondary key
+    /// for this machine.
+    pub fn recover_secondary<Q: AsRef<Path>>(&mut self, db_path: Q) -> Result<(Vec<Secret>, usize)> {
+        let (target_public, _) = self.migration_target()?;
+        let target = Target { public: hex_encode(&target_public.marshall()?), approval: None };
+        let (secrets, secondary_keys) = db::with_db_locking(db_path, self.config.db_locking, |db| {
+            Ok((db.list_secrets("", "")?, db.list_secondary_keys()?))
+        })?;
+        let mut recovered = Vec::new();
+        let mut skipped = 0;
+        for secret in secrets {
+            let secondary_key = secondary_keys.iter()
+                .find(|key| key.secret_id == secret.id && key.target == target.public);
+            match secondary_key {
+                Some(_) if secret.metadata_encrypted => return Err(Error::MetadataNotRecoverable),
+                Some(key) => recovered.push(migrated_secret(secret, &key.public_data, &key.duplicate, &key.seed)),
+                None => skipped += 1,
+            }
+        }
+        let secrets = self.import_migrated(Bundle { target, approver: None, secrets: recovered })?;
+        Ok((secrets, skipped))
+    }
+
+    /// Imports secrets duplicated to this machine's migration target, wrapping their keys under the primary key.
+    pub fn import_migrated(&mut self, bundle: Bundle) -> Result<Vec<Secret>> {
+        let (target_public, target_private) = self.migration_target()?;
+        if bundle.target.public != hex_encode(&target_public.marshall()?) {
+            return Err(Error::MigrationTargetMismatch)
+        }
+        if let Some(approver) = &bundle.approver {
+            let public = hex_decode(approver)
+                .and_then(|data| Public::unmarshall(&data).ok())
+                .ok_or(Error::MalformedMigrationData)?;
+            self.tpm().add_migration_branch(MigrationBranch::Approver(public));
+            self.remember_migration_approver(approver)?;
+        }
+        let primary_key = *self.primary_key();
+        let mut secrets = Vec::new();
+        for migrated in bundle.secrets {
+            let public = hex_decode(&migrated.public)
+                .and_then(|data| Public::unmarshall(&data).ok())
+                .ok_or(Error::MalformedMigrationData)?;
+            let duplicate = hex_decode(&migrated.duplicate)
+                .and_then(|data| Private::try_from(data).ok())
+                .ok_or(Error::MalformedMigrationData)?;
+            let seed = hex_decode(&migrated.seed)
+                .and_then(|data| EncryptedSecret::try_from(data).ok())
+                .ok_or(Error::MalformedMigrationData)?;
+            log::info!("importing key of secret {}", migrated.to_secret());
+            self.observer.on_tpm_op(TpmOperation::ImportKey);
+            let hmac_key = self.tpm().import_hmac_key(
+                primary_key,
+                (target_public.clone(), target_private.clone()),
+                public,
+                duplicate,
+                seed,
+            )?;
+            let mut secret = migrated.to_secret();
+            secret.public_data = hmac_key.public.marshall()?;
+            secret.private_data = hmac_key.private.to_vec();
+            secrets.push(secret);
+        }
+
+        let stored_secrets = secrets.iter()
+            .map(|secret| self.protect_metadata(secret))
+            .collect::<Result<Vec<_>>>()?;
+        log::info!("adding {} imported secrets to database", stored_secrets.len());
+        let ids = self.with_db(|db| {
+            stored_secrets.into_iter()
+                .map(|secret| Ok(db.add_secret(secret)?.id))
+                .collect::<db::Result<Vec<_>>>()
+        })?;
+        for (secret, id) in secrets.iter_mut().zip(ids) {
+            secret.id = id;
+        }
+        Ok(secrets)
+    }
+
+    /// Returns all secrets with their keys as wrapped under the primary key, and their metadata decrypted,
+    /// so that they can be put back using add_wrapped if the database is lost.
+    pub fn export_wrapped(&mut self) -> Result<Vec<WrappedSecret>> {
+        let secrets = self.with_db(|db| db.list_secrets("", ""))?;
+        secrets.into_iter()
+            .map(|secret| Ok(WrappedSecret::from(self.decrypt_metadata(secret)?)))
+            .collect()
+    }
+
+    /// Checks that the wrapped keys of the given secrets, which need not be in the database, load under the primary key.
+    pub fn check_wrapped_keys(&mut self, secrets: &[Secret]) -> Result<Vec<Problem>> {
+        let primary_key = *self.primary_key();
+        let mut problems = Vec::new();
+        for secret in secrets {
+            let Some((public, private)) = wrapped_key(secret) else {
+                problems.push(Problem::MalformedKey(secret.clone()));
+                continue;
+            };
+            let hmac_key = HmacKey::new(primary_key, public, private);
+            if let Err(e) = self.tpm().check_hmac_key(&hmac_key) {
+                problems.push(Problem::KeyNotLoadable(secret.clone(), e.to_string()));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Adds the given secrets, whose keys are already wrapped under the primary key, e.g. by export_wrapped.
+    /// Secrets whose key is already in the database are left out.
+    /// Returns the added secrets and the ones that were left out.
+    pub fn add_wrapped(&mut self, secrets: Vec<Secret>) -> Result<(Vec<Secret>, Vec<Secret>)> {
+        let existing = self.with_db(|db| db.list_secrets("", ""))?
+            .into_iter()
+            .map(|secret| secret.public_data)
+            .collect::<HashSet<_>>();
+        let (skipped, mut secrets): (Vec<_>, Vec<_>) = secrets.into_iter()
+            .partition(|secret| existing.contains(&secret.public_data));
+        let stored_secrets = secrets.iter()
+            .map(|secret| self.protect_metadata(secret))
+            .collect::<Result<Vec<_>>>()?;
+        log::info!("adding {} wrapped secrets to database", stored_secrets.len());
+        let ids = self.with_db(|db| {
+            stored_secrets.into_iter()
+                .map(|secret| Ok(db.add_secret(secret)?.id))
+                .collect::<db::Result<Vec<_>>>()
+        })?;
+        for (secret, id) in secrets.iter_mut().zip(ids) {
+            secret.id = id;
+        }
+        Ok((secrets, skipped))
+    }
+
+    /// Makes the TPM aware of this machine's migration target, and the migration approvers of machines secrets
+    /// were imported from, so that keys created for or with either can be moved to another primary key.
+    fn add_known_migration_branches(&mut self) {
+        if let Ok((target, _)) = self.migration_target() {
+            self.tpm().add_migration_branch(MigrationBranch::NewParent(target));
+        }
+        let approvers = std::fs::read_to_string(self.config.migration_approvers_path()).unwrap_or_default();
+        for approver in approvers.lines().filter_map(hex_decode) {
+            match Public::unmarshall(&approver) {
+                Ok(public) => self.tpm().add_migration_branch(MigrationBranch::Approver(public)),
+                Err(e) => log::warn!("ignoring malformed migration approver: {}", e),
+            }
+        }
+    }
+
+    /// Records the given hex-encoded migration approver of another machine, for add_known_migration_branches.
+    /// The approvers need no protection, since the TPM only accepts the one a key was created with.
+    fn remember_migration_approver(&self, approver: &str) -> Result<()> {
+        let path = self.config.migration_approvers_path();
+        let approvers = std::fs::read_to_string(&path).unwrap_or_default();
+        if !approvers.lines().any(|line| line == approver) {
+            housekeeping::atomic_write(&path, format!("{}{}\n", approvers, approver))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the migration target created by create_migration_target.
+    fn migration_target(&self) -> Result<(Public, Private)> {
+        let contents = std::fs::read_to_string(self.config.migration_target_path())
+            .or(Err(Error::MigrationTargetUnavailable))?;
+        let mut lines = contents.lines().map(hex_decode);
+        let public = lines.next()
+            .flatten()
+            .and_then(|data| Public::unmarshall(&data).ok())
+            .ok_or(Error::MalformedMigrationData)?;
+        let private = lines.next()
+            .flatten()
+            .and_then(|data| Private::try_from(data).ok())
+            .ok_or(Error::MalformedMigrationData)?;
+        Ok((public, private))
     }
 
     pub fn gen(&mut self, secret_id: i64, timestamp: SystemTime) -> Result<String> {
+        self.gen_with_pin(secret_id, timestamp, None)
+    }
+
+    /// Like gen, but supplies the given PIN to the TPM for secrets protected by one.
+    /// Too many wrong PINs trigger the TPM's dictionary attack lockout.
+    pub fn gen_with_pin(&mut self, secret_id: i64, timestamp: SystemTime, pin: Option<&str>) -> Result<String> {
+        let code = self.peek_with_pin(secret_id, timestamp, pin)?;
+        self.record_use(secret_id, timestamp)?;
+        Ok(code)
+    }
+
+    /// Like gen_with_pin, but doesn't record that the secret was used.
+    /// For codes which are only compared against, or shown next to the one actually used.
+    pub fn peek_with_pin(&mut self, secret_id: i64, timestamp: SystemTime, pin: Option<&str>) -> Result<String> {
         log::info!("getting secret from secrets database");
         let secret = self.with_db(|db| {
             db.get_secret(secret_id)
         })?;
 
         log::info!("loading secret hmac key");
-        let hmac_key = HmacKey::new(
+        let mut hmac_key = HmacKey::new(
             *self.primary_key(),
             Public::unmarshall(&secret.public_data)?,
-            secret.private_data.try_into()?
+            secret.private_data.clone().try_into()?
         );
+        if secret.has_pin {
+            let pin = pin.ok_or(Error::PinRequired)?;
+            hmac_key = hmac_key.with_auth_value(pin_to_auth(pin)?);
+        }
 
         log::info!("generating one time code");
-        let ts = timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs() / secret.interval as u64;
+        self.observer.on_tpm_op(TpmOperation::GenerateCode);
+        let ts = secret.time_step(timestamp);
         let hash = self.tpm().hmac(hmac_key, ts.to_be_bytes().to_vec().try_into()?)?;
         Ok(totp_code_to_string(&hash, secret.digits as u32))
     }
 
+    /// Records that the given secret was used at the given time, as gen does.
+    pub fn record_use(&mut self, secret_id: i64, timestamp: SystemTime) -> Result<()> {
+        log::info!("recording secret usage");
+        let now = timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let secret = self.with_db(|db| {
+            db.record_use(secret_id, now)?;
+            db.get_secret(secret_id)
+        })?;
+        let secret = self.decrypt_metadata(secret)?;
+        self.observer.on_secret_used(&secret);
+        Ok(())
+    }
+
     fn tpm(&mut self) -> &mut TPM {
         match &mut self.tpm {
             Some(tpm) => tpm,
@@ -280,7 +1231,242 @@ fn totp_code_to_string(hash: &Digest, digits: u32) -> String {
     format!("{:0>w$}", code, w = digits as usize)
 }
 
-fn read_primary_key_persistent_handle(config: &Config) -> Result<u32> {
+/// Combines the metadata of the given secret with a duplicate of its key.
+fn migrated_secret(secret: Secret, public: &[u8], duplicate: &[u8], seed: &[u8]) -> MigratedSecret {
+    MigratedSecret {
+        service: secret.service,
+        account: secret.account,
+        digits: secret.digits,
+        interval: secret.interval,
+        tags: secret.tags,
+        notes: secret.notes,
+        url: secret.url,
+        icon: secret.icon,
+        pinned: secret.pinned,
+        created_at: secret.created_at,
+        rotate_after_days: secret.rotate_after_days,
+        has_pin: secret.has_pin,
+        offset_seconds: secret.offset_seconds,
+        t0: secret.t0,
+        public: hex_encode(public),
+        duplicate: hex_encode(duplicate),
+        seed: hex_encode(seed),
+    }
+}
+
+/// Decodes the wrapped HMAC key of the given secret, or returns None if it's malformed.
+fn wrapped_key(secret: &Secret) -> Option<(Public, Private)> {
+    let public = Public::unmarshall(&secret.public_data).ok()?;
+    let private = Private::try_from(secret.private_data.clone()).ok()?;
+    Some((public, private))
+}
+
+/// Turns a PIN into the auth value of an HMAC key.
+fn pin_to_auth(pin: &str) -> Result<Auth> {
+    Ok(Auth::try_from(pin.as_bytes().to_vec())?)
+}
+
+/// Identifies a secret to the user, even if its metadata is still encrypted.
+fn describe(secret: &Secret) -> String {
+    if secret.metadata_encrypted {
+        format!("secret {} (encrypted metadata)", secret.id)
+    } else {
+        format!("secret {} {}", secret.id, secret)
+    }
+}
+
+/// Substring match with the same semantics as matching names in the database, which compares them as folded by fold().
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    fold_case(haystack).contains(&fold_case(needle))
+}
+
+pub(crate) fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+pub(crate) fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Notifies an observer when presence verification starts and finishes, so that it's only told about verifications
+/// which actually happen, and of how they went.
+struct ObservedPresenceVerifier<'a> {
+    pv: Box<dyn PresenceVerifier>,
+    observer: &'a mut dyn Observer,
+}
+
+impl PresenceVerifier for ObservedPresenceVerifier<'_> {
+    fn owner_present(&mut self) -> presence_verification::Result<bool> {
+        self.observer.on_pv_started();
+        let result = self.pv.owner_present();
+        self.observer.on_pv_finished(matches!(result, Ok(true)));
+        result
+    }
+}
+
+/// Connects to the TPM after verifying user presence, creating primary keys from the configured template,
+/// binding them to the configured PCRs if any, and requiring the configured presence verifier's consent for new keys.
+fn open_tpm(pv: Box<dyn PresenceVerifier>, config: &Config, observer: &mut dyn Observer) -> Result<TPM> {
+    let mut tpm = TPM::new(Box::new(ObservedPresenceVerifier { pv, observer }), &config.tpm)?;
+    tpm.set_primary_key_template(config.primary_key);
+    if let Some(handles) = config.persistent_handle {
+        tpm.set_persistent_handles(handles);
+    }
+    if !config.pcrs.is_empty() {
+        tpm.set_pcr_policy(&config.pcrs)?;
+    }
+    if let Some(pv_policy) = &config.pv_policy {
+        tpm.set_pv_policy(pv_policy.read_verifier_key().map_err(tpm::Error::from)?, Box::new(pv_policy.signer()));
+    }
+    Ok(tpm)
+}
+
+/// Creates the migration approver under the given primary key, and writes it to the system data directory.
+fn create_migration_approver(config: &Config, tpm: &mut TPM, primary_key: KeyHandle) -> Result<Public> {
+    log::info!("creating migration approver at {}", config.migration_approver_path().to_str().unwrap());
+    let (public, private) = tpm.create_migration_approver(primary_key)?;
+    write_migration_approver(&config.migration_approver_path(), &public, &private)?;
+    Ok(public)
+}
+
+/// Sets the migration approver of migratable keys created by the given TPM, creating it first if this is
+/// the first time since upgrading from a version without one. Keys created without one can only be rekeyed.
+fn load_migration_approver(config: &Config, tpm: &mut TPM, primary_key: KeyHandle) {
+    let approver = match read_migration_approver(config) {
+        Ok((public, _)) => Ok(public),
+        Err(Error::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => create_migration_approver(config, tpm, primary_key),
+        Err(e) => Err(e),
+    };
+    match approver {
+        Ok(approver) => tpm.set_migration_approver(approver),
+        Err(e) => log::warn!("unable to load migration approver; new migratable keys can't be migrated: {:?}", e),
+    }
+}
+
+fn write_migration_approver(path: &Path, public: &Public, private: &Private) -> Result<()> {
+    let contents = format!("{}\n{}\n", hex_encode(&public.marshall()?), hex_encode(private));
+    Ok(write_private(path, contents.as_bytes())?)
+}
+
+/// Reads the migration approver written by create_migration_approver.
+fn read_migration_approver(config: &Config) -> Result<(Public, Private)> {
+    let contents = std::fs::read_to_string(config.migration_approver_path())?;
+    let mut lines = contents.lines().map(hex_decode);
+    let public = lines.next()
+        .flatten()
+        .and_then(|data| Public::unmarshall(&data).ok())
+        .ok_or(Error::MalformedMigrationData)?;
+    let private = lines.next()
+        .flatten()
+        .and_then(|data| Private::try_from(data).ok())
+        .ok_or(Error::MalformedMigrationData)?;
+    Ok((public, private))
+}
+
+fn persistent_handle_to_u32(handle: Persistent) -> u32 {
+    match handle {
+        Persistent::Persistent(persistent_tpm_handle) => persistent_tpm_handle.into(),
+    }
+}
+
+/// Writes the given data to a new file only readable by its owner.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    file.write_all(data)
+}
+
+/// Returns the path under which the new version of the given system file is journaled during a rekey.
+/// Unlike temporary files, journaled files are never cleaned up by housekeeping.
+fn rekey_journal_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".rekey");
+    PathBuf::from(path)
+}
+
+fn remove_rekey_journal(config: &Config) {
+    let _ = std::fs::remove_file(rekey_journal_path(&config.primary_key_handle_path()));
+    let _ = std::fs::remove_file(rekey_journal_path(&config.migration_approver_path()));
+    let _ = std::fs::remove_file(rekey_journal_path(&config.auth_value_path()));
+}
+
+/// Moves the journaled system files of a committed rekey into place, then evicts the old primary key
+/// if it's known. The auth value is moved last, so an interruption can always be recovered from.
+fn finish_rekey(config: &Config, tpm: &mut TPM, old_primary_key: Option<(u32, Vec<u8>)>) -> Result<()> {
+    let approver_journal = rekey_journal_path(&config.migration_approver_path());
+    if approver_journal.exists() {
+        std::fs::rename(approver_journal, config.migration_approver_path())?;
+    }
+    let handle_journal = rekey_journal_path(&config.primary_key_handle_path());
+    if handle_journal.exists() {
+        std::fs::rename(handle_journal, config.primary_key_handle_path())?;
+    }
+    std::fs::rename(rekey_journal_path(&config.auth_value_path()), config.auth_value_path())?;
+
+    match old_primary_key {
+        Some((old_handle, old_auth_value)) => {
+            log::info!("deleting old primary key from tpm");
+            if let Err(e) = tpm.delete_persistent_primary(old_handle, old_auth_value.try_into()?) {
+                log::warn!("unable to delete old primary key: {:?}", e);
+            }
+        },
+        None => log::warn!("unable to delete old primary key of interrupted rekey; its handle is unknown"),
+    }
+    Ok(())
+}
+
+/// Completes a rekey which was interrupted after its database transaction was committed,
+/// or rolls it back if the secrets are still wrapped under the old primary key.
+fn recover_interrupted_rekey(config: &Config, tpm: &mut TPM) -> Result<()> {
+    log::warn!("found an interrupted rekey; recovering");
+    let handle_journal = rekey_journal_path(&config.primary_key_handle_path());
+    if !handle_journal.exists() {
+        // The handle is only moved into place after the commit, so the rekey was interrupted while finishing.
+        return finish_rekey(config, tpm, None)
+    }
+
+    let old_handle = read_primary_key_persistent_handle(config)?;
+    let old_auth_value = read_auth_value(config)?;
+    let new_handle: u32 = std::fs::read_to_string(&handle_journal)?
+        .trim()
+        .parse().or(Err(Error::KeyHandleError))?;
+    let new_auth_value = std::fs::read(rekey_journal_path(&config.auth_value_path()))?;
+    let new_primary_key = match tpm.get_persistent_primary(new_handle, new_auth_value.clone().try_into()?) {
+        Ok(primary_key) => primary_key,
+        Err(e) => {
+            log::warn!("new primary key of interrupted rekey is unusable ({:?}); discarding it", e);
+            remove_rekey_journal(config);
+            return Ok(())
+        },
+    };
+
+    let committed = {
+        let _euid = EuidSwapGuard::real_user()?;
+        let secrets = match config.secrets_db_path().exists() {
+            true => db::with_db_locking(config.secrets_db_path(), config.db_locking, |db| db.list_secrets("", ""))?,
+            false => Vec::new(),
+        };
+        match secrets.iter().find_map(wrapped_key) {
+            Some((public, private)) => tpm.check_hmac_key(&HmacKey::new(new_primary_key, public, private)).is_ok(),
+            None => true,
+        }
+    };
+    if committed {
+        log::info!("secrets are wrapped under the new primary key; completing rekey");
+        finish_rekey(config, tpm, Some((old_handle, old_auth_value)))
+    } else {
+        log::info!("secrets are still wrapped under the old primary key; rolling back rekey");
+        tpm.delete_persistent_primary(new_handle, new_auth_value.try_into()?)?;
+        remove_rekey_journal(config);
+        Ok(())
+    }
+}
+
+pub(crate) fn read_primary_key_persistent_handle(config: &Config) -> Result<u32> {
     std::fs::read_to_string(config.primary_key_handle_path())?
         .trim()
         .parse().or(Err(Error::KeyHandleError))
@@ -292,6 +1478,8 @@ fn read_auth_value(config: &Config) -> Result<Vec<u8>> {
 
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
     use tempfile::TempDir;
     use testutil::tpm::SwTpm;
     use tss_esapi::constants::response_code::{
@@ -323,7 +1511,7 @@ mod tests {
     fn with_tpm_fails_if_presence_verification_fails() {
         let (config, _tepmdir, _swtpm) = setup();
         TotpStore::init(config.clone()).unwrap();
-        match TotpStore::with_tpm_ex(Box::new(ConstPresenceVerifier::new(false)), config.clone()) {
+        match TotpStore::with_tpm_ex(Box::new(ConstPresenceVerifier::new(false)), config.clone(), Box::new(NoObserver)) {
             Ok(_) => panic!("with_tpm did not fail even though presence verification failed"),
             Err(Error::TpmError(tpm::Error::PresenceVerificationFailed)) => {},
             Err(e) => panic!("with_tpm failed with the wrong error: {:#?}", e),
@@ -334,7 +1522,7 @@ mod tests {
     fn with_tpm_fails_if_presence_verification_errors() {
         let (config, _tepmdir, _swtpm) = setup();
         TotpStore::init(config.clone()).unwrap();
-        match TotpStore::with_tpm_ex(Box::new(FailingPresenceVerifier), config.clone()) {
+        match TotpStore::with_tpm_ex(Box::new(FailingPresenceVerifier), config.clone(), Box::new(NoObserver)) {
             Ok(_) => panic!("with_tpm did not fail even though presence verification failed"),
             Err(Error::TpmError(tpm::Error::PresenceVerificationError(_))) => {},
             Err(e) => panic!("with_tpm failed with the wrong error: {:#?}", e),
@@ -363,7 +1551,7 @@ mod tests {
     fn list_on_empty_store_returns_empty_list() {
         let (config, _tepmdir, _swtpm) = setup();
         TotpStore::init(config.clone()).unwrap();
-        let secrets = TotpStore::without_tpm(config).list(None, None).unwrap();
+        let secrets = TotpStore::without_tpm(config).unwrap().list(None, None).unwrap();
         assert_eq!(secrets, vec![]);
     }
 
@@ -378,6 +1566,26 @@ mod tests {
         assert_eq!(secrets, vec![secret1, secret2]);
     }
 
+    #[test]
+    fn batch_adds_all_secrets_or_none() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let result = store.batch(|store| {
+            store.add("firstsvc", "firstacc", None, None, "hello".as_bytes())?;
+            assert_eq!(store.list(None, None)?.len(), 1);
+            store.gen(-1, SystemTime::now())
+        });
+        assert!(matches!(result, Err(Error::DBError(db::Error::NoSuchElement))));
+        assert_eq!(store.list(None, None).unwrap(), vec![]);
+
+        let secrets = store.batch(|store| Ok::<_, Error>(vec![
+            store.add("firstsvc", "firstacc", None, None, "hello".as_bytes())?,
+            store.add("secondsvc", "secondacc", None, None, "hello".as_bytes())?,
+        ])).unwrap();
+        assert_eq!(store.list(None, None).unwrap(), secrets);
+    }
+
     #[test]
     fn list_properly_filters_secrets() {
         let (config, _tepmdir, _swtpm) = setup();
@@ -401,6 +1609,20 @@ mod tests {
         assert_eq!(store.list(None, Some("acc")).unwrap(), vec![secret1.clone(), secret2.clone()]);
     }
 
+    #[test]
+    fn find_filters_secrets_on_tag() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let secret1 = store.add_ex("firstsvc", "firstacc", None, None, "hello".as_bytes(), None, |s| {
+            s.tags = vec!["work".to_owned()];
+        }).unwrap();
+        let secret2 = store.add("secondsvc", "secondacc", None, None, "hello".as_bytes()).unwrap();
+        assert_eq!(secret1.tags, vec!["work".to_owned()]);
+        assert_eq!(store.find(&SecretFilter { tag: Some("work"), ..Default::default() }).unwrap(), vec![secret1.clone()]);
+        assert_eq!(store.find(&SecretFilter::default()).unwrap(), vec![secret1, secret2]);
+    }
+
     #[test]
     fn del_deletes_secrets() {
         let (config, _tepmdir, _swtpm) = setup();
@@ -413,6 +1635,23 @@ mod tests {
         assert_eq!(secrets, vec![secret2]);
     }
 
+    #[test]
+    fn del_all_deletes_secrets_atomically_and_for_good() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let secret1 = store.add("firstsvc", "firstacc", None, None, "hello".as_bytes()).unwrap();
+        let secret2 = store.add("secondsvc", "secondacc", None, None, "hello".as_bytes()).unwrap();
+        let secret3 = store.add("thirdsvc", "thirdacc", None, None, "hello".as_bytes()).unwrap();
+
+        assert!(store.del_all(&[secret1.id, secret3.id + 1]).is_err());
+        assert_eq!(store.list(None, None).unwrap().len(), 3);
+
+        store.del_all(&[secret1.id, secret3.id]).unwrap();
+        assert_eq!(store.list(None, None).unwrap(), vec![secret2]);
+        assert_eq!(store.undo().unwrap(), None);
+    }
+
     #[test]
     fn del_on_nonexistent_id_errors() {
         let (config, _tepmdir, _swtpm) = setup();
@@ -446,6 +1685,203 @@ mod tests {
         }
     }
 
+    #[test]
+    fn gen_on_secret_with_pin_requires_correct_pin() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let plain = store.add("firstsvc", "firstacc", None, None, "hello".as_bytes()).unwrap();
+        let secret = store.add_ex("firstsvc", "secondacc", None, None, "hello".as_bytes(), Some("1234"), |_| {}).unwrap();
+        assert!(secret.has_pin);
+        assert!(store.list(None, Some("secondacc")).unwrap()[0].has_pin);
+
+        assert_eq!(store.gen_with_pin(secret.id, UNIX_EPOCH, Some("1234")).unwrap(), store.gen(plain.id, UNIX_EPOCH).unwrap());
+        match store.gen(secret.id, UNIX_EPOCH).unwrap_err() {
+            Error::PinRequired => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+        match store.gen_with_pin(secret.id, UNIX_EPOCH, Some("4321")).unwrap_err() {
+            Error::TpmError(tpm::Error::AuthFailed) => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn gen_records_usage() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let secret = store.add("firstsvc", "firstacc", None, None, "hello".as_bytes()).unwrap();
+        store.gen(secret.id, UNIX_EPOCH + Duration::from_secs(1000)).unwrap();
+        store.gen(secret.id, UNIX_EPOCH + Duration::from_secs(2000)).unwrap();
+        let secret = store.list(None, None).unwrap().pop().unwrap();
+        assert_eq!(secret.last_used_at, Some(2000));
+        assert_eq!(secret.use_count, 2);
+    }
+
+    #[test]
+    fn undo_restores_deleted_secret() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let secret = store.add("firstsvc", "firstacc", None, None, "hello".as_bytes()).unwrap();
+        let code = store.gen(secret.id, UNIX_EPOCH).unwrap();
+        store.del(secret.id).unwrap();
+        assert_eq!(store.list(None, None).unwrap(), vec![]);
+
+        let (mutation, _) = store.undo().unwrap().unwrap();
+        assert_eq!(mutation, Mutation::Del(secret.id));
+        assert_eq!(store.gen(secret.id, UNIX_EPOCH).unwrap(), code);
+    }
+
+    #[test]
+    fn undo_removes_added_secret() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let secret = store.add("firstsvc", "firstacc", None, None, "hello".as_bytes()).unwrap();
+        let (mutation, _) = store.undo().unwrap().unwrap();
+        assert_eq!(mutation, Mutation::Add(secret.id));
+        assert_eq!(store.list(None, None).unwrap(), vec![]);
+        assert!(store.undo().unwrap().is_none());
+    }
+
+    #[test]
+    fn replace_changes_secret_but_keeps_metadata() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let old = store.add_ex("firstsvc", "firstacc", None, None, "hello".as_bytes(), None, |secret| {
+            secret.tags = vec!["work".to_owned()];
+        }).unwrap();
+        let old_code = store.gen(old.id, UNIX_EPOCH).unwrap();
+        let new = store.replace_ex(old.id, "firstsvc", "firstacc", Some(8), None, "goodbye".as_bytes(), None, |_| {}).unwrap();
+
+        let secrets = store.list(None, None).unwrap();
+        assert_eq!(secrets, vec![new.clone()]);
+        assert_eq!(new.digits, 8);
+        assert_eq!(new.tags, vec!["work".to_owned()]);
+        assert_eq!(new.use_count, 1);
+        assert_ne!(store.gen(new.id, UNIX_EPOCH).unwrap(), old_code);
+    }
+
+    #[test]
+    fn normalize_names_stores_names_in_nfc_and_names_match_either_way() {
+        let (mut config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config.clone()).unwrap();
+        let decomposed = store.add("Cafe\u{301}", "Jose\u{301}", None, None, "hello".as_bytes()).unwrap();
+        assert_eq!(decomposed.service, "Cafe\u{301}");
+        assert_eq!(store.list(Some("café"), Some("josé")).unwrap(), vec![decomposed.clone()]);
+        drop(store);
+
+        config.normalize_names = true;
+        let mut store = TotpStore::with_tpm(config).unwrap();
+        let composed = store.add("Cafe\u{301}", "Ana", None, None, "hello".as_bytes()).unwrap();
+        assert_eq!(composed.service, "Caf\u{e9}");
+        assert_eq!(store.list(Some("Cafe\u{301}"), None).unwrap(), vec![decomposed.clone(), composed.clone()]);
+        let found = store.find(&SecretFilter { service: "CAFÉ", account: "ana", exact: true, ..Default::default() }).unwrap();
+        assert_eq!(found, vec![composed]);
+    }
+
+    #[test]
+    fn encrypted_metadata_is_unreadable_in_database() {
+        let (mut config, _tepmdir, _swtpm) = setup();
+        config.encrypt_metadata = true;
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config.clone()).unwrap();
+        let secret1 = store.add_ex("firstsvc", "firstacc", None, None, "hello".as_bytes(), None, |secret| {
+            secret.notes = Some("backup codes in the safe".to_owned());
+            secret.url = Some("https://firstsvc.example.com".to_owned());
+        }).unwrap();
+        let secret2 = store.add("SecondSvc", "secondacc", None, None, "hello".as_bytes()).unwrap();
+
+        let stored = db::with_db(config.secrets_db_path(), |db| db.get_secret(secret1.id)).unwrap();
+        assert!(stored.metadata_encrypted);
+        assert!(!stored.service.contains("firstsvc"));
+        assert!(!stored.account.contains("firstacc"));
+        assert!(!stored.notes.unwrap().contains("safe"));
+        assert!(!stored.url.unwrap().contains("example"));
+
+        assert_eq!(store.list(None, None).unwrap(), vec![secret1.clone(), secret2.clone()]);
+        assert_eq!(store.list(Some("first"), None).unwrap(), vec![secret1.clone()]);
+        assert_eq!(store.list(Some("secondsvc"), Some("acc")).unwrap(), vec![secret2.clone()]);
+        match TotpStore::without_tpm(config).unwrap().list(None, None).unwrap_err() {
+            Error::MetadataKeyUnavailable => {},
+            err => panic!("wrong error: {:#?}", err),
+        }
+    }
+
+    #[test]
+    fn enabling_metadata_encryption_encrypts_existing_secrets() {
+        let (mut config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let secret = TotpStore::with_tpm(config.clone()).unwrap()
+            .add("firstsvc", "firstacc", None, None, "hello".as_bytes())
+            .unwrap();
+
+        config.encrypt_metadata = true;
+        let mut store = TotpStore::with_tpm(config.clone()).unwrap();
+        let stored = db::with_db(config.secrets_db_path(), |db| db.get_secret(secret.id)).unwrap();
+        assert!(stored.metadata_encrypted);
+        assert_ne!(stored.service, secret.service);
+        assert_eq!(store.list(Some("firstsvc"), None).unwrap(), vec![secret.clone()]);
+        store.gen(secret.id, UNIX_EPOCH).unwrap();
+        store.del(secret.id).unwrap();
+        let (_, undone) = store.undo().unwrap().unwrap();
+        assert_eq!(undone.service, secret.service);
+    }
+
+    #[test]
+    fn verify_reports_malformed_keys() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let mut store = TotpStore::with_tpm(config.clone()).unwrap();
+        store.add("firstsvc", "firstacc", None, None, "hello".as_bytes()).unwrap();
+        let broken = Secret::new("secondsvc".to_owned(), "secondacc".to_owned(), None, None, vec![1, 2, 3], vec![]);
+        let broken = db::with_db(config.secrets_db_path(), |db| db.add_secret(broken)).unwrap();
+
+        let problems = TotpStore::without_tpm(config).unwrap().verify().unwrap();
+        assert_eq!(problems.len(), 1);
+        match &problems[0] {
+            Problem::MalformedKey(secret) => assert_eq!(secret.id, broken.id),
+            problem => panic!("wrong problem: {:#?}", problem),
+        }
+        assert!(store.verify_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn verify_keys_reports_keys_wrapped_under_old_primary_key() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let secret = TotpStore::with_tpm(config.clone()).unwrap()
+            .add("firstsvc", "firstacc", None, None, "hello".as_bytes())
+            .unwrap();
+        let secrets_db_backup = tempfile::NamedTempFile::new().unwrap();
+        std::fs::copy(config.secrets_db_path(), secrets_db_backup.path()).unwrap();
+        TotpStore::clear(config.clone(), true).unwrap();
+        TotpStore::init(config.clone()).unwrap();
+        std::fs::copy(secrets_db_backup.path(), config.secrets_db_path()).unwrap();
+
+        let mut store = TotpStore::with_tpm(config.clone()).unwrap();
+        assert!(store.verify().unwrap().is_empty());
+        let problems = store.verify_keys().unwrap();
+        assert_eq!(problems.len(), 1);
+        match &problems[0] {
+            Problem::KeyNotLoadable(problem_secret, _) => assert_eq!(problem_secret, &secret),
+            problem => panic!("wrong problem: {:#?}", problem),
+        }
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(hex_encode(&[0x00, 0x7f, 0xff]), "007fff");
+        assert_eq!(hex_decode("007fff"), Some(vec![0x00, 0x7f, 0xff]));
+        assert_eq!(hex_decode("007ff"), None);
+        assert_eq!(hex_decode("zz"), None);
+        assert_eq!(hex_decode("ä0"), None);
+    }
+
     #[test]
     fn with_tpm_errors_after_system_clear() {
         let (config, _tepmdir, _swtpm) = setup();
@@ -480,6 +1916,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn system_clear_does_not_evict_configured_handle_it_did_not_record() {
+        let (mut config, _tepmdir, _swtpm) = setup();
+        config.persistent_handle = Some(tpm::PersistentHandles::Fixed(0x81000100));
+        TotpStore::init(config.clone()).unwrap();
+        let auth_value_backup = tempfile::NamedTempFile::new().unwrap();
+        let primary_key_handle_backup = tempfile::NamedTempFile::new().unwrap();
+        std::fs::copy(config.auth_value_path(), auth_value_backup.path()).unwrap();
+        std::fs::copy(config.primary_key_handle_path(), primary_key_handle_backup.path()).unwrap();
+        std::fs::remove_file(config.primary_key_handle_path()).unwrap();
+        TotpStore::clear(config.clone(), true).unwrap();
+
+        std::fs::copy(auth_value_backup.path(), config.auth_value_path()).unwrap();
+        std::fs::copy(primary_key_handle_backup.path(), config.primary_key_handle_path()).unwrap();
+        TotpStore::with_tpm(config).unwrap();
+    }
+
     #[test]
     fn new_primary_key_can_not_be_used_to_access_old_secrets() {
         let (config, _tepmdir, _swtpm) = setup();
@@ -518,6 +1971,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn observer_is_notified_of_pv_tpm_operations_and_usage() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let observer = RecordingObserver(events.clone());
+        let mut store = TotpStore::with_tpm_observed(config, Box::new(observer)).unwrap();
+        let secret = store.add("firstsvc", "firstacc", None, None, "hello".as_bytes()).unwrap();
+        store.gen(secret.id, UNIX_EPOCH).unwrap();
+        assert_eq!(*events.borrow(), vec![
+            "pv started".to_owned(),
+            "pv finished: true".to_owned(),
+            "loading primary key".to_owned(),
+            "creating key".to_owned(),
+            "generating code".to_owned(),
+            "used firstsvc (firstacc)".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn observer_is_notified_of_failed_pv() {
+        let (config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let observer = Box::new(RecordingObserver(events.clone()));
+        TotpStore::with_tpm_ex(Box::new(ConstPresenceVerifier::new(false)), config, observer).unwrap_err();
+        assert_eq!(*events.borrow(), vec!["pv started".to_owned(), "pv finished: false".to_owned()]);
+    }
+
+    #[test]
+    fn observer_is_not_notified_of_pv_when_opening_the_tpm_fails_first() {
+        let (mut config, _tepmdir, _swtpm) = setup();
+        TotpStore::init(config.clone()).unwrap();
+        config.tpm = "potato".to_owned();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let observer = Box::new(RecordingObserver(events.clone()));
+        TotpStore::with_tpm_observed(config, observer).unwrap_err();
+        assert!(events.borrow().is_empty());
+    }
+
     fn setup() -> (Config, TempDir, SwTpm) {
         let tempdir = TempDir::new().unwrap();
         let sysdir = tempdir.path().join("sys");
@@ -528,6 +2021,26 @@ mod tests {
         (cfg, tempdir, swtpm)
     }
 
+    struct RecordingObserver(Rc<RefCell<Vec<String>>>);
+
+    impl Observer for RecordingObserver {
+        fn on_pv_started(&mut self) {
+            self.0.borrow_mut().push("pv started".to_owned());
+        }
+
+        fn on_pv_finished(&mut self, verified: bool) {
+            self.0.borrow_mut().push(format!("pv finished: {}", verified));
+        }
+
+        fn on_tpm_op(&mut self, op: TpmOperation) {
+            self.0.borrow_mut().push(op.to_string());
+        }
+
+        fn on_secret_used(&mut self, secret: &Secret) {
+            self.0.borrow_mut().push(format!("used {}", secret));
+        }
+    }
+
     struct FailingPresenceVerifier;
 
     impl PresenceVerifier for FailingPresenceVerifier {
@@ -1,76 +1,370 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
-use rand::RngCore;
 use tss_esapi::{
-    attributes::ObjectAttributes, constants::{
-        response_code::FormatOneResponseCode, StartupType, Tss2ResponseCode
+    attributes::{ObjectAttributes, SessionAttributesBuilder}, constants::{
+        response_code::{FormatOneResponseCode, FormatZeroResponseCode}, CommandCode, PropertyTag, SessionType, StartupType, Tss2ResponseCode, Tss2ResponseCodeKind
     }, handles::{
-        KeyHandle, ObjectHandle, PersistentTpmHandle, TpmHandle
+        KeyHandle, ObjectHandle, PersistentTpmHandle, SessionHandle, TpmHandle
     }, interface_types::{
         algorithm::{
-            HashingAlgorithm, PublicAlgorithm
-        }, dynamic_handles::Persistent, resource_handles::{
+            HashingAlgorithm, PublicAlgorithm, SymmetricMode
+        }, dynamic_handles::Persistent, ecc::EccCurve, key_bits::RsaKeyBits, resource_handles::{
             Hierarchy, Provision
-        }
+        }, session_handles::{AuthSession, PolicySession}
     }, structures::{
-        Auth, Digest, HmacScheme, KeyedHashScheme, MaxBuffer, Private, Public,
-        PublicKeyedHashParameters, SymmetricCipherParameters,
-        SymmetricDefinitionObject
-    }, Context, TctiNameConf
+        Auth, Data, Digest, DigestList, EccParameter, EccScheme, Name, Nonce, EccPoint, EncryptedSecret, HashScheme, HmacScheme, InitialValue, KeyedHashScheme,
+        MaxBuffer, PcrSelectionList, PcrSlot, Private, Public, PublicEccParametersBuilder, PublicKeyRsa, PublicKeyedHashParameters, PublicRsaParametersBuilder,
+        RsaExponent, Signature, SignatureScheme, SymmetricCipherParameters, SymmetricDefinition, SymmetricDefinitionObject
+    }, constants::tss::{TPM2_RH_NULL, TPM2_ST_HASHCHECK}, tss2_esys::TPMT_TK_HASHCHECK, Context, TctiNameConf, WrapperErrorKind
 };
 
-use crate::presence_verification::{self, PresenceVerifier};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{presence_verification::{self, signer::PresenceSigner, PresenceVerifier}, rng, tcti::{check_tcti_loadable, uses_resource_manager, validate_tcti, InvalidTcti, MissingTcti}};
+
+/// A TPM context, along with the PCRs that primary keys are bound to, if any,
+/// the template of primary keys created using it, the presence verification policy of HMAC keys, if any,
+/// the persistent handles primary keys may be stored at, the HMAC session shared by its commands, once started,
+/// whether the TPM is started up and shut down by us rather than by a resource manager,
+/// and the ways migratable HMAC keys may be duplicated that it knows of.
+#[derive(Debug)]
+pub struct TPM(Context, Option<PcrSelectionList>, PrimaryKeyTemplate, Option<PvPolicy>, PersistentHandles, Option<AuthSession>, bool, Vec<MigrationBranch>);
+
+/// Besides being re-wrapped under another primary key, a migratable HMAC key may be duplicated either to any new parent
+/// approved by a migration approver, or to a single new parent fixed when the key was created.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationBranch {
+    Approver(Public),
+    NewParent(Public),
+}
 
+/// Presence verification enforced by the TPM: HMAC keys can only be used with an authorization
+/// signed by the verifier's key, which the signer is asked for.
 #[derive(Debug)]
-pub struct TPM(Context);
+struct PvPolicy {
+    verifier: Public,
+    signer: Box<dyn PresenceSigner>,
+}
+
+const TPM_CC_HMAC: u32 = 0x155;
+const TPM_CC_POLICY_SIGNED: u32 = 0x160;
+const TPM_CC_POLICY_AUTH_VALUE: u32 = 0x16b;
+const TPM_CC_POLICY_AUTHORIZE: u32 = 0x16a;
+const TPM_CC_POLICY_OR: u32 = 0x171;
+const TPM_CC_POLICY_DUPLICATION_SELECT: u32 = 0x188;
+const TPM_RH_NULL: u32 = 0x40000007;
+const TPM_ALG_SHA1: u16 = 0x0004;
+const TPM_RC_YIELDED: u32 = 0x908;
+const TPM_RC_RETRY: u32 = 0x922;
+const TSS2_BASE_RC_TRY_AGAIN: u32 = 9;
+
+/// How many times an operation is retried when the TPM is temporarily unable to process it.
+const MAX_RETRIES: u32 = 5;
+
+/// How long to wait before the first retry. The delay is doubled for every retry after that.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// The kind of storage key to use as primary key.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrimaryKeyAlgorithm {
+    /// An AES-256 symmetric key.
+    #[default]
+    Aes,
+    /// An ECC key on the NIST P-256 curve, as recommended by the TCG.
+    Ecc,
+    /// An RSA-2048 key.
+    Rsa,
+}
+
+/// Hash algorithm used to compute the name and policy of a primary key.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NameHash {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl From<NameHash> for HashingAlgorithm {
+    fn from(value: NameHash) -> Self {
+        match value {
+            NameHash::Sha256 => HashingAlgorithm::Sha256,
+            NameHash::Sha384 => HashingAlgorithm::Sha384,
+            NameHash::Sha512 => HashingAlgorithm::Sha512,
+        }
+    }
+}
+
+/// Persistent handles that primary keys may be made persistent at.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PersistentHandles {
+    /// Always use this handle, failing if it's taken.
+    Fixed(u32),
+    /// Use the first free handle in this inclusive range.
+    Range { first: u32, last: u32 },
+}
+
+impl Default for PersistentHandles {
+    /// The owner's range of persistent handles.
+    fn default() -> Self {
+        PersistentHandles::Range { first: 0x81000000, last: 0x8100ffff }
+    }
+}
+
+impl PersistentHandles {
+    /// Returns true if the given handle is one of these handles.
+    pub fn contains(&self, handle: u32) -> bool {
+        match *self {
+            PersistentHandles::Fixed(fixed) => handle == fixed,
+            PersistentHandles::Range { first, last } => (first..=last).contains(&handle),
+        }
+    }
+}
+
+impl std::fmt::Display for PersistentHandles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PersistentHandles::Fixed(handle) => write!(f, "{:#010x}", handle),
+            PersistentHandles::Range { first, last } => write!(f, "{:#010x}-{:#010x}", first, last),
+        }
+    }
+}
+
+/// Symmetric cipher used by a primary key to protect the keys wrapped under it.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SymmetricCipher {
+    Aes128Cfb,
+    Aes256Cfb,
+}
+
+impl From<SymmetricCipher> for SymmetricDefinitionObject {
+    fn from(value: SymmetricCipher) -> Self {
+        match value {
+            SymmetricCipher::Aes128Cfb => SymmetricDefinitionObject::AES_128_CFB,
+            SymmetricCipher::Aes256Cfb => SymmetricDefinitionObject::AES_256_CFB,
+        }
+    }
+}
+
+/// The parts of the primary key template that can be configured.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PrimaryKeyTemplate {
+    pub algorithm: PrimaryKeyAlgorithm,
+    pub name_hash: NameHash,
+    /// Defaults to AES-256 for AES primary keys and AES-128 otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symmetric: Option<SymmetricCipher>,
+    /// If true, the primary key is exempt from the TPM's dictionary attack protection,
+    /// so that failed authorizations using it never lock the TPM out.
+    pub no_da: bool,
+}
+
+impl PrimaryKeyTemplate {
+    fn symmetric(&self) -> SymmetricDefinitionObject {
+        match (self.symmetric, self.algorithm) {
+            (Some(symmetric), _) => symmetric.into(),
+            (None, PrimaryKeyAlgorithm::Aes) => SymmetricDefinitionObject::AES_256_CFB,
+            (None, _) => SymmetricDefinitionObject::AES_128_CFB,
+        }
+    }
+}
 
 impl TPM {
-    pub fn new(mut pv: Box<dyn PresenceVerifier>, tcti: &str) -> Result<Self> {
+    pub fn new(mut pv: Box<dyn PresenceVerifier + '_>, tcti: &str) -> Result<Self> {
+        // A mistyped or unusable configuration should fail right away, rather than after the user has proven their presence
+        validate_tcti(tcti)?;
+        check_tcti_loadable(tcti)?;
         if !pv.owner_present()? {
             return Err(Error::PresenceVerificationFailed)
         }
         let tcti_cfg = TctiNameConf::from_str(tcti)?;
         let ctx = Context::new(tcti_cfg)?;
-        let mut tpm = TPM(ctx);
-        tpm.0.startup(StartupType::Clear)?;
+        let manage_lifecycle = !uses_resource_manager(tcti);
+        let mut tpm = TPM(ctx, None, PrimaryKeyTemplate::default(), None, PersistentHandles::default(), None, manage_lifecycle, Vec::new());
+        if manage_lifecycle {
+            retry_transient(|| tpm.0.startup(StartupType::Clear))?;
+        } else {
+            log::info!("{} uses a resource manager; not starting up tpm", tcti);
+        }
         Ok(tpm)
     }
 }
 
 impl Drop for TPM {
     fn drop(&mut self) {
-        self.0.shutdown(StartupType::State).unwrap();
+        if let Some(session) = self.5.take() {
+            let _ = self.0.flush_context(SessionHandle::from(session).into());
+        }
+        if self.6 {
+            if let Err(e) = self.0.shutdown(StartupType::State) {
+                log::warn!("unable to shut down tpm: {}", e);
+            }
+        }
     }
 }
 
+/// Identifying information reported by the TPM itself.
+#[derive(Debug, PartialEq)]
+pub struct TpmInfo {
+    pub manufacturer: String,
+    pub vendor: String,
+    pub firmware_version: String,
+    pub spec_revision: String,
+}
+
 #[derive(Debug)]
 pub struct HmacKey {
     pub primary_key: KeyHandle,
     pub public: Public,
     pub private: Private,
+    /// The auth value (PIN) of the key, if it has one.
+    pub auth_value: Option<Auth>,
 }
 
 impl HmacKey {
     pub fn new(primary_key: KeyHandle, public: Public, private: Private) -> Self {
-        HmacKey {primary_key, public, private}
+        HmacKey {primary_key, public, private, auth_value: None}
+    }
+
+    /// Sets the auth value to use when generating HMACs with this key.
+    pub fn with_auth_value(mut self, auth_value: Auth) -> Self {
+        self.auth_value = Some(auth_value);
+        self
     }
 }
 
+/// An AES key wrapped under the primary key, used to encrypt data such as secret metadata.
+/// The key itself is generated by the TPM and never leaves it unwrapped.
+#[derive(Debug, Clone)]
+pub struct SymmetricKey {
+    pub primary_key: KeyHandle,
+    pub public: Public,
+    pub private: Private,
+}
+
+impl SymmetricKey {
+    pub fn new(primary_key: KeyHandle, public: Public, private: Private) -> Self {
+        SymmetricKey {primary_key, public, private}
+    }
+}
+
+/// Decodes TPM properties which are really big-endian, NUL-padded ASCII strings.
+fn property_string(values: &[u32]) -> String {
+    values.iter()
+        .flat_map(|x| x.to_be_bytes())
+        .filter(|x| *x != 0)
+        .map(|x| x as char)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub enum Error {
     TpmError(tss_esapi::Error),
     PresenceVerificationError(presence_verification::Error),
     PresenceVerificationFailed,
+    TctiNotLoadable(MissingTcti),
+    /// The configured TCTI is malformed or not supported.
+    InvalidTcti(InvalidTcti),
     EvictPrimaryKeyFailed,
     DropPrivilegesFailed,
+    /// The primary key is bound to PCRs whose values have changed since it was created.
+    PcrPolicyFailed,
+    /// The wrong auth value (PIN) was given for a key.
+    AuthFailed,
+    /// The TPM refuses to authorize anything until its dictionary attack lockout expires.
+    LockedOut,
+    /// The key requires a signature from a presence verifier, but none is configured.
+    PvPolicyUnavailable,
+    /// The TPM rejected the presence verifier's signature, e.g. because the key requires another verifier.
+    PvPolicyFailed,
+    /// All of the persistent handles that primary keys may be stored at are taken.
+    NoFreePersistentHandle(PersistentHandles),
+    /// The key can only be duplicated with the approval of a migration approver other than the configured one.
+    MigrationApproverUnavailable,
+    /// The new parent of a key to duplicate has not been approved by the migration approver.
+    MigrationNotApproved,
+}
+
+impl Error {
+    /// Returns a stable, machine-readable code for the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::TpmError(_) => "tpm.failed",
+            Error::PresenceVerificationError(_) => "pv.error",
+            Error::PresenceVerificationFailed => "pv.failed",
+            Error::TctiNotLoadable(_) => "tpm.tcti-not-loadable",
+            Error::InvalidTcti(_) => "tpm.invalid-tcti",
+            Error::EvictPrimaryKeyFailed => "tpm.evict-failed",
+            Error::DropPrivilegesFailed => "privileges.drop-failed",
+            Error::PcrPolicyFailed => "tpm.pcr-policy-failed",
+            Error::AuthFailed => "tpm.auth-failed",
+            Error::LockedOut => "tpm.locked-out",
+            Error::PvPolicyUnavailable => "tpm.pv-policy-unavailable",
+            Error::PvPolicyFailed => "tpm.pv-policy-failed",
+            Error::NoFreePersistentHandle(_) => "tpm.no-free-handle",
+            Error::MigrationApproverUnavailable => "tpm.migration-approver-unavailable",
+            Error::MigrationNotApproved => "tpm.migration-not-approved",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::TpmError(e) => write!(f, "{}", e),
+            Error::PresenceVerificationError(presence_verification::Error::ImplementationSpecificError(e)) => {
+                write!(f, "presence verification failed: {}", e)
+            },
+            Error::PresenceVerificationFailed => f.write_str("presence could not be verified"),
+            Error::TctiNotLoadable(missing) => write!(f, "unable to load {}", missing.library),
+            Error::InvalidTcti(invalid) => write!(f, "{}", invalid),
+            Error::EvictPrimaryKeyFailed => f.write_str("unable to evict the primary key from its persistent handle"),
+            Error::DropPrivilegesFailed => f.write_str("unable to drop privileges"),
+            Error::PcrPolicyFailed => f.write_str("the primary key is bound to pcrs which have changed since it was created"),
+            Error::AuthFailed => f.write_str("wrong pin"),
+            Error::LockedOut => f.write_str("the tpm is locked out after too many wrong pins"),
+            Error::PvPolicyUnavailable => f.write_str("the key requires the consent of a presence verifier, but none is configured"),
+            Error::PvPolicyFailed => f.write_str("the tpm rejected the presence verifier's consent"),
+            Error::NoFreePersistentHandle(handles) => write!(f, "no free persistent handle for the primary key in {}", handles),
+            Error::MigrationApproverUnavailable => f.write_str("the key was created with another migration approver"),
+            Error::MigrationNotApproved => f.write_str("the migration target has not been approved"),
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
 impl From<tss_esapi::Error> for Error {
     fn from(value: tss_esapi::Error) -> Self {
-        Error::TpmError(value)
+        match value {
+            tss_esapi::Error::Tss2Error(rc) if rc.kind() == Some(Tss2ResponseCodeKind::AuthFail) => {
+                Error::AuthFailed
+            },
+            tss_esapi::Error::Tss2Error(rc) if rc.kind() == Some(Tss2ResponseCodeKind::Lockout) => {
+                Error::LockedOut
+            },
+            value => Error::TpmError(value),
+        }
+    }
+}
+
+impl From<MissingTcti> for Error {
+    fn from(value: MissingTcti) -> Self {
+        Error::TctiNotLoadable(value)
+    }
+}
+
+impl From<InvalidTcti> for Error {
+    fn from(value: InvalidTcti) -> Self {
+        Error::InvalidTcti(value)
     }
 }
 
@@ -81,37 +375,141 @@ impl From<presence_verification::Error> for Error {
 }
 
 impl TPM {
+    /// Queries the TPM for its manufacturer, vendor string, firmware version and spec revision.
+    pub fn info(&mut self) -> Result<TpmInfo> {
+        let manufacturer = self.0.get_tpm_property(PropertyTag::Manufacturer)?.unwrap_or(0);
+        let vendor = [
+            PropertyTag::VendorString1,
+            PropertyTag::VendorString2,
+            PropertyTag::VendorString3,
+            PropertyTag::VendorString4,
+        ].into_iter()
+            .map(|tag| self.0.get_tpm_property(tag).map(|x| x.unwrap_or(0)))
+            .collect::<tss_esapi::Result<Vec<u32>>>()?;
+        let fw1 = self.0.get_tpm_property(PropertyTag::FirmwareVersion1)?.unwrap_or(0);
+        let fw2 = self.0.get_tpm_property(PropertyTag::FirmwareVersion2)?.unwrap_or(0);
+        let revision = self.0.get_tpm_property(PropertyTag::Revision)?.unwrap_or(0);
+        Ok(TpmInfo {
+            manufacturer: property_string(&[manufacturer]),
+            vendor: property_string(&vendor),
+            firmware_version: format!("{}.{}.{}.{}", fw1 >> 16, fw1 & 0xffff, fw2 >> 16, fw2 & 0xffff),
+            spec_revision: format!("{}.{:02}", revision / 100, revision % 100),
+        })
+    }
+
+    /// Binds primary keys created from now on to the current values of the given SHA-256 PCRs,
+    /// and satisfies that binding when using primary keys. A bound primary key requires both its auth value
+    /// and unchanged PCRs, so it becomes unusable when e.g. another OS or a modified boot chain is booted.
+    pub fn set_pcr_policy(&mut self, pcrs: &[u8]) -> Result<()> {
+        let slots = pcrs.iter()
+            .map(|pcr| PcrSlot::try_from(1u32.checked_shl(*pcr as u32).unwrap_or(0)))
+            .collect::<tss_esapi::Result<Vec<_>>>()?;
+        let selection = PcrSelectionList::builder()
+            .with_selection(HashingAlgorithm::Sha256, &slots)
+            .build()?;
+        self.1 = Some(selection);
+        Ok(())
+    }
+
+    /// Sets the template of primary keys created from now on.
+    pub fn set_primary_key_template(&mut self, template: PrimaryKeyTemplate) {
+        self.2 = template;
+    }
+
+    /// Sets the persistent handles that primary keys created from now on may be stored at.
+    pub fn set_persistent_handles(&mut self, handles: PersistentHandles) {
+        self.4 = handles;
+    }
+
+    /// Makes non-migratable HMAC keys created from now on require an authorization signed by the given verifier
+    /// key for every HMAC, and asks the given signer for it when using such keys.
+    /// Even a modified totpm can then not use the keys without the verifier's consent.
+    pub fn set_pv_policy(&mut self, verifier: Public, signer: Box<dyn PresenceSigner>) {
+        self.3 = Some(PvPolicy { verifier, signer });
+    }
+
     pub fn create_persistent_primary(&mut self, auth_value: Auth) -> Result<Persistent> {
+        let template = self.2;
+        let name_hash = HashingAlgorithm::from(template.name_hash);
+        let auth_policy = match self.1.clone() {
+            Some(pcrs) => {
+                let session = start_policy_session(&mut self.0, SessionType::Trial, name_hash)?;
+                let result = pcr_policy(&mut self.0, session, pcrs)
+                    .and_then(|_| self.0.policy_get_digest(session));
+                self.0.flush_context(SessionHandle::from(session).into())?;
+                result?
+            },
+            None => Digest::default(),
+        };
         let object_attributes = ObjectAttributes::builder()
-            .with_user_with_auth(true)
+            .with_user_with_auth(self.1.is_none())
             .with_fixed_tpm(true)
             .with_fixed_parent(true)
             .with_sensitive_data_origin(true)
             .with_decrypt(true)
             .with_restricted(true)
+            .with_no_da(template.no_da)
             .build()?;
 
-        let public = Public::builder()
-            .with_public_algorithm(PublicAlgorithm::SymCipher)
-            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        let builder = Public::builder()
+            .with_name_hashing_algorithm(name_hash)
             .with_object_attributes(object_attributes)
-            .with_symmetric_cipher_parameters(SymmetricCipherParameters::new(SymmetricDefinitionObject::AES_256_CFB))
-            .with_symmetric_cipher_unique_identifier(Digest::default())
-            .build()?;
+            .with_auth_policy(auth_policy);
 
+        // Primary keys are derived from the hierarchy seed and their template, so each one needs
+        // some randomness in its template or sensitive data to not be identical to the previous one.
         let mut initial = [0u8;32];
-        rand::thread_rng().fill_bytes(&mut initial);
+        rng::fill_bytes(&mut initial);
+        let (public, sensitive_data) = match template.algorithm {
+            PrimaryKeyAlgorithm::Aes => {
+                let public = builder
+                    .with_public_algorithm(PublicAlgorithm::SymCipher)
+                    .with_symmetric_cipher_parameters(SymmetricCipherParameters::new(template.symmetric()))
+                    .with_symmetric_cipher_unique_identifier(Digest::default())
+                    .build()?;
+                (public, Some(initial.to_vec().try_into()?))
+            },
+            PrimaryKeyAlgorithm::Ecc => {
+                let public = builder
+                    .with_public_algorithm(PublicAlgorithm::Ecc)
+                    .with_ecc_parameters(
+                        PublicEccParametersBuilder::new_restricted_decryption_key(
+                            template.symmetric(),
+                            EccCurve::NistP256,
+                        ).build()?
+                    )
+                    .with_ecc_unique_identifier(EccPoint::new(initial.to_vec().try_into()?, EccParameter::default()))
+                    .build()?;
+                (public, None)
+            },
+            PrimaryKeyAlgorithm::Rsa => {
+                let public = builder
+                    .with_public_algorithm(PublicAlgorithm::Rsa)
+                    .with_rsa_parameters(
+                        PublicRsaParametersBuilder::new_restricted_decryption_key(
+                            template.symmetric(),
+                            RsaKeyBits::Rsa2048,
+                            RsaExponent::default(),
+                        ).build()?
+                    )
+                    .with_rsa_unique_identifier(initial.to_vec().try_into()?)
+                    .build()?;
+                (public, None)
+            },
+        };
 
-        self.0.execute_with_nullauth_session(|ctx| {
+        let handles = self.4;
+        self.execute_with_hmac_session(|ctx| {
+            let persistent_handle = find_free_persistent_handle(ctx, handles)?
+                .ok_or(Error::NoFreePersistentHandle(handles))?;
             let cpkr = ctx.create_primary(
                 Hierarchy::Owner,
                 public,
                 Some(auth_value.clone()),
-                Some(initial.to_vec().try_into().unwrap()),
+                sensitive_data,
                 None,
                 None,
             )?;
-            let persistent_handle = find_next_persistent_handle(ctx)?;
             ctx.evict_control(Provision::Owner, cpkr.key_handle.into(), persistent_handle)?;
             ctx.flush_context(cpkr.key_handle.into())?;
             Ok(persistent_handle)
@@ -119,7 +517,7 @@ impl TPM {
     }
 
     pub fn get_persistent_primary(&mut self, handle: u32, auth_value: Auth) -> Result<KeyHandle> {
-        self.0.execute_with_nullauth_session(|ctx| {
+        self.execute_with_hmac_session(|ctx| {
             let handle = ctx.tr_from_tpm_public(TpmHandle::Persistent(PersistentTpmHandle::new(handle)?))?;
             ctx.tr_set_auth(handle, auth_value)?;
             Ok(handle.into())
@@ -127,7 +525,7 @@ impl TPM {
     }
 
     pub fn delete_persistent_primary(&mut self, handle: u32, auth_value: Auth) -> Result<()> {
-        self.0.execute_with_nullauth_session(|ctx| {
+        self.execute_with_hmac_session(|ctx| {
             let persistent_handle = PersistentTpmHandle::new(handle)?;
             let object_handle = ctx.tr_from_tpm_public(TpmHandle::Persistent(persistent_handle))?;
             ctx.tr_set_auth(object_handle, auth_value)?;
@@ -140,55 +538,783 @@ impl TPM {
         })
     }
 
-    pub fn create_hmac_key(&mut self, primary_key: KeyHandle, key_material: &[u8]) -> Result<HmacKey> {
-        let hmac_key = self.0.execute_with_nullauth_session(|ctx| {
+    /// Creates an HMAC key from the given key material, wrapped under the primary key.
+    /// A migratable key can be duplicated to another TPM using duplicate_hmac_key; other keys are bound to this TPM.
+    pub fn create_hmac_key(&mut self, primary_key: KeyHandle, key_material: &[u8], migratable: bool) -> Result<HmacKey> {
+        self.create_hmac_key_with_auth(primary_key, key_material, migratable, None)
+    }
+
+    /// Like create_hmac_key, but protects the key with the given auth value (PIN).
+    /// The auth value is needed to generate HMACs with the key, but not to duplicate or rewrap it.
+    pub fn create_hmac_key_with_auth(
+        &mut self,
+        primary_key: KeyHandle,
+        key_material: &[u8],
+        migratable: bool,
+        auth_value: Option<Auth>,
+    ) -> Result<HmacKey> {
+        let branch = match migratable {
+            true => Some(self.migration_approver().cloned().map(MigrationBranch::Approver)),
+            false => None,
+        };
+        self.create_hmac_key_with_branch(primary_key, key_material, branch, auth_value)
+    }
+
+    /// Like create_hmac_key_with_auth, but creates a migratable key which can only be duplicated to the given new parent,
+    /// with no need for approval.
+    pub fn create_hmac_key_for_new_parent(
+        &mut self,
+        primary_key: KeyHandle,
+        key_material: &[u8],
+        new_parent: Public,
+        auth_value: Option<Auth>,
+    ) -> Result<HmacKey> {
+        self.create_hmac_key_with_branch(primary_key, key_material, Some(Some(MigrationBranch::NewParent(new_parent))), auth_value)
+    }
+
+    /// Creates an HMAC key, which is migratable with the given branch, if any, if migration is Some.
+    fn create_hmac_key_with_branch(
+        &mut self,
+        primary_key: KeyHandle,
+        key_material: &[u8],
+        migration: Option<Option<MigrationBranch>>,
+        auth_value: Option<Auth>,
+    ) -> Result<HmacKey> {
+        let migratable = migration.is_some();
+        let pv_verifier = self.3.as_ref().filter(|_| !migratable).map(|policy| policy.verifier.clone());
+        let auth_policy = match (&pv_verifier, migration) {
+            (_, Some(branch)) => self.migration_policy_digest(branch.as_ref())?,
+            (Some(verifier), None) => self.pv_policy_digest(verifier.clone(), auth_value.is_some())?,
+            (None, None) => Digest::default(),
+        };
+        let hmac_key = self.with_parent_auth(primary_key, |ctx| {
             ctx.create(
                 primary_key,
                 Public::KeyedHash {
                     object_attributes: ObjectAttributes::builder()
                         .with_sign_encrypt(true)
-                        .with_user_with_auth(true)
-                        .with_fixed_parent(true)
-                        .with_fixed_tpm(true)
+                        .with_user_with_auth(pv_verifier.is_none())
+                        .with_fixed_parent(!migratable)
+                        .with_fixed_tpm(!migratable)
                         .with_sensitive_data_origin(false)
                         .build()
                         .unwrap(),
                     name_hashing_algorithm: HashingAlgorithm::Sha256,
-                    auth_policy: Digest::default(),
+                    auth_policy: auth_policy.clone(),
                     parameters: PublicKeyedHashParameters::new(
                         KeyedHashScheme::Hmac { hmac_scheme: HmacScheme::new(HashingAlgorithm::Sha1) }
                     ),
                     unique: Digest::default(),
                 },
-                None,
+                auth_value.clone(),
                 Some(key_material.try_into()?),
                 None,
                 None
             )
         })?;
-        Ok(HmacKey::new(primary_key, hmac_key.out_public, hmac_key.out_private))
+        let hmac_key = HmacKey::new(primary_key, hmac_key.out_public, hmac_key.out_private);
+        Ok(match auth_value {
+            Some(auth_value) => hmac_key.with_auth_value(auth_value),
+            None => hmac_key,
+        })
+    }
+
+    /// Loads the given HMAC key under its primary key and immediately flushes it again,
+    /// to check that the key is still usable.
+    pub fn check_hmac_key(&mut self, hmac_key: &HmacKey) -> Result<()> {
+        let key_handle = self.with_parent_auth(hmac_key.primary_key, |ctx| {
+            ctx.load(hmac_key.primary_key, hmac_key.private.clone(), hmac_key.public.clone())
+        })?;
+        Ok(self.0.flush_context(key_handle.into())?)
+    }
+
+    /// Creates an RSA storage key under the primary key, for another TPM to duplicate keys to.
+    /// Only the public part of the key ever leaves this TPM.
+    pub fn create_migration_target(&mut self, primary_key: KeyHandle) -> Result<(Public, Private)> {
+        let target = self.with_parent_auth(primary_key, |ctx| {
+            let public = Public::builder()
+                .with_public_algorithm(PublicAlgorithm::Rsa)
+                .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+                .with_object_attributes(
+                    ObjectAttributes::builder()
+                        .with_decrypt(true)
+                        .with_restricted(true)
+                        .with_user_with_auth(true)
+                        .with_fixed_parent(true)
+                        .with_fixed_tpm(true)
+                        .with_sensitive_data_origin(true)
+                        .build()?
+                )
+                .with_rsa_parameters(
+                    PublicRsaParametersBuilder::new_restricted_decryption_key(
+                        SymmetricDefinitionObject::AES_128_CFB,
+                        RsaKeyBits::Rsa2048,
+                        RsaExponent::default(),
+                    ).build()?
+                )
+                .with_rsa_unique_identifier(PublicKeyRsa::default())
+                .build()?;
+            ctx.create(primary_key, public, None, None, None, None)
+        })?;
+        Ok((target.out_public, target.out_private))
     }
 
-    pub fn hmac(&mut self, hmac_key: HmacKey, buffer: MaxBuffer) -> tss_esapi::Result<Digest> {
-        self.0.execute_with_nullauth_session(|ctx| {
-            let key_handle = ctx.load(hmac_key.primary_key, hmac_key.private, hmac_key.public)?;
-            let result = ctx.hmac(key_handle.into(), buffer, HashingAlgorithm::Sha1);
-            ctx.flush_context(key_handle.into())?;
+    /// Duplicates the given migratable HMAC key to a migration target created by another TPM.
+    /// The returned duplicate and seed can only be imported by that TPM. Unless the key predates approvals or was
+    /// created for this target, the TPM refuses to duplicate it without the migration approver's approval of the target.
+    pub fn duplicate_hmac_key(
+        &mut self,
+        hmac_key: &HmacKey,
+        target: Public,
+        approval: Option<Signature>,
+    ) -> Result<(Private, EncryptedSecret)> {
+        let key_handle = self.with_parent_auth(hmac_key.primary_key, |ctx| {
+            ctx.load(hmac_key.primary_key, hmac_key.private.clone(), hmac_key.public.clone())
+        })?;
+        let target_handle = self.0.execute_without_session(|ctx| ctx.load_external_public(target.clone(), Hierarchy::Null));
+        let result = target_handle.map_err(Error::from).and_then(|target_handle| {
+            let key_policy = hmac_key.public.auth_policy();
+            let branch = MigrationBranch::NewParent(target);
+            let result = self.duplicate_key(key_handle, key_policy, target_handle.into(), &[branch], approval, SymmetricDefinitionObject::Null);
+            self.0.flush_context(target_handle.into())?;
             result
+        });
+        self.0.flush_context(key_handle.into())?;
+        let (_, duplicate, seed) = result?;
+        Ok((duplicate, seed))
+    }
+
+    /// Creates the migration approver: a signing key under the primary key, whose signatures approve
+    /// new parents for migratable HMAC keys created while it's set using set_migration_approver.
+    /// Like those keys, it can be re-wrapped under another primary key, but not duplicated to another TPM.
+    pub fn create_migration_approver(&mut self, primary_key: KeyHandle) -> Result<(Public, Private)> {
+        let auth_policy = self.migration_policy_digest(None)?;
+        let approver = self.with_parent_auth(primary_key, |ctx| {
+            let public = Public::builder()
+                .with_public_algorithm(PublicAlgorithm::Ecc)
+                .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+                .with_object_attributes(
+                    ObjectAttributes::builder()
+                        .with_sign_encrypt(true)
+                        .with_user_with_auth(true)
+                        .with_sensitive_data_origin(true)
+                        .build()?
+                )
+                .with_auth_policy(auth_policy.clone())
+                .with_ecc_parameters(
+                    PublicEccParametersBuilder::new_unrestricted_signing_key(
+                        EccScheme::EcDsa(HashScheme::new(HashingAlgorithm::Sha256)),
+                        EccCurve::NistP256,
+                    ).build()?
+                )
+                .with_ecc_unique_identifier(EccPoint::default())
+                .build()?;
+            ctx.create(primary_key, public, None, None, None, None)
+        })?;
+        Ok((approver.out_public, approver.out_private))
+    }
+
+    /// Sets the migration approver that migratable HMAC keys created from now on can only be duplicated
+    /// to another TPM with the approval of.
+    pub fn set_migration_approver(&mut self, approver: Public) {
+        self.7.insert(0, MigrationBranch::Approver(approver));
+    }
+
+    /// Returns the migration approver of migratable HMAC keys created from now on, if any.
+    pub fn migration_approver(&self) -> Option<&Public> {
+        self.7.iter().find_map(|branch| match branch {
+            MigrationBranch::Approver(approver) => Some(approver),
+            MigrationBranch::NewParent(_) => None,
         })
     }
+
+    /// Makes keys created with the given migration branch, e.g. on another TPM, duplicable by this one.
+    pub fn add_migration_branch(&mut self, branch: MigrationBranch) {
+        if !self.7.contains(&branch) {
+            self.7.push(branch);
+        }
+    }
+
+    /// Approves duplicating migratable HMAC keys to the given new parent, by signing the policy
+    /// selecting it with the given migration approver, wrapped under the primary key.
+    pub fn approve_new_parent(
+        &mut self,
+        primary_key: KeyHandle,
+        approver: (Public, Private),
+        new_parent: Public,
+    ) -> Result<Signature> {
+        let new_parent_name = self.name_of(new_parent)?;
+        let approved_policy = self.duplication_select_digest(&new_parent_name)?;
+        // aHash for an empty policyRef.
+        let a_hash = self.sha256(approved_policy.value())?;
+        let (public, private) = approver;
+        let key_handle = self.with_parent_auth(primary_key, |ctx| ctx.load(primary_key, private.clone(), public.clone()))?;
+        let validation = TPMT_TK_HASHCHECK {
+            tag: TPM2_ST_HASHCHECK,
+            hierarchy: TPM2_RH_NULL,
+            digest: Default::default(),
+        };
+        let result = self.execute_with_hmac_session(|ctx| {
+            ctx.sign(key_handle, a_hash.clone(), SignatureScheme::Null, validation.try_into()?)
+        });
+        self.0.flush_context(key_handle.into())?;
+        Ok(result?)
+    }
+
+    /// Imports an HMAC key duplicated to the given migration target, and re-wraps it under the primary key.
+    /// The key stays migratable, and its key material never leaves the TPM unencrypted.
+    pub fn import_hmac_key(
+        &mut self,
+        primary_key: KeyHandle,
+        target: (Public, Private),
+        public: Public,
+        duplicate: Private,
+        seed: EncryptedSecret,
+    ) -> Result<HmacKey> {
+        let (target_public, target_private) = target;
+        let target_handle = self.with_parent_auth(primary_key, |ctx| {
+            ctx.load(primary_key, target_private.clone(), target_public.clone())
+        })?;
+        let branch = MigrationBranch::NewParent(target_public);
+        let result = self.import_via(primary_key, target_handle, public.clone(), duplicate, seed, branch);
+        self.0.flush_context(target_handle.into())?;
+        Ok(HmacKey::new(primary_key, public, result?))
+    }
+
+    /// Imports the given duplicate under the migration target, then moves it to the primary key.
+    /// The key may have been created for the target, rather than with the approver of its old TPM.
+    fn import_via(
+        &mut self,
+        primary_key: KeyHandle,
+        target_handle: KeyHandle,
+        public: Public,
+        duplicate: Private,
+        seed: EncryptedSecret,
+        target_branch: MigrationBranch,
+    ) -> Result<Private> {
+        let private = retry_transient(|| self.execute_with_hmac_session(|ctx| {
+            ctx.import(target_handle.into(), None, public.clone(), duplicate.clone(), seed.clone(), SymmetricDefinitionObject::Null)
+        }))?;
+        self.rewrap(target_handle, private, public, primary_key, &[target_branch])
+    }
+
+    /// Re-wraps the given migratable HMAC key under another primary key on this TPM.
+    pub fn rewrap_hmac_key(&mut self, hmac_key: &HmacKey, new_primary_key: KeyHandle) -> Result<HmacKey> {
+        let private = self.rewrap(hmac_key.primary_key, hmac_key.private.clone(), hmac_key.public.clone(), new_primary_key, &[])?;
+        Ok(HmacKey::new(new_primary_key, hmac_key.public.clone(), private))
+    }
+
+    /// Moves the given key from one parent to another on this TPM, returning its private part under the new parent.
+    /// Since primary keys are symmetric, this uses a duplicate with only an inner wrapper,
+    /// whose key is generated by the TPM and discarded right after the import.
+    fn rewrap(
+        &mut self,
+        parent: KeyHandle,
+        private: Private,
+        public: Public,
+        new_parent: KeyHandle,
+        branches: &[MigrationBranch],
+    ) -> Result<Private> {
+        let key_handle = self.with_parent_auth(parent, |ctx| ctx.load(parent, private.clone(), public.clone()))?;
+        let key_policy = public.auth_policy();
+        let result = self.duplicate_key(key_handle, key_policy, ObjectHandle::Null, branches, None, SymmetricDefinitionObject::AES_128_CFB);
+        self.0.flush_context(key_handle.into())?;
+        let (inner_key, duplicate, seed) = result?;
+        self.with_parent_auth(new_parent, |ctx| {
+            ctx.import(
+                new_parent.into(),
+                Some(inner_key.clone()),
+                public.clone(),
+                duplicate.clone(),
+                seed.clone(),
+                SymmetricDefinitionObject::AES_128_CFB,
+            )
+        })
+    }
+
+    /// Duplicates the given key to the given new parent, or to no parent, satisfying the duplication policy
+    /// the key was created with. Keys created before migration targets had to be approved can be duplicated anywhere.
+    /// Other keys need PolicyDuplicationSelect of the new parent and, if it's neither no parent nor the one
+    /// the key was created for, an approval of that policy by the key's migration approver.
+    /// The key's migration branch is looked for among the given ones and the ones this TPM knows of.
+    fn duplicate_key(
+        &mut self,
+        key_handle: KeyHandle,
+        key_policy: &Digest,
+        new_parent: ObjectHandle,
+        branches: &[MigrationBranch],
+        approval: Option<Signature>,
+        inner_wrapper: SymmetricDefinitionObject,
+    ) -> Result<(Data, Private, EncryptedSecret)> {
+        if *key_policy == self.execute_with_hmac_session(legacy_duplication_policy)? {
+            log::warn!("key predates approved migration targets; duplicating it without approval");
+            return Ok(duplicate_with_policy(&mut self.0, key_handle.into(), new_parent, inner_wrapper)?)
+        }
+
+        let object_name = self.0.tr_get_name(key_handle.into())?;
+        let new_parent_name = match new_parent {
+            ObjectHandle::Null => null_name()?,
+            new_parent => self.0.tr_get_name(new_parent)?,
+        };
+        let rewrap_policy = self.rewrap_policy_digest()?;
+        let mut branch = None;
+        if *key_policy != rewrap_policy {
+            let candidates = branches.iter().chain(self.7.clone().iter()).cloned().collect::<Vec<_>>();
+            for candidate in candidates {
+                let branch_policy = self.branch_policy_digest(&candidate)?;
+                if *key_policy == self.policy_or_digest(&[&rewrap_policy, &branch_policy])? {
+                    branch = Some((candidate, branch_policy));
+                    break;
+                }
+            }
+            if branch.is_none() {
+                return Err(Error::MigrationApproverUnavailable)
+            }
+        }
+
+        let authorization = match (&branch, new_parent) {
+            (Some((MigrationBranch::Approver(approver), _)), new_parent) if new_parent != ObjectHandle::Null => {
+                let signature = approval.ok_or(Error::MigrationNotApproved)?;
+                let approver_name = self.name_of(approver.clone())?;
+                let approved_policy = self.duplication_select_digest(&new_parent_name)?;
+                let a_hash = self.sha256(approved_policy.value())?;
+                let approver_handle = self.0.execute_without_session(|ctx| {
+                    ctx.load_external_public(approver.clone(), Hierarchy::Owner)
+                })?;
+                let ticket = self.0.execute_without_session(|ctx| ctx.verify_signature(approver_handle, a_hash, signature));
+                self.0.flush_context(approver_handle.into())?;
+                Some((approved_policy, approver_name, ticket.or(Err(Error::MigrationNotApproved))?))
+            },
+            _ => None,
+        };
+
+        let session = start_policy_session(&mut self.0, SessionType::Policy, HashingAlgorithm::Sha256)?;
+        let result = self.0.policy_duplication_select(session, object_name, new_parent_name, false)
+            .and_then(|_| match authorization {
+                Some((approved_policy, approver_name, ticket)) => {
+                    self.0.policy_authorize(session, approved_policy, Nonce::default(), &approver_name, ticket)
+                },
+                None => Ok(()),
+            })
+            .and_then(|_| match branch {
+                Some((_, branch_policy)) => {
+                    let mut digests = DigestList::new();
+                    digests.add(rewrap_policy)?;
+                    digests.add(branch_policy)?;
+                    self.0.policy_or(session, digests)
+                },
+                None => Ok(()),
+            })
+            .and_then(|_| self.0.execute_with_session(Some(session.into()), |ctx| {
+                ctx.duplicate(key_handle.into(), new_parent, None, inner_wrapper)
+            }));
+        self.0.flush_context(SessionHandle::from(session).into())?;
+        result.map_err(|e| policy_error(e, Error::MigrationNotApproved))
+    }
+
+    /// Computes the policy of migratable keys: they can be duplicated to no parent at all, in order to re-wrap them
+    /// under another primary key, or as allowed by the given migration branch, if any.
+    fn migration_policy_digest(&mut self, branch: Option<&MigrationBranch>) -> Result<Digest> {
+        let rewrap_policy = self.rewrap_policy_digest()?;
+        match branch {
+            Some(branch) => {
+                let branch_policy = self.branch_policy_digest(branch)?;
+                Ok(self.policy_or_digest(&[&rewrap_policy, &branch_policy])?)
+            },
+            None => Ok(rewrap_policy),
+        }
+    }
+
+    /// Computes the policy of the given migration branch: PolicyAuthorize by the approver, without policyRef,
+    /// or PolicyDuplicationSelect of the new parent.
+    fn branch_policy_digest(&mut self, branch: &MigrationBranch) -> tss_esapi::Result<Digest> {
+        match branch {
+            MigrationBranch::Approver(approver) => {
+                let approver_name = self.name_of(approver.clone())?;
+                let digest = self.sha256(&[
+                    &[0u8; 32][..],
+                    &TPM_CC_POLICY_AUTHORIZE.to_be_bytes(),
+                    approver_name.value(),
+                ].concat())?;
+                self.sha256(digest.value())
+            },
+            MigrationBranch::NewParent(new_parent) => {
+                let new_parent_name = self.name_of(new_parent.clone())?;
+                self.duplication_select_digest(&new_parent_name)
+            },
+        }
+    }
+
+    /// Computes PolicyDuplicationSelect of no new parent, which allows re-wrapping a key under another primary key.
+    fn rewrap_policy_digest(&mut self) -> tss_esapi::Result<Digest> {
+        self.duplication_select_digest(&null_name()?)
+    }
+
+    /// Computes PolicyDuplicationSelect of the given new parent, without including the name of the duplicated key.
+    fn duplication_select_digest(&mut self, new_parent_name: &Name) -> tss_esapi::Result<Digest> {
+        self.sha256(&[
+            &[0u8; 32][..],
+            &TPM_CC_POLICY_DUPLICATION_SELECT.to_be_bytes(),
+            new_parent_name.value(),
+            &[0u8],
+        ].concat())
+    }
+
+    /// Computes PolicyOR of the given branches.
+    fn policy_or_digest(&mut self, branches: &[&Digest]) -> tss_esapi::Result<Digest> {
+        let mut data = [&[0u8; 32][..], &TPM_CC_POLICY_OR.to_be_bytes()].concat();
+        for branch in branches {
+            data.extend_from_slice(branch.value());
+        }
+        self.sha256(&data)
+    }
+
+    /// Computes an HMAC with the given key. If the key carries a presence verification policy,
+    /// the configured signer is asked to authorize this particular HMAC first.
+    pub fn hmac(&mut self, hmac_key: HmacKey, buffer: MaxBuffer) -> Result<Digest> {
+        let needs_pv_policy = !hmac_key.public.object_attributes().user_with_auth();
+        let has_auth_value = hmac_key.auth_value.is_some();
+        let key_handle = self.with_parent_auth(hmac_key.primary_key, |ctx| {
+            ctx.load(hmac_key.primary_key, hmac_key.private.clone(), hmac_key.public.clone())
+        })?;
+        if let Some(auth_value) = hmac_key.auth_value {
+            self.0.tr_set_auth(key_handle.into(), auth_value)?;
+        }
+        let result = if needs_pv_policy {
+            self.hmac_with_pv_policy(key_handle, has_auth_value, buffer)
+        } else {
+            retry_transient(|| self.execute_with_hmac_session(|ctx| {
+                ctx.hmac(key_handle.into(), buffer.clone(), HashingAlgorithm::Sha1)
+            }))
+                .map_err(Error::from)
+        };
+        self.0.flush_context(key_handle.into())?;
+        result
+    }
+
+    /// Computes an HMAC with a key carrying a presence verification policy, using a policy session
+    /// satisfied by the verifier's signature. The signature covers the command parameters (cpHash),
+    /// so it authorizes this HMAC of this key and nothing else.
+    fn hmac_with_pv_policy(&mut self, key_handle: KeyHandle, with_auth_value: bool, buffer: MaxBuffer) -> Result<Digest> {
+        let verifier = self.3.as_ref().ok_or(Error::PvPolicyUnavailable)?.verifier.clone();
+        let key_name = self.0.tr_get_name(key_handle.into())?;
+        let cp_hash = self.sha256(&[
+            &TPM_CC_HMAC.to_be_bytes()[..],
+            key_name.value(),
+            &(buffer.len() as u16).to_be_bytes(),
+            buffer.value(),
+            &TPM_ALG_SHA1.to_be_bytes(),
+        ].concat())?;
+        // aHash for an empty nonceTPM and policyRef, and no expiration. Binding the approval to the policy session
+        // would need the session's nonceTPM, which tss-esapi 7.5 has no way of getting (Esys_TRSess_GetNonceTPM isn't
+        // wrapped), and without a nonce, the TPM measures expiration from its absolute time since startup rather than
+        // from when the session was started, so setting one would make approvals fail once the machine has been up
+        // for that long. Until then, a replayed approval can only reproduce the code of the time step it was given for,
+        // since cpHash covers the counter.
+        let a_hash = self.sha256(&[&0u32.to_be_bytes()[..], cp_hash.value()].concat())?;
+        let signature = match self.3.as_mut().unwrap().signer.sign(&a_hash)? {
+            Some(signature) => signature,
+            None => return Err(Error::PresenceVerificationFailed),
+        };
+
+        let verifier_handle = self.0.execute_without_session(|ctx| ctx.load_external_public(verifier, Hierarchy::Null))?;
+        let session = start_policy_session(&mut self.0, SessionType::Policy, HashingAlgorithm::Sha256)?;
+        let result = self.execute_with_hmac_session(|ctx| ctx.policy_signed(
+            session,
+            verifier_handle.into(),
+            Nonce::default(),
+            cp_hash,
+            Nonce::default(),
+            None,
+            signature,
+        ))
+            .and_then(|_| if with_auth_value { self.0.policy_auth_value(session) } else { Ok(()) })
+            .and_then(|_| retry_transient(|| self.0.execute_with_session(Some(session.into()), |ctx| {
+                ctx.hmac(key_handle.into(), buffer.clone(), HashingAlgorithm::Sha1)
+            })));
+        self.0.flush_context(SessionHandle::from(session).into())?;
+        self.0.flush_context(verifier_handle.into())?;
+        // PolicySigned itself fails if the signature doesn't verify; anything else fails the HMAC.
+        result.map_err(|e| match e {
+            tss_esapi::Error::Tss2Error(rc) if rc.kind() == Some(Tss2ResponseCodeKind::Signature) => Error::PvPolicyFailed,
+            e => policy_error(e, Error::PvPolicyFailed),
+        })
+    }
+
+    /// Computes the policy of HMAC keys requiring a signature from the given verifier,
+    /// i.e. PolicySigned without policyRef, followed by PolicyAuthValue if the key has a PIN.
+    fn pv_policy_digest(&mut self, verifier: Public, with_auth_value: bool) -> Result<Digest> {
+        let verifier_name = self.name_of(verifier)?;
+        let digest = self.sha256(&[&[0u8; 32][..], &TPM_CC_POLICY_SIGNED.to_be_bytes(), verifier_name.value()].concat())?;
+        let mut digest = self.sha256(digest.value())?;
+        if with_auth_value {
+            digest = self.sha256(&[digest.value(), &TPM_CC_POLICY_AUTH_VALUE.to_be_bytes()].concat())?;
+        }
+        Ok(digest)
+    }
+
+    /// Returns the name of the given public key, as computed by the TPM.
+    fn name_of(&mut self, public: Public) -> tss_esapi::Result<Name> {
+        let handle = self.0.execute_without_session(|ctx| ctx.load_external_public(public, Hierarchy::Null))?;
+        let name = self.0.tr_get_name(handle.into());
+        self.0.flush_context(handle.into())?;
+        name
+    }
+
+    fn sha256(&mut self, data: &[u8]) -> tss_esapi::Result<Digest> {
+        let data = MaxBuffer::try_from(data.to_vec())?;
+        let (digest, _) = self.0.execute_without_session(|ctx| ctx.hash(data, HashingAlgorithm::Sha256, Hierarchy::Null))?;
+        Ok(digest)
+    }
+
+    pub fn create_symmetric_key(&mut self, primary_key: KeyHandle) -> Result<SymmetricKey> {
+        let symmetric_key = self.with_parent_auth(primary_key, |ctx| {
+            let public = Public::builder()
+                .with_public_algorithm(PublicAlgorithm::SymCipher)
+                .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+                .with_object_attributes(
+                    ObjectAttributes::builder()
+                        .with_decrypt(true)
+                        .with_sign_encrypt(true)
+                        .with_user_with_auth(true)
+                        .with_fixed_parent(true)
+                        .with_fixed_tpm(true)
+                        .with_sensitive_data_origin(true)
+                        .build()?
+                )
+                .with_symmetric_cipher_parameters(SymmetricCipherParameters::new(SymmetricDefinitionObject::AES_256_CFB))
+                .with_symmetric_cipher_unique_identifier(Digest::default())
+                .build()?;
+            ctx.create(primary_key, public, None, None, None, None)
+        })?;
+        Ok(SymmetricKey::new(primary_key, symmetric_key.out_public, symmetric_key.out_private))
+    }
+
+    /// Encrypts or decrypts the given data with AES-CFB, using the given key and IV.
+    pub fn encrypt_decrypt(
+        &mut self,
+        key: &SymmetricKey,
+        decrypt: bool,
+        iv: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let key_handle = self.with_parent_auth(key.primary_key, |ctx| {
+            ctx.load(key.primary_key, key.private.clone(), key.public.clone())
+        })?;
+        let result = retry_transient(|| {
+            self.execute_with_hmac_session(|ctx| encrypt_decrypt_chunks(ctx, key_handle, decrypt, iv, data))
+        });
+        self.0.flush_context(key_handle.into())?;
+        Ok(result?)
+    }
+
+    /// Executes a single command authorized by the given parent key. If the parent is bound to PCRs,
+    /// the command is executed in a policy session satisfying the binding; otherwise in an HMAC session.
+    /// The command is retried if the TPM is temporarily unable to process it.
+    /// Since the PCR policy session is the only policy session involved, a policy failure means the PCRs have changed.
+    fn with_parent_auth<T, F>(&mut self, parent: KeyHandle, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Context) -> tss_esapi::Result<T>,
+    {
+        retry_transient(|| self.with_parent_auth_once(parent, &mut f))
+            .map_err(|e| policy_error(e, Error::PcrPolicyFailed))
+    }
+
+    fn with_parent_auth_once<T, F>(&mut self, parent: KeyHandle, f: &mut F) -> tss_esapi::Result<T>
+    where
+        F: FnMut(&mut Context) -> tss_esapi::Result<T>,
+    {
+        let pcrs = match &self.1 {
+            Some(pcrs) => pcrs.clone(),
+            None => return self.execute_with_hmac_session(f),
+        };
+        let (public, _, _) = self.0.execute_without_session(|ctx| ctx.read_public(parent))?;
+        if public.object_attributes().user_with_auth() {
+            return self.execute_with_hmac_session(f)
+        }
+
+        let session = start_policy_session(&mut self.0, SessionType::Policy, public.name_hashing_algorithm())?;
+        let (attributes, mask) = SessionAttributesBuilder::new()
+            .with_decrypt(true)
+            .with_encrypt(true)
+            .build();
+        let result = self.0.tr_sess_set_attributes(session.into(), attributes, mask)
+            .and_then(|_| pcr_policy(&mut self.0, session, pcrs))
+            .and_then(|_| self.0.execute_with_session(Some(session.into()), f));
+        self.0.flush_context(SessionHandle::from(session).into())?;
+        result
+    }
+
+    /// Executes commands in an HMAC session, like Context::execute_with_nullauth_session, but reuses one session
+    /// for the lifetime of the context rather than starting and flushing a new one every time.
+    /// If the commands fail, the session is flushed, so that a session the TPM has given up on is never reused.
+    fn execute_with_hmac_session<T, E, F>(&mut self, f: F) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut Context) -> std::result::Result<T, E>,
+        E: From<tss_esapi::Error>,
+    {
+        let session = match self.5 {
+            Some(session) => session,
+            None => {
+                let session = self.0.start_auth_session(
+                    None,
+                    None,
+                    None,
+                    SessionType::Hmac,
+                    SymmetricDefinition::AES_128_CFB,
+                    HashingAlgorithm::Sha256,
+                )?.ok_or(tss_esapi::Error::WrapperError(WrapperErrorKind::WrongValueFromTpm))?;
+                let (attributes, mask) = SessionAttributesBuilder::new()
+                    .with_continue_session(true)
+                    .with_decrypt(true)
+                    .with_encrypt(true)
+                    .build();
+                if let Err(e) = self.0.tr_sess_set_attributes(session, attributes, mask) {
+                    self.0.flush_context(SessionHandle::from(session).into())?;
+                    return Err(e.into())
+                }
+                self.5 = Some(session);
+                session
+            },
+        };
+        let result = self.0.execute_with_session(Some(session), f);
+        if result.is_err() {
+            self.5 = None;
+            if let Err(e) = self.0.flush_context(SessionHandle::from(session).into()) {
+                log::info!("unable to flush hmac session: {}", e);
+            }
+        }
+        result
+    }
+}
+
+/// Runs the given operation, retrying it with exponential backoff for as long as the TPM or resource manager
+/// reports that it's temporarily unable to process it, up to MAX_RETRIES times.
+fn retry_transient<T>(mut f: impl FnMut() -> tss_esapi::Result<T>) -> tss_esapi::Result<T> {
+    let mut delay = INITIAL_RETRY_DELAY;
+    for _ in 0..MAX_RETRIES {
+        match f() {
+            Err(err) if is_transient(&err) => {
+                log::info!("tpm is busy ({}); retrying in {} ms", err, delay.as_millis());
+                std::thread::sleep(delay);
+                delay *= 2;
+            },
+            result => return result,
+        }
+    }
+    f()
+}
+
+/// Returns true if the given error means that the command was not executed, but may succeed if retried:
+/// TPM_RC_RETRY or TPM_RC_YIELDED from the TPM, or a try again response from the TCTI or resource manager.
+fn is_transient(err: &tss_esapi::Error) -> bool {
+    match err {
+        tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatZero(FormatZeroResponseCode(code))) => {
+            let (layer, base) = (code >> 16, code & 0xffff);
+            base == TPM_RC_RETRY || base == TPM_RC_YIELDED || (layer != 0 && base == TSS2_BASE_RC_TRY_AGAIN)
+        },
+        _ => false,
+    }
+}
+
+/// The TPM can only process MaxBuffer::MAX_SIZE bytes at a time, so longer data is processed in chunks,
+/// chaining the IV returned by the TPM into the next chunk.
+fn encrypt_decrypt_chunks(
+    ctx: &mut Context,
+    key_handle: KeyHandle,
+    decrypt: bool,
+    iv: &[u8],
+    data: &[u8],
+) -> tss_esapi::Result<Vec<u8>> {
+    let mut iv = InitialValue::try_from(iv)?;
+    let mut output = Vec::with_capacity(data.len());
+    for chunk in data.chunks(MaxBuffer::MAX_SIZE) {
+        let (out_data, next_iv) = ctx.encrypt_decrypt_2(
+            key_handle,
+            decrypt,
+            SymmetricMode::Cfb,
+            MaxBuffer::try_from(chunk)?,
+            iv,
+        )?;
+        output.extend_from_slice(&out_data);
+        iv = next_iv;
+    }
+    Ok(output)
+}
+
+/// Starts a policy or trial session, whose policy digest is computed using the given hash algorithm.
+fn start_policy_session(ctx: &mut Context, session_type: SessionType, hash: HashingAlgorithm) -> tss_esapi::Result<PolicySession> {
+    let session = ctx.start_auth_session(
+        None,
+        None,
+        None,
+        session_type,
+        SymmetricDefinition::AES_128_CFB,
+        hash,
+    )?.ok_or(tss_esapi::Error::WrapperError(WrapperErrorKind::WrongValueFromTpm))?;
+    PolicySession::try_from(session)
+}
+
+/// Computes the policy migratable keys were created with before migration targets had to be approved,
+/// which allows a key to be duplicated to any new parent, and nothing else.
+fn legacy_duplication_policy(ctx: &mut Context) -> tss_esapi::Result<Digest> {
+    let session = start_policy_session(ctx, SessionType::Trial, HashingAlgorithm::Sha256)?;
+    let result = ctx.policy_command_code(session, CommandCode::Duplicate)
+        .and_then(|_| ctx.policy_get_digest(session));
+    ctx.flush_context(SessionHandle::from(session).into())?;
+    result
+}
+
+/// Extends the given policy or trial session with the policy binding primary keys to the given PCRs' current values,
+/// and to their auth value.
+fn pcr_policy(ctx: &mut Context, session: PolicySession, pcrs: PcrSelectionList) -> tss_esapi::Result<()> {
+    ctx.policy_pcr(session, Digest::default(), pcrs)?;
+    ctx.policy_auth_value(session)
+}
+
+/// Maps TPM_RC_POLICY_FAIL to the given error for the policy that failed. The TPM reports policy failures
+/// as failures of the command the policy session authorizes, so only the caller knows which policy it was.
+fn policy_error(error: tss_esapi::Error, policy_failed: Error) -> Error {
+    match error {
+        tss_esapi::Error::Tss2Error(rc) if rc.kind() == Some(Tss2ResponseCodeKind::PolicyFail) => policy_failed,
+        error => Error::from(error),
+    }
+}
+
+/// Duplicates the given object, satisfying the legacy duplication policy.
+fn duplicate_with_policy(
+    ctx: &mut Context,
+    object: ObjectHandle,
+    new_parent: ObjectHandle,
+    inner_wrapper: SymmetricDefinitionObject,
+) -> tss_esapi::Result<(Data, Private, EncryptedSecret)> {
+    let session = start_policy_session(ctx, SessionType::Policy, HashingAlgorithm::Sha256)?;
+    let result = ctx.policy_command_code(session, CommandCode::Duplicate)
+        .and_then(|_| ctx.execute_with_session(Some(session.into()), |ctx| {
+            ctx.duplicate(object, new_parent, None, inner_wrapper)
+        }));
+    ctx.flush_context(SessionHandle::from(session).into())?;
+    result
+}
+
+/// Returns the name of TPM_RH_NULL, i.e. of no new parent at all.
+fn null_name() -> tss_esapi::Result<Name> {
+    Name::try_from(TPM_RH_NULL.to_be_bytes().to_vec())
 }
 
-fn find_next_persistent_handle(ctx: &mut Context) -> tss_esapi::Result<Persistent> {
-    let persistent_handle_start = 0x81000000u32;
-    let persistent_handle_end = 0x8100FFFFu32;
-    for h in persistent_handle_start .. persistent_handle_end {
+/// Returns the first of the given persistent handles which is not in use, if any.
+fn find_free_persistent_handle(ctx: &mut Context, handles: PersistentHandles) -> tss_esapi::Result<Option<Persistent>> {
+    let (first, last) = match handles {
+        PersistentHandles::Fixed(handle) => (handle, handle),
+        PersistentHandles::Range { first, last } => (first, last),
+    };
+    for h in first ..= last {
         let handle = PersistentTpmHandle::new(h)?;
         let result = ctx.tr_from_tpm_public(TpmHandle::Persistent(handle));
         match result.err() {
             Some(tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatOne(FormatOneResponseCode(0x18b)))) => {
                 // unused handle found!
-                return Ok(Persistent::Persistent(handle));
+                return Ok(Some(Persistent::Persistent(handle)));
             },
             Some(e) => {
                 // something else went wrong
@@ -199,7 +1325,7 @@ fn find_next_persistent_handle(ctx: &mut Context) -> tss_esapi::Result<Persisten
             },
         }
     }
-    panic!("unable to find a free persistent handle")
+    Ok(None)
 }
 
 #[cfg(test)]
@@ -231,6 +1357,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn info_reports_manufacturer() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let info = tpm.info().unwrap();
+        assert!(!info.manufacturer.is_empty());
+        assert!(!info.firmware_version.is_empty());
+    }
+
+    #[test]
+    fn property_string_strips_padding() {
+        assert_eq!(property_string(&[0x49424d00]), "IBM");
+        assert_eq!(property_string(&[0x53572020, 0x2054504d, 0, 0]), "SW   TPM");
+    }
+
+    #[test]
+    fn is_transient_recognizes_retry_yielded_and_busy() {
+        let rc = |code| tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatZero(FormatZeroResponseCode(code)));
+        assert!(is_transient(&rc(TPM_RC_RETRY)));
+        assert!(is_transient(&rc(TPM_RC_YIELDED)));
+        assert!(is_transient(&rc(0xa0009)));
+        assert!(!is_transient(&rc(0x9)));
+        assert!(!is_transient(&rc(0x101)));
+        assert!(!is_transient(&tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatOne(FormatOneResponseCode(0x18b)))));
+    }
+
+    #[test]
+    fn retry_transient_retries_until_success() {
+        let mut attempts = 0;
+        let result = retry_transient(|| {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatZero(FormatZeroResponseCode(TPM_RC_RETRY)))),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn retry_transient_gives_up_on_persistent_errors() {
+        let mut attempts = 0;
+        let result: tss_esapi::Result<()> = retry_transient(|| {
+            attempts += 1;
+            Err(tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatOne(FormatOneResponseCode(0x18b))))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn policy_failures_are_mapped_to_the_given_error() {
+        let policy_fail = || tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatOne(FormatOneResponseCode(0x99d)));
+        let auth_fail = tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatOne(FormatOneResponseCode(0x98e)));
+        assert_eq!(policy_error(policy_fail(), Error::PvPolicyFailed), Error::PvPolicyFailed);
+        assert_eq!(policy_error(policy_fail(), Error::MigrationNotApproved), Error::MigrationNotApproved);
+        assert_eq!(policy_error(auth_fail, Error::PvPolicyFailed), Error::AuthFailed);
+        assert_eq!(Error::from(policy_fail()), Error::TpmError(policy_fail()));
+    }
+
     #[test]
     fn persistent_handle_can_be_loaded() {
         let swtpm = SwTpm::new();
@@ -245,6 +1432,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn asymmetric_primary_keys_can_wrap_hmac_keys_and_are_unique() {
+        for algorithm in [PrimaryKeyAlgorithm::Ecc, PrimaryKeyAlgorithm::Rsa] {
+            let swtpm = SwTpm::new();
+            let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+            let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+            tpm.set_primary_key_template(PrimaryKeyTemplate { algorithm, ..Default::default() });
+            let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+            let handle1 = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+            let primary1 = tpm.get_persistent_primary(handle1, auth_value.clone()).unwrap();
+            let handle2 = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+            let primary2 = tpm.get_persistent_primary(handle2, auth_value).unwrap();
+
+            let hmac_key = tpm.create_hmac_key(primary1, &[0,0,0,0,0,0,0,0,0,0], false).unwrap();
+            let other_primary = HmacKey::new(primary2, hmac_key.public.clone(), hmac_key.private.clone());
+            tpm.check_hmac_key(&other_primary).unwrap_err();
+            tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn pcr_bound_primary_key_can_use_other_name_hash() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        tpm.set_pcr_policy(&[16]).unwrap();
+        tpm.set_primary_key_template(PrimaryKeyTemplate { name_hash: NameHash::Sha384, no_da: true, ..Default::default() });
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
+        let hmac_key = tpm.create_hmac_key(primary_key, &[0,0,0,0,0,0,0,0,0,0], false).unwrap();
+        tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap();
+    }
+
     #[test]
     fn can_create_hmac_keys_with_primary_key() {
         let swtpm = SwTpm::new();
@@ -253,9 +1474,9 @@ mod tests {
         let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
         let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
         let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
-        tpm.create_hmac_key(primary_key, &vec![0,0,0,0,0,0,0,0,0,0]).unwrap();
-        tpm.create_hmac_key(primary_key, &vec![1,0,0,0,0,0,0,0,0,0]).unwrap();
-        tpm.create_hmac_key(primary_key, &vec![2,0,0,0,0,0,0,0,0,0]).unwrap();
+        tpm.create_hmac_key(primary_key, &vec![0,0,0,0,0,0,0,0,0,0], false).unwrap();
+        tpm.create_hmac_key(primary_key, &vec![1,0,0,0,0,0,0,0,0,0], false).unwrap();
+        tpm.create_hmac_key(primary_key, &vec![2,0,0,0,0,0,0,0,0,0], false).unwrap();
     }
 
     #[test]
@@ -266,12 +1487,209 @@ mod tests {
         let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
         let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
         let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
-        let hmac_key = tpm.create_hmac_key(primary_key, &vec![0,0,0,0,0,0,0,0,0,0]).unwrap();
+        let hmac_key = tpm.create_hmac_key(primary_key, &vec![0,0,0,0,0,0,0,0,0,0], false).unwrap();
         let actual_hmac = tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap();
         let expected_hmac = vec![182, 189, 192, 170, 215, 154, 110, 241, 228, 231, 163, 147, 13, 47, 3, 230, 196, 75, 126, 89];
         assert_eq!(actual_hmac.as_slice(), &expected_hmac)
     }
 
+    #[test]
+    fn migratable_hmac_key_can_be_moved_to_another_tpm() {
+        let source_swtpm = SwTpm::new();
+        let target_swtpm = SwTpm::new();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let mut source = TPM::new(Box::new(presence_verification::ConstPresenceVerifier::new(true)), &source_swtpm.tcti).unwrap();
+        let mut target = TPM::new(Box::new(presence_verification::ConstPresenceVerifier::new(true)), &target_swtpm.tcti).unwrap();
+        let source_handle = persistent_to_u32(source.create_persistent_primary(auth_value.clone()).unwrap());
+        let source_primary = source.get_persistent_primary(source_handle, auth_value.clone()).unwrap();
+        let target_handle = persistent_to_u32(target.create_persistent_primary(auth_value.clone()).unwrap());
+        let target_primary = target.get_persistent_primary(target_handle, auth_value).unwrap();
+
+        let approver = source.create_migration_approver(source_primary).unwrap();
+        source.set_migration_approver(approver.0.clone());
+        target.add_migration_branch(MigrationBranch::Approver(approver.0.clone()));
+        let migration_target = target.create_migration_target(target_primary).unwrap();
+        let hmac_key = source.create_hmac_key(source_primary, &[0,0,0,0,0,0,0,0,0,0], true).unwrap();
+        let approval = source.approve_new_parent(source_primary, approver, migration_target.0.clone()).unwrap();
+        let (duplicate, seed) = source.duplicate_hmac_key(&hmac_key, migration_target.0.clone(), Some(approval)).unwrap();
+        let imported = target.import_hmac_key(target_primary, migration_target, hmac_key.public.clone(), duplicate, seed).unwrap();
+
+        assert_eq!(
+            target.hmac(imported, "potato".as_bytes().try_into().unwrap()).unwrap(),
+            source.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn migratable_hmac_key_can_only_be_duplicated_to_approved_new_parent() {
+        let (swtpm, target_swtpm) = (SwTpm::new(), SwTpm::new());
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let mut target = TPM::new(Box::new(presence_verification::ConstPresenceVerifier::new(true)), &target_swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value.clone()).unwrap();
+        let target_handle = persistent_to_u32(target.create_persistent_primary(auth_value.clone()).unwrap());
+        let target_primary = target.get_persistent_primary(target_handle, auth_value).unwrap();
+        let approved = target.create_migration_target(target_primary).unwrap();
+        let unapproved = target.create_migration_target(target_primary).unwrap();
+
+        let approver = tpm.create_migration_approver(primary_key).unwrap();
+        tpm.set_migration_approver(approver.0.clone());
+        let hmac_key = tpm.create_hmac_key(primary_key, &[0,0,0,0,0,0,0,0,0,0], true).unwrap();
+        let approval = tpm.approve_new_parent(primary_key, approver, approved.0.clone()).unwrap();
+
+        assert_eq!(tpm.duplicate_hmac_key(&hmac_key, unapproved.0.clone(), None).unwrap_err(), Error::MigrationNotApproved);
+        tpm.duplicate_hmac_key(&hmac_key, unapproved.0, Some(approval.clone())).unwrap_err();
+        tpm.duplicate_hmac_key(&hmac_key, approved.0, Some(approval)).unwrap();
+    }
+
+    #[test]
+    fn hmac_key_for_new_parent_can_only_be_duplicated_to_it() {
+        let (swtpm, target_swtpm) = (SwTpm::new(), SwTpm::new());
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let mut target = TPM::new(Box::new(presence_verification::ConstPresenceVerifier::new(true)), &target_swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value.clone()).unwrap();
+        let target_handle = persistent_to_u32(target.create_persistent_primary(auth_value.clone()).unwrap());
+        let target_primary = target.get_persistent_primary(target_handle, auth_value).unwrap();
+        let new_parent = target.create_migration_target(target_primary).unwrap();
+        let other = target.create_migration_target(target_primary).unwrap();
+
+        let hmac_key = tpm.create_hmac_key_for_new_parent(primary_key, &[0,0,0,0,0,0,0,0,0,0], new_parent.0.clone(), None).unwrap();
+        tpm.duplicate_hmac_key(&hmac_key, other.0, None).unwrap_err();
+        let (duplicate, seed) = tpm.duplicate_hmac_key(&hmac_key, new_parent.0.clone(), None).unwrap();
+        let imported = target.import_hmac_key(target_primary, new_parent, hmac_key.public.clone(), duplicate, seed).unwrap();
+        assert_eq!(
+            target.hmac(imported, "potato".as_bytes().try_into().unwrap()).unwrap(),
+            tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn migratable_hmac_key_can_be_rewrapped_under_new_primary_key() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let old_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let old_primary = tpm.get_persistent_primary(old_handle, auth_value.clone()).unwrap();
+        let new_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let new_primary = tpm.get_persistent_primary(new_handle, auth_value.clone()).unwrap();
+
+        let hmac_key = tpm.create_hmac_key(old_primary, &[0,0,0,0,0,0,0,0,0,0], true).unwrap();
+        let rewrapped = tpm.rewrap_hmac_key(&hmac_key, new_primary).unwrap();
+        let expected_hmac = tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap();
+        tpm.delete_persistent_primary(old_handle, auth_value).unwrap();
+        assert_eq!(tpm.hmac(rewrapped, "potato".as_bytes().try_into().unwrap()).unwrap(), expected_hmac);
+    }
+
+    #[test]
+    fn hmac_key_with_auth_value_requires_correct_auth_value() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
+        let pin: Auth = "1234".as_bytes().try_into().unwrap();
+        let hmac_key = tpm.create_hmac_key_with_auth(primary_key, &[0,0,0,0,0,0,0,0,0,0], false, Some(pin.clone())).unwrap();
+        let public = hmac_key.public.clone();
+        let private = hmac_key.private.clone();
+        tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap();
+
+        let wrong_pin = HmacKey::new(primary_key, public.clone(), private.clone())
+            .with_auth_value("4321".as_bytes().try_into().unwrap());
+        let err = tpm.hmac(wrong_pin, "potato".as_bytes().try_into().unwrap()).unwrap_err();
+        assert_eq!(err, Error::AuthFailed);
+
+        let no_pin = HmacKey::new(primary_key, public, private);
+        tpm.hmac(no_pin, "potato".as_bytes().try_into().unwrap()).unwrap_err();
+    }
+
+    #[test]
+    fn hmac_key_with_pv_policy_requires_verifier_signature() {
+        let (swtpm, verifier_tpm) = (SwTpm::new(), SwTpm::new());
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
+        let plain_key = tpm.create_hmac_key(primary_key, &[0,0,0,0,0,0,0,0,0,0], false).unwrap();
+        let expected = tpm.hmac(plain_key, "potato".as_bytes().try_into().unwrap()).unwrap();
+        let (signer, verifier) = TpmSigner::new(&verifier_tpm.tcti);
+        let present = signer.present.clone();
+        tpm.set_pv_policy(verifier, Box::new(signer));
+        let pin: Auth = "1234".as_bytes().try_into().unwrap();
+        let hmac_key = tpm.create_hmac_key_with_auth(primary_key, &[0,0,0,0,0,0,0,0,0,0], false, Some(pin)).unwrap();
+        assert!(!hmac_key.public.object_attributes().user_with_auth());
+        let (public, private) = (hmac_key.public.clone(), hmac_key.private.clone());
+        assert_eq!(tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap(), expected);
+
+        present.set(false);
+        let hmac_key = HmacKey::new(primary_key, public.clone(), private.clone())
+            .with_auth_value("1234".as_bytes().try_into().unwrap());
+        let err = tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap_err();
+        assert_eq!(err, Error::PresenceVerificationFailed);
+
+        tpm.3 = None;
+        let hmac_key = HmacKey::new(primary_key, public, private)
+            .with_auth_value("1234".as_bytes().try_into().unwrap());
+        let err = tpm.hmac(hmac_key, "potato".as_bytes().try_into().unwrap()).unwrap_err();
+        assert_eq!(err, Error::PvPolicyUnavailable);
+    }
+
+    #[test]
+    fn pcr_bound_primary_key_is_unusable_after_pcr_change() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        tpm.set_pcr_policy(&[16]).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
+        let hmac_key = tpm.create_hmac_key(primary_key, &[0,0,0,0,0,0,0,0,0,0], false).unwrap();
+        tpm.check_hmac_key(&hmac_key).unwrap();
+
+        let mut digests = tss_esapi::structures::DigestValues::new();
+        digests.set(HashingAlgorithm::Sha256, Digest::try_from(vec![1u8; 32]).unwrap());
+        tpm.0.execute_with_nullauth_session(|ctx| ctx.pcr_extend(tss_esapi::handles::PcrHandle::Pcr16, digests)).unwrap();
+        assert_eq!(tpm.check_hmac_key(&hmac_key).unwrap_err(), Error::PcrPolicyFailed);
+    }
+
+    #[test]
+    fn fixed_hmac_key_cant_be_duplicated() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
+        let migration_target = tpm.create_migration_target(primary_key).unwrap();
+        let hmac_key = tpm.create_hmac_key(primary_key, &[0,0,0,0,0,0,0,0,0,0], false).unwrap();
+        tpm.duplicate_hmac_key(&hmac_key, migration_target.0, None).unwrap_err();
+    }
+
+    #[test]
+    fn symmetric_key_can_encrypt_and_decrypt_long_data() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        let primary_key = tpm.get_persistent_primary(key_handle, auth_value).unwrap();
+        let key = tpm.create_symmetric_key(primary_key).unwrap();
+        let iv = [7u8; 16];
+        let plaintext = (0..3000).map(|x| x as u8).collect::<Vec<u8>>();
+        let ciphertext = tpm.encrypt_decrypt(&key, false, &iv, &plaintext).unwrap();
+        assert_eq!(ciphertext.len(), plaintext.len());
+        assert_ne!(ciphertext, plaintext);
+        assert_eq!(tpm.encrypt_decrypt(&key, true, &iv, &ciphertext).unwrap(), plaintext);
+        assert_eq!(tpm.encrypt_decrypt(&key, false, &iv, &[]).unwrap(), Vec::<u8>::new());
+    }
+
     #[test]
     fn primary_key_with_wrong_auth_value_is_useless() {
         let swtpm = SwTpm::new();
@@ -281,7 +1699,7 @@ mod tests {
         let wrong_auth_value: Auth = "hella".as_bytes().try_into().unwrap();
         let key_handle = persistent_to_u32(tpm.create_persistent_primary(auth_value).unwrap());
         let primary_key = tpm.get_persistent_primary(key_handle, wrong_auth_value).unwrap();
-        let err = tpm.create_hmac_key(primary_key, &vec![0,0,0,0,0,0,0,0,0,0]).unwrap_err();
+        let err = tpm.create_hmac_key(primary_key, &vec![0,0,0,0,0,0,0,0,0,0], false).unwrap_err();
         match err {
             Error::TpmError(tss_esapi::Error::Tss2Error(Tss2ResponseCode::FormatOne(FormatOneResponseCode(code)))) => {
                 assert_eq!(code, 0x98e)
@@ -334,6 +1752,25 @@ mod tests {
         tpm.get_persistent_primary(key3, auth_value.clone()).unwrap();
     }
 
+    #[test]
+    fn primary_key_is_stored_at_configured_handle() {
+        let swtpm = SwTpm::new();
+        let pv = Box::new(presence_verification::ConstPresenceVerifier::new(true));
+        let mut tpm = TPM::new(pv, &swtpm.tcti).unwrap();
+        let auth_value: Auth = "hello".as_bytes().try_into().unwrap();
+        tpm.set_persistent_handles(PersistentHandles::Fixed(0x81000100));
+        let key = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        assert_eq!(key, 0x81000100);
+        assert_eq!(
+            tpm.create_persistent_primary(auth_value.clone()).unwrap_err(),
+            Error::NoFreePersistentHandle(PersistentHandles::Fixed(0x81000100)),
+        );
+
+        tpm.set_persistent_handles(PersistentHandles::Range { first: 0x81000100, last: 0x81000101 });
+        let key = persistent_to_u32(tpm.create_persistent_primary(auth_value.clone()).unwrap());
+        assert_eq!(key, 0x81000101);
+    }
+
     fn persistent_to_u32(p: Persistent) -> u32 {
         match p {
             tss_esapi::interface_types::dynamic_handles::Persistent::Persistent(persistent_tpm_handle) => {
@@ -342,6 +1779,50 @@ mod tests {
         }
     }
 
+    /// Signs authorizations using an ECDSA key in another TPM, if the user is "present".
+    struct TpmSigner {
+        ctx: Context,
+        key: KeyHandle,
+        present: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    impl TpmSigner {
+        fn new(tcti: &str) -> (Self, Public) {
+            let mut ctx = Context::new(TctiNameConf::from_str(tcti).unwrap()).unwrap();
+            ctx.startup(StartupType::Clear).unwrap();
+            let template = tss_esapi::utils::create_unrestricted_signing_ecc_public(
+                tss_esapi::structures::EccScheme::EcDsa(tss_esapi::structures::HashScheme::new(HashingAlgorithm::Sha256)),
+                EccCurve::NistP256,
+            ).unwrap();
+            let key = ctx.execute_with_nullauth_session(|ctx| {
+                ctx.create_primary(Hierarchy::Owner, template, None, None, None, None)
+            }).unwrap();
+            let signer = TpmSigner { ctx, key: key.key_handle, present: std::rc::Rc::new(std::cell::Cell::new(true)) };
+            (signer, key.out_public)
+        }
+    }
+
+    impl PresenceSigner for TpmSigner {
+        fn sign(&mut self, digest: &Digest) -> presence_verification::Result<Option<tss_esapi::structures::Signature>> {
+            if !self.present.get() {
+                return Ok(None)
+            }
+            let validation = tss_esapi::tss2_esys::TPMT_TK_HASHCHECK {
+                tag: tss_esapi::constants::tss::TPM2_ST_HASHCHECK,
+                hierarchy: tss_esapi::constants::tss::TPM2_RH_NULL,
+                digest: Default::default(),
+            };
+            let key = self.key;
+            let signature = self.ctx.execute_with_nullauth_session(|ctx| ctx.sign(
+                key,
+                digest.clone(),
+                tss_esapi::structures::SignatureScheme::Null,
+                validation.try_into()?,
+            )).unwrap();
+            Ok(Some(signature))
+        }
+    }
+
     struct FailingPresenceVerifier;
 
     impl PresenceVerifier for FailingPresenceVerifier {
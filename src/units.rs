@@ -0,0 +1,230 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use serde::{de::{self, Visitor}, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Duration units, from largest to smallest.
+const DURATION_UNITS: [(&str, u64); 5] = [
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("m", 60),
+    ("s", 1),
+];
+
+/// Size units, from largest to smallest.
+const SIZE_UNITS: [(&str, u64); 4] = [
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+    ("B", 1),
+];
+
+/// A duration with a resolution of one second, written as e.g. "30s", "5m" or "7d".
+/// A plain number is interpreted as seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanDuration(Duration);
+
+impl HumanDuration {
+    pub const fn from_secs(secs: u64) -> Self {
+        HumanDuration(Duration::from_secs(secs))
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secs = parse_with_units(s, &DURATION_UNITS, &[])
+            .ok_or(format!("invalid duration: '{}' (expected e.g. 30s, 5m, 1h, 7d or 2w)", s))?;
+        Ok(HumanDuration::from_secs(secs))
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format_with_units(self.0.as_secs(), &DURATION_UNITS))
+    }
+}
+
+/// A size in bytes, written as e.g. "512B", "64KiB" or "10MiB".
+/// A plain number is interpreted as bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub const fn from_bytes(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+
+    pub fn as_bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let aliases = [("G", "GiB"), ("M", "MiB"), ("K", "KiB")];
+        let bytes = parse_with_units(s, &SIZE_UNITS, &aliases)
+            .ok_or(format!("invalid size: '{}' (expected e.g. 512B, 64KiB or 10MiB)", s))?;
+        Ok(ByteSize(bytes))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format_with_units(self.0, &SIZE_UNITS))
+    }
+}
+
+/// Parses a number followed by an optional unit. Without a unit, the smallest unit is assumed.
+/// Returns None on malformed input, unknown units or overflow.
+fn parse_with_units(s: &str, units: &[(&str, u64)], aliases: &[(&str, &str)]) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number = number.parse::<u64>().ok()?;
+    let unit = unit.trim();
+    let unit = aliases.iter().find(|(alias, _)| *alias == unit).map(|(_, x)| *x).unwrap_or(unit);
+    let multiplier = if unit.is_empty() {
+        units.last()?.1
+    } else {
+        units.iter().find(|(name, _)| *name == unit)?.1
+    };
+    number.checked_mul(multiplier)
+}
+
+/// Formats the given value using the largest unit that represents it exactly.
+fn format_with_units(value: u64, units: &[(&str, u64)]) -> String {
+    let (name, multiplier) = units.iter()
+        .find(|(_, multiplier)| value > 0 && value.is_multiple_of(*multiplier))
+        .unwrap_or(units.last().unwrap());
+    format!("{}{}", value / multiplier, name)
+}
+
+/// Accepts both strings with units and plain integers, for compatibility with configs written
+/// before units were supported.
+struct UnitVisitor<T>(std::marker::PhantomData<T>);
+
+impl <'de, T: FromStr<Err = String> + From<u64>> Visitor<'de> for UnitVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a non-negative integer or a string with a unit suffix")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<T, E> {
+        Ok(T::from(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<T, E> {
+        u64::try_from(v)
+            .map(T::from)
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+        T::from_str(v).map_err(E::custom)
+    }
+}
+
+impl From<u64> for HumanDuration {
+    fn from(secs: u64) -> Self {
+        HumanDuration::from_secs(secs)
+    }
+}
+
+impl From<u64> for ByteSize {
+    fn from(bytes: u64) -> Self {
+        ByteSize::from_bytes(bytes)
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl <'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UnitVisitor(std::marker::PhantomData))
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl <'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UnitVisitor(std::marker::PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_derive::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Values {
+        timeout: HumanDuration,
+        size: ByteSize,
+    }
+
+    #[test]
+    fn durations_parse_with_and_without_units() {
+        assert_eq!(HumanDuration::from_str("45").unwrap(), HumanDuration::from_secs(45));
+        assert_eq!(HumanDuration::from_str("30s").unwrap(), HumanDuration::from_secs(30));
+        assert_eq!(HumanDuration::from_str("5m").unwrap(), HumanDuration::from_secs(300));
+        assert_eq!(HumanDuration::from_str(" 7d ").unwrap(), HumanDuration::from_secs(7 * 86400));
+        assert!(HumanDuration::from_str("").is_err());
+        assert!(HumanDuration::from_str("s").is_err());
+        assert!(HumanDuration::from_str("5y").is_err());
+        assert!(HumanDuration::from_str("-5s").is_err());
+        assert!(HumanDuration::from_str("99999999999999999999w").is_err());
+    }
+
+    #[test]
+    fn sizes_parse_with_and_without_units() {
+        assert_eq!(ByteSize::from_str("512").unwrap(), ByteSize::from_bytes(512));
+        assert_eq!(ByteSize::from_str("64KiB").unwrap(), ByteSize::from_bytes(64 * 1024));
+        assert_eq!(ByteSize::from_str("10M").unwrap(), ByteSize::from_bytes(10 * 1024 * 1024));
+        assert!(ByteSize::from_str("10 parsecs").is_err());
+    }
+
+    #[test]
+    fn values_are_formatted_using_largest_exact_unit() {
+        assert_eq!(HumanDuration::from_secs(0).to_string(), "0s");
+        assert_eq!(HumanDuration::from_secs(90).to_string(), "90s");
+        assert_eq!(HumanDuration::from_secs(3600).to_string(), "1h");
+        assert_eq!(HumanDuration::from_secs(14 * 86400).to_string(), "2w");
+        assert_eq!(ByteSize::from_bytes(1536).to_string(), "1536B");
+        assert_eq!(ByteSize::from_bytes(2048).to_string(), "2KiB");
+    }
+
+    #[test]
+    fn values_round_trip_through_toml() {
+        let values = Values { timeout: HumanDuration::from_secs(45), size: ByteSize::from_bytes(1 << 20) };
+        let toml_str = toml::to_string(&values).unwrap();
+        assert_eq!(toml_str, "timeout = \"45s\"\nsize = \"1MiB\"\n");
+        assert_eq!(toml::from_str::<Values>(&toml_str).unwrap(), values);
+    }
+
+    #[test]
+    fn plain_integers_deserialize_as_smallest_unit() {
+        let values: Values = toml::from_str("timeout = 10\nsize = 4096").unwrap();
+        assert_eq!(values.timeout, HumanDuration::from_secs(10));
+        assert_eq!(values.size, ByteSize::from_bytes(4096));
+        assert!(toml::from_str::<Values>("timeout = -1\nsize = 1").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use tempfile::TempDir;
+use testutil::tpm::SwTpm;
+use totpm::{config::Config, presence_verification::PresenceVerificationMethod, reference, totp_store::TotpStore};
+
+/// Test secrets of various lengths, including the RFC 6238 SHA-1 seed,
+/// a key longer than the HMAC block size and one with leading zero bytes.
+const SECRETS: [&[u8]; 5] = [
+    b"12345678901234567890",
+    b"\x00\x00\x00\x01",
+    b"hello",
+    &[0xff; 64],
+    &[0x5a; 80],
+];
+
+const TIMESTAMPS: [u64; 7] = [0, 59, 1111111109, 1111111111, 1234567890, 2000000000, 20000000000];
+
+#[test]
+fn tpm_codes_match_reference_implementation() {
+    let tempdir = TempDir::new().unwrap();
+    let swtpm = SwTpm::new();
+    let config = Config::default(
+        true,
+        swtpm.tcti.clone(),
+        Some(tempdir.path().join("sys")),
+        Some(tempdir.path().join("user")),
+        Some(PresenceVerificationMethod::None),
+    );
+    TotpStore::init(config.clone()).unwrap();
+    let mut store = TotpStore::with_tpm(config).unwrap();
+
+    for (i, secret) in SECRETS.iter().enumerate() {
+        for digits in [6, 7, 8] {
+            for interval in [30, 60] {
+                let stored = store.add(&format!("svc{}", i), "acct", Some(digits), Some(interval), secret).unwrap();
+                for timestamp in TIMESTAMPS {
+                    let time = UNIX_EPOCH + Duration::from_secs(timestamp);
+                    assert_eq!(
+                        store.gen(stored.id, time).unwrap(),
+                        reference::totp(secret, time, interval, digits),
+                        "secret {}, {} digits, interval {}, timestamp {}",
+                        i, digits, interval, timestamp,
+                    );
+                }
+            }
+        }
+    }
+}